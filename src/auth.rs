@@ -0,0 +1,174 @@
+use hmac::{
+    Hmac,
+    Mac,
+};
+use sha2::Sha256;
+use std::net::SocketAddr;
+use std::time::{
+    Duration,
+    SystemTime,
+    UNIX_EPOCH,
+};
+use crate::framing::{
+    PEER_INFO,
+    MIGRATE,
+};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Size of the timestamp leading the MAC of a Register, Join or Query: milliseconds since the Unix epoch, big endian.
+pub const TIMESTAMP_SIZE: usize = 8;
+/// How far the time at which the MAC of a Register, Join or Query was made may be from the receiver's clock,
+/// either way, for the MAC to be accepted. The clocks of peers and holepuncher must agree to within it.
+pub const MAX_MAC_AGE: Duration = Duration::from_secs(60);
+
+// Milliseconds since the Unix epoch, or 0 for times before it.
+fn unix_millis(time: SystemTime) -> u64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since_epoch) => u64::try_from(since_epoch.as_millis()).unwrap_or(u64::MAX),
+        Err(_) => 0,
+    }
+}
+
+// The MAC covers the message type as well, so that the MAC of a Join can't be replayed as a Register,
+// and the time it was made at, so that a captured one is only good for MAX_MAC_AGE.
+// The session ID and metadata are preceded by their lengths, and each candidate by a space, which no address contains,
+// so that bytes can't be moved from one to the next.
+fn keyed_mac(key: &[u8], msg_type: u16, timestamp: u64, session_id: &[u8], metadata: &[u8], candidates: &[SocketAddr]) -> HmacSha256 {
+    // HMAC accepts keys of any length
+    let mut mac = HmacSha256::new_from_slice(key).unwrap();
+    mac.update(&msg_type.to_be_bytes());
+    mac.update(&timestamp.to_be_bytes());
+    mac.update(&(session_id.len() as u16).to_be_bytes());
+    mac.update(session_id);
+    mac.update(&(metadata.len() as u16).to_be_bytes());
+    mac.update(metadata);
+    for candidate in candidates {
+        mac.update(b" ");
+        mac.update(candidate.to_string().as_bytes());
+    }
+    return mac;
+}
+
+/// Computes the MAC of a Register, Join or Query message for the given session, keyed with the pre-shared key.
+/// It covers the metadata and candidate addresses of the message as well, pass empty ones for messages without them.
+/// It is made up of the time it was made at (TIMESTAMP_SIZE bytes) followed by the HMAC over it.
+pub fn session_mac(key: &[u8], msg_type: u16, session_id: &[u8], metadata: &[u8], candidates: &[SocketAddr], made_at: SystemTime) -> Vec<u8> {
+    let timestamp = unix_millis(made_at);
+    let mut mac = timestamp.to_be_bytes().to_vec();
+    mac.extend_from_slice(&keyed_mac(key, msg_type, timestamp, session_id, metadata, candidates).finalize().into_bytes());
+    return mac;
+}
+
+/// Checks the MAC of a Register, Join or Query message in constant time, and that it was made within MAX_MAC_AGE of now.
+/// This does not catch a captured message replayed soon enough, the holepuncher remembers recent MACs for that.
+pub fn verify_session_mac(key: &[u8], msg_type: u16, session_id: &[u8], metadata: &[u8], candidates: &[SocketAddr], mac: &[u8], now: SystemTime) -> bool {
+    if mac.len() < TIMESTAMP_SIZE {
+        return false;
+    }
+    let (timestamp, mac) = mac.split_at(TIMESTAMP_SIZE);
+    let timestamp = u64::from_be_bytes(timestamp.try_into().unwrap());
+    if unix_millis(now).abs_diff(timestamp) > MAX_MAC_AGE.as_millis() as u64 {
+        return false;
+    }
+    keyed_mac(key, msg_type, timestamp, session_id, metadata, candidates).verify_slice(mac).is_ok()
+}
+
+// The MAC of a PeerInfo or Migrate covers the address as well, so that another address can't be slipped in
+// under a captured MAC. The session ID's length comes first, since the address follows it.
+fn addr_keyed_mac(key: &[u8], msg_type: u16, session_id: &[u8], addr: SocketAddr) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).unwrap();
+    mac.update(&msg_type.to_be_bytes());
+    mac.update(&(session_id.len() as u16).to_be_bytes());
+    mac.update(session_id);
+    mac.update(addr.to_string().as_bytes());
+    return mac;
+}
+
+// The candidates follow the peer address, each after a space, which no address contains.
+// Without candidates, the MAC is the same as before they existed.
+fn peer_info_keyed_mac(key: &[u8], session_id: &[u8], peer_addr: SocketAddr, candidates: &[SocketAddr]) -> HmacSha256 {
+    let mut mac = addr_keyed_mac(key, PEER_INFO, session_id, peer_addr);
+    for candidate in candidates {
+        mac.update(b" ");
+        mac.update(candidate.to_string().as_bytes());
+    }
+    return mac;
+}
+
+/// Computes the MAC with which a holepuncher vouches for the peer address and candidate addresses in a PeerInfo.
+pub fn peer_info_mac(key: &[u8], session_id: &[u8], peer_addr: SocketAddr, candidates: &[SocketAddr]) -> Vec<u8> {
+    peer_info_keyed_mac(key, session_id, peer_addr, candidates).finalize().into_bytes().to_vec()
+}
+
+/// Checks the MAC of a PeerInfo message in constant time.
+pub fn verify_peer_info_mac(key: &[u8], session_id: &[u8], peer_addr: SocketAddr, candidates: &[SocketAddr], mac: &[u8]) -> bool {
+    peer_info_keyed_mac(key, session_id, peer_addr, candidates).verify_slice(mac).is_ok()
+}
+
+/// Computes the MAC with which a server proves that it may move its session to the new address.
+pub fn migrate_mac(key: &[u8], session_id: &[u8], new_addr: SocketAddr) -> Vec<u8> {
+    addr_keyed_mac(key, MIGRATE, session_id, new_addr).finalize().into_bytes().to_vec()
+}
+
+/// Checks the MAC of a Migrate message in constant time.
+pub fn verify_migrate_mac(key: &[u8], session_id: &[u8], new_addr: SocketAddr, mac: &[u8]) -> bool {
+    addr_keyed_mac(key, MIGRATE, session_id, new_addr).verify_slice(mac).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framing::{
+        JOIN,
+        REGISTER,
+    };
+    
+    #[test]
+    fn session_macs_verify_only_while_fresh() {
+        let made_at = SystemTime::now();
+        let mac = session_mac(b"secret", REGISTER, b"session", &[], &[], made_at);
+        assert_eq!(mac.len(), crate::messages::SESSION_MAC_SIZE);
+        assert!(verify_session_mac(b"secret", REGISTER, b"session", &[], &[], &mac, made_at + Duration::from_secs(1)));
+        // clocks slightly behind are fine too
+        assert!(verify_session_mac(b"secret", REGISTER, b"session", &[], &[], &mac, made_at - Duration::from_secs(1)));
+        assert!(!verify_session_mac(b"secret", REGISTER, b"session", &[], &[], &mac, made_at + MAX_MAC_AGE + Duration::from_secs(1)));
+        assert!(!verify_session_mac(b"secret", REGISTER, b"session", &[], &[], &mac, made_at - MAX_MAC_AGE - Duration::from_secs(1)));
+    }
+    
+    #[test]
+    fn session_macs_cover_the_message() {
+        let now = SystemTime::now();
+        let mac = session_mac(b"secret", REGISTER, b"session", &[], &[], now);
+        assert!(!verify_session_mac(b"wrong", REGISTER, b"session", &[], &[], &mac, now));
+        assert!(!verify_session_mac(b"secret", JOIN, b"session", &[], &[], &mac, now));
+        assert!(!verify_session_mac(b"secret", REGISTER, b"other session", &[], &[], &mac, now));
+        // moving the timestamp invalidates the MAC
+        let mut retimed = mac.clone();
+        retimed[TIMESTAMP_SIZE - 1] ^= 0x01;
+        assert!(!verify_session_mac(b"secret", REGISTER, b"session", &[], &[], &retimed, now));
+    }
+    
+    #[test]
+    fn session_macs_cover_metadata_and_candidates() {
+        let now = SystemTime::now();
+        let candidates: Vec<SocketAddr> = vec!["10.0.0.2:5".parse().unwrap(), "[fe80::1%3]:6".parse().unwrap()];
+        let mac = session_mac(b"secret", REGISTER, b"session", b"meta", &candidates, now);
+        assert!(verify_session_mac(b"secret", REGISTER, b"session", b"meta", &candidates, &mac, now));
+        assert!(!verify_session_mac(b"secret", REGISTER, b"session", b"other", &candidates, &mac, now));
+        assert!(!verify_session_mac(b"secret", REGISTER, b"session", b"meta", &candidates[..1], &mac, now));
+        assert!(!verify_session_mac(b"secret", REGISTER, b"session", b"meta", &["10.0.0.3:5".parse().unwrap()], &mac, now));
+        // bytes can't be moved between the session ID and the metadata
+        assert!(!verify_session_mac(b"secret", REGISTER, b"sessionm", b"eta", &candidates, &mac, now));
+    }
+    
+    #[test]
+    fn peer_info_macs_cover_the_candidates() {
+        let candidates: Vec<SocketAddr> = vec!["10.0.0.2:5".parse().unwrap(), "[fe80::1%3]:6".parse().unwrap()];
+        let peer_addr: SocketAddr = "1.2.3.4:5".parse().unwrap();
+        let mac = peer_info_mac(b"key", b"s", peer_addr, &candidates);
+        assert!(verify_peer_info_mac(b"key", b"s", peer_addr, &candidates, &mac));
+        assert!(!verify_peer_info_mac(b"key", b"s", peer_addr, &candidates[..1], &mac));
+        assert!(!verify_peer_info_mac(b"key", b"s", peer_addr, &[], &mac));
+    }
+}
@@ -0,0 +1,75 @@
+use std::time::{
+    Duration,
+    Instant,
+};
+use std::sync::{
+    Arc,
+    Mutex,
+};
+
+// Timeouts are cut down to this when computing deadlines, since adding something like Duration::MAX
+// to an Instant overflows. A century is as good as forever for a timeout.
+pub(crate) const FOREVER: Duration = Duration::from_secs(100 * 365 * 24 * 60 * 60);
+
+// Returns the instant at which a timeout starting at start runs out.
+// Timeouts too large to be added to an Instant are treated as effectively infinite.
+pub(crate) fn deadline_after(start: Instant, timeout: Duration) -> Instant {
+    match start.checked_add(timeout) {
+        Some(deadline) => deadline,
+        None => start + FOREVER,
+    }
+}
+
+/// Where clients, servers and holepunchers take the current time from for their keepalives and timeouts.
+/// SystemClock is the real implementation; MockClock provides one for tests which only moves when told to.
+/// The timeouts passed to calls such as wait_for_event are always measured in real time,
+/// so that a call returns even if the clock stands still. A transport may supply its own clock, see transport::Transport::clock.
+pub trait Clock: Send + Sync {
+    /// Returns the current time.
+    fn now(&self) -> Instant;
+}
+
+/// The real clock, reading Instant::now()
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock for tests, which stands still until it is advanced. Its clones share the same time,
+/// so a test can keep one and hand the other to a client, server or holepuncher.
+#[derive(Debug, Clone)]
+pub struct MockClock {
+    now: Arc<Mutex<Instant>>,
+}
+
+impl MockClock {
+    /// Creates a clock standing at the current time, so that timers which were scheduled
+    /// before it was handed over stay due at the same moments.
+    pub fn new() -> Self {
+        Self {
+            now: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    /// Moves the clock forward by the given duration.
+    pub fn advance(&self, by: Duration) {
+        let mut now = self.now.lock().unwrap();
+        *now += by;
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+}
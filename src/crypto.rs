@@ -0,0 +1,86 @@
+use chacha20poly1305::{
+    ChaCha20Poly1305,
+    Key,
+    Nonce,
+};
+use chacha20poly1305::aead::{
+    Aead,
+    AeadCore,
+    KeyInit,
+    OsRng,
+};
+
+/// Size of the nonce prepended to every encrypted datagram
+pub const NONCE_SIZE: usize = 12;
+/// Size of the authentication tag appended to every encrypted datagram
+pub const TAG_SIZE: usize = 16;
+/// Number of bytes encryption adds to a datagram
+pub const ENCRYPTION_OVERHEAD: usize = NONCE_SIZE + TAG_SIZE;
+
+/// Encrypts and authenticates datagrams with ChaCha20-Poly1305 under a key shared by the peers.
+/// Encrypted datagrams are laid out as: 12 B random nonce + ciphertext + 16 B tag.
+pub struct DataCipher {
+    aead: ChaCha20Poly1305,
+}
+
+impl DataCipher {
+    pub fn new(key: &[u8; 32]) -> Self {
+        Self {
+            aead: ChaCha20Poly1305::new(Key::from_slice(key)),
+        }
+    }
+    
+    /// Encrypts a datagram under a fresh random nonce.
+    pub fn encrypt(&self, plaintext: &[u8]) -> Vec<u8> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        // encrypting into a Vec only fails if the plaintext exceeds ChaCha20's limit of 256 GiB
+        let ciphertext = self.aead.encrypt(&nonce, plaintext).unwrap();
+        let mut out = Vec::with_capacity(NONCE_SIZE + ciphertext.len());
+        out.extend_from_slice(&nonce);
+        out.extend_from_slice(&ciphertext);
+        return out;
+    }
+    
+    /// Verifies and decrypts a datagram produced by encrypt.
+    /// Returns None if the datagram is too short, was tampered with or was encrypted under a different key.
+    pub fn decrypt(&self, data: &[u8]) -> Option<Vec<u8>> {
+        if data.len() < ENCRYPTION_OVERHEAD {
+            return None;
+        }
+        let nonce = Nonce::from_slice(&data[..NONCE_SIZE]);
+        match self.aead.decrypt(nonce, &data[NONCE_SIZE..]) {
+            Ok(plaintext) => {
+                return Some(plaintext);
+            },
+            Err(_) => {
+                return None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn encrypted_datagrams_decrypt_to_the_plaintext() {
+        let cipher = DataCipher::new(&[7; 32]);
+        let encrypted = cipher.encrypt(b"hello there");
+        assert_eq!(encrypted.len(), b"hello there".len() + ENCRYPTION_OVERHEAD);
+        assert_eq!(cipher.decrypt(&encrypted), Some(b"hello there".to_vec()));
+    }
+    
+    #[test]
+    fn tampered_datagrams_are_rejected() {
+        let cipher = DataCipher::new(&[7; 32]);
+        let encrypted = cipher.encrypt(b"hello there");
+        for i in 0..encrypted.len() {
+            let mut tampered = encrypted.clone();
+            tampered[i] ^= 0x01;
+            assert_eq!(cipher.decrypt(&tampered), None);
+        }
+        assert_eq!(cipher.decrypt(&encrypted[..ENCRYPTION_OVERHEAD - 1]), None);
+        assert_eq!(DataCipher::new(&[8; 32]).decrypt(&encrypted), None);
+    }
+}
@@ -0,0 +1,230 @@
+use std::net::{
+    SocketAddr,
+    UdpSocket,
+    Ipv4Addr,
+    Ipv6Addr,
+};
+use std::time::{
+    Duration,
+    Instant,
+};
+use crate::messages::*;
+use crate::protocol_socket::*;
+use crate::error::RuphinError;
+use crate::clock::deadline_after;
+use crate::passive_client::{
+    PassiveClient,
+    PassiveClientConfig,
+};
+
+/// Time to wait for the answer to a single AddrReq
+const PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+/// Number of AddrReqs sent before a probe is considered unanswered
+const PROBE_ATTEMPTS: u32 = 3;
+
+/// How a NAT treats UDP traffic, as far as holepunching is concerned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NatType {
+    /// Not behind a NAT: the holepuncher sees the local address
+    Open,
+    /// The public address is the same for every destination, and any port of a host which was sent to can answer.
+    /// Full cone NATs are reported as this too, since telling them apart would need a second holepuncher host.
+    RestrictedCone,
+    /// The public address is the same for every destination, but only the exact address + port sent to can answer.
+    /// Holepunching works as long as both peers send to each other.
+    PortRestrictedCone,
+    /// The public address differs per destination, so the address reported by the holepuncher is useless to the peer.
+    /// Direct connections will most likely fail, consider relaying.
+    Symmetric,
+    /// Behind a NAT, but the holepuncher has no probe port to classify it with
+    Unknown,
+}
+
+// Returns the address to bind to for talking to the given destination: any interface and port, of the same family.
+fn unspecified_for(dest: SocketAddr) -> SocketAddr {
+    if dest.is_ipv6() {
+        return SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0));
+    } else {
+        return SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0));
+    }
+}
+
+// Binds a socket for talking to the given destination.
+fn bind_for(dest: SocketAddr) -> Result<ProtocolSocket, RuphinError> {
+    match ProtocolSocket::bind(unspecified_for(dest).to_string()) {
+        Ok(sock) => {
+            return Ok(sock);
+        },
+        Err(e) => {
+            return Err(RuphinError::Bind(e));
+        }
+    }
+}
+
+/// Checks that a holepuncher is up, by sending it a single HelloReq and waiting for its HelloResp.
+/// Returns the round-trip time, or Err(RuphinError::Timeout) if no answer arrived within the timeout.
+/// Since only one HelloReq is sent, a lost datagram also results in a timeout.
+pub fn ping_holepuncher(holepuncher: SocketAddr, timeout: Duration) -> Result<Duration, RuphinError> {
+    if timeout.is_zero() {
+        return Err(RuphinError::InvalidConfig("timeout must not be zero"));
+    }
+    let sock = bind_for(holepuncher)?;
+    let sent_at = Instant::now();
+    let end_time = deadline_after(sent_at, timeout);
+    match sock.send_message(&Message::HelloReq(HelloReqContents::default()), holepuncher) {
+        Ok(()) => {},
+        Err(e) => {
+            return Err(RuphinError::from(e));
+        }
+    };
+    loop {
+        let now = Instant::now();
+        if now >= end_time {
+            return Err(RuphinError::Timeout);
+        }
+        sock.set_read_timeout(Some(end_time - now)).unwrap();
+        match sock.get_message() {
+            Ok((Message::HelloResp(_), source)) if source == holepuncher => {
+                return Ok(sent_at.elapsed());
+            },
+            Ok(_) => {
+                // something else, keep waiting
+                continue;
+            },
+            Err(e) if e.is_fatal() => {
+                return Err(RuphinError::from(e));
+            },
+            Err(_) => {
+                // timeout or a corrupted datagram, the loop checks the deadline
+                continue;
+            }
+        }
+    }
+}
+
+/// Checks whether a server has registered the given session at the holepuncher, without joining it.
+/// The holepuncher answers from its session store, so no PeerInfo is sent and no holepunching is triggered.
+/// The question is repeated until the holepuncher answers or the timeout passes, in which case
+/// Err(RuphinError::Timeout) is returned. Queries are sent unauthenticated, so a holepuncher which requires
+/// a pre-shared key never answers; use PassiveClient::query_session with an auth_key for those.
+pub fn check_session(holepuncher: SocketAddr, session_id: &[u8], timeout: Duration) -> Result<bool, RuphinError> {
+    if timeout.is_zero() {
+        return Err(RuphinError::InvalidConfig("timeout must not be zero"));
+    }
+    let mut config = PassiveClientConfig::default();
+    config.handshake_timeout = timeout;
+    config.message_timeout = config.message_timeout.min(timeout);
+    match PassiveClient::query_session(holepuncher, session_id, &config) {
+        Ok(_) => {
+            return Ok(true);
+        },
+        Err(RuphinError::SessionNotFound) => {
+            return Ok(false);
+        },
+        Err(e) => {
+            return Err(e);
+        }
+    }
+}
+
+// Sends AddrReqs to dest until an AddrResp arrives from expect_from, or all attempts are used up.
+fn probe(sock: &ProtocolSocket, dest: SocketAddr, change_port: bool, expect_from: SocketAddr)
+    -> Result<Option<AddrRespContents>, RuphinError> {
+    let request = Message::AddrReq(AddrReqContents {
+        change_port,
+    });
+    for _ in 0..PROBE_ATTEMPTS {
+        match sock.send_message(&request, dest) {
+            Ok(()) => {},
+            Err(e) if !e.is_fatal() => {
+                // transient error, the next attempt sends it again
+            },
+            Err(e) => {
+                return Err(RuphinError::from(e));
+            }
+        };
+        // wait for the answer, ignoring anything else
+        loop {
+            match sock.get_message() {
+                Ok((Message::AddrResp(contents), source)) if source == expect_from => {
+                    return Ok(Some(contents));
+                },
+                Ok(_) => {
+                    continue;
+                },
+                Err(e) if e.is_fatal() => {
+                    return Err(RuphinError::from(e));
+                },
+                Err(_) => {
+                    // most likely a timeout, try again
+                    break;
+                }
+            }
+        }
+    }
+    return Ok(None);
+}
+
+/// Finds out what kind of NAT this host is behind, by asking the holepuncher from which address its requests arrive.
+/// The holepuncher needs a probe port (see PassiveHolepuncherConfig::probe_addr) to tell the kinds of NAT apart,
+/// without one only Open or Unknown is returned. Takes a few seconds, since some probes are expected to go unanswered.
+/// Returns Err(RuphinError::Timeout) if the holepuncher doesn't answer at all.
+pub fn detect_nat_type(holepuncher: SocketAddr) -> Result<NatType, RuphinError> {
+    let sock = bind_for(holepuncher)?;
+    sock.set_read_timeout(Some(PROBE_TIMEOUT)).unwrap();
+    let local_port = match sock.local_addr() {
+        Ok(addr) => addr.port(),
+        Err(e) => {
+            return Err(RuphinError::Io(e));
+        }
+    };
+
+    // first, the plain public address
+    let first = match probe(&sock, holepuncher, false, holepuncher)? {
+        Some(contents) => contents,
+        None => {
+            return Err(RuphinError::Timeout);
+        }
+    };
+
+    // Without a NAT, the holepuncher sees the local address of the interface which routes to it.
+    // A connected socket reveals that interface without sending anything.
+    let local_ip = match UdpSocket::bind(unspecified_for(holepuncher)).and_then(|sock| {
+        sock.connect(holepuncher)?;
+        sock.local_addr()
+    }) {
+        Ok(addr) => addr.ip(),
+        Err(e) => {
+            return Err(RuphinError::Io(e));
+        }
+    };
+    if first.mapped_addr == SocketAddr::new(local_ip, local_port) {
+        return Ok(NatType::Open);
+    }
+
+    let probe_addr = match first.probe_port {
+        Some(port) => SocketAddr::new(holepuncher.ip(), port),
+        None => {
+            return Ok(NatType::Unknown);
+        }
+    };
+
+    // Can another port answer? This has to be asked before sending anything to the probe port,
+    // which would open the NAT for it.
+    let other_port_answers = probe(&sock, holepuncher, true, probe_addr)?.is_some();
+
+    // Does the public address stay the same for another destination?
+    let second = match probe(&sock, probe_addr, false, probe_addr)? {
+        Some(contents) => contents,
+        None => {
+            return Err(RuphinError::Timeout);
+        }
+    };
+    if second.mapped_addr != first.mapped_addr {
+        return Ok(NatType::Symmetric);
+    }
+    if other_port_answers {
+        return Ok(NatType::RestrictedCone);
+    }
+    return Ok(NatType::PortRestrictedCone);
+}
@@ -0,0 +1,122 @@
+use std::fmt;
+use crate::protocol_socket::*;
+
+/// Step of the handshake which did not complete in time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeStage {
+    /// The server sent Registers, but the holepuncher never acknowledged them
+    WaitingForRegisterAck,
+    /// The client sent Joins, but the holepuncher never answered with the server's address
+    WaitingForPeerInfo,
+    /// The client got the server's address, but the server never answered its HelloReqs
+    WaitingForHelloResp,
+}
+
+impl fmt::Display for HandshakeStage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandshakeStage::WaitingForRegisterAck => write!(f, "waiting for a RegisterAck from the holepuncher"),
+            HandshakeStage::WaitingForPeerInfo => write!(f, "waiting for a PeerInfo from the holepuncher"),
+            HandshakeStage::WaitingForHelloResp => write!(f, "waiting for a HelloResp from the peer"),
+        }
+    }
+}
+
+/// Errors returned by the public API of ruphin
+#[derive(Debug)]
+pub enum RuphinError {
+    /// The socket could not be bound to the requested address
+    Bind(std::io::Error),
+    /// An I/O error occurred on the socket
+    Io(std::io::Error),
+    /// The handshake with the holepuncher or the peer did not complete in time
+    HandshakeTimeout { stage: HandshakeStage },
+    /// An operation did not complete within its timeout
+    Timeout,
+    /// The peer has not responded to keepalives for too long and is considered gone
+    PeerTimedOut,
+    /// The holepuncher does not know the requested session
+    SessionNotFound,
+    /// The holepuncher refused to register the session, because it is full or the session ID is taken by another peer
+    RegisterRejected,
+    /// A message could not be serialized, e.g. because its payload is too large
+    Serialization,
+    /// A datagram is too large to be sent, even when split into fragments
+    DatagramTooLarge { size: usize, max: usize },
+    /// A fatal error occurred while receiving a message which could not be deserialized
+    Deserialization,
+    /// The OS only sent part of a datagram
+    IncompleteSend(usize),
+    /// A configuration value is invalid
+    InvalidConfig(&'static str),
+    /// No connected client identified itself with the given handle
+    UnknownHandle,
+    /// The peer's address is of an address family the socket cannot send to, e.g. IPv4 from an IPv6-only socket
+    AddressFamilyMismatch(std::net::SocketAddr),
+    /// The holepuncher's address could not be resolved, e.g. because the host name is unknown
+    Resolve(std::io::Error),
+    /// The holepuncher's address resolved only to addresses the socket can't use, e.g. IPv6 ones when bound to an IPv4 address
+    NoUsableAddress,
+}
+
+impl fmt::Display for RuphinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RuphinError::Bind(e) => write!(f, "Socket bind error: {}", e),
+            RuphinError::Io(e) => write!(f, "I/O error: {}", e),
+            RuphinError::HandshakeTimeout { stage } => write!(f, "Handshake timed out {}", stage),
+            RuphinError::Timeout => write!(f, "Operation timed out"),
+            RuphinError::PeerTimedOut => write!(f, "Peer timed out"),
+            RuphinError::SessionNotFound => write!(f, "Session not found"),
+            RuphinError::RegisterRejected => write!(f, "Session registration rejected by the holepuncher"),
+            RuphinError::Serialization => write!(f, "Message serialization failed"),
+            RuphinError::DatagramTooLarge { size, max } => write!(f, "Datagram too large: {} bytes (max {})", size, max),
+            RuphinError::Deserialization => write!(f, "Message deserialization failed"),
+            RuphinError::IncompleteSend(n) => write!(f, "Incomplete send: only {} bytes were sent", n),
+            RuphinError::InvalidConfig(reason) => write!(f, "Invalid configuration: {}", reason),
+            RuphinError::UnknownHandle => write!(f, "No client with this handle"),
+            RuphinError::AddressFamilyMismatch(addr) => write!(f, "Cannot reach {} from a socket of another address family", addr),
+            RuphinError::Resolve(e) => write!(f, "Address resolution failed: {}", e),
+            RuphinError::NoUsableAddress => write!(f, "The address did not resolve to any usable address"),
+        }
+    }
+}
+
+impl std::error::Error for RuphinError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RuphinError::Bind(e) => Some(e),
+            RuphinError::Io(e) => Some(e),
+            RuphinError::Resolve(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl RuphinError {
+    // Returns true if the error means the holepuncher couldn't be reached at the address tried,
+    // so that another address it resolved to may still work.
+    pub(crate) fn is_unreachable(&self) -> bool {
+        matches!(self, RuphinError::Bind(_) | RuphinError::Io(_) | RuphinError::HandshakeTimeout { .. })
+    }
+}
+
+impl From<SendError> for RuphinError {
+    fn from(e: SendError) -> Self {
+        match e {
+            SendError::SerializationFailed => RuphinError::Serialization,
+            SendError::MessageTooLarge { size, max } => RuphinError::DatagramTooLarge { size, max },
+            SendError::IO(e) => RuphinError::Io(e),
+            SendError::IncompleteSend(n) => RuphinError::IncompleteSend(n),
+        }
+    }
+}
+
+impl From<ReceiveError> for RuphinError {
+    fn from(e: ReceiveError) -> Self {
+        match e {
+            ReceiveError::IO(e) => RuphinError::Io(e),
+            _ => RuphinError::Deserialization,
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use std::fmt;
+use std::net::{
+    SocketAddr,
+    IpAddr,
+    Ipv4Addr,
+    Ipv6Addr,
+};
+use crate::messages::Message;
+use crate::protocol_socket::ProtocolSocket;
+use crate::error::RuphinError;
+
+/// Something that happened on a connection, as returned by wait_for_event
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// A datagram was received from the given peer
+    Data(SocketAddr, Vec<u8>),
+    /// A peer completed the HelloReq/HelloResp handshake
+    PeerConnected(SocketAddr),
+    /// A peer said Goodbye and left the session
+    PeerDisconnected(SocketAddr),
+    /// A peer has not been heard from for too long and is no longer considered connected
+    PeerTimedOut(SocketAddr),
+    /// Several reliable datagrams in a row sent to the peer went unacknowledged (see max_unacked_reliable),
+    /// so it is most likely gone, even if it hasn't been silent for long enough to time out yet
+    PeerLost(SocketAddr),
+    /// The holepuncher has not been heard from for longer than the configured holepuncher_timeout.
+    /// Existing connections are unaffected, but new peers can't find this one through it, so consider failing over to another.
+    HolepuncherTimedOut(SocketAddr),
+    /// A LocalInterrupt was received from localhost
+    Interrupted,
+    /// Nothing happened before the timeout elapsed
+    Timeout,
+}
+
+/// Callback invoked with every message received by a client or server, including ones it ignores.
+pub type MessageObserver = Box<dyn FnMut(&Message, SocketAddr) + Send>;
+
+/// Why a received datagram was dropped without being acted on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropReason {
+    /// The datagram could not be deserialized: it is corrupted, truncated or not a ruphin message at all
+    Malformed,
+    /// The message is of a type which is not expected here, e.g. a Register sent to a client
+    UnexpectedMessage,
+    /// The message is only accepted from a particular peer, e.g. a PeerInfo which didn't come from the holepuncher,
+    /// or a LocalInterrupt which didn't come from localhost
+    WrongSource,
+    /// The message is for a session this peer doesn't serve or know
+    WrongSession,
+    /// Data from a peer which never completed the HelloReq/HelloResp handshake, or, on a client, from anyone but the server.
+    /// At a MultiSessionServer, also a HelloReq from a peer which no PeerInfo introduced.
+    /// At a holepuncher, a RelayData to or from a peer which didn't join the session
+    UnknownPeer,
+    /// Data, or a fragment of it, whose deadline had passed by the time it arrived. At a holepuncher, by the time it was to be relayed
+    Expired,
+    /// The message's MAC did not verify with the pre-shared key
+    Unauthenticated,
+    /// An encrypted datagram failed to decrypt: its authentication tag did not verify, because it was tampered with
+    /// or encrypted under another key
+    Undecryptable,
+    /// The holepuncher is receiving too many requests from the source's IP address
+    RateLimited,
+}
+
+impl fmt::Display for DropReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DropReason::Malformed => write!(f, "malformed datagram"),
+            DropReason::UnexpectedMessage => write!(f, "unexpected message"),
+            DropReason::WrongSource => write!(f, "wrong source"),
+            DropReason::WrongSession => write!(f, "wrong session"),
+            DropReason::UnknownPeer => write!(f, "unknown peer"),
+            DropReason::Expired => write!(f, "expired"),
+            DropReason::Unauthenticated => write!(f, "unauthenticated"),
+            DropReason::Undecryptable => write!(f, "undecryptable"),
+            DropReason::RateLimited => write!(f, "rate limited"),
+        }
+    }
+}
+
+/// Callback invoked with the reason and source of every datagram a client, server or holepuncher drops.
+pub type DropObserver = Box<dyn FnMut(DropReason, SocketAddr) + Send>;
+
+/// Step of a client's handshake, reported to the callback given to PassiveClient::with_progress
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeProgress {
+    /// Joins were sent to the holepunchers, for the attempt-th time
+    SentJoin { attempt: u32 },
+    /// A holepuncher answered with the server's address
+    GotPeerInfo { holepuncher: SocketAddr, server: SocketAddr },
+    /// A HelloReq was sent to the server, for the attempt-th time
+    SentHelloReq { attempt: u32 },
+    /// The server couldn't be reached directly, so it will be talked to through the holepuncher
+    Relayed,
+    /// The server answered, the handshake is complete
+    Connected,
+}
+
+impl fmt::Display for HandshakeProgress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandshakeProgress::SentJoin { attempt: 1 } => write!(f, "sent Join"),
+            HandshakeProgress::SentJoin { attempt } => write!(f, "sent Join attempt {}", attempt),
+            HandshakeProgress::GotPeerInfo { .. } => write!(f, "got PeerInfo"),
+            HandshakeProgress::SentHelloReq { attempt: 1 } => write!(f, "sent HelloReq"),
+            HandshakeProgress::SentHelloReq { attempt } => write!(f, "sent HelloReq attempt {}", attempt),
+            HandshakeProgress::Relayed => write!(f, "relayed"),
+            HandshakeProgress::Connected => write!(f, "connected"),
+        }
+    }
+}
+
+/// Wakes up a client, server or holepuncher blocked in wait_for_data, wait_for_event or serve with allow_interrupt set.
+/// The handle can be cloned and sent to other threads.
+/// It sends a LocalInterrupt from localhost, so it only works if the target socket is bound to a loopback or unspecified address.
+#[derive(Debug, Clone)]
+pub struct InterruptHandle {
+    /// Loopback address + port of the socket to interrupt
+    target: SocketAddr,
+}
+
+impl InterruptHandle {
+    // Creates a handle for the socket bound to the given local address.
+    pub(crate) fn new(local_addr: SocketAddr) -> Self {
+        let mut target = local_addr;
+        if target.ip().is_unspecified() {
+            // bound to all interfaces, so reachable via the loopback interface of the same family
+            match target.ip() {
+                IpAddr::V4(_) => target.set_ip(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                IpAddr::V6(_) => target.set_ip(IpAddr::V6(Ipv6Addr::LOCALHOST)),
+            }
+        }
+        Self { target }
+    }
+
+    /// Returns the address the LocalInterrupts are sent to.
+    pub fn target(&self) -> SocketAddr {
+        self.target
+    }
+
+    /// Sends a LocalInterrupt to the socket. If nobody is waiting on it with allow_interrupt set,
+    /// the interrupt is ignored by the next call to wait_for_data, wait_for_event or serve.
+    pub fn interrupt(&self) -> Result<(), RuphinError> {
+        let bind_addr = match self.target {
+            SocketAddr::V4(_) => "127.0.0.1:0",
+            SocketAddr::V6(_) => "[::1]:0",
+        };
+        let sock = match ProtocolSocket::bind(bind_addr) {
+            Ok(sock) => sock,
+            Err(e) => {
+                return Err(RuphinError::Bind(e));
+            }
+        };
+        match sock.send_message(&Message::LocalInterrupt, self.target) {
+            Ok(()) => {
+                return Ok(());
+            },
+            Err(e) => {
+                return Err(RuphinError::from(e));
+            }
+        }
+    }
+}
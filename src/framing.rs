@@ -0,0 +1,227 @@
+// Framing of messages on the wire: the header, the trailing checksum and the message type constants.
+// Everything here works on byte slices and only uses core, without allocating,
+// so that the same codec can be used on targets without std or a heap. Message builds on top of it.
+// It is all the crate consists of without the std feature, see lib.rs.
+
+pub const LOCAL_INTERRUPT: u16 = 1;
+pub const REGISTER: u16 = 2;
+pub const JOIN: u16 = 3;
+pub const PEER_INFO: u16 = 4;
+pub const DATA: u16 = 5;
+pub const REGISTER_ACK: u16 = 6;
+pub const SESSION_NOT_FOUND: u16 = 7;
+pub const HELLO_REQ: u16 = 8;
+pub const HELLO_RESP: u16 = 9;
+pub const DATA_FRAGMENT: u16 = 10;
+pub const GOODBYE: u16 = 11;
+pub const PING: u16 = 12;
+pub const PONG: u16 = 13;
+pub const REGISTER_REJECTED: u16 = 14;
+pub const RELAY_DATA: u16 = 15;
+pub const RELIABLE_DATA: u16 = 16;
+pub const DATA_ACK: u16 = 17;
+pub const ADDR_REQ: u16 = 18;
+pub const ADDR_RESP: u16 = 19;
+pub const QUERY: u16 = 20;
+pub const QUERY_RESULT: u16 = 21;
+pub const MIGRATE: u16 = 22;
+
+/// Set in the type field of a Data message whose payload is LZ4-compressed (see the `compression` feature).
+pub const COMPRESSED_FLAG: u16 = 0x8000;
+/// Set in the type field of a Data or DataFragment message whose payload starts with an 8 B deadline (see DataContents::expires_at).
+pub const EXPIRES_FLAG: u16 = 0x4000;
+/// Set in the type field of a Register, Join or PeerInfo message which carries candidate addresses.
+pub const CANDIDATES_FLAG: u16 = 0x2000;
+
+pub const MAX_DATA_SIZE: usize = 1024;
+/// Maximum length of a session ID. Session IDs are prefixed with their length as a single byte on the wire.
+pub const MAX_SESSION_ID_SIZE: usize = 255;
+/// Maximum size of the metadata a server can attach to its session. Prefixed with its length as a single byte on the wire.
+pub const MAX_METADATA_SIZE: usize = 255;
+/// Maximum size of the handle a client can identify itself with in its HelloReqs.
+pub const MAX_HANDLE_SIZE: usize = 64;
+/// Maximum number of candidate addresses a Register, Join or PeerInfo message can carry.
+pub const MAX_CANDIDATES: usize = 4;
+/// Maximum size of a serialized message carried inside a RelayData message.
+/// Large enough for a Data or DataFragment message with a full payload.
+pub const MAX_RELAY_PAYLOAD_SIZE: usize = MAX_DATA_SIZE + 64;
+
+/// Magic prefix ("RU") at the very start of every message, used to reject unrelated traffic early.
+pub const MAGIC: [u8; 2] = [0x52, 0x55];
+/// Size of the message header: 2 B magic + 2 B length + 2 B type.
+pub const HEADER_SIZE: usize = 6;
+/// Size of the trailing CRC32 checksum. Zero if the `checksum` feature is disabled.
+pub const CHECKSUM_SIZE: usize = if cfg!(feature = "checksum") { 4 } else { 0 };
+// Size of the largest address on the wire (IPv6, see Message::serialize_addr)
+const MAX_ADDR_SIZE: usize = 23;
+/// Size of the largest valid message: a RelayData with the longest session ID, an IPv6 address and a full payload.
+/// Larger datagrams are rejected without being parsed.
+pub const MAX_MESSAGE_SIZE: usize = HEADER_SIZE + 1 + MAX_SESSION_ID_SIZE + MAX_ADDR_SIZE + MAX_RELAY_PAYLOAD_SIZE + CHECKSUM_SIZE;
+
+// lookup table for the CRC32 (IEEE 802.3, reflected polynomial 0xEDB88320)
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            if crc & 1 == 1 {
+                crc = (crc >> 1) ^ 0xEDB88320;
+            } else {
+                crc >>= 1;
+            }
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// Computes the CRC32 (IEEE) checksum of the given bytes.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xFFFFFFFFu32;
+    for byte in bytes {
+        crc = CRC32_TABLE[((crc ^ u32::from(*byte)) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+/// Reasons why a datagram could not be turned into a Message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeserializeError {
+    /// The datagram does not start with the protocol's magic prefix
+    BadMagic,
+    /// The datagram is not a well-formed message
+    Malformed,
+    /// The trailing checksum does not match the contents of the datagram
+    ChecksumMismatch,
+    /// The datagram, or the length stated in its header, exceeds MAX_MESSAGE_SIZE
+    TooLarge,
+}
+
+/// Returns the size of a message carrying a payload of the given size.
+pub const fn frame_size(payload_len: usize) -> usize {
+    HEADER_SIZE + payload_len + CHECKSUM_SIZE
+}
+
+/// Writes a message of the given type carrying the given payload to the start of the buffer:
+/// the header, the payload and (if enabled) the trailing checksum.
+/// Returns the number of bytes written, or Err if the message would be larger than MAX_MESSAGE_SIZE or the buffer.
+pub fn encode_frame(msg_type: u16, payload: &[u8], out: &mut [u8]) -> Result<usize, ()> {
+    let total_len = frame_size(payload.len());
+    if total_len > MAX_MESSAGE_SIZE || total_len > out.len() {
+        return Err(());
+    }
+    // add the header: magic, packet size and type
+    // MAX_MESSAGE_SIZE fits into the u16 length field
+    out[0..2].copy_from_slice(&MAGIC);
+    out[2..4].copy_from_slice(&(total_len as u16).to_be_bytes());
+    out[4..6].copy_from_slice(&msg_type.to_be_bytes());
+    out[HEADER_SIZE..HEADER_SIZE + payload.len()].copy_from_slice(payload);
+    
+    // the checksum covers the header and the payload
+    if cfg!(feature = "checksum") {
+        let checksum_at = HEADER_SIZE + payload.len();
+        let checksum = crc32(&out[0..checksum_at]);
+        out[checksum_at..total_len].copy_from_slice(&checksum.to_be_bytes());
+    }
+    return Ok(total_len);
+}
+
+/// Returns the length of the first message in a datagram carrying several concatenated messages,
+/// as stated in its header. Only checks that the stated length is plausible; decode_frame checks the rest.
+pub fn first_frame_len(from: &[u8]) -> Result<usize, DeserializeError> {
+    if from.len() < MAGIC.len() || from[0..MAGIC.len()] != MAGIC {
+        return Err(DeserializeError::BadMagic);
+    }
+    if from.len() < HEADER_SIZE + CHECKSUM_SIZE {
+        return Err(DeserializeError::Malformed);
+    }
+    let stated_length = usize::from(u16::from_be_bytes([from[2], from[3]]));
+    if stated_length > MAX_MESSAGE_SIZE {
+        return Err(DeserializeError::TooLarge);
+    }
+    if stated_length < HEADER_SIZE + CHECKSUM_SIZE || stated_length > from.len() {
+        return Err(DeserializeError::Malformed);
+    }
+    return Ok(stated_length);
+}
+
+/// Checks the header and (if enabled) the trailing checksum of a received datagram.
+/// Returns the message type and the payload, which is not parsed any further.
+pub fn decode_frame(from: &[u8]) -> Result<(u16, &[u8]), DeserializeError> {
+    // before anything else, check that this is one of our messages at all
+    if from.len() < MAGIC.len() || from[0..MAGIC.len()] != MAGIC {
+        return Err(DeserializeError::BadMagic);
+    }
+
+    // measure and check the size of the package
+    let length = from.len();
+    if length < HEADER_SIZE + CHECKSUM_SIZE {
+        // error: not enough bytes for the header and checksum
+        return Err(DeserializeError::Malformed);
+    }
+    
+    // the rest of the header consists of length and message type
+    let stated_length = usize::from(u16::from_be_bytes([from[2], from[3]]));
+    let msg_type = u16::from_be_bytes([from[4], from[5]]);
+
+    // reject oversized messages before looking at their contents
+    if length > MAX_MESSAGE_SIZE || stated_length > MAX_MESSAGE_SIZE {
+        return Err(DeserializeError::TooLarge);
+    }
+
+    // check that the stated length matches the actual message length
+    // since we're working with datagrams, it should match exactly
+    if length != stated_length {
+        return Err(DeserializeError::Malformed);
+    }
+
+    // verify the trailing checksum
+    if cfg!(feature = "checksum") {
+        let checksum_at = length - CHECKSUM_SIZE;
+        let expected = u32::from_be_bytes([
+            from[checksum_at], from[checksum_at + 1], from[checksum_at + 2], from[checksum_at + 3],
+        ]);
+        if crc32(&from[0..checksum_at]) != expected {
+            return Err(DeserializeError::ChecksumMismatch);
+        }
+    }
+    return Ok((msg_type, &from[HEADER_SIZE..length - CHECKSUM_SIZE]));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn encoded_frames_match_serialized_messages() {
+        use crate::messages::*;
+
+        let mut buf = [0u8; MAX_MESSAGE_SIZE];
+        let len = encode_frame(DATA, b"hello", &mut buf).unwrap();
+        let serialized = Message::Data(DataContents { data: Payload::from(b"hello".to_vec()), expires_at: None }).serialize().unwrap();
+        assert_eq!(&buf[..len], &serialized[..]);
+
+        let len = encode_frame(HELLO_REQ, &[], &mut buf).unwrap();
+        assert_eq!(&buf[..len], &Message::HelloReq(HelloReqContents::default()).serialize().unwrap()[..]);
+        assert_eq!(decode_frame(&buf[..len]), Ok((HELLO_REQ, &[][..])));
+    }
+
+    #[test]
+    fn decoding_returns_the_encoded_type_and_payload() {
+        let mut buf = [0u8; 64];
+        let len = encode_frame(PING, &[1, 2, 3], &mut buf).unwrap();
+        assert_eq!(len, frame_size(3));
+        assert_eq!(first_frame_len(&buf[..len]), Ok(len));
+        assert_eq!(decode_frame(&buf[..len]), Ok((PING, &[1, 2, 3][..])));
+    }
+
+    #[test]
+    fn encoding_into_a_short_buffer_fails() {
+        assert!(encode_frame(DATA, b"hello", &mut [0u8; 8]).is_err());
+    }
+}
@@ -0,0 +1,51 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{
+    BuildHasher,
+    Hasher,
+};
+use std::time::Duration;
+
+// Returns a pseudo-random number in [0, 1).
+// Every RandomState is keyed differently, which is random enough for spreading out timers.
+fn random_unit() -> f64 {
+    let hash = RandomState::new().build_hasher().finish();
+    // keep the 53 bits which fit into the mantissa of an f64
+    (hash >> 11) as f64 / (1u64 << 53) as f64
+}
+
+// Randomly lengthens or shortens an interval by up to the given fraction of it.
+// A fraction of zero returns the interval unchanged.
+pub(crate) fn jittered(interval: Duration, fraction: f64) -> Duration {
+    if fraction == 0.0 {
+        return interval;
+    }
+    let factor = 1.0 + fraction * (2.0 * random_unit() - 1.0);
+    // lengthening an interval close to Duration::MAX overflows, it's as good as forever anyway
+    return Duration::try_from_secs_f64(interval.as_secs_f64() * factor).unwrap_or(Duration::MAX);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jittered_intervals_vary_within_the_band() {
+        let interval = Duration::from_secs(10);
+        let intervals: Vec<Duration> = (0..200).map(|_| jittered(interval, 0.2)).collect();
+        assert!(intervals.iter().all(|i| *i >= Duration::from_secs(8) && *i <= Duration::from_secs(12)));
+        assert!(intervals.iter().any(|i| *i < Duration::from_secs(9)));
+        assert!(intervals.iter().any(|i| *i > Duration::from_secs(11)));
+    }
+
+    #[test]
+    fn zero_jitter_keeps_the_interval() {
+        assert_eq!(jittered(Duration::from_secs(10), 0.0), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn huge_intervals_saturate() {
+        for _ in 0..20 {
+            assert!(jittered(Duration::MAX, 0.5) >= Duration::MAX / 2);
+        }
+    }
+}
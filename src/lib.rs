@@ -1,7 +1,42 @@
-mod messages;
-mod protocol_socket;
-pub mod passive_client;
-pub mod passive_server;
-pub mod passive_holepuncher;
-pub mod active_client;
-pub mod active_server;
\ No newline at end of file
+// Without the std feature, only the allocation-free framing module is built, which needs nothing but core.
+#![cfg_attr(not(feature = "std"), no_std)]
+
+// Emits a tracing event if the "tracing" feature is enabled, and compiles to nothing otherwise.
+// Usage is the same as tracing's level macros, e.g. trace_event!(debug, source = %addr, "message");
+#[cfg(feature = "std")]
+macro_rules! trace_event {
+    ($level:ident, $($arg:tt)+) => {
+        #[cfg(feature = "tracing")]
+        tracing::$level!($($arg)+);
+    };
+}
+
+pub mod framing;
+#[cfg(feature = "std")]
+pub mod messages;
+#[cfg(feature = "std")]
+pub mod protocol_socket;
+#[cfg(feature = "std")]
+pub mod transport;
+#[cfg(feature = "std")]
+pub mod clock;
+#[cfg(feature = "std")]
+pub mod error;
+#[cfg(feature = "std")]
+pub mod event;
+#[cfg(feature = "std")]
+mod jitter;
+#[cfg(feature = "encryption")]
+pub mod crypto;
+#[cfg(feature = "auth")]
+pub mod auth;
+#[cfg(feature = "std")]
+pub mod passive_client;
+#[cfg(feature = "std")]
+pub mod passive_server;
+#[cfg(feature = "std")]
+pub mod multi_session_server;
+#[cfg(feature = "std")]
+pub mod passive_holepuncher;
+#[cfg(feature = "std")]
+pub mod diagnostics;
\ No newline at end of file
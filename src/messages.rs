@@ -1,3 +1,4 @@
+use std::fmt;
 use std::net::{
     SocketAddr,
     Ipv4Addr,
@@ -5,62 +6,328 @@ use std::net::{
     SocketAddrV4,
     SocketAddrV6,
 };
+use std::time::{
+    Duration,
+    Instant,
+    SystemTime,
+    UNIX_EPOCH,
+};
+use std::collections::{
+    HashMap,
+    VecDeque,
+};
+
+// the wire framing, constants and checksum are shared with the allocation-free codec
+pub use crate::framing::*;
+use crate::clock::FOREVER;
+
+/// Maximum number of fragments a single datagram can be split into.
+pub const MAX_FRAGMENT_COUNT: usize = 64;
+/// Largest datagram that can be sent, after splitting it into fragments.
+pub const MAX_FRAGMENTED_DATA_SIZE: usize = MAX_DATA_SIZE * MAX_FRAGMENT_COUNT;
+/// Number of recent sequence numbers remembered per peer, to deliver reliable datagrams only once.
+pub const DUPLICATE_WINDOW: usize = 256;
+/// Size of the MAC carried by authenticated PeerInfo and Migrate messages (HMAC-SHA256).
+pub const MAC_SIZE: usize = 32;
+/// Size of the MAC carried by authenticated Register, Join and Query messages:
+/// the 8 B time it was made at, followed by a MAC_SIZE B HMAC.
+pub const SESSION_MAC_SIZE: usize = 8 + MAC_SIZE;
+/// Time after which an incomplete fragmented datagram is discarded.
+pub const FRAGMENT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Maximum number of incomplete fragmented datagrams kept per sender. Further ones evict that sender's oldest.
+pub const MAX_PARTIAL_PER_SOURCE: usize = 8;
+/// Maximum number of incomplete fragmented datagrams kept in total. Further ones evict the oldest of all.
+pub const MAX_PARTIAL_DATAGRAMS: usize = 128;
 
-pub const LOCAL_INTERRUPT: u16 = 1;
-pub const REGISTER: u16 = 2;
-pub const JOIN: u16 = 3;
-pub const PEER_INFO: u16 = 4;
-pub const DATA: u16 = 5;
-pub const REGISTER_ACK: u16 = 6;
-pub const SESSION_NOT_FOUND: u16 = 7;
-pub const HELLO_REQ: u16 = 8;
-pub const HELLO_RESP: u16 = 9;
+// wire types of a Data message with a compressed payload and/or a deadline
+const COMPRESSED_DATA: u16 = DATA | COMPRESSED_FLAG;
+const EXPIRING_DATA: u16 = DATA | EXPIRES_FLAG;
+const COMPRESSED_EXPIRING_DATA: u16 = DATA | COMPRESSED_FLAG | EXPIRES_FLAG;
+// wire type of a DataFragment with a deadline
+const EXPIRING_DATA_FRAGMENT: u16 = DATA_FRAGMENT | EXPIRES_FLAG;
+// wire types of the messages which carry candidate addresses
+const REGISTER_WITH_CANDIDATES: u16 = REGISTER | CANDIDATES_FLAG;
+const JOIN_WITH_CANDIDATES: u16 = JOIN | CANDIDATES_FLAG;
+const PEER_INFO_WITH_CANDIDATES: u16 = PEER_INFO | CANDIDATES_FLAG;
 
-pub const MAX_DATA_SIZE: usize = 1024;
-pub const MAX_SESSION_ID_SIZE: usize = 20;
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct RegisterContents {
     pub session_id: Vec<u8>,
+    /// Opaque data describing the session, which clients can query before joining. At most MAX_METADATA_SIZE bytes.
+    pub metadata: Vec<u8>,
+    /// Local addresses at which clients may reach the server besides the one the holepuncher sees,
+    /// e.g. from the same LAN. At most MAX_CANDIDATES.
+    pub candidates: Vec<SocketAddr>,
+    /// SESSION_MAC_SIZE bytes proving knowledge of the holepuncher's pre-shared key, or empty if authentication is not used
+    pub mac: Vec<u8>,
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct JoinContents {
     pub session_id: Vec<u8>,
+    /// Local addresses at which the server may reach the client besides the one the holepuncher sees,
+    /// like RegisterContents::candidates.
+    pub candidates: Vec<SocketAddr>,
+    /// SESSION_MAC_SIZE bytes proving knowledge of the holepuncher's pre-shared key, or empty if authentication is not used
+    pub mac: Vec<u8>,
+}
+
+/// Asks the holepuncher for the metadata of a session, without joining it.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct QueryContents {
+    pub session_id: Vec<u8>,
+    /// SESSION_MAC_SIZE bytes proving knowledge of the holepuncher's pre-shared key, or empty if authentication is not used
+    pub mac: Vec<u8>,
+}
+
+/// The holepuncher's answer to a Query for an existing session.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct QueryResultContents {
+    pub session_id: Vec<u8>,
+    /// The metadata the server registered the session with, empty if it didn't set any
+    pub metadata: Vec<u8>,
+}
+
+/// Opens a connection or keeps it alive.
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct HelloReqContents {
+    /// Application-level ID of the client, at most MAX_HANDLE_SIZE bytes, so that the server can address it by that.
+    /// Empty if the sender doesn't identify itself, e.g. when sent by a server.
+    pub handle: Vec<u8>,
 }
 
+/// Answers a HelloReq.
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct HelloRespContents {
+    /// Keepalive interval the sender proposes to the peer, sent by servers so that their clients keep the
+    /// connection alive at least as often as they do. Whole milliseconds, non-zero and at most u32::MAX of them.
+    /// None if the sender doesn't propose one, e.g. when sent by a client or the holepuncher.
+    pub keepalive_interval: Option<Duration>,
+}
+
+impl HelloRespContents {
+    /// Returns a HelloResp proposing the given keepalive interval, rounded into the range that can be sent.
+    pub fn proposing(interval: Duration) -> Self {
+        let millis = interval.as_millis().clamp(1, u32::MAX as u128) as u64;
+        return Self {
+            keepalive_interval: Some(Duration::from_millis(millis)),
+        };
+    }
+}
+
+/// What a Data message carries. With the bytes feature, this is a bytes::Bytes, so that clones of a message
+/// share its payload instead of copying it.
+#[cfg(feature = "bytes")]
+pub type Payload = bytes::Bytes;
+/// What a Data message carries. With the bytes feature, this is a bytes::Bytes, so that clones of a message
+/// share its payload instead of copying it.
+#[cfg(not(feature = "bytes"))]
+pub type Payload = Vec<u8>;
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DataContents {
+    pub data: Payload,
+    /// Time (milliseconds since the Unix epoch) after which the receiver drops the message instead of delivering it,
+    /// for data which is worthless when late. Compared against the receiver's clock, so the peers' clocks need to be
+    /// roughly in sync. None if the message never expires.
+    pub expires_at: Option<u64>,
+}
+
+impl DataContents {
+    /// Takes the payload out as a Vec<u8>. With the bytes feature, it is only copied if its buffer is shared.
+    pub fn into_vec(self) -> Vec<u8> {
+        #[cfg(feature = "bytes")]
+        return Vec::from(self.data);
+        #[cfg(not(feature = "bytes"))]
+        return self.data;
+    }
+
+    /// Returns the deadline for a message which expires after the given time to live, as used for expires_at.
+    /// Times to live too large to be represented are treated as effectively infinite.
+    pub fn deadline_after(ttl: Duration) -> u64 {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO);
+        return u64::try_from((now + ttl.min(FOREVER)).as_millis()).unwrap_or(u64::MAX);
+    }
+
+    /// Returns true if the message has a deadline and it has passed.
+    pub fn is_expired(&self) -> bool {
+        return self.expires_at.is_some_and(is_past);
+    }
+}
+
+// Returns true if the given deadline, in milliseconds since the Unix epoch as used for expires_at, has passed.
+pub(crate) fn is_past(deadline: u64) -> bool {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO);
+    return now.as_millis() > u128::from(deadline);
+}
+
+/// One piece of a datagram too large to fit into a single Data message.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DataFragmentContents {
+    /// Identifies the datagram this fragment belongs to (unique per sender)
+    pub message_id: u32,
+    /// Position of this fragment within the datagram
+    pub index: u16,
+    /// Total number of fragments the datagram was split into
+    pub count: u16,
+    pub data: Vec<u8>,
+    /// Deadline of the whole datagram, like DataContents::expires_at. Every fragment of a datagram carries the same one.
+    pub expires_at: Option<u64>,
+}
+
+impl DataFragmentContents {
+    /// Returns true if the fragment has a deadline and it has passed.
+    pub fn is_expired(&self) -> bool {
+        return self.expires_at.is_some_and(is_past);
+    }
+}
+
+/// A datagram which the receiver acknowledges with a DataAck. Retransmitted until acknowledged.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ReliableDataContents {
+    /// Identifies the datagram (unique per sender), so that retransmissions can be recognized
+    pub seq: u32,
     pub data: Vec<u8>,
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DataAckContents {
+    /// The sequence number of the acknowledged ReliableData
+    pub seq: u32,
+}
+
+/// Asks the holepuncher which address the request came from, as used for detecting the type of NAT.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AddrReqContents {
+    /// Answer from the holepuncher's other port (the probe port if asked on the main port, and vice versa)
+    pub change_port: bool,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AddrRespContents {
+    /// The address the AddrReq came from, as seen by the holepuncher
+    pub mapped_addr: SocketAddr,
+    /// The holepuncher's probe port, if it has one
+    pub probe_port: Option<u16>,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct PeerInfoContents {
+    /// The session the Join was for, so that peers can tell which session the address belongs to
+    pub session_id: Vec<u8>,
     pub peer_addr: SocketAddr,
+    /// Further addresses the peer may be reachable at, as announced in its Register or Join. At most MAX_CANDIDATES.
+    pub candidates: Vec<SocketAddr>,
+    /// MAC_SIZE bytes proving that the PeerInfo comes from a holepuncher with the pre-shared key, or empty
+    pub mac: Vec<u8>,
 }
 
+/// Moves a session to another server address, e.g. during a rolling deploy.
+/// Sent by the server owning the session to the holepuncher, which tells the session's clients about the new address.
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MigrateContents {
+    pub session_id: Vec<u8>,
+    /// The address the session's server is reachable at from now on
+    pub new_addr: SocketAddr,
+    /// MAC_SIZE bytes proving knowledge of the holepuncher's pre-shared key, or empty if authentication is not used
+    pub mac: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct RegisterAckContents {
     pub session_id: Vec<u8>,
+    /// The address the Register came from, as seen by the holepuncher.
+    /// None if the holepuncher doesn't report it.
+    pub mapped_addr: Option<SocketAddr>,
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SessionNotFoundContents {
     pub session_id: Vec<u8>,
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RegisterRejectedContents {
+    pub session_id: Vec<u8>,
+}
+
+/// A message relayed by the holepuncher between two peers which cannot reach each other directly.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RelayDataContents {
+    /// Session the two peers belong to
+    pub session_id: Vec<u8>,
+    /// When sent by the server: the client to relay to.
+    /// When delivered by the holepuncher: the peer the message originates from.
+    /// Ignored in messages sent by clients, since they can only talk to the server.
+    pub peer_addr: SocketAddr,
+    /// The relayed message, serialized
+    pub payload: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct GoodbyeContents {
+    pub session_id: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PingContents {
+    /// Arbitrary value, echoed back in the Pong
+    pub nonce: u32,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PongContents {
+    /// The nonce of the Ping being answered
+    pub nonce: u32,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Message {
     LocalInterrupt,
     Register(RegisterContents),
     Join(JoinContents),
     Data(DataContents),
+    DataFragment(DataFragmentContents),
     PeerInfo(PeerInfoContents),
     RegisterAck(RegisterAckContents),
     SessionNotFound(SessionNotFoundContents),
-    HelloReq,
-    HelloResp,
+    HelloReq(HelloReqContents),
+    HelloResp(HelloRespContents),
+    Goodbye(GoodbyeContents),
+    RegisterRejected(RegisterRejectedContents),
+    RelayData(RelayDataContents),
+    Ping(PingContents),
+    Pong(PongContents),
+    ReliableData(ReliableDataContents),
+    DataAck(DataAckContents),
+    AddrReq(AddrReqContents),
+    AddrResp(AddrRespContents),
+    Query(QueryContents),
+    QueryResult(QueryResultContents),
+    Migrate(MigrateContents),
 }
 
 impl Message {
@@ -72,275 +339,1661 @@ impl Message {
         (u16::from(top_byte) << 8) | u16::from(bottom_byte)
     }
     
-    // internal function for reducing code repetition
-    fn serialize_payload_carrier(packet_type: u16, payload: &[u8]) -> Result<Vec<u8>, ()> {
-        let payload_len = payload.len();
-        let total_len = match u16::try_from(payload_len + 4) {
-            Ok(len) => {
-                len
+    // Appends the wire representation of an address:
+    // IPv4: 1 B (addr type = 4) + 4 B (addr) + 2 B (port) = 7 B
+    // IPv6: 1 B (addr type = 6) + 16 B (addr) + 2 B (port) + 4 B (scope ID) = 23 B
+    fn serialize_addr(addr: &SocketAddr, out: &mut Vec<u8>) {
+        match addr {
+            SocketAddr::V4(v4_addr) => {
+                out.push(4u8);
+                out.extend_from_slice(&v4_addr.ip().octets());
+            },
+            SocketAddr::V6(v6_addr) => {
+                out.push(6u8);
+                out.extend_from_slice(&v6_addr.ip().octets());
+            },
+        }
+        let (port_top, port_bot) = Self::to_net(addr.port());
+        out.extend_from_slice(&[port_top, port_bot]);
+        if let SocketAddr::V6(v6_addr) = addr {
+            // link-local addresses are unusable without their scope
+            out.extend_from_slice(&v6_addr.scope_id().to_be_bytes());
+        }
+    }
+
+    // Parses an address written by serialize_addr from the start of the given bytes.
+    // Returns the address and the number of bytes it occupied, or None if the bytes don't start with a valid address.
+    fn deserialize_addr(from: &[u8]) -> Option<(SocketAddr, usize)> {
+        if from.len() >= 7 && from[0] == 4 {
+            // IPv4 address
+            let port = Self::from_net(from[5], from[6]);
+            let addr = Ipv4Addr::from([from[1], from[2], from[3], from[4]]);
+            return Some((SocketAddr::V4(SocketAddrV4::new(addr, port)), 7));
+        } else if from.len() >= 23 && from[0] == 6 {
+            // IPv6 address
+            let port = Self::from_net(from[17], from[18]);
+            let mut addr_bytes = [0u8; 16];
+            addr_bytes.copy_from_slice(&from[1..17]);
+            let addr = Ipv6Addr::from(addr_bytes);
+            let scope_id = u32::from_be_bytes([from[19], from[20], from[21], from[22]]);
+            return Some((SocketAddr::V6(SocketAddrV6::new(addr, port, 0, scope_id)), 23));
+        } else {
+            return None;
+        }
+    }
+
+    // Appends the wire representation of a session ID: 1 B length + the session ID itself.
+    // Returns Err if the session ID is longer than MAX_SESSION_ID_SIZE.
+    fn serialize_session_id(session_id: &[u8], out: &mut Vec<u8>) -> Result<(), ()> {
+        return Self::serialize_prefixed(session_id, MAX_SESSION_ID_SIZE, out);
+    }
+
+    // Reads the data of a Data message, decompressing it if it was sent compressed.
+    fn deserialize_data(from: &[u8], compressed: bool) -> Result<Vec<u8>, DeserializeError> {
+        if !compressed {
+            if from.len() > MAX_DATA_SIZE {
+                // datagram too big
+                return Err(DeserializeError::Malformed);
+            }
+            return Ok(from.to_vec());
+        }
+        // the data must not grow beyond MAX_DATA_SIZE when decompressed either
+        #[cfg(feature = "compression")]
+        match lz4_flex::block::decompress(from, MAX_DATA_SIZE) {
+            Ok(data) => {
+                return Ok(data);
             },
+            Err(_) => {
+                return Err(DeserializeError::Malformed);
+            }
+        }
+        // without the compression feature, compressed data can't be read
+        #[cfg(not(feature = "compression"))]
+        return Err(DeserializeError::Malformed);
+    }
+
+    // Parses a session ID written by serialize_session_id from the start of the given bytes.
+    // Returns the session ID and the number of bytes it occupied, or None if the bytes are too short.
+    fn deserialize_session_id(from: &[u8]) -> Option<(Vec<u8>, usize)> {
+        return Self::deserialize_prefixed(from, MAX_SESSION_ID_SIZE);
+    }
+
+    // Appends the wire representation of session metadata: 1 B length + the metadata itself.
+    // Returns Err if the metadata is longer than MAX_METADATA_SIZE.
+    fn serialize_metadata(metadata: &[u8], out: &mut Vec<u8>) -> Result<(), ()> {
+        return Self::serialize_prefixed(metadata, MAX_METADATA_SIZE, out);
+    }
+
+    // Parses metadata written by serialize_metadata, like deserialize_session_id.
+    fn deserialize_metadata(from: &[u8]) -> Option<(Vec<u8>, usize)> {
+        return Self::deserialize_prefixed(from, MAX_METADATA_SIZE);
+    }
+
+    // Returns the wire type of a message which carries the given candidate addresses.
+    // Without candidates, the message keeps its plain type so that older peers can still read it.
+    fn type_with_candidates(msg_type: u16, candidates: &[SocketAddr]) -> u16 {
+        if candidates.is_empty() {
+            return msg_type;
+        } else {
+            return msg_type | CANDIDATES_FLAG;
+        }
+    }
+
+    // Appends the wire representation of candidate addresses, if there are any: 1 B count + the addresses.
+    // Returns Err if there are more than MAX_CANDIDATES.
+    fn serialize_candidates(candidates: &[SocketAddr], out: &mut Vec<u8>) -> Result<(), ()> {
+        if candidates.is_empty() {
+            return Ok(());
+        }
+        if candidates.len() > MAX_CANDIDATES {
+            return Err(());
+        }
+        out.push(candidates.len() as u8);
+        for addr in candidates {
+            Self::serialize_addr(addr, out);
+        }
+        return Ok(());
+    }
+
+    // Parses candidate addresses written by serialize_candidates, if the message type says there are any.
+    // Returns them and the rest of the bytes.
+    fn split_candidates(msg_type: u16, from: &[u8]) -> Result<(Vec<SocketAddr>, &[u8]), DeserializeError> {
+        let mut candidates = Vec::new();
+        if msg_type & CANDIDATES_FLAG == 0 {
+            return Ok((candidates, from));
+        }
+        if from.is_empty() || usize::from(from[0]) > MAX_CANDIDATES {
+            return Err(DeserializeError::Malformed);
+        }
+        let mut rest = &from[1..];
+        for _ in 0..from[0] {
+            match Self::deserialize_addr(rest) {
+                Some((addr, addr_len)) => {
+                    candidates.push(addr);
+                    rest = &rest[addr_len..];
+                },
+                None => {
+                    return Err(DeserializeError::Malformed);
+                }
+            }
+        }
+        return Ok((candidates, rest));
+    }
+
+    // Appends 1 B length + the bytes themselves, if there are at most max (and at most 255) bytes.
+    fn serialize_prefixed(bytes: &[u8], max: usize, out: &mut Vec<u8>) -> Result<(), ()> {
+        let len = match u8::try_from(bytes.len()) {
+            Ok(len) if usize::from(len) <= max => len,
             _ => {
-                return Err(())
+                return Err(());
+            }
+        };
+        out.push(len);
+        out.extend_from_slice(bytes);
+        return Ok(());
+    }
+
+    // Parses bytes written by serialize_prefixed from the start of the given bytes.
+    // Returns them and the number of bytes they occupied, including the length.
+    fn deserialize_prefixed(from: &[u8], max: usize) -> Option<(Vec<u8>, usize)> {
+        if from.is_empty() {
+            return None;
+        }
+        let len = usize::from(from[0]);
+        if len > max || from.len() < 1 + len {
+            return None;
+        }
+        return Some((from[1..1 + len].to_vec(), 1 + len));
+    }
+
+    // internal function for reducing code repetition
+    // lays out the header, the payload and (if enabled) the trailing checksum
+    fn serialize_payload_carrier(packet_type: u16, payload: &[u8], out: &mut Vec<u8>) -> Result<(), ()> {
+        out.clear();
+        out.resize(frame_size(payload.len()), 0);
+        encode_frame(packet_type, payload, out)?;
+        return Ok(());
+    }
+
+    /// Serializes a RelayData carrying the given already serialized message into the buffer, like serialize_into would,
+    /// but without copying the message into a RelayDataContents first. Useful for sending the same message through
+    /// the relay to many peers.
+    pub fn serialize_relayed_into(session_id: &[u8], peer_addr: SocketAddr, relayed: &[u8], buf: &mut Vec<u8>) -> Result<(), ()> {
+        if relayed.len() > MAX_RELAY_PAYLOAD_SIZE {
+            return Err(());
+        }
+        // session ID + peer address + relayed message
+        let mut payload = Vec::new();
+        Self::serialize_session_id(session_id, &mut payload)?;
+        Self::serialize_addr(&peer_addr, &mut payload);
+        payload.extend_from_slice(relayed);
+        return Self::serialize_payload_carrier(RELAY_DATA, &payload, buf);
+    }
+
+    /// Serializes a Data message with the given payload and deadline into the buffer, like serialize_into would,
+    /// but without copying the payload into a DataContents first. Useful for payloads held in a shared buffer.
+    pub fn serialize_data_into(data: &[u8], expires_at: Option<u64>, buf: &mut Vec<u8>) -> Result<(), ()> {
+        let data_len = data.len();
+        if data_len > MAX_DATA_SIZE {
+            return Err(());
+        }
+        if let Some(deadline) = expires_at {
+            // 8 B deadline, followed by the (possibly compressed) data
+            let mut payload = Vec::with_capacity(8 + data_len);
+            payload.extend_from_slice(&deadline.to_be_bytes());
+            #[cfg(feature = "compression")]
+            {
+                let compressed = lz4_flex::block::compress(data);
+                if compressed.len() < data_len {
+                    payload.extend_from_slice(&compressed);
+                    return Self::serialize_payload_carrier(COMPRESSED_EXPIRING_DATA, &payload, buf);
+                }
+            }
+            payload.extend_from_slice(data);
+            return Self::serialize_payload_carrier(EXPIRING_DATA, &payload, buf);
+        }
+        // compress the data if that makes it smaller, e.g. for repetitive text
+        #[cfg(feature = "compression")]
+        {
+            let compressed = lz4_flex::block::compress(data);
+            if compressed.len() < data_len {
+                return Self::serialize_payload_carrier(COMPRESSED_DATA, &compressed, buf);
+            }
+        }
+        return Self::serialize_payload_carrier(DATA, data, buf);
+    }
+
+    /// Serializes several messages into a single datagram, one after the other, each with its own header and checksum.
+    /// Fails if any of them can't be serialized, or if together they are larger than MAX_MESSAGE_SIZE.
+    pub fn serialize_batch(msgs: &[Message]) -> Result<Vec<u8>, ()> {
+        if msgs.is_empty() {
+            return Err(());
+        }
+        let mut batch = Vec::new();
+        let mut buf = Vec::new();
+        for msg in msgs {
+            msg.serialize_into(&mut buf)?;
+            batch.extend_from_slice(&buf);
+        }
+        if batch.len() > MAX_MESSAGE_SIZE {
+            return Err(());
+        }
+        return Ok(batch);
+    }
+
+    /// Splits a datagram made by serialize_batch into its messages, in order.
+    /// A datagram carrying a single message is a batch of one. Fails if any of the messages is invalid.
+    pub fn deserialize_batch(from: &[u8]) -> Result<Vec<Message>, DeserializeError> {
+        if from.len() > MAX_MESSAGE_SIZE {
+            return Err(DeserializeError::TooLarge);
+        }
+        let mut msgs = Vec::new();
+        let mut rest = from;
+        while !rest.is_empty() || msgs.is_empty() {
+            let length = first_frame_len(rest)?;
+            msgs.push(Self::deserialize(&rest[0..length])?);
+            rest = &rest[length..];
+        }
+        return Ok(msgs);
+    }
+
+    /// Returns the wire type of this message, i.e. one of the message type constants (REGISTER, JOIN, etc.)
+    pub fn type_id(&self) -> u16 {
+        match self {
+            Message::LocalInterrupt => LOCAL_INTERRUPT,
+            Message::Register(_) => REGISTER,
+            Message::Join(_) => JOIN,
+            Message::Data(_) => DATA,
+            Message::DataFragment(_) => DATA_FRAGMENT,
+            Message::PeerInfo(_) => PEER_INFO,
+            Message::RegisterAck(_) => REGISTER_ACK,
+            Message::SessionNotFound(_) => SESSION_NOT_FOUND,
+            Message::HelloReq(_) => HELLO_REQ,
+            Message::HelloResp(_) => HELLO_RESP,
+            Message::Goodbye(_) => GOODBYE,
+            Message::RegisterRejected(_) => REGISTER_REJECTED,
+            Message::RelayData(_) => RELAY_DATA,
+            Message::Ping(_) => PING,
+            Message::Pong(_) => PONG,
+            Message::ReliableData(_) => RELIABLE_DATA,
+            Message::DataAck(_) => DATA_ACK,
+            Message::AddrReq(_) => ADDR_REQ,
+            Message::AddrResp(_) => ADDR_RESP,
+            Message::Query(_) => QUERY,
+            Message::QueryResult(_) => QUERY_RESULT,
+            Message::Migrate(_) => MIGRATE,
+        }
+    }
+
+    /// Returns the wire type of a serialized message without deserializing it, e.g. to route or drop a datagram cheaply.
+    /// Only the header is looked at: the magic, and whether the stated length matches the length of the buffer.
+    /// Returns None if the buffer is too short or doesn't hold exactly one message. The checksum is not verified,
+    /// so deserialize may still reject the message. Flags such as COMPRESSED_FLAG are part of the returned type.
+    pub fn peek_type(buf: &[u8]) -> Option<u16> {
+        match first_frame_len(buf) {
+            Ok(len) if len == buf.len() => {
+                return Some(u16::from_be_bytes([buf[4], buf[5]]));
             },
+            _ => {
+                return None;
+            }
+        }
+    }
+
+    /// Returns the deadline of a serialized Data or DataFragment message, as in its expires_at, without deserializing it,
+    /// e.g. to drop an expired datagram before relaying it. None if the message has no deadline or isn't one of those.
+    pub fn peek_expires_at(buf: &[u8]) -> Option<u64> {
+        let msg_type = Self::peek_type(buf)?;
+        let expiring = msg_type & EXPIRES_FLAG != 0 && matches!(msg_type & !COMPRESSED_FLAG & !EXPIRES_FLAG, DATA | DATA_FRAGMENT);
+        if !expiring || buf.len() < HEADER_SIZE + 8 {
+            return None;
+        }
+        return Some(u64::from_be_bytes(buf[HEADER_SIZE..HEADER_SIZE + 8].try_into().unwrap()));
+    }
+
+    /// If this message carries more data than fits into a single message, returns the size of that data
+    /// and the maximum allowed, which is why serialize would fail. Such data has to be fragmented.
+    pub fn oversized_payload(&self) -> Option<(usize, usize)> {
+        let (size, max) = match self {
+            Message::Data(contents) => (contents.data.len(), MAX_DATA_SIZE),
+            Message::ReliableData(contents) => (contents.data.len(), MAX_DATA_SIZE),
+            Message::DataFragment(contents) => (contents.data.len(), MAX_DATA_SIZE),
+            Message::RelayData(contents) => (contents.payload.len(), MAX_RELAY_PAYLOAD_SIZE),
+            _ => {
+                return None;
+            }
         };
-        let mut msg = vec![0u8; usize::from(total_len)];
-        // add the header: packet size and type
-        let (len_top, len_bot) = Self::to_net(total_len);
-        let (type_top, type_bot) = Self::to_net(packet_type);
-        
-        msg[0] = len_top;
-        msg[1] = len_bot;
-        msg[2] = type_top;
-        msg[3] = type_bot;
-        // TODO make this a faster copy method
-        for i in 0..payload_len {
-            msg[4+i] = payload[i];
+        if size > max {
+            return Some((size, max));
+        }
+        return None;
+    }
+
+    /// Returns the name of this message's type, for logging
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Message::LocalInterrupt => "LocalInterrupt",
+            Message::Register(_) => "Register",
+            Message::Join(_) => "Join",
+            Message::Data(_) => "Data",
+            Message::DataFragment(_) => "DataFragment",
+            Message::PeerInfo(_) => "PeerInfo",
+            Message::RegisterAck(_) => "RegisterAck",
+            Message::SessionNotFound(_) => "SessionNotFound",
+            Message::HelloReq(_) => "HelloReq",
+            Message::HelloResp(_) => "HelloResp",
+            Message::Goodbye(_) => "Goodbye",
+            Message::RegisterRejected(_) => "RegisterRejected",
+            Message::RelayData(_) => "RelayData",
+            Message::Ping(_) => "Ping",
+            Message::Pong(_) => "Pong",
+            Message::ReliableData(_) => "ReliableData",
+            Message::DataAck(_) => "DataAck",
+            Message::AddrReq(_) => "AddrReq",
+            Message::AddrResp(_) => "AddrResp",
+            Message::Query(_) => "Query",
+            Message::QueryResult(_) => "QueryResult",
+            Message::Migrate(_) => "Migrate",
         }
-        
-        return Ok(msg);
     }
 
     pub fn serialize(&self) -> Result<Vec<u8>, ()> {
+        let mut buf = Vec::new();
+        self.serialize_into(&mut buf)?;
+        return Ok(buf);
+    }
+
+    /// Like serialize, but writes the message into the given buffer, replacing its contents.
+    /// Reusing one buffer for many messages saves allocating a new one each time.
+    /// If serialization fails, the buffer's contents are unspecified.
+    pub fn serialize_into(&self, buf: &mut Vec<u8>) -> Result<(), ()> {
         match self {
             Message::LocalInterrupt => {
-                // Length = 4, type = 1
-                let (type_top, type_bot) = Self::to_net(LOCAL_INTERRUPT);
-                return Ok(vec![0u8, 4u8, type_top, type_bot]);
+                return Self::serialize_payload_carrier(LOCAL_INTERRUPT, &[], buf);
             },
-            Message::HelloReq => {
-                // Length = 4, type = 1
-                let (type_top, type_bot) = Self::to_net(HELLO_REQ);
-                return Ok(vec![0u8, 4u8, type_top, type_bot]);
+            Message::HelloReq(contents) => {
+                if contents.handle.len() > MAX_HANDLE_SIZE {
+                    return Err(());
+                }
+                // the handle, if any
+                return Self::serialize_payload_carrier(HELLO_REQ, &contents.handle, buf);
             },
-            Message::HelloResp => {
-                // Length = 4, type = 1
-                let (type_top, type_bot) = Self::to_net(HELLO_RESP);
-                return Ok(vec![0u8, 4u8, type_top, type_bot]);
+            Message::HelloResp(contents) => {
+                // the proposed keepalive interval in milliseconds, if any
+                match contents.keepalive_interval {
+                    Some(interval) => {
+                        let millis = interval.as_millis();
+                        if millis == 0 || millis > u32::MAX as u128 {
+                            return Err(());
+                        }
+                        return Self::serialize_payload_carrier(HELLO_RESP, &(millis as u32).to_be_bytes(), buf);
+                    },
+                    None => {
+                        return Self::serialize_payload_carrier(HELLO_RESP, &[], buf);
+                    }
+                }
             },
             Message::Register(contents)=> {
-                let session_id_len = contents.session_id.len();
-                if session_id_len > MAX_SESSION_ID_SIZE {
+                if !contents.mac.is_empty() && contents.mac.len() != SESSION_MAC_SIZE {
                     return Err(());
                 }
-                return Self::serialize_payload_carrier(REGISTER, &contents.session_id);
+                // session ID + metadata + candidates, if any + MAC, if any
+                let mut payload = Vec::new();
+                Self::serialize_session_id(&contents.session_id, &mut payload)?;
+                Self::serialize_metadata(&contents.metadata, &mut payload)?;
+                Self::serialize_candidates(&contents.candidates, &mut payload)?;
+                payload.extend_from_slice(&contents.mac);
+                return Self::serialize_payload_carrier(Self::type_with_candidates(REGISTER, &contents.candidates), &payload, buf);
             },
             Message::RegisterAck(contents)=> {
-                let session_id_len = contents.session_id.len();
-                if session_id_len > MAX_SESSION_ID_SIZE {
-                    return Err(());
+                // session ID + observed address, if any
+                let mut payload = Vec::new();
+                Self::serialize_session_id(&contents.session_id, &mut payload)?;
+                if let Some(addr) = &contents.mapped_addr {
+                    Self::serialize_addr(addr, &mut payload);
                 }
-                return Self::serialize_payload_carrier(REGISTER_ACK, &contents.session_id);
+                return Self::serialize_payload_carrier(REGISTER_ACK, &payload, buf);
             },
             Message::Join(contents)=> {
-                let session_id_len = contents.session_id.len();
-                if session_id_len > MAX_SESSION_ID_SIZE {
+                if !contents.mac.is_empty() && contents.mac.len() != SESSION_MAC_SIZE {
                     return Err(());
                 }
-                return Self::serialize_payload_carrier(JOIN, &contents.session_id);
+                // session ID + candidates, if any + MAC, if any
+                let mut payload = Vec::new();
+                Self::serialize_session_id(&contents.session_id, &mut payload)?;
+                Self::serialize_candidates(&contents.candidates, &mut payload)?;
+                payload.extend_from_slice(&contents.mac);
+                return Self::serialize_payload_carrier(Self::type_with_candidates(JOIN, &contents.candidates), &payload, buf);
             },
-            Message::SessionNotFound(contents)=> {
-                let session_id_len = contents.session_id.len();
-                if session_id_len > MAX_SESSION_ID_SIZE {
+            Message::Query(contents)=> {
+                if !contents.mac.is_empty() && contents.mac.len() != SESSION_MAC_SIZE {
                     return Err(());
                 }
-                return Self::serialize_payload_carrier(SESSION_NOT_FOUND, &contents.session_id);
+                // session ID + MAC, if any
+                let mut payload = Vec::new();
+                Self::serialize_session_id(&contents.session_id, &mut payload)?;
+                payload.extend_from_slice(&contents.mac);
+                return Self::serialize_payload_carrier(QUERY, &payload, buf);
+            },
+            Message::QueryResult(contents)=> {
+                // session ID + metadata
+                let mut payload = Vec::new();
+                Self::serialize_session_id(&contents.session_id, &mut payload)?;
+                Self::serialize_metadata(&contents.metadata, &mut payload)?;
+                return Self::serialize_payload_carrier(QUERY_RESULT, &payload, buf);
+            },
+            Message::Goodbye(contents)=> {
+                let mut payload = Vec::new();
+                Self::serialize_session_id(&contents.session_id, &mut payload)?;
+                return Self::serialize_payload_carrier(GOODBYE, &payload, buf);
+            },
+            Message::RegisterRejected(contents)=> {
+                let mut payload = Vec::new();
+                Self::serialize_session_id(&contents.session_id, &mut payload)?;
+                return Self::serialize_payload_carrier(REGISTER_REJECTED, &payload, buf);
+            },
+            Message::Ping(contents)=> {
+                return Self::serialize_payload_carrier(PING, &contents.nonce.to_be_bytes(), buf);
+            },
+            Message::Pong(contents)=> {
+                return Self::serialize_payload_carrier(PONG, &contents.nonce.to_be_bytes(), buf);
+            },
+            Message::SessionNotFound(contents)=> {
+                let mut payload = Vec::new();
+                Self::serialize_session_id(&contents.session_id, &mut payload)?;
+                return Self::serialize_payload_carrier(SESSION_NOT_FOUND, &payload, buf);
             },
             Message::PeerInfo(contents)=> {
-                match contents.peer_addr {
-                    SocketAddr::V4(v4_addr) => {
-                        // the length here would be 4 B (header) + 1 B (addr type) + 4 B (addr) + 2 B (port) = 11 B
-                        let addr_bytes = v4_addr.ip().octets();
-                        let (port_top, port_bot) = Self::to_net(v4_addr.port());
-                        let (type_top, type_bot) = Self::to_net(PEER_INFO);
-                        return Ok(
-                            vec![
-                                0u8, 11u8, type_top, type_bot,
-                                4u8, addr_bytes[0], addr_bytes[1], addr_bytes[2], addr_bytes[3],
-                                port_top, port_bot,
-                        ]);
-                    },
-                    SocketAddr::V6(v6_addr) => {
-                        // the length here would be 4 B (header) + 1 B (addr type) + 16 B (addr) + 2 B (port) = 23 B
-                        let addr_bytes = v6_addr.ip().octets();
-                        let (port_top, port_bot) = Self::to_net(v6_addr.port());
-                        let (type_top, type_bot) = Self::to_net(PEER_INFO);
-                        return Ok(
-                            vec![
-                                0u8, 23u8, type_top, type_bot,
-                                6u8,
-                                addr_bytes[0], addr_bytes[1], addr_bytes[2], addr_bytes[3],
-                                addr_bytes[4], addr_bytes[5], addr_bytes[6], addr_bytes[7],
-                                addr_bytes[8], addr_bytes[9], addr_bytes[10], addr_bytes[11],
-                                addr_bytes[12], addr_bytes[13], addr_bytes[14], addr_bytes[15],
-                                port_top, port_bot,
-                            ]);
-                    },
+                if !contents.mac.is_empty() && contents.mac.len() != MAC_SIZE {
+                    return Err(());
                 }
+                // session ID + peer address + candidates, if any + MAC, if any
+                let mut payload = Vec::new();
+                Self::serialize_session_id(&contents.session_id, &mut payload)?;
+                Self::serialize_addr(&contents.peer_addr, &mut payload);
+                Self::serialize_candidates(&contents.candidates, &mut payload)?;
+                payload.extend_from_slice(&contents.mac);
+                return Self::serialize_payload_carrier(Self::type_with_candidates(PEER_INFO, &contents.candidates), &payload, buf);
+            },
+            Message::Migrate(contents)=> {
+                if !contents.mac.is_empty() && contents.mac.len() != MAC_SIZE {
+                    return Err(());
+                }
+                // session ID + new address + MAC, if any
+                let mut payload = Vec::new();
+                Self::serialize_session_id(&contents.session_id, &mut payload)?;
+                Self::serialize_addr(&contents.new_addr, &mut payload);
+                payload.extend_from_slice(&contents.mac);
+                return Self::serialize_payload_carrier(MIGRATE, &payload, buf);
+            },
+            Message::RelayData(contents)=> {
+                return Self::serialize_relayed_into(&contents.session_id, contents.peer_addr, &contents.payload, buf);
             },
             Message::Data(contents)=> {
-                let data_len = contents.data.len();
-                if data_len > MAX_DATA_SIZE {
+                return Self::serialize_data_into(&contents.data, contents.expires_at, buf);
+            },
+            Message::ReliableData(contents)=> {
+                if contents.data.len() > MAX_DATA_SIZE {
                     return Err(());
                 }
-                return Self::serialize_payload_carrier(DATA, &contents.data);
+                // 4 B sequence number, followed by the data
+                let mut payload = Vec::with_capacity(4 + contents.data.len());
+                payload.extend_from_slice(&contents.seq.to_be_bytes());
+                payload.extend_from_slice(&contents.data);
+                return Self::serialize_payload_carrier(RELIABLE_DATA, &payload, buf);
+            },
+            Message::DataAck(contents)=> {
+                return Self::serialize_payload_carrier(DATA_ACK, &contents.seq.to_be_bytes(), buf);
+            },
+            Message::AddrReq(contents)=> {
+                // 1 B flags, only the lowest bit (change port) is used
+                return Self::serialize_payload_carrier(ADDR_REQ, &[u8::from(contents.change_port)], buf);
+            },
+            Message::AddrResp(contents)=> {
+                // mapped address + 2 B probe port, zero if there is none
+                let mut payload = Vec::new();
+                Self::serialize_addr(&contents.mapped_addr, &mut payload);
+                payload.extend_from_slice(&contents.probe_port.unwrap_or(0).to_be_bytes());
+                return Self::serialize_payload_carrier(ADDR_RESP, &payload, buf);
+            },
+            Message::DataFragment(contents)=> {
+                if contents.data.len() > MAX_DATA_SIZE {
+                    return Err(());
+                }
+                // an 8 B deadline if it has one, then 4 B message ID + 2 B index + 2 B count, followed by the data
+                let (index_top, index_bot) = Self::to_net(contents.index);
+                let (count_top, count_bot) = Self::to_net(contents.count);
+                let mut payload = Vec::with_capacity(16 + contents.data.len());
+                if let Some(deadline) = contents.expires_at {
+                    payload.extend_from_slice(&deadline.to_be_bytes());
+                }
+                payload.extend_from_slice(&contents.message_id.to_be_bytes());
+                payload.extend_from_slice(&[index_top, index_bot, count_top, count_bot]);
+                payload.extend_from_slice(&contents.data);
+                let msg_type = if contents.expires_at.is_some() { EXPIRING_DATA_FRAGMENT } else { DATA_FRAGMENT };
+                return Self::serialize_payload_carrier(msg_type, &payload, buf);
             },
         }
     }
 
-    pub fn deserialize(from: &[u8]) -> Result<Message, ()> {
-        // measure and check the size of the package
+    // Each message type checks its own length in its arm, rather than in guards which would fall through to the unknown type arm.
+    #[allow(clippy::collapsible_match)]
+    pub fn deserialize(from: &[u8]) -> Result<Message, DeserializeError> {
+        // check the framing, then continue with only the header and payload
+        let (msg_type, payload) = decode_frame(from)?;
+        let from = &from[0..HEADER_SIZE + payload.len()];
         let length = from.len();
-        if length < 4 {
-            // error: not enough bytes for the header
-            return Err(());
-        }
-        
-        // header consists of length and message type
-        let len_top = from[0];
-        let len_bot = from[1];
-        let type_top = from[2];
-        let type_bot = from[3];
-        
-        // parse the type
-        let msg_type = Self::from_net(type_top, type_bot);
-
-        // check that the stated length matches the actual message length
-        // since we're working with datagrams, it should match exactly
-        if length != usize::from(Self::from_net(len_top, len_bot)) {
-            return Err(());
-        }
 
         match msg_type {
             LOCAL_INTERRUPT => {
-                if length == 4 {
+                if length == HEADER_SIZE {
                     return Ok(Message::LocalInterrupt);
                 } else {
-                    return Err(());
+                    return Err(DeserializeError::Malformed);
                 }
             },
             HELLO_REQ => {
-                if length == 4 {
-                    return Ok(Message::HelloReq);
+                if length - HEADER_SIZE <= MAX_HANDLE_SIZE {
+                    return Ok(Message::HelloReq(HelloReqContents {
+                        handle: from[HEADER_SIZE..].to_vec(),
+                    }));
                 } else {
-                    return Err(());
+                    return Err(DeserializeError::Malformed);
                 }
             },
             HELLO_RESP => {
-                if length == 4 {
-                    return Ok(Message::HelloResp);
+                // older peers send no proposal at all
+                if length == HEADER_SIZE {
+                    return Ok(Message::HelloResp(HelloRespContents::default()));
+                } else if length == HEADER_SIZE + 4 {
+                    let millis = u32::from_be_bytes(from[HEADER_SIZE..HEADER_SIZE + 4].try_into().unwrap());
+                    if millis == 0 {
+                        return Err(DeserializeError::Malformed);
+                    }
+                    return Ok(Message::HelloResp(HelloRespContents {
+                        keepalive_interval: Some(Duration::from_millis(millis as u64)),
+                    }));
                 } else {
-                    return Err(());
+                    return Err(DeserializeError::Malformed);
                 }
             },
-            REGISTER => {
-                let session_id_len = length - 4;
-                if session_id_len > MAX_SESSION_ID_SIZE {
-                    // session ID too big
-                    return Err(())
-                }
-                
-                let mut session_id = vec![0u8; session_id_len];
-                // TODO more efficient data copying
-                for i in 0..session_id_len {
-                    session_id[i] = from[4+i]
+            REGISTER | REGISTER_WITH_CANDIDATES => {
+                let payload = &from[HEADER_SIZE..];
+                let (session_id, id_len) = match Self::deserialize_session_id(payload) {
+                    Some(parsed) => parsed,
+                    None => {
+                        return Err(DeserializeError::Malformed);
+                    }
+                };
+                let (metadata, metadata_len) = match Self::deserialize_metadata(&payload[id_len..]) {
+                    Some(parsed) => parsed,
+                    None => {
+                        return Err(DeserializeError::Malformed);
+                    }
+                };
+                let (candidates, mac) = Self::split_candidates(msg_type, &payload[id_len + metadata_len..])?;
+                if !mac.is_empty() && mac.len() != SESSION_MAC_SIZE {
+                    return Err(DeserializeError::Malformed);
                 }
                 return Ok(Message::Register(RegisterContents {
-                    session_id
+                    session_id,
+                    metadata,
+                    candidates,
+                    mac: mac.to_vec(),
                 }));
             },
             REGISTER_ACK => {
-                let session_id_len = length - 4;
-                if session_id_len > MAX_SESSION_ID_SIZE {
-                    // session ID too big
-                    return Err(())
-                }
-                
-                let mut session_id = vec![0u8; session_id_len];
-                // TODO more efficient data copying
-                for i in 0..session_id_len {
-                    session_id[i] = from[4+i]
-                }
-                return Ok(Message::RegisterAck(RegisterAckContents {
-                    session_id
+                let payload = &from[HEADER_SIZE..];
+                match Self::deserialize_session_id(payload) {
+                    Some((session_id, id_len)) if id_len == payload.len() => {
+                        return Ok(Message::RegisterAck(RegisterAckContents {
+                            session_id,
+                            mapped_addr: None,
+                        }));
+                    },
+                    Some((session_id, id_len)) => {
+                        match Self::deserialize_addr(&payload[id_len..]) {
+                            Some((addr, addr_len)) if id_len + addr_len == payload.len() => {
+                                return Ok(Message::RegisterAck(RegisterAckContents {
+                                    session_id,
+                                    mapped_addr: Some(addr),
+                                }));
+                            },
+                            _ => {
+                                return Err(DeserializeError::Malformed);
+                            }
+                        }
+                    },
+                    _ => {
+                        return Err(DeserializeError::Malformed);
+                    }
+                }
+            },
+            JOIN | JOIN_WITH_CANDIDATES => {
+                let payload = &from[HEADER_SIZE..];
+                let (session_id, id_len) = match Self::deserialize_session_id(payload) {
+                    Some(parsed) => parsed,
+                    None => {
+                        return Err(DeserializeError::Malformed);
+                    }
+                };
+                let (candidates, mac) = Self::split_candidates(msg_type, &payload[id_len..])?;
+                if !mac.is_empty() && mac.len() != SESSION_MAC_SIZE {
+                    return Err(DeserializeError::Malformed);
+                }
+                return Ok(Message::Join(JoinContents {
+                    session_id,
+                    candidates,
+                    mac: mac.to_vec(),
                 }));
             },
-            JOIN => {
-                let session_id_len = length - 4;
-                if session_id_len > MAX_SESSION_ID_SIZE {
-                    // session ID too big
-                    return Err(())
+            QUERY => {
+                let payload = &from[HEADER_SIZE..];
+                match Self::deserialize_session_id(payload) {
+                    Some((session_id, id_len)) if id_len == payload.len() || id_len + SESSION_MAC_SIZE == payload.len() => {
+                        return Ok(Message::Query(QueryContents {
+                            session_id,
+                            mac: payload[id_len..].to_vec(),
+                        }));
+                    },
+                    _ => {
+                        return Err(DeserializeError::Malformed);
+                    }
                 }
-                
-                let mut session_id = vec![0u8; session_id_len];
-                // TODO more efficient data copying
-                for i in 0..session_id_len {
-                    session_id[i] = from[4+i]
+            },
+            QUERY_RESULT => {
+                let payload = &from[HEADER_SIZE..];
+                let (session_id, id_len) = match Self::deserialize_session_id(payload) {
+                    Some(parsed) => parsed,
+                    None => {
+                        return Err(DeserializeError::Malformed);
+                    }
+                };
+                match Self::deserialize_metadata(&payload[id_len..]) {
+                    Some((metadata, metadata_len)) if id_len + metadata_len == payload.len() => {
+                        return Ok(Message::QueryResult(QueryResultContents {
+                            session_id,
+                            metadata,
+                        }));
+                    },
+                    _ => {
+                        return Err(DeserializeError::Malformed);
+                    }
                 }
-                return Ok(Message::Join(JoinContents {
-                    session_id
+            },
+            GOODBYE => {
+                let payload = &from[HEADER_SIZE..];
+                match Self::deserialize_session_id(payload) {
+                    Some((session_id, id_len)) if id_len == payload.len() => {
+                        return Ok(Message::Goodbye(GoodbyeContents {
+                            session_id
+                        }));
+                    },
+                    _ => {
+                        return Err(DeserializeError::Malformed);
+                    }
+                }
+            },
+            REGISTER_REJECTED => {
+                let payload = &from[HEADER_SIZE..];
+                match Self::deserialize_session_id(payload) {
+                    Some((session_id, id_len)) if id_len == payload.len() => {
+                        return Ok(Message::RegisterRejected(RegisterRejectedContents {
+                            session_id
+                        }));
+                    },
+                    _ => {
+                        return Err(DeserializeError::Malformed);
+                    }
+                }
+            },
+            PING => {
+                let payload = &from[HEADER_SIZE..];
+                if payload.len() != 4 {
+                    return Err(DeserializeError::Malformed);
+                }
+                return Ok(Message::Ping(PingContents {
+                    nonce: u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]),
+                }));
+            },
+            PONG => {
+                let payload = &from[HEADER_SIZE..];
+                if payload.len() != 4 {
+                    return Err(DeserializeError::Malformed);
+                }
+                return Ok(Message::Pong(PongContents {
+                    nonce: u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]),
                 }));
             },
             SESSION_NOT_FOUND => {
-                let session_id_len = length - 4;
-                if session_id_len > MAX_SESSION_ID_SIZE {
-                    // session ID too big
-                    return Err(())
-                }
-                
-                let mut session_id = vec![0u8; session_id_len];
-                // TODO more efficient data copying
-                for i in 0..session_id_len {
-                    session_id[i] = from[4+i]
-                }
-                return Ok(Message::SessionNotFound(SessionNotFoundContents {
-                    session_id
+                let payload = &from[HEADER_SIZE..];
+                match Self::deserialize_session_id(payload) {
+                    Some((session_id, id_len)) if id_len == payload.len() => {
+                        return Ok(Message::SessionNotFound(SessionNotFoundContents {
+                            session_id
+                        }));
+                    },
+                    _ => {
+                        return Err(DeserializeError::Malformed);
+                    }
+                }
+            },
+            PEER_INFO | PEER_INFO_WITH_CANDIDATES => {
+                let payload = &from[HEADER_SIZE..];
+                let (session_id, id_len) = match Self::deserialize_session_id(payload) {
+                    Some(x) => x,
+                    None => {
+                        return Err(DeserializeError::Malformed);
+                    }
+                };
+                let rest = &payload[id_len..];
+                let (peer_addr, addr_len) = match Self::deserialize_addr(rest) {
+                    Some(x) => x,
+                    None => {
+                        return Err(DeserializeError::Malformed);
+                    }
+                };
+                let (candidates, mac) = Self::split_candidates(msg_type, &rest[addr_len..])?;
+                if !mac.is_empty() && mac.len() != MAC_SIZE {
+                    return Err(DeserializeError::Malformed);
+                }
+                return Ok(Message::PeerInfo(PeerInfoContents {
+                    session_id,
+                    peer_addr,
+                    candidates,
+                    mac: mac.to_vec(),
                 }));
             },
-            PEER_INFO => {
-                if length == 11 && from[4] == 4 {
-                    // IPv4 address
-                    let port = Self::from_net(from[9], from[10]);
-                    let addr = Ipv4Addr::from([from[5], from[6], from[7], from[8]]);
-                    let peer_addr = SocketAddr::V4(SocketAddrV4::new(addr, port));
-                    return Ok(Message::PeerInfo(PeerInfoContents {
-                        peer_addr,
-                    }));
-                } else if length == 23 && from[4] == 6 {
-                    // IPv6 address
-                    let port = Self::from_net(from[21], from[22]);
-                    let addr = Ipv6Addr::from([
-                        from[5], from[6], from[7], from[8],
-                        from[9], from[10], from[11], from[12],
-                        from[13], from[14], from[15], from[16],
-                        from[17], from[18], from[19], from[20],
-                    ]);
-                    let peer_addr = SocketAddr::V6(SocketAddrV6::new(addr, port, 0, 0));
-                    return Ok(Message::PeerInfo(PeerInfoContents {
-                        peer_addr,
-                    }));
-                } else {
-                    return Err(());
+            MIGRATE => {
+                let payload = &from[HEADER_SIZE..];
+                let (session_id, id_len) = match Self::deserialize_session_id(payload) {
+                    Some(x) => x,
+                    None => {
+                        return Err(DeserializeError::Malformed);
+                    }
+                };
+                let rest = &payload[id_len..];
+                match Self::deserialize_addr(rest) {
+                    Some((new_addr, addr_len)) if addr_len == rest.len() || addr_len + MAC_SIZE == rest.len() => {
+                        return Ok(Message::Migrate(MigrateContents {
+                            session_id,
+                            new_addr,
+                            mac: rest[addr_len..].to_vec(),
+                        }));
+                    },
+                    _ => {
+                        return Err(DeserializeError::Malformed);
+                    }
                 }
             },
-            DATA => {
-                let data_len = length - 4;
-                if data_len > MAX_DATA_SIZE {
-                    // datagram too big
-                    return Err(())
+            RELAY_DATA => {
+                let payload = &from[HEADER_SIZE..];
+                let (session_id, id_len) = match Self::deserialize_session_id(payload) {
+                    Some(x) => x,
+                    None => {
+                        return Err(DeserializeError::Malformed);
+                    }
+                };
+                let rest = &payload[id_len..];
+                let (peer_addr, addr_len) = match Self::deserialize_addr(rest) {
+                    Some(x) => x,
+                    None => {
+                        return Err(DeserializeError::Malformed);
+                    }
+                };
+                let relayed = &rest[addr_len..];
+                if relayed.len() > MAX_RELAY_PAYLOAD_SIZE {
+                    return Err(DeserializeError::Malformed);
                 }
-                
-                let mut data = vec![0u8; data_len];
-                // TODO more efficient data copying
-                for i in 0..data_len {
-                    data[i] = from[4+i]
+                return Ok(Message::RelayData(RelayDataContents {
+                    session_id,
+                    peer_addr,
+                    payload: relayed.to_vec(),
+                }));
+            },
+            DATA | COMPRESSED_DATA | EXPIRING_DATA | COMPRESSED_EXPIRING_DATA => {
+                let mut payload = &from[HEADER_SIZE..];
+                let mut expires_at = None;
+                if msg_type & EXPIRES_FLAG != 0 {
+                    // 8 B deadline in front of the data
+                    if payload.len() < 8 {
+                        return Err(DeserializeError::Malformed);
+                    }
+                    expires_at = Some(u64::from_be_bytes(payload[0..8].try_into().unwrap()));
+                    payload = &payload[8..];
                 }
+                let data = Self::deserialize_data(payload, msg_type & COMPRESSED_FLAG != 0)?;
                 return Ok(Message::Data(DataContents {
-                    data
+                    // takes the buffer over without copying it
+                    data: Payload::from(data),
+                    expires_at,
+                }));
+            },
+            RELIABLE_DATA => {
+                let payload = &from[HEADER_SIZE..];
+                if payload.len() < 4 || payload.len() - 4 > MAX_DATA_SIZE {
+                    return Err(DeserializeError::Malformed);
+                }
+                return Ok(Message::ReliableData(ReliableDataContents {
+                    seq: u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]),
+                    data: payload[4..].to_vec(),
+                }));
+            },
+            DATA_ACK => {
+                let payload = &from[HEADER_SIZE..];
+                if payload.len() != 4 {
+                    return Err(DeserializeError::Malformed);
+                }
+                return Ok(Message::DataAck(DataAckContents {
+                    seq: u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]),
+                }));
+            },
+            ADDR_REQ => {
+                let payload = &from[HEADER_SIZE..];
+                if payload.len() != 1 {
+                    return Err(DeserializeError::Malformed);
+                }
+                return Ok(Message::AddrReq(AddrReqContents {
+                    change_port: payload[0] & 1 == 1,
+                }));
+            },
+            ADDR_RESP => {
+                let payload = &from[HEADER_SIZE..];
+                match Self::deserialize_addr(payload) {
+                    Some((mapped_addr, addr_len)) if addr_len + 2 == payload.len() => {
+                        let probe_port = Self::from_net(payload[addr_len], payload[addr_len + 1]);
+                        return Ok(Message::AddrResp(AddrRespContents {
+                            mapped_addr,
+                            probe_port: if probe_port == 0 { None } else { Some(probe_port) },
+                        }));
+                    },
+                    _ => {
+                        return Err(DeserializeError::Malformed);
+                    }
+                }
+            },
+            DATA_FRAGMENT | EXPIRING_DATA_FRAGMENT => {
+                let mut payload = &from[HEADER_SIZE..];
+                let mut expires_at = None;
+                if msg_type & EXPIRES_FLAG != 0 {
+                    // 8 B deadline in front of the fragment
+                    if payload.len() < 8 {
+                        return Err(DeserializeError::Malformed);
+                    }
+                    expires_at = Some(u64::from_be_bytes(payload[0..8].try_into().unwrap()));
+                    payload = &payload[8..];
+                }
+                if payload.len() < 8 || payload.len() - 8 > MAX_DATA_SIZE {
+                    return Err(DeserializeError::Malformed);
+                }
+                let message_id = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]);
+                let index = Self::from_net(payload[4], payload[5]);
+                let count = Self::from_net(payload[6], payload[7]);
+                return Ok(Message::DataFragment(DataFragmentContents {
+                    message_id,
+                    index,
+                    count,
+                    data: payload[8..].to_vec(),
+                    expires_at,
                 }));
             },
             _ => {
-                return Err(());
+                return Err(DeserializeError::Malformed);
             },
         }
     }
-}
\ No newline at end of file
+}
+
+// Shows the type and metadata of a message, but never its payload, which may be sensitive or large.
+// Use Debug for a full dump.
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Message::LocalInterrupt => {
+                write!(f, "{}", self.type_name())
+            },
+            Message::HelloResp(HelloRespContents { keepalive_interval: None }) => {
+                write!(f, "{}", self.type_name())
+            },
+            Message::HelloResp(HelloRespContents { keepalive_interval: Some(interval) }) => {
+                write!(f, "HelloResp(keepalive interval {} ms)", interval.as_millis())
+            },
+            Message::HelloReq(contents) if contents.handle.is_empty() => {
+                write!(f, "{}", self.type_name())
+            },
+            Message::HelloReq(contents) => {
+                write!(f, "HelloReq(handle {} bytes)", contents.handle.len())
+            },
+            Message::Register(contents) => {
+                write!(f, "Register(session ID {} bytes, metadata {} bytes, MAC {} bytes)",
+                    contents.session_id.len(), contents.metadata.len(), contents.mac.len())
+            },
+            Message::Query(contents) => {
+                write!(f, "Query(session ID {} bytes, MAC {} bytes)", contents.session_id.len(), contents.mac.len())
+            },
+            Message::QueryResult(contents) => {
+                write!(f, "QueryResult(session ID {} bytes, metadata {} bytes)", contents.session_id.len(), contents.metadata.len())
+            },
+            Message::Join(contents) => {
+                write!(f, "Join(session ID {} bytes, MAC {} bytes)", contents.session_id.len(), contents.mac.len())
+            },
+            Message::RegisterAck(RegisterAckContents { session_id, .. })
+            | Message::SessionNotFound(SessionNotFoundContents { session_id })
+            | Message::Goodbye(GoodbyeContents { session_id })
+            | Message::RegisterRejected(RegisterRejectedContents { session_id }) => {
+                write!(f, "{}(session ID {} bytes)", self.type_name(), session_id.len())
+            },
+            Message::PeerInfo(contents) => {
+                if contents.candidates.is_empty() {
+                    write!(f, "PeerInfo({})", contents.peer_addr)
+                } else {
+                    write!(f, "PeerInfo({}, {} candidates)", contents.peer_addr, contents.candidates.len())
+                }
+            },
+            Message::Migrate(contents) => {
+                write!(f, "Migrate({})", contents.new_addr)
+            },
+            Message::RelayData(contents) => {
+                write!(f, "RelayData({}, {} bytes)", contents.peer_addr, contents.payload.len())
+            },
+            Message::Data(contents) => {
+                write!(f, "Data({} bytes)", contents.data.len())
+            },
+            Message::ReliableData(contents) => {
+                write!(f, "ReliableData(seq {}, {} bytes)", contents.seq, contents.data.len())
+            },
+            Message::DataAck(contents) => {
+                write!(f, "DataAck(seq {})", contents.seq)
+            },
+            Message::AddrReq(contents) => {
+                write!(f, "AddrReq(change port {})", contents.change_port)
+            },
+            Message::AddrResp(contents) => {
+                write!(f, "AddrResp({})", contents.mapped_addr)
+            },
+            Message::DataFragment(contents) => {
+                write!(f, "DataFragment(message {}, {}/{}, {} bytes)", contents.message_id, contents.index + 1, contents.count, contents.data.len())
+            },
+            Message::Ping(contents) => {
+                write!(f, "Ping(nonce {})", contents.nonce)
+            },
+            Message::Pong(contents) => {
+                write!(f, "Pong(nonce {})", contents.nonce)
+            },
+        }
+    }
+}
+
+/// Splits a datagram into DataFragment messages, each carrying at most MAX_DATA_SIZE bytes and the datagram's deadline, if any.
+/// Returns Err if the datagram would need more than MAX_FRAGMENT_COUNT fragments.
+pub fn fragment(data: &[u8], message_id: u32, expires_at: Option<u64>) -> Result<Vec<Message>, ()> {
+    let count = data.len().div_ceil(MAX_DATA_SIZE);
+    if count == 0 || count > MAX_FRAGMENT_COUNT {
+        return Err(());
+    }
+    let count = u16::try_from(count).unwrap();
+
+    let mut fragments = Vec::with_capacity(usize::from(count));
+    for (index, chunk) in data.chunks(MAX_DATA_SIZE).enumerate() {
+        fragments.push(Message::DataFragment(DataFragmentContents {
+            message_id,
+            index: u16::try_from(index).unwrap(),
+            count,
+            data: chunk.to_vec(),
+            expires_at,
+        }));
+    }
+    return Ok(fragments);
+}
+
+/// A datagram of which only some fragments have arrived so far
+struct PartialDatagram {
+    fragments: Vec<Option<Vec<u8>>>,
+    num_received: usize,
+    first_seen_at: Instant,
+    /// Number of datagrams started before this one, to find the oldest
+    order: u64,
+}
+
+/// Collects DataFragment messages and puts the original datagrams back together.
+/// Fragments may arrive in any order. Datagrams which are not completed within the timeout are discarded.
+/// At most MAX_PARTIAL_PER_SOURCE incomplete datagrams are kept per sender and MAX_PARTIAL_DATAGRAMS in total,
+/// the oldest ones are discarded to make room for new ones.
+pub struct Reassembler {
+    /// Incomplete datagrams, keyed by sender and message ID
+    partial: HashMap<(SocketAddr, u32), PartialDatagram>,
+    timeout: Duration,
+    /// Number of incomplete datagrams started so far
+    started: u64,
+}
+
+impl Reassembler {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            partial: HashMap::new(),
+            timeout,
+            started: 0,
+        }
+    }
+
+    /// Adds a fragment received from the given source at the given time, as read from the owner's clock.
+    /// Returns the complete datagram once its last missing fragment has arrived.
+    pub fn insert(&mut self, source: SocketAddr, contents: DataFragmentContents, now: Instant) -> Option<Vec<u8>> {
+        let count = usize::from(contents.count);
+        let index = usize::from(contents.index);
+        if count == 0 || count > MAX_FRAGMENT_COUNT || index >= count {
+            // invalid fragment, ignore it
+            return None;
+        }
+
+        let key = (source, contents.message_id);
+        if !self.partial.contains_key(&key) {
+            self.make_room(source);
+            self.started += 1;
+        }
+        let order = self.started;
+        let partial = self.partial.entry(key).or_insert_with(|| PartialDatagram {
+            fragments: vec![None; count],
+            num_received: 0,
+            first_seen_at: now,
+            order,
+        });
+        if partial.fragments.len() != count {
+            // inconsistent fragment count for this message ID, ignore the fragment
+            return None;
+        }
+        if partial.fragments[index].is_some() {
+            // duplicate fragment
+            return None;
+        }
+        partial.fragments[index] = Some(contents.data);
+        partial.num_received += 1;
+        if partial.num_received < count {
+            return None;
+        }
+
+        // all fragments are here, stitch them together
+        let partial = self.partial.remove(&key).unwrap();
+        let mut data = Vec::new();
+        for fragment in partial.fragments {
+            data.extend_from_slice(&fragment.unwrap());
+        }
+        return Some(data);
+    }
+
+    // Discards the oldest incomplete datagrams until there is room for another one from the given source,
+    // so that fragments which are never completed can't take up unbounded memory.
+    fn make_room(&mut self, source: SocketAddr) {
+        let from_source = self.partial.keys().filter(|(from, _)| *from == source).count();
+        if from_source >= MAX_PARTIAL_PER_SOURCE {
+            self.evict_oldest(|from| from == source);
+        }
+        if self.partial.len() >= MAX_PARTIAL_DATAGRAMS {
+            self.evict_oldest(|_| true);
+        }
+    }
+
+    // Discards the incomplete datagram which was started first among those from senders matching the filter.
+    fn evict_oldest<F: Fn(SocketAddr) -> bool>(&mut self, filter: F) {
+        let oldest = self.partial.iter()
+            .filter(|((from, _), _)| filter(*from))
+            .min_by_key(|(_, partial)| partial.order)
+            .map(|(key, _)| *key);
+        if let Some(oldest) = oldest {
+            self.partial.remove(&oldest);
+        }
+    }
+
+    /// Discards datagrams which have been incomplete for longer than the timeout at the given time.
+    pub fn evict_expired(&mut self, now: Instant) {
+        let timeout = self.timeout;
+        self.partial.retain(|_, partial| now.saturating_duration_since(partial.first_seen_at) <= timeout);
+    }
+}
+
+/// Remembers the sequence numbers of recently received reliable datagrams, so that retransmissions are delivered only once.
+pub struct DuplicateFilter {
+    /// The last DUPLICATE_WINDOW sequence numbers seen from each sender
+    seen: HashMap<SocketAddr, VecDeque<u32>>,
+}
+
+impl DuplicateFilter {
+    pub fn new() -> Self {
+        Self {
+            seen: HashMap::new(),
+        }
+    }
+
+    /// Records a sequence number received from the given source.
+    /// Returns false if it was seen recently, i.e. the datagram is a duplicate.
+    pub fn insert(&mut self, source: SocketAddr, seq: u32) -> bool {
+        let seen = self.seen.entry(source).or_default();
+        if seen.contains(&seq) {
+            return false;
+        }
+        if seen.len() >= DUPLICATE_WINDOW {
+            seen.pop_front();
+        }
+        seen.push_back(seq);
+        return true;
+    }
+
+    /// Forgets the sequence numbers seen from the given source, e.g. because it disconnected.
+    pub fn forget(&mut self, source: SocketAddr) {
+        self.seen.remove(&source);
+    }
+}
+
+impl Default for DuplicateFilter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Serializes and deserializes a message. Messages aren't PartialEq, so tests compare their Debug output.
+    fn round_trip(msg: &Message) -> Message {
+        let bytes = msg.serialize().unwrap();
+        return Message::deserialize(&bytes).unwrap();
+    }
+
+    #[test]
+    fn crc32_matches_reference_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        let msgs = [
+            Message::HelloReq(HelloReqContents::default()),
+            Message::Data(DataContents { data: Payload::from(vec![1, 2, 3]), expires_at: None }),
+            Message::Register(RegisterContents { session_id: b"session".to_vec(), metadata: Vec::new(), candidates: Vec::new(), mac: Vec::new() }),
+            Message::PeerInfo(PeerInfoContents {
+                session_id: b"session".to_vec(),
+                peer_addr: "[::1]:5000".parse().unwrap(),
+                candidates: vec![],
+                mac: vec![],
+            }),
+        ];
+        for msg in &msgs {
+            assert_eq!(format!("{:?}", round_trip(msg)), format!("{:?}", msg));
+        }
+    }
+
+    #[cfg(feature = "checksum")]
+    #[test]
+    fn flipped_byte_fails_checksum() {
+        let msg = Message::Data(DataContents { data: Payload::from(vec![7; 16]), expires_at: None });
+        let bytes = msg.serialize().unwrap();
+        // everything after the magic and the length field is covered by the checksum only
+        for i in 4..bytes.len() {
+            let mut corrupted = bytes.clone();
+            corrupted[i] ^= 0x01;
+            assert_eq!(Message::deserialize(&corrupted).unwrap_err(), DeserializeError::ChecksumMismatch, "byte {}", i);
+        }
+    }
+
+    #[test]
+    fn serialized_messages_start_with_magic() {
+        let bytes = Message::HelloReq(HelloReqContents::default()).serialize().unwrap();
+        assert_eq!(bytes[0..2], MAGIC);
+    }
+
+    #[test]
+    fn missing_magic_is_rejected() {
+        let bytes = Message::Register(RegisterContents { session_id: vec![1, 2, 3], metadata: Vec::new(), candidates: Vec::new(), mac: Vec::new() })
+            .serialize().unwrap();
+        assert_eq!(Message::deserialize(&bytes[2..]).unwrap_err(), DeserializeError::BadMagic);
+        assert_eq!(Message::deserialize(&[]).unwrap_err(), DeserializeError::BadMagic);
+        // random traffic is rejected by its first bytes, even if the rest looks like a message
+        let mut foreign = bytes.clone();
+        foreign[0] = b'G';
+        assert_eq!(Message::deserialize(&foreign).unwrap_err(), DeserializeError::BadMagic);
+    }
+
+    #[test]
+    fn full_payload_round_trips() {
+        let data: Vec<u8> = (0..MAX_DATA_SIZE).map(|i| (i % 251) as u8).collect();
+        match round_trip(&Message::Data(DataContents { data: Payload::from(data.clone()), expires_at: None })) {
+            Message::Data(contents) => assert_eq!(contents.data, data),
+            other => panic!("unexpected message {:?}", other),
+        }
+    }
+
+    #[test]
+    fn ipv6_scope_id_round_trips() {
+        let addr = SocketAddr::V6(SocketAddrV6::new("fe80::1".parse().unwrap(), 4000, 0, 3));
+        let msg = Message::PeerInfo(PeerInfoContents {
+            session_id: b"session".to_vec(),
+            peer_addr: addr,
+            candidates: vec![addr],
+            mac: vec![],
+        });
+        match round_trip(&msg) {
+            Message::PeerInfo(contents) => {
+                assert_eq!(contents.peer_addr, addr);
+                assert_eq!(contents.candidates, vec![addr]);
+            },
+            other => panic!("unexpected message {:?}", other),
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn peer_info_round_trips_through_json() {
+        let msg = Message::PeerInfo(PeerInfoContents {
+            session_id: b"session".to_vec(),
+            peer_addr: "[fe80::1%3]:4000".parse().unwrap(),
+            candidates: vec!["192.168.1.10:4000".parse().unwrap()],
+            mac: vec![],
+        });
+        let json = serde_json::to_string(&msg).unwrap();
+        let parsed: Message = serde_json::from_str(&json).unwrap();
+        assert_eq!(format!("{:?}", parsed), format!("{:?}", msg));
+    }
+
+    #[test]
+    fn long_session_ids_round_trip() {
+        let session_id = vec![7u8; 40];
+        let msgs = [
+            Message::Register(RegisterContents { session_id: session_id.clone(), metadata: Vec::new(), candidates: Vec::new(), mac: Vec::new() }),
+            Message::Join(JoinContents { session_id: session_id.clone(), candidates: Vec::new(), mac: Vec::new() }),
+        ];
+        for msg in &msgs {
+            assert_eq!(format!("{:?}", round_trip(msg)), format!("{:?}", msg));
+        }
+        let too_long = Message::Join(JoinContents { session_id: vec![0; MAX_SESSION_ID_SIZE + 1], candidates: Vec::new(), mac: Vec::new() });
+        assert!(too_long.serialize().is_err());
+    }
+
+    #[test]
+    fn type_ids_match_the_wire_types() {
+        let msgs = [
+            (Message::LocalInterrupt, LOCAL_INTERRUPT, "LocalInterrupt"),
+            (Message::HelloReq(HelloReqContents::default()), HELLO_REQ, "HelloReq"),
+            (Message::HelloResp(HelloRespContents::default()), HELLO_RESP, "HelloResp"),
+            (Message::Register(RegisterContents { session_id: vec![1], metadata: Vec::new(), candidates: vec![], mac: vec![] }), REGISTER, "Register"),
+            (Message::Join(JoinContents { session_id: vec![1], candidates: vec![], mac: vec![] }), JOIN, "Join"),
+            (Message::SessionNotFound(SessionNotFoundContents { session_id: vec![1] }), SESSION_NOT_FOUND, "SessionNotFound"),
+            (Message::Goodbye(GoodbyeContents { session_id: vec![1] }), GOODBYE, "Goodbye"),
+            (Message::Data(DataContents { data: Payload::from(vec![1]), expires_at: None }), DATA, "Data"),
+            (Message::DataAck(DataAckContents { seq: 3 }), DATA_ACK, "DataAck"),
+            (Message::Ping(PingContents { nonce: 3 }), PING, "Ping"),
+        ];
+        for (msg, type_id, type_name) in &msgs {
+            assert_eq!(msg.type_id(), *type_id, "{}", type_name);
+            assert_eq!(msg.type_name(), *type_name);
+            assert_eq!(Message::peek_type(&msg.serialize().unwrap()), Some(*type_id), "{}", type_name);
+        }
+    }
+
+    #[test]
+    fn display_leaves_out_the_payload() {
+        let data = Message::Data(DataContents { data: Payload::from(vec![0xAB; 1024]), expires_at: None });
+        assert_eq!(data.to_string(), "Data(1024 bytes)");
+        let peer_info = Message::PeerInfo(PeerInfoContents {
+            session_id: b"secret session".to_vec(),
+            peer_addr: "192.0.2.1:5000".parse().unwrap(),
+            candidates: vec![],
+            mac: vec![],
+        });
+        assert_eq!(peer_info.to_string(), "PeerInfo(192.0.2.1:5000)");
+        let goodbye = Message::Goodbye(GoodbyeContents { session_id: b"secret session".to_vec() });
+        assert_eq!(goodbye.to_string(), "Goodbye(session ID 14 bytes)");
+    }
+
+    // Returns a buffer of the given size whose header claims that size, so that only the size is wrong with it.
+    fn frame_of_size(size: usize) -> Vec<u8> {
+        let mut frame = vec![0u8; size];
+        frame[..2].copy_from_slice(&MAGIC);
+        frame[2..4].copy_from_slice(&(size as u16).to_be_bytes());
+        frame[4..6].copy_from_slice(&DATA.to_be_bytes());
+        return frame;
+    }
+
+    #[test]
+    fn largest_message_is_accepted() {
+        let msg = Message::RelayData(RelayDataContents {
+            session_id: vec![1; MAX_SESSION_ID_SIZE],
+            peer_addr: "[::1]:5000".parse().unwrap(),
+            payload: vec![0; MAX_RELAY_PAYLOAD_SIZE],
+        });
+        let bytes = msg.serialize().unwrap();
+        assert_eq!(bytes.len(), MAX_MESSAGE_SIZE);
+        assert!(Message::deserialize(&bytes).is_ok());
+        assert_eq!(Message::deserialize(&frame_of_size(MAX_MESSAGE_SIZE + 1)).unwrap_err(), DeserializeError::TooLarge);
+    }
+
+    #[test]
+    fn register_ack_round_trips_with_and_without_mapped_addr() {
+        for mapped_addr in [None, Some("203.0.113.7:4000".parse().unwrap()), Some("[fe80::1%3]:9".parse().unwrap())] {
+            let ack = Message::RegisterAck(RegisterAckContents { session_id: vec![1], mapped_addr });
+            match round_trip(&ack) {
+                Message::RegisterAck(contents) => assert_eq!(contents.mapped_addr, mapped_addr),
+                other => panic!("expected a RegisterAck, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn register_metadata_round_trips_before_the_mac() {
+        let register = Message::Register(RegisterContents {
+            session_id: vec![1],
+            metadata: vec![9; 32],
+            candidates: vec![],
+            mac: vec![3; SESSION_MAC_SIZE],
+        });
+        match round_trip(&register) {
+            Message::Register(contents) => {
+                assert_eq!(contents.metadata, vec![9; 32]);
+                assert_eq!(contents.mac, vec![3; SESSION_MAC_SIZE]);
+            },
+            other => panic!("expected a Register, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn serialize_into_matches_serialize_over_a_used_buffer() {
+        let msgs = [
+            Message::Data(DataContents { data: Payload::from(vec![7; 300]), expires_at: None }),
+            Message::HelloResp(HelloRespContents::default()),
+            Message::Register(RegisterContents { session_id: b"abc".to_vec(), metadata: b"m".to_vec(), candidates: vec![], mac: vec![] }),
+        ];
+        let mut buf = vec![0xAA; 2000];
+        for msg in &msgs {
+            msg.serialize_into(&mut buf).unwrap();
+            assert_eq!(buf, msg.serialize().unwrap());
+        }
+        assert!(Message::Data(DataContents { data: Payload::from(vec![0; MAX_MESSAGE_SIZE]), expires_at: None }).serialize_into(&mut buf).is_err());
+    }
+
+    #[test]
+    fn deadlines_round_trip() {
+        for data in [vec![], vec![5; 600], (0..200).collect()] {
+            let msg = Message::Data(DataContents { data: Payload::from(data.clone()), expires_at: Some(123456789) });
+            match round_trip(&msg) {
+                Message::Data(contents) => {
+                    assert_eq!(contents.data, data);
+                    assert_eq!(contents.expires_at, Some(123456789));
+                },
+                other => panic!("expected Data, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn data_expires_once_its_deadline_passed() {
+        assert!(DataContents { data: Payload::from(vec![]), expires_at: Some(0) }.is_expired());
+        assert!(!DataContents { data: Payload::from(vec![]), expires_at: Some(DataContents::deadline_after(Duration::from_secs(5))) }.is_expired());
+        assert!(!DataContents { data: Payload::from(vec![]), expires_at: None }.is_expired());
+    }
+
+    #[test]
+    fn fragments_carry_the_datagram_deadline() {
+        let data = vec![9; MAX_DATA_SIZE + 10];
+        for msg in fragment(&data, 3, Some(123456789)).unwrap() {
+            let bytes = msg.serialize().unwrap();
+            assert_eq!(Message::peek_type(&bytes), Some(DATA_FRAGMENT | EXPIRES_FLAG));
+            assert_eq!(Message::peek_expires_at(&bytes), Some(123456789));
+            match round_trip(&msg) {
+                Message::DataFragment(contents) => assert_eq!(contents.expires_at, Some(123456789)),
+                other => panic!("expected DataFragment, got {:?}", other),
+            }
+        }
+        let lasting = fragment(&data, 4, None).unwrap()[0].serialize().unwrap();
+        assert_eq!(Message::peek_type(&lasting), Some(DATA_FRAGMENT));
+        assert_eq!(Message::peek_expires_at(&lasting), None);
+        assert!(fragment_of(0, 0, 1).expires_at.is_none());
+        assert!(DataFragmentContents { expires_at: Some(0), ..fragment_of(0, 0, 1) }.is_expired());
+    }
+
+    #[test]
+    fn huge_times_to_live_never_expire() {
+        let deadline = DataContents::deadline_after(Duration::MAX);
+        assert!(deadline > DataContents::deadline_after(Duration::from_secs(365 * 24 * 60 * 60)));
+        assert!(!DataContents { data: Payload::from(vec![]), expires_at: Some(deadline) }.is_expired());
+    }
+
+    #[test]
+    fn migrate_round_trips() {
+        let migrate = Message::Migrate(MigrateContents { session_id: b"x".to_vec(), new_addr: "[::1]:5".parse().unwrap(), mac: vec![3; MAC_SIZE] });
+        match round_trip(&migrate) {
+            Message::Migrate(contents) => {
+                assert_eq!(contents.session_id, b"x");
+                assert_eq!(contents.new_addr, "[::1]:5".parse().unwrap());
+                assert_eq!(contents.mac, vec![3; MAC_SIZE]);
+            },
+            other => panic!("expected a Migrate, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn serialize_relayed_into_matches_a_relay_data() {
+        let peer_addr = "127.0.0.1:4000".parse().unwrap();
+        let mut buf = vec![0xAA; 100];
+        Message::serialize_relayed_into(b"s", peer_addr, &[1, 2, 3], &mut buf).unwrap();
+        let relay_data = Message::RelayData(RelayDataContents { session_id: b"s".to_vec(), peer_addr, payload: vec![1, 2, 3] });
+        assert_eq!(buf, relay_data.serialize().unwrap());
+    }
+
+    #[test]
+    fn serialize_data_into_matches_a_data() {
+        let mut buf = vec![0xAA; 100];
+        for (data, expires_at) in [(vec![1, 2, 3], None), (vec![9; 500], Some(123456789))] {
+            Message::serialize_data_into(&data, expires_at, &mut buf).unwrap();
+            assert_eq!(buf, Message::Data(DataContents { data: Payload::from(data), expires_at }).serialize().unwrap());
+        }
+        assert!(Message::serialize_data_into(&[0; MAX_MESSAGE_SIZE], None, &mut buf).is_err());
+    }
+
+    #[test]
+    fn proposed_keepalive_intervals_round_trip() {
+        for contents in [HelloRespContents::default(), HelloRespContents::proposing(Duration::from_secs(5))] {
+            let interval = contents.keepalive_interval;
+            match round_trip(&Message::HelloResp(contents)) {
+                Message::HelloResp(contents) => assert_eq!(contents.keepalive_interval, interval),
+                other => panic!("expected a HelloResp, got {:?}", other),
+            }
+        }
+        // rounded up to the wire's resolution
+        assert_eq!(HelloRespContents::proposing(Duration::from_micros(3)).keepalive_interval, Some(Duration::from_millis(1)));
+    }
+
+    #[test]
+    fn batches_round_trip() {
+        let msgs = vec![
+            Message::Register(RegisterContents { session_id: b"s".to_vec(), metadata: vec![1], candidates: vec![], mac: vec![] }),
+            Message::Ping(PingContents { nonce: 7 }),
+        ];
+        let bytes = Message::serialize_batch(&msgs).unwrap();
+        assert_eq!(format!("{:?}", Message::deserialize_batch(&bytes).unwrap()), format!("{:?}", msgs));
+        // a single message is a batch of one, but a batch is not a single message
+        assert_eq!(Message::deserialize_batch(&msgs[1].serialize().unwrap()).unwrap().len(), 1);
+        assert!(Message::deserialize(&bytes).is_err());
+    }
+
+    #[test]
+    fn truncated_and_empty_batches_are_rejected() {
+        let bytes = Message::serialize_batch(&[Message::Ping(PingContents { nonce: 7 }), Message::Pong(PongContents { nonce: 7 })]).unwrap();
+        assert!(Message::deserialize_batch(&bytes[..bytes.len() - 1]).is_err());
+        assert!(Message::deserialize_batch(&[]).is_err());
+        assert!(Message::serialize_batch(&[]).is_err());
+    }
+
+    #[test]
+    fn candidates_round_trip() {
+        let candidates: Vec<SocketAddr> = vec!["10.0.0.2:5".parse().unwrap(), "[fe80::1%3]:6".parse().unwrap()];
+        let peer_addr: SocketAddr = "1.2.3.4:5".parse().unwrap();
+        for msg in [
+            Message::Register(RegisterContents { session_id: b"s".to_vec(), metadata: vec![1], candidates: candidates.clone(), mac: vec![9; SESSION_MAC_SIZE] }),
+            Message::Join(JoinContents { session_id: b"s".to_vec(), candidates: candidates.clone(), mac: Vec::new() }),
+            Message::PeerInfo(PeerInfoContents { session_id: b"s".to_vec(), peer_addr, candidates: candidates.clone(), mac: vec![1; MAC_SIZE] }),
+        ] {
+            assert_eq!(format!("{:?}", round_trip(&msg)), format!("{:?}", msg));
+        }
+        // without candidates, a PeerInfo goes out as before they existed
+        let plain = Message::PeerInfo(PeerInfoContents { session_id: b"s".to_vec(), peer_addr, candidates: Vec::new(), mac: Vec::new() });
+        assert_eq!(Message::peek_type(&plain.serialize().unwrap()), Some(PEER_INFO));
+        let too_many = vec![peer_addr; MAX_CANDIDATES + 1];
+        assert!(Message::Join(JoinContents { session_id: b"s".to_vec(), candidates: too_many, mac: Vec::new() }).serialize().is_err());
+    }
+    
+    #[test]
+    fn peek_type_reads_only_the_header() {
+        let ping = Message::Ping(PingContents { nonce: 3 }).serialize().unwrap();
+        assert_eq!(Message::peek_type(&ping), Some(PING));
+        // flags are part of the type
+        let expiring = Message::Data(DataContents { data: Payload::from(vec![1]), expires_at: Some(5) }).serialize().unwrap();
+        assert_eq!(Message::peek_type(&expiring), Some(DATA | EXPIRES_FLAG));
+        // the checksum is not verified
+        let mut corrupted = ping.clone();
+        let last = corrupted.len() - 1;
+        corrupted[last] ^= 0xFF;
+        assert_eq!(Message::peek_type(&corrupted), Some(PING));
+        
+        assert_eq!(Message::peek_type(&[]), None);
+        assert_eq!(Message::peek_type(&ping[..5]), None);
+        assert_eq!(Message::peek_type(&ping[..ping.len() - 1]), None);
+        let mut wrong_magic = ping.clone();
+        wrong_magic[0] ^= 0xFF;
+        assert_eq!(Message::peek_type(&wrong_magic), None);
+        // a batch is more than one message
+        let batch = Message::serialize_batch(&[Message::Ping(PingContents { nonce: 3 }), Message::Ping(PingContents { nonce: 4 })]).unwrap();
+        assert_eq!(Message::peek_type(&batch), None);
+    }
+    
+    #[test]
+    fn reassembler_times_out_by_the_given_time() {
+        let source: SocketAddr = "10.0.0.1:1000".parse().unwrap();
+        let start = Instant::now();
+        let mut reassembler = Reassembler::new(FRAGMENT_TIMEOUT);
+        assert_eq!(reassembler.insert(source, fragment_of(0, 0, 2), start), None);
+        assert_eq!(reassembler.insert(source, fragment_of(1, 0, 2), start + FRAGMENT_TIMEOUT), None);
+        reassembler.evict_expired(start + FRAGMENT_TIMEOUT + Duration::from_millis(1));
+        // only the datagram started at the beginning timed out, however long ago that really was
+        assert_eq!(reassembler.partial.len(), 1);
+        assert!(reassembler.insert(source, fragment_of(1, 1, 2), start + FRAGMENT_TIMEOUT).is_some());
+        assert_eq!(reassembler.insert(source, fragment_of(0, 1, 2), start + FRAGMENT_TIMEOUT), None);
+    }
+    
+    // Returns the wire type in the header of a serialized message.
+    #[cfg(feature = "compression")]
+    fn wire_type(bytes: &[u8]) -> u16 {
+        return u16::from_be_bytes([bytes[4], bytes[5]]);
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn compressible_payloads_are_sent_compressed() {
+        let data = br#"{"temp":21.5,"unit":"C"}"#.repeat(40);
+        let bytes = Message::Data(DataContents { data: Payload::from(data.clone()), expires_at: None }).serialize().unwrap();
+        assert!(bytes.len() < data.len() / 3);
+        assert_eq!(wire_type(&bytes), DATA | COMPRESSED_FLAG);
+        match Message::deserialize(&bytes).unwrap() {
+            Message::Data(contents) => assert_eq!(contents.data, data),
+            other => panic!("expected Data, got {:?}", other),
+        }
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn incompressible_payloads_are_sent_as_is() {
+        // xorshift noise, which LZ4 can't shrink
+        let mut state = 0x2545F491u32;
+        let noise: Vec<u8> = (0..1000).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            (state >> 24) as u8
+        }).collect();
+        for data in [noise, vec![]] {
+            let bytes = Message::Data(DataContents { data: Payload::from(data.clone()), expires_at: None }).serialize().unwrap();
+            assert_eq!(wire_type(&bytes), DATA);
+            match Message::deserialize(&bytes).unwrap() {
+                Message::Data(contents) => assert_eq!(contents.data, data),
+                other => panic!("expected Data, got {:?}", other),
+            }
+        }
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn payloads_decompressing_beyond_the_limit_are_rejected() {
+        let compressed = lz4_flex::block::compress(&[0u8; MAX_DATA_SIZE * 5]);
+        let mut frame = vec![0u8; frame_size(compressed.len())];
+        encode_frame(DATA | COMPRESSED_FLAG, &compressed, &mut frame).unwrap();
+        assert_eq!(Message::deserialize(&frame).unwrap_err(), DeserializeError::Malformed);
+    }
+
+    // Returns the given fragment of a datagram of count fragments.
+    fn fragment_of(message_id: u32, index: u16, count: u16) -> DataFragmentContents {
+        return DataFragmentContents {
+            message_id,
+            index,
+            count,
+            data: vec![index as u8; 10],
+            expires_at: None,
+        };
+    }
+
+    #[test]
+    fn reassembler_limits_incomplete_datagrams_per_source() {
+        let mut reassembler = Reassembler::new(FRAGMENT_TIMEOUT);
+        let source: SocketAddr = "10.0.0.1:1000".parse().unwrap();
+        let other: SocketAddr = "10.0.0.2:1000".parse().unwrap();
+        assert_eq!(reassembler.insert(other, fragment_of(0, 0, 2), Instant::now()), None);
+        for message_id in 0..=MAX_PARTIAL_PER_SOURCE as u32 {
+            assert_eq!(reassembler.insert(source, fragment_of(message_id, 0, 2), Instant::now()), None);
+        }
+        assert_eq!(reassembler.partial.len(), MAX_PARTIAL_PER_SOURCE + 1);
+        // the oldest one of the flooding source made room, the other source's datagram is kept
+        assert_eq!(reassembler.insert(source, fragment_of(0, 1, 2), Instant::now()), None);
+        assert!(reassembler.insert(source, fragment_of(MAX_PARTIAL_PER_SOURCE as u32, 1, 2), Instant::now()).is_some());
+        assert!(reassembler.insert(other, fragment_of(0, 1, 2), Instant::now()).is_some());
+    }
+
+    #[test]
+    fn reassembler_limits_incomplete_datagrams_in_total() {
+        let mut reassembler = Reassembler::new(FRAGMENT_TIMEOUT);
+        for i in 0..=MAX_PARTIAL_DATAGRAMS {
+            let source = SocketAddr::from(([10, 0, (i >> 8) as u8, i as u8], 1000));
+            assert_eq!(reassembler.insert(source, fragment_of(0, 0, 2), Instant::now()), None);
+        }
+        assert_eq!(reassembler.partial.len(), MAX_PARTIAL_DATAGRAMS);
+        let first = SocketAddr::from(([10, 0, 0, 0], 1000));
+        assert_eq!(reassembler.insert(first, fragment_of(0, 1, 2), Instant::now()), None);
+    }
+
+    #[test]
+    fn reassembler_puts_reordered_fragments_together() {
+        let data: Vec<u8> = (0..3000u32).map(|i| i as u8).collect();
+        let mut reassembler = Reassembler::new(FRAGMENT_TIMEOUT);
+        let source: SocketAddr = "10.0.0.1:1000".parse().unwrap();
+        let mut result = None;
+        for msg in fragment(&data, 7, None).unwrap().into_iter().rev() {
+            if let Message::DataFragment(contents) = msg {
+                result = reassembler.insert(source, contents, Instant::now());
+            }
+        }
+        assert_eq!(result, Some(data));
+    }
+
+    #[cfg(feature = "bytes")]
+    #[test]
+    fn cloned_data_shares_its_payload() {
+        let payload = Payload::from(vec![5; 1000]);
+        let msg = Message::Data(DataContents { data: payload.clone(), expires_at: None });
+        let expected = msg.serialize().unwrap();
+        // one copy of the message per recipient, all backed by the same buffer
+        for copy in vec![msg; 10] {
+            match &copy {
+                Message::Data(contents) => assert_eq!(contents.data.as_ptr(), payload.as_ptr()),
+                other => panic!("expected a Data, got {:?}", other),
+            }
+            assert_eq!(copy.serialize().unwrap(), expected);
+        }
+    }
+}
@@ -0,0 +1,888 @@
+use std::net::{
+    SocketAddr,
+    Ipv4Addr,
+    Ipv6Addr,
+    ToSocketAddrs,
+};
+use std::time::{
+    Duration,
+    Instant,
+};
+use std::collections::{
+    HashMap,
+    HashSet,
+};
+use crate::messages::*;
+use crate::protocol_socket::*;
+use crate::error::{
+    RuphinError,
+    HandshakeStage,
+};
+use crate::event::{
+    DropReason,
+    DropObserver,
+    InterruptHandle,
+};
+use crate::passive_server::PassiveServerConfig;
+use crate::jitter::jittered;
+use crate::clock::deadline_after;
+#[cfg(feature = "encryption")]
+use crate::crypto::DataCipher;
+
+/// A datagram received by a MultiSessionServer: the ID of the session it belongs to, the client which sent it and the datagram.
+pub type SessionDatagram = (Vec<u8>, SocketAddr, Vec<u8>);
+
+/// A server which maintains and serves on several sessions through a single socket.
+/// Clients are attributed to the session they joined, as reported by the holepuncher's PeerInfo,
+/// and admitted once they complete the HelloReq/HelloResp handshake, like PassiveServer's.
+/// All sessions are registered with the metadata from the config. Datagrams are returned one by one as they arrive,
+/// so the config's max_queued_data has no effect.
+pub struct MultiSessionServer {
+    /// Underlying socket
+    proto_socket: ProtocolSocket,
+    /// Address of the holepuncher the sessions are registered with
+    holepuncher: SocketAddr,
+    /// IDs of the registered sessions
+    sessions: HashSet<Vec<u8>>,
+    /// Local addresses announced to the holepuncher along with every Register
+    candidates: Vec<SocketAddr>,
+    /// Keepalive interval. Default is 10 seconds.
+    keepalive_interval: Duration,
+    /// Fraction by which keepalive intervals are randomized
+    keepalive_jitter: f64,
+    /// Time after which the server should send keepalives to the holepuncher.
+    next_keepalive_at: Instant,
+    /// Puts fragmented datagrams back together
+    reassembler: Reassembler,
+    /// Message ID for the next fragmented datagram sent
+    next_message_id: u32,
+    /// Recognizes retransmitted reliable datagrams
+    duplicate_filter: DuplicateFilter,
+    /// Clients which completed the HelloReq/HelloResp handshake: the session they joined and the last time they were heard from
+    clients: HashMap<SocketAddr, (Vec<u8>, Instant)>,
+    /// Addresses announced by the holepuncher's PeerInfos, which haven't completed the handshake yet:
+    /// the session they joined and the time they were announced
+    pending_clients: HashMap<SocketAddr, (Vec<u8>, Instant)>,
+    /// Time after which a silent client is forgotten
+    client_timeout: Duration,
+    /// Clients which talk to the server through the holepuncher's relay
+    relayed_clients: HashSet<SocketAddr>,
+    /// Time for which sends are retried while the OS send buffer is full
+    send_retry_budget: Duration,
+    /// IP TTL of the HelloReq sent to newly joined clients, if lowered
+    initial_hello_ttl: Option<u32>,
+    /// Last time a message was received from the holepuncher
+    last_heard_from_holepuncher: Instant,
+    /// Whether the holepuncher timing out has already been reported
+    holepuncher_timed_out: bool,
+    /// Used to authenticate the keepalive Registers, and for the settings applied while serving
+    config: PassiveServerConfig,
+    /// Encrypts and decrypts datagrams, if a key is configured
+    #[cfg(feature = "encryption")]
+    cipher: Option<DataCipher>,
+    /// Callback invoked with the reason for every dropped datagram, if set
+    drop_observer: Option<DropObserver>,
+}
+
+impl MultiSessionServer {
+    /// Registers the sessions with the holepuncher, using the default configuration.
+    pub fn new(holepuncher: impl ToSocketAddrs, session_ids: Vec<Vec<u8>>)
+        -> Result<Self, RuphinError> {
+        Self::with_config(holepuncher, session_ids, PassiveServerConfig::default())
+    }
+
+    /// Registers the sessions with the holepuncher. Fails unless every session is acknowledged.
+    /// Like PassiveServer::with_config, the holepuncher's address can be a host name, whose addresses are tried one after the other.
+    pub fn with_config(holepuncher: impl ToSocketAddrs, session_ids: Vec<Vec<u8>>, config: PassiveServerConfig)
+        -> Result<Self, RuphinError> {
+        config.validate()?;
+        let mut holepunchers = resolve_by_family(holepuncher, config.bind_addr)?.into_iter().flatten().peekable();
+        loop {
+            // there's at least one address, and the last one's result is returned
+            let holepuncher = holepunchers.next().unwrap();
+            match Self::register_at(holepuncher, session_ids.clone(), config.clone()) {
+                Err(e) if e.is_unreachable() && holepunchers.peek().is_some() => {
+                    continue;
+                },
+                result => {
+                    return result;
+                }
+            }
+        }
+    }
+
+    // Binds a socket and registers the sessions with the holepuncher at the given address.
+    fn register_at(holepuncher: SocketAddr, session_ids: Vec<Vec<u8>>, config: PassiveServerConfig)
+        -> Result<Self, RuphinError> {
+        // bind a protocol socket to the configured address, or to an arbitrary port that can reach the holepuncher
+        let bind_addr = match config.bind_addr {
+            Some(bind_addr) => bind_addr,
+            None if holepuncher.is_ipv6() => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+            None => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        };
+        let sock = match ProtocolSocket::bind(bind_addr.to_string()) {
+            Ok(sock) => sock,
+            Err(e) => {
+                return Err(RuphinError::Bind(e));
+            }
+        };
+
+        let sessions: HashSet<Vec<u8>> = session_ids.into_iter().collect();
+
+        // deadline after which the attempt to create a server is considered failed
+        let end_time = deadline_after(Instant::now(), config.handshake_timeout);
+        // Set the protocol socket's message timeout (will be undone after the function returns)
+        sock.set_read_timeout(Some(config.message_timeout)).unwrap();
+
+        // Send a Register for every session, and expect a RegisterAck back for each.
+        // Sessions which were not acknowledged yet are retried together.
+        let mut unacknowledged = sessions.clone();
+        let candidates = if config.gather_candidates {
+            sock.local_candidates(holepuncher)
+        } else {
+            Vec::new()
+        };
+        let mut next_retry_at = Instant::now();
+        // gap between rounds of Registers, backing off exponentially like PassiveServer's retries
+        let mut retry_gap = config.retry_interval;
+        let mut num_rounds = 0;
+        while !unacknowledged.is_empty() {
+            let out_of_retries = match config.register_retries {
+                Some(max_registers) => num_rounds >= max_registers && Instant::now() >= next_retry_at,
+                None => false,
+            };
+            if Instant::now() >= end_time || out_of_retries {
+                // timeout, could not register all sessions
+                return Err(RuphinError::HandshakeTimeout {
+                    stage: HandshakeStage::WaitingForRegisterAck,
+                });
+            }
+
+            // if we're past the next_retry_at deadline, (re)send the Registers and reset the deadline
+            if Instant::now() >= next_retry_at {
+                for session_id in &unacknowledged {
+                    let request = Message::Register(RegisterContents {
+                        session_id: session_id.clone(),
+                        metadata: config.metadata.clone(),
+                        candidates: candidates.clone(),
+                        // a fresh MAC for every attempt, since the holepuncher only accepts one for a while
+                        mac: config.register_mac(session_id, &candidates),
+                    });
+                    match sock.send_message(&request, holepuncher) {
+                        Ok(()) => {},
+                        Err(e) if !e.is_fatal() => {
+                            // transient error, the next retry will send it again
+                        },
+                        Err(e) => {
+                            return Err(RuphinError::from(e));
+                        }
+                    };
+                }
+                next_retry_at = deadline_after(Instant::now(), retry_gap);
+                retry_gap = retry_gap.saturating_mul(2).min(config.max_retry_interval);
+                num_rounds += 1;
+            }
+
+            // Wait for a response. This will either succeed, timeout, or fail fatally.
+            let (response, source) = match sock.get_message() {
+                Ok((response, source)) => (response, source),
+                Err(e) => {
+                    if e.is_fatal() {
+                        // fatal error, return
+                        return Err(RuphinError::from(e));
+                    } else {
+                        // nonfatal error, ignore and retry
+                        continue;
+                    }
+                },
+            };
+            if source != holepuncher {
+                // message is not from the holepuncher, ignore it
+                continue;
+            }
+            match response {
+                Message::RegisterAck(contents) => {
+                    unacknowledged.remove(&contents.session_id);
+                },
+                Message::RegisterRejected(contents) if unacknowledged.contains(&contents.session_id) => {
+                    return Err(RuphinError::RegisterRejected);
+                },
+                _ => {
+                    // some other message arrived, ignore it
+                    continue;
+                }
+            }
+        }
+
+        // all sessions were registered successfully!
+        // remove the timeout on the socket
+        sock.set_read_timeout(None).unwrap();
+        return Ok(Self {
+            proto_socket: sock,
+            holepuncher,
+            sessions,
+            candidates,
+            keepalive_interval: config.keepalive_interval,
+            keepalive_jitter: config.keepalive_jitter,
+            next_keepalive_at: deadline_after(Instant::now(), jittered(config.keepalive_interval, config.keepalive_jitter)),
+            reassembler: Reassembler::new(FRAGMENT_TIMEOUT),
+            next_message_id: 0,
+            duplicate_filter: DuplicateFilter::new(),
+            clients: HashMap::new(),
+            pending_clients: HashMap::new(),
+            client_timeout: config.client_timeout,
+            relayed_clients: HashSet::new(),
+            send_retry_budget: config.send_retry_budget,
+            initial_hello_ttl: config.initial_hello_ttl,
+            #[cfg(feature = "encryption")]
+            cipher: config.encryption_key.as_ref().map(DataCipher::new),
+            drop_observer: None,
+            last_heard_from_holepuncher: Instant::now(),
+            holepuncher_timed_out: false,
+            config,
+        });
+    }
+
+    /// Returns the IDs of the sessions served.
+    pub fn sessions(&self) -> Vec<Vec<u8>> {
+        self.sessions.iter().cloned().collect()
+    }
+
+    /// Registers another session with the holepuncher. This does not wait for the acknowledgement:
+    /// if the holepuncher rejects the session, it is removed again while waiting for data.
+    pub fn add_session(&mut self, session_id: Vec<u8>) -> Result<(), RuphinError> {
+        let request = Message::Register(RegisterContents {
+            session_id: session_id.clone(),
+            metadata: self.config.metadata.clone(),
+            candidates: self.candidates.clone(),
+            mac: self.config.register_mac(&session_id, &self.candidates),
+        });
+        match self.proto_socket.send_message(&request, self.holepuncher) {
+            Ok(()) => {},
+            Err(e) if !e.is_fatal() => {
+                // transient error, the next keepalive registers the session
+            },
+            Err(e) => {
+                return Err(RuphinError::from(e));
+            }
+        };
+        self.sessions.insert(session_id);
+        return Ok(());
+    }
+
+    /// Tells the holepuncher to forget about one of the sessions, and stops sending keepalives for it.
+    /// Clients which joined it are forgotten as well.
+    pub fn remove_session(&mut self, session_id: &[u8]) -> Result<(), RuphinError> {
+        if !self.sessions.remove(session_id) {
+            return Err(RuphinError::SessionNotFound);
+        }
+        let clients: Vec<SocketAddr> = self.clients.iter().chain(&self.pending_clients)
+            .filter(|(_, (client_session_id, _))| client_session_id.as_slice() == session_id)
+            .map(|(addr, _)| *addr)
+            .collect();
+        for addr in clients {
+            self.forget_client(addr);
+        }
+        let msg = Message::Goodbye(GoodbyeContents {
+            session_id: session_id.to_vec(),
+        });
+        match self.proto_socket.send_message(&msg, self.holepuncher) {
+            Ok(()) => {
+                return Ok(());
+            },
+            Err(e) => {
+                return Err(RuphinError::from(e));
+            }
+        }
+    }
+
+    /// Returns the clients of the given session which have been heard from within the client timeout.
+    pub fn connected_clients(&self, session_id: &[u8]) -> Vec<SocketAddr> {
+        self.clients.iter()
+            .filter(|(_, (client_session_id, last_seen))| {
+                client_session_id.as_slice() == session_id && last_seen.elapsed() <= self.client_timeout
+            })
+            .map(|(addr, _)| *addr)
+            .collect()
+    }
+
+    /// Returns the session the given client joined, if it is known.
+    pub fn session_of(&self, client: SocketAddr) -> Option<&[u8]> {
+        match self.clients.get(&client) {
+            Some((session_id, _)) => Some(session_id),
+            None => None,
+        }
+    }
+
+    /// Returns the last time a message was received from the holepuncher, or the sessions were registered if none was since.
+    /// Keepalives are acknowledged by the holepuncher, so this normally lags by at most keepalive_interval.
+    pub fn holepuncher_last_seen(&self) -> Instant {
+        return self.last_heard_from_holepuncher;
+    }
+
+    /// Returns true if the holepuncher_timeout from the config passed without hearing from the holepuncher,
+    /// and nothing was heard from it since. This is how a caller tells that case apart when wait_for_data returns Ok(None).
+    pub fn holepuncher_timed_out(&self) -> bool {
+        return self.holepuncher_timed_out;
+    }
+
+    // Returns the time at which the holepuncher is considered gone, if it is watched and hasn't been reported as gone yet.
+    fn holepuncher_deadline(&self) -> Option<Instant> {
+        match self.config.holepuncher_timeout {
+            Some(holepuncher_timeout) if !self.holepuncher_timed_out => {
+                return Some(deadline_after(self.last_heard_from_holepuncher, holepuncher_timeout));
+            },
+            _ => {
+                return None;
+            }
+        }
+    }
+
+    /// Returns a snapshot of the socket's traffic counters.
+    pub fn stats(&self) -> SocketStats {
+        self.proto_socket.stats()
+    }
+
+    /// Returns the local address the socket is bound to. The IP is unspecified (0.0.0.0) unless bound to a specific interface.
+    pub fn local_addr(&self) -> Result<SocketAddr, RuphinError> {
+        match self.proto_socket.local_addr() {
+            Ok(addr) => {
+                return Ok(addr);
+            },
+            Err(e) => {
+                return Err(RuphinError::Io(e));
+            }
+        }
+    }
+
+    /// Returns a handle which other threads can use to interrupt wait_for_data with allow_interrupt set.
+    pub fn interrupt_handle(&self) -> Result<InterruptHandle, RuphinError> {
+        return Ok(InterruptHandle::new(self.local_addr()?));
+    }
+
+    /// Sets a callback which is invoked with the reason and source of every datagram the server drops,
+    /// including ones which fail to deserialize. With the "tracing" feature, drops are also logged as debug events.
+    pub fn set_drop_observer<F>(&mut self, observer: F) where F: FnMut(DropReason, SocketAddr) + Send + 'static {
+        self.drop_observer = Some(Box::new(observer));
+    }
+
+    /// Removes the callback set by set_drop_observer.
+    pub fn clear_drop_observer(&mut self) {
+        self.drop_observer = None;
+    }
+
+    // Reports a datagram from the given source which is dropped for the given reason.
+    fn dropped(&mut self, reason: DropReason, source: SocketAddr) {
+        trace_event!(debug, source = %source, reason = %reason, "multi-session server dropped datagram");
+        if let Some(observer) = &mut self.drop_observer {
+            observer(reason, source);
+        }
+    }
+
+    // Encrypts an outgoing datagram, if encryption is enabled.
+    fn seal<P>(&self, data: P) -> P where P: AsRef<[u8]> + From<Vec<u8>> {
+        #[cfg(feature = "encryption")]
+        if let Some(cipher) = &self.cipher {
+            return P::from(cipher.encrypt(data.as_ref()));
+        }
+        return data;
+    }
+
+    // Decrypts an incoming datagram, if encryption is enabled. A datagram which fails to decrypt
+    // was tampered with or encrypted under another key, it is reported as dropped and None is returned.
+    #[cfg(feature = "encryption")]
+    fn open(&mut self, data: Vec<u8>, source: SocketAddr) -> Option<Vec<u8>> {
+        let cipher = match &self.cipher {
+            Some(cipher) => cipher,
+            None => {
+                return Some(data);
+            }
+        };
+        match cipher.decrypt(&data) {
+            Some(plaintext) => {
+                return Some(plaintext);
+            },
+            None => {
+                self.dropped(DropReason::Undecryptable, source);
+                return None;
+            }
+        }
+    }
+
+    #[cfg(not(feature = "encryption"))]
+    fn open(&mut self, data: Vec<u8>, _source: SocketAddr) -> Option<Vec<u8>> {
+        Some(data)
+    }
+
+    // Sends a message to a client. Messages to relayed clients go through the holepuncher.
+    fn send_to_peer(&self, msg: &Message, to: SocketAddr) -> Result<(), SendError> {
+        if !self.relayed_clients.contains(&to) {
+            return self.proto_socket.send_message_blocking(msg, to, self.send_retry_budget);
+        }
+        let session_id = match self.clients.get(&to) {
+            Some((session_id, _)) => session_id.clone(),
+            None => {
+                // the client was forgotten, and with it the session to relay under
+                return self.proto_socket.send_message_blocking(msg, to, self.send_retry_budget);
+            }
+        };
+        let payload = match msg.serialize() {
+            Ok(bytes) => bytes,
+            Err(()) => {
+                return Err(SendError::serialization(msg));
+            }
+        };
+        let relayed = Message::RelayData(RelayDataContents {
+            session_id,
+            peer_addr: to,
+            payload,
+        });
+        return self.proto_socket.send_message_blocking(&relayed, self.holepuncher, self.send_retry_budget);
+    }
+
+    // Unwraps a message relayed by the holepuncher, so that it can be handled as if the client had sent it directly.
+    // Other messages are returned as they are.
+    fn unwrap_relayed(&mut self, msg: Message, source: SocketAddr) -> (Message, SocketAddr) {
+        if let Message::RelayData(contents) = &msg {
+            if source == self.holepuncher && self.sessions.contains(&contents.session_id) {
+                if let Ok(inner) = Message::deserialize(&contents.payload) {
+                    // remember to answer the client through the relay as well, if the holepuncher introduced it to this session.
+                    // Messages from anyone else are handled like those of any unknown peer.
+                    let pending_session = self.pending_clients.get(&contents.peer_addr).map(|(session_id, _)| session_id.as_slice());
+                    let introduced = self.session_of(contents.peer_addr).or(pending_session) == Some(contents.session_id.as_slice());
+                    if introduced {
+                        self.relayed_clients.insert(contents.peer_addr);
+                    }
+                    return (inner, contents.peer_addr);
+                }
+            }
+        }
+        return (msg, source);
+    }
+
+    // Records that a known client was heard from. Returns the session it joined, or None if the client is unknown.
+    fn touch_client(&mut self, addr: SocketAddr) -> Option<Vec<u8>> {
+        match self.clients.get_mut(&addr) {
+            Some((session_id, last_seen)) => {
+                *last_seen = Instant::now();
+                return Some(session_id.clone());
+            },
+            None => {
+                return None;
+            }
+        }
+    }
+
+    // Records that a client was heard from in the HelloReq/HelloResp handshake. A client announced by a PeerInfo
+    // is admitted to the session it joined. Returns false if the holepuncher never introduced the client.
+    fn admit_client(&mut self, addr: SocketAddr) -> bool {
+        if let Some((session_id, _)) = self.pending_clients.remove(&addr) {
+            self.clients.insert(addr, (session_id, Instant::now()));
+            return true;
+        }
+        return self.touch_client(addr).is_some();
+    }
+
+    fn forget_client(&mut self, addr: SocketAddr) {
+        self.clients.remove(&addr);
+        self.pending_clients.remove(&addr);
+        self.relayed_clients.remove(&addr);
+        self.duplicate_filter.forget(addr);
+    }
+
+    // Forgets clients that stopped sending keepalives.
+    fn prune_clients(&mut self) {
+        let client_timeout = self.client_timeout;
+        let timed_out: Vec<SocketAddr> = self.clients.iter()
+            .filter(|(_, (_, last_seen))| last_seen.elapsed() > client_timeout)
+            .map(|(addr, _)| *addr)
+            .collect();
+        for addr in timed_out {
+            self.forget_client(addr);
+        }
+        // and announced addresses which never completed the handshake, such as the candidates a client didn't use
+        self.pending_clients.retain(|_, (_, announced)| announced.elapsed() <= client_timeout);
+    }
+
+    /// Sends a datagram to a client of any of the sessions.
+    /// Datagrams larger than MAX_DATA_SIZE are split into fragments, which the receiver puts back together.
+    /// Takes a Vec<u8> or, with the bytes feature, a bytes::Bytes, which is shared rather than copied if it fits unencrypted.
+    pub fn send_datagram(&mut self, to: SocketAddr, data: impl Into<Payload>) -> Result<(), RuphinError> {
+        let data: Payload = self.seal(data.into());
+        let messages = if data.len() <= MAX_DATA_SIZE {
+            vec![Message::Data(DataContents {
+                data,
+                expires_at: self.config.data_ttl.map(DataContents::deadline_after),
+            })]
+        } else {
+            let fragments = match fragment(&data, self.next_message_id, self.config.data_ttl.map(DataContents::deadline_after)) {
+                Ok(fragments) => fragments,
+                Err(()) => {
+                    return Err(RuphinError::DatagramTooLarge {
+                        size: data.len(),
+                        max: MAX_FRAGMENTED_DATA_SIZE,
+                    });
+                }
+            };
+            self.next_message_id = self.next_message_id.wrapping_add(1);
+            fragments
+        };
+        for msg in messages {
+            match self.send_to_peer(&msg, to) {
+                Ok(()) => {},
+                Err(e) => {
+                    return Err(RuphinError::from(e));
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    /// Tells the holepuncher to forget about all sessions. No more keepalives are sent afterwards.
+    pub fn disconnect(&mut self) -> Result<(), RuphinError> {
+        let session_ids = self.sessions();
+        for session_id in session_ids {
+            self.remove_session(&session_id)?;
+        }
+        return Ok(());
+    }
+
+    /// Serve messages on the socket until you get a datagram from a client of one of the sessions.
+    /// This method should be called regularly to ensure keepalives are sent, connection requests answered, etc.
+    /// Returns the ID of the session the datagram belongs to, the client which sent it and the datagram.
+    /// If no data is received after a specified timeout, it returns Ok(None).
+    /// If a timeout of None is specified, this function will not return until it has data.
+    /// A timeout too large to be added to the current time, such as Duration::MAX, is as good as None.
+    /// An exception to this is: If allow_interrupt is true, the function will return if it receives a LocalInterrupt message from localhost, again with Ok(None).
+    /// Likewise, if holepuncher_timeout is configured and passes without hearing from the holepuncher, it returns Ok(None) once,
+    /// and holepuncher_timed_out tells it apart.
+    /// With answer_lan_joins configured, Joins which clients broadcast on the LAN for one of the sessions are answered too.
+    /// Datagrams from clients which did not join through the holepuncher are dropped, since their session is unknown,
+    /// and so are those from clients which haven't completed the HelloReq/HelloResp handshake yet.
+    pub fn wait_for_data(&mut self, timeout: Option<Duration>, allow_interrupt: bool) -> Result<Option<SessionDatagram>, RuphinError> {
+        // Represents the current time.
+        // Measured before instances of being used if there was a syscall or I/O operation since it was last measured.
+        let mut now = Instant::now();
+
+        // this is the time when the function should return
+        let return_at = timeout.map(|timeout| deadline_after(now, timeout));
+
+        // await messages in a loop
+        loop {
+            // Re-measure the time since there might've been an I/O operation before that.
+            now = Instant::now();
+
+            // drop fragmented datagrams that will never be completed
+            self.reassembler.evict_expired(now);
+            // forget clients that stopped sending keepalives
+            self.prune_clients();
+
+            // Is it time to send keepalives?
+            if now > self.next_keepalive_at {
+                // send a keepalive (Register) for every session to the holepuncher
+                for session_id in &self.sessions {
+                    let msg = Message::Register(RegisterContents {
+                        session_id: session_id.clone(),
+                        metadata: self.config.metadata.clone(),
+                        candidates: self.candidates.clone(),
+                        mac: self.config.register_mac(session_id, &self.candidates),
+                    });
+                    match self.proto_socket.send_message(&msg, self.holepuncher) {
+                        Ok(()) => {},
+                        Err(e) if !e.is_fatal() => {
+                            // transient error, skip this keepalive, the next one is sent as scheduled
+                        },
+                        Err(e) => {
+                            return Err(RuphinError::from(e));
+                        }
+                    };
+                }
+                // We did an I/O operation, so re-measure the current time.
+                now = Instant::now();
+
+                // schedule the next keepalive
+                self.next_keepalive_at = deadline_after(now, jittered(self.keepalive_interval, self.keepalive_jitter));
+            }
+
+            // Has the holepuncher gone silent?
+            let holepuncher_deadline = self.holepuncher_deadline();
+            if let Some(holepuncher_deadline) = holepuncher_deadline {
+                if now > holepuncher_deadline {
+                    self.holepuncher_timed_out = true;
+                    self.proto_socket.set_read_timeout(None).unwrap();
+                    return Ok(None);
+                }
+            }
+
+            // Is it time to return?
+            if let Some(return_at) = return_at {
+                if now > return_at {
+                    self.proto_socket.set_read_timeout(None).unwrap();
+                    return Ok(None);
+                }
+            }
+
+            // determine the next wakeup time: the keepalive, the return or the holepuncher's deadline, whichever comes first
+            let next_wakeup = [return_at, holepuncher_deadline].into_iter().flatten()
+                .fold(self.next_keepalive_at, |earliest, deadline| earliest.min(deadline));
+            if next_wakeup <= now {
+                // no time, return to beginning of loop
+                continue;
+            }
+
+            // set the timeout on the socket, roughly until next_wakeup
+            self.proto_socket.set_read_timeout(Some(next_wakeup - now)).unwrap();
+
+            // await the next message
+            let received = match self.proto_socket.get_message_with_source() {
+                Ok((msg, source)) => {
+                    if source == self.holepuncher {
+                        self.last_heard_from_holepuncher = Instant::now();
+                        self.holepuncher_timed_out = false;
+                    }
+                    Ok(self.unwrap_relayed(msg, source))
+                },
+                Err((e, Some(source))) => {
+                    // a datagram arrived, but it isn't a message
+                    self.dropped(DropReason::Malformed, source);
+                    Err(e)
+                },
+                Err((e, None)) => Err(e),
+            };
+            match received {
+                Ok((Message::HelloReq(_), source)) if !self.admit_client(source) => {
+                    // a peer the holepuncher didn't introduce, or one whose PeerInfo hasn't arrived yet.
+                    // Don't answer it, so that it asks again once we know which session it joined.
+                    self.dropped(DropReason::UnknownPeer, source);
+                },
+                Ok((Message::HelloReq(_), source)) => {
+                    // a client is connecting or sending a keepalive
+                    // send the source a HelloResp, proposing our keepalive interval
+                    let resp = Message::HelloResp(HelloRespContents::proposing(self.keepalive_interval));
+                    match self.send_to_peer(&resp, source) {
+                        Ok(()) => {},
+                        Err(e) if !e.is_fatal() => {
+                            // transient error, the peer will ask again
+                        },
+                        Err(e) => {
+                            return Err(RuphinError::from(e));
+                        }
+                    };
+                },
+                Ok((Message::HelloResp(_), source)) => {
+                    // a client answered our HelloReq
+                    if !self.admit_client(source) {
+                        self.dropped(DropReason::UnknownPeer, source);
+                    }
+                },
+                Ok((Message::Goodbye(contents), source)) => {
+                    // a client is leaving its session
+                    match self.session_of(source) {
+                        Some(session_id) if session_id == contents.session_id.as_slice() => {
+                            self.forget_client(source);
+                        },
+                        Some(_) => {
+                            self.dropped(DropReason::WrongSession, source);
+                        },
+                        None => {
+                            self.dropped(DropReason::UnknownPeer, source);
+                        }
+                    }
+                },
+                Ok((Message::Ping(contents), source)) => {
+                    // answer with a Pong carrying the same nonce
+                    let response = Message::Pong(PongContents {
+                        nonce: contents.nonce,
+                    });
+                    match self.send_to_peer(&response, source) {
+                        Ok(()) => {},
+                        Err(e) if !e.is_fatal() => {
+                            // transient error, the peer will ask again
+                        },
+                        Err(e) => {
+                            return Err(RuphinError::from(e));
+                        }
+                    };
+                },
+                Ok((Message::PeerInfo(_), source)) if source != self.holepuncher => {
+                    // PeerInfos are only trusted from the holepuncher
+                    self.dropped(DropReason::WrongSource, source);
+                },
+                Ok((Message::PeerInfo(contents), source)) if !self.sessions.contains(&contents.session_id) => {
+                    self.dropped(DropReason::WrongSession, source);
+                },
+                Ok((Message::PeerInfo(contents), source)) if !self.config.is_authentic_peer_info(&contents) => {
+                    self.dropped(DropReason::Unauthenticated, source);
+                },
+                Ok((Message::PeerInfo(contents), _)) => {
+                    // a client joined one of our sessions, at one of its addresses
+                    // a dual-stack holepuncher may hand out an address of the other family, which we can't reach
+                    let addrs = std::iter::once(contents.peer_addr).chain(contents.candidates);
+                    for peer_addr in addrs.filter_map(|addr| self.proto_socket.reachable_addr(addr)) {
+                        // remember which session the client joined, it is admitted once it completes the handshake.
+                        // The addresses it doesn't use are pruned later.
+                        self.pending_clients.insert(peer_addr, (contents.session_id.clone(), Instant::now()));
+                        // send a HelloReq to the peer, once.
+                        let result = match self.initial_hello_ttl {
+                            Some(ttl) => self.proto_socket.send_message_with_ttl(&Message::HelloReq(HelloReqContents::default()), peer_addr, ttl),
+                            None => self.proto_socket.send_message(&Message::HelloReq(HelloReqContents::default()), peer_addr),
+                        };
+                        match result {
+                            Ok(()) => {},
+                            Err(e) if !e.is_fatal() => {
+                                // transient error, the peer's own HelloReqs can still open the path
+                            },
+                            Err(e) => {
+                                return Err(RuphinError::from(e));
+                            }
+                        };
+                    }
+                },
+                Ok((Message::Join(contents), source)) if self.config.answer_lan_joins && !self.sessions.contains(&contents.session_id) => {
+                    // looking for another server on the LAN
+                    self.dropped(DropReason::WrongSession, source);
+                },
+                Ok((Message::Join(contents), source)) if self.config.answer_lan_joins && !self.config.is_authentic_join(&contents) => {
+                    self.dropped(DropReason::Unauthenticated, source);
+                },
+                Ok((Message::Join(contents), source)) if self.config.answer_lan_joins => {
+                    // a client on the LAN is looking for one of our sessions, tell it our address on the interface which reaches it.
+                    // It goes on with the HelloReq/HelloResp handshake as if the holepuncher had introduced it.
+                    if let Some(addr) = self.proto_socket.routed_addr(source) {
+                        let info = Message::PeerInfo(PeerInfoContents {
+                            mac: self.config.peer_info_mac(&contents.session_id, addr),
+                            session_id: contents.session_id.clone(),
+                            peer_addr: addr,
+                            candidates: Vec::new(),
+                        });
+                        self.pending_clients.insert(source, (contents.session_id, Instant::now()));
+                        match self.proto_socket.send_message(&info, source) {
+                            Ok(()) => {},
+                            Err(e) if !e.is_fatal() => {
+                                // transient error, the client broadcasts again
+                            },
+                            Err(e) => {
+                                return Err(RuphinError::from(e));
+                            }
+                        };
+                    }
+                },
+                Ok((Message::RegisterRejected(contents), source)) => {
+                    // a session added later was refused by the holepuncher, stop serving it
+                    if source == self.holepuncher {
+                        self.sessions.remove(&contents.session_id);
+                    } else {
+                        self.dropped(DropReason::WrongSource, source);
+                    }
+                },
+                Ok((Message::RegisterAck(_), source)) if source != self.holepuncher => {
+                    self.dropped(DropReason::WrongSource, source);
+                },
+                Ok((Message::RegisterAck(contents), source)) if !self.sessions.contains(&contents.session_id) => {
+                    self.dropped(DropReason::WrongSession, source);
+                },
+                Ok((Message::RegisterAck(_), _)) => {
+                    // a keepalive was acknowledged, nothing to do
+                },
+                Ok((Message::Data(contents), source)) if contents.is_expired() => {
+                    // the data sat in a queue or relay for too long and is of no use anymore, drop it
+                    self.dropped(DropReason::Expired, source);
+                },
+                Ok((Message::Data(contents), source)) => {
+                    // got some data, return it if we know which session it belongs to
+                    match self.touch_client(source) {
+                        Some(session_id) => {
+                            if let Some(data) = self.open(contents.into_vec(), source) {
+                                self.proto_socket.set_read_timeout(None).unwrap();
+                                return Ok(Some((session_id, source, data)));
+                            }
+                        },
+                        None => {
+                            self.dropped(DropReason::UnknownPeer, source);
+                        }
+                    }
+                },
+                Ok((Message::ReliableData(contents), source)) => {
+                    let session_id = match self.touch_client(source) {
+                        Some(session_id) => session_id,
+                        None => {
+                            // unknown client, don't acknowledge what we drop
+                            self.dropped(DropReason::UnknownPeer, source);
+                            continue;
+                        }
+                    };
+                    // acknowledge every copy, the acknowledgement of an earlier one may have been lost
+                    let ack = Message::DataAck(DataAckContents {
+                        seq: contents.seq,
+                    });
+                    match self.send_to_peer(&ack, source) {
+                        Ok(()) => {},
+                        Err(e) if !e.is_fatal() => {
+                            // transient error, the peer will retransmit
+                        },
+                        Err(e) => {
+                            return Err(RuphinError::from(e));
+                        }
+                    };
+                    // but return only the first one
+                    if self.duplicate_filter.insert(source, contents.seq) {
+                        if let Some(data) = self.open(contents.data, source) {
+                            self.proto_socket.set_read_timeout(None).unwrap();
+                            return Ok(Some((session_id, source, data)));
+                        }
+                    }
+                },
+                Ok((Message::DataFragment(contents), source)) if contents.is_expired() => {
+                    // a piece of a datagram which is of no use anymore, drop it
+                    self.dropped(DropReason::Expired, source);
+                },
+                Ok((Message::DataFragment(contents), source)) => {
+                    // got a piece of a larger datagram, return it once it's complete
+                    match self.touch_client(source) {
+                        Some(session_id) => {
+                            if let Some(data) = self.reassembler.insert(source, contents, Instant::now()).and_then(|data| self.open(data, source)) {
+                                self.proto_socket.set_read_timeout(None).unwrap();
+                                return Ok(Some((session_id, source, data)));
+                            }
+                        },
+                        None => {
+                            self.dropped(DropReason::UnknownPeer, source);
+                        }
+                    }
+                },
+                Ok((Message::LocalInterrupt, source)) if allow_interrupt => {
+                    // received a local interrupt and interrupts are allowed
+                    // check that the source is localhost. If yes, return Ok(None). Otherwise ignore.
+                    if source.ip().is_loopback() {
+                        self.proto_socket.set_read_timeout(None).unwrap();
+                        return Ok(None);
+                    } else {
+                        self.dropped(DropReason::WrongSource, source);
+                        continue;
+                    }
+                },
+                Ok((_, source)) => {
+                    // another message was received, ignore it
+                    self.dropped(DropReason::UnexpectedMessage, source);
+                    continue;
+                },
+                Err(e) => {
+                    if e.is_fatal() {
+                        // fatal error, return
+                        return Err(RuphinError::from(e));
+                    } else {
+                        // nonfatal error, likely a timeout. Ignore and retry.
+                        continue;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Drop for MultiSessionServer {
+    fn drop(&mut self) {
+        // best effort, the sessions expire at the holepuncher eventually anyway
+        let _ = self.disconnect();
+    }
+}
@@ -1,360 +1,1863 @@
-use std::net::SocketAddr;
-use std::time::{
-    Duration,
-    Instant,
-};
-use crate::messages::*;
-use crate::protocol_socket::*;
-
-/// a client connects to a single server.
-pub struct PassiveClient { 
-    /// Underlying socket
-    proto_socket: ProtocolSocket,
-    /// Address of the holepuncher the session is registered with
-    holepuncher: SocketAddr,
-    /// Address of the server the client is connected to
-    server: SocketAddr,
-    // ID of the session
-    // session_id: Vec<u8>,
-    /// Keepalive interval. Default is 10 seconds.
-    keepalive_interval: Duration,
-    /// Time after which the client should send a keepalive to the server it's connected to.
-    next_keepalive_at: Instant,
-}
-
-impl PassiveClient {
-    pub fn new(holepuncher: SocketAddr, session_id: Vec<u8>)
-        -> Result<Self, String> {
-        // bind a protocol socket to 0.0.0.0:0
-        let sock = match ProtocolSocket::bind("0.0.0.0:0") {
-            Ok(sock) => sock,
-            Err(e) => {
-                return Err(format!("{:?}", e)); // TODO error handling
-            }
-        };
-        
-        // Timeout behaviour:
-        // Up to 10 seconds for the session
-        // individual message timeout = 500 ms
-        // minimal inter-message time = 400 ms
-        // Retry the HelloReq/HelloResp handshake up to 3 times
-        let total_timeout = Duration::from_secs(10);
-        let indiv_timeout = Duration::from_millis(500);
-        let inter_message_time = Duration::from_millis(400);
-        let num_hello_retries = 3;
-        
-        // deadline after which the attempt to create a server is considered failed
-        let end_time = Instant::now() + total_timeout;
-        // Set the protocol socket's message timeout (will be undone after the function returns)
-        sock.set_read_timeout(Some(indiv_timeout)).unwrap();
-        
-        // We will send a Join message to the holepuncher.
-        // We will then wait for either a PeerInfo or SessionNotFound message.
-        // If we got a PeerInfo message, we will try to send a HelloReq to that peer and expect a HelloResp back.
-        // Once that is done, we've established our session and we're done.
-        // construct the message for the holepuncher
-        let request = Message::Join(JoinContents {
-            session_id: session_id.clone(),
-        });
-        
-        // send the request initially
-        match sock.send_message(&request, holepuncher) {
-            Ok(()) => {},
-            Err(e) => {
-                return Err(format!("Message send error: {:?}", e));
-            }
-        };
-        // schedule the earliest time for the next attempt
-        let mut next_retry_at = Instant::now() + inter_message_time;
-        
-        // enter a retry loop
-        'join_loop: while Instant::now() < end_time {
-            // retry the Register message
-            if Instant::now() > next_retry_at {
-                match sock.send_message(&request, holepuncher) {
-                    Ok(()) => {
-                        next_retry_at = Instant::now() + inter_message_time;
-                    },
-                    Err(e) => {
-                        return Err(format!("Message send error: {:?}", e));
-                    }
-                };
-            }
-            
-            // Wait for a response. This will either succeed, timeout, or fatally fail.
-            let (response, source) = match sock.get_message() {
-                Ok((ack, source)) => (ack, source),
-                Err(e) => {
-                    if e.is_fatal() {
-                        // fatal error, return
-                        return Err(format!("Fatal receive error: {:?}", e));
-                    } else {
-                        // nonfatal error, ignore and retry
-                        continue 'join_loop;
-                    }
-                },
-            };
-            
-            // check the response type
-            match response {
-                Message::PeerInfo(PeerInfoContents {
-                    peer_addr
-                }) => {
-                    // got the info of another peer
-                    if source != holepuncher {
-                        // message is not from the holepuncher, ignore it
-                        continue 'join_loop;
-                    }
-                    
-                    // TODO check session ID somehow?
-                    
-                    // start trying the HelloReq/HelloResp handshake
-                    // repeatedly send a HelloReq to the other peer and await a HelloResp
-                    match sock.send_message(&Message::HelloReq, peer_addr) {
-                        Ok(()) => {},
-                        Err(e) => {
-                            return Err(format!("Message send error: {:?}", e));
-                        }
-                    }
-                    // schedule the next time when we can retry a HelloReq
-                    let mut next_hello_retry_at = Instant::now() + inter_message_time;
-                    // count how many HelloReq's we've already sent
-                    let mut num_attempts = 1;
-                    
-                    // retry loop
-                    'hello_loop: while num_attempts < num_hello_retries && Instant::now() < end_time {
-                        // is it time to retry the HelloReq?
-                        if Instant::now() < next_hello_retry_at {
-                            match sock.send_message(&Message::HelloReq, peer_addr) {
-                                Ok(()) => {
-                                    // reschedule the next hello retry and count up the attempts
-                                    next_hello_retry_at = Instant::now() + inter_message_time;
-                                    num_attempts += 1;
-                                },
-                                Err(e) => {
-                                    return Err(format!("Message Send Error: {:?}", e));
-                                }
-                            }
-                        }
-                        
-                        // wait for a message
-                        match sock.get_message() {
-                            Ok((Message::HelloResp, source)) => {
-                                if source != peer_addr {
-                                    // wrong source, ignore
-                                    continue 'hello_loop;
-                                }
-                                // a HelloResp arrived, we're done
-                                
-                                // remove the timeout on the socket
-                                sock.set_read_timeout(None).unwrap();
-                                // construct a passive client and return it
-                                return Ok(Self {
-                                    proto_socket: sock,
-                                    holepuncher,
-                                    server: peer_addr,
-                                    //session_id,
-                                    keepalive_interval: Duration::from_secs(10),
-                                    next_keepalive_at: Instant::now() + Duration::from_secs(10),
-                                });
-                            },
-                            Ok(_) => {
-                                // some other message arrived, ignore it
-                                continue 'hello_loop;
-                            }
-                            Err(e) => {
-                                if e.is_fatal() {
-                                    // fatal error, return
-                                    return Err(format!("Fatal receive error: {:?}", e));
-                                } else {
-                                    // nonfatal error, likely a timeout. Ignore and retry.
-                                    continue 'hello_loop;
-                                }
-                            }
-                        };
-                    }
-                    // couldn't get a HelloResp, retry the join
-                    continue 'join_loop;
-                },
-                Message::SessionNotFound(contents) => {
-                    if contents.session_id == session_id {
-                        // session not found, return.
-                        return Err(format!("Session not found"));
-                    } else {
-                        // wrong session ID, ignore
-                        continue 'join_loop;
-                    }
-                },
-                _ => {
-                    // some other message arrived, ignore it and retry
-                    continue 'join_loop;
-                }
-            }
-        }
-        
-        // timeout, could not register session
-        return Err(format!("Holepuncher handshake timed out."));
-    }
-    
-    // Sends a datagram through the protocol socket to the given target
-    pub fn send_datagram(&mut self, to: SocketAddr, data: Vec<u8>) -> Result<(), String> {
-        let msg = Message::Data(DataContents {
-            data,
-        });
-        
-        match self.proto_socket.send_message(&msg, to) {
-            Ok(()) => {
-                return Ok(());
-            },
-            Err(e) => {
-                return Err(format!("Message send error: {:?}", e));
-            }
-        }
-    }
-    
-    // Get the listening port of the socket.
-    // Returns Err if the local address cannot be obtained.
-    pub fn get_port(&self) -> Result<u16, ()> {
-        self.proto_socket.get_port()
-    }
-    
-    // Returns the IP address + port of the server this client is connected to
-    pub fn get_server(&self) -> SocketAddr {
-        self.server
-    }
-    
-    /// Serve messages on the socket until you get a datagram from someone.
-    /// This method should be called regularly to ensure keepalives are sent, connection requests answered, etc.
-    /// If no data is received after a specified timeout, it returns Ok(None).
-    /// If a timeout of None is specified, this function will not return until it has data.
-    /// An exception to this is: If allow_interrupt is true, the function will return if it receives a LocalInterrupt message from localhost, again with Ok(None).
-    pub fn wait_for_data(&mut self, timeout: Option<Duration>, allow_interrupt: bool) -> Result<Option<(SocketAddr, Vec<u8>)>, String> {
-        // Represents the current time.
-        // Measured before instances of being used if there was a syscall or I/O operation since it was last measured.
-        let mut now = Instant::now();
-        
-        // this is the time when the function should return
-        let return_at = match timeout {
-            None => None,
-            Some(timeout) => Some(now + timeout),
-        };
-        
-        // await messages in a loop
-        loop {
-            // Re-measure the time since there might've been an I/O operation before that.
-            now = Instant::now();
-            
-            // Is it time to send a keepalive?
-            if now > self.next_keepalive_at {
-                // send a keepalive (HelloReq) to server
-                let msg = Message::HelloReq;
-                let addr = self.server;
-                
-                // TODO we can track the time since the last HelloResp to see if the server is still online?
-                match self.proto_socket.send_message(&msg, addr) {
-                    Ok(()) => {},
-                    Err(e) => {
-                        return Err(format!("Message send error: {:?}", e));
-                    }
-                };
-                // We did an I/O operation, so re-measure the current time.
-                now = Instant::now();
-                
-                // schedule the next keepalive
-                self.next_keepalive_at = now + self.keepalive_interval;
-            }
-            
-            // Is it time to return?
-            if let Some(return_at) = return_at {
-                if now > return_at {
-                    self.proto_socket.set_read_timeout(None).unwrap();
-                    return Ok(None);
-                }
-            }
-            
-            // determine the next wakeup time
-            let next_wakeup = if let Some(return_at) = return_at {
-                if return_at > self.next_keepalive_at {
-                    // Have to first do a keepalive
-                    self.next_keepalive_at
-                } else {
-                    // Return before it's time for the keepalive
-                    return_at
-                }
-            } else {
-                // no return time; wake up when it's time for the next keepalive
-                self.next_keepalive_at 
-            };
-            
-            // determine how much time we give the socket to wait for messages
-            let socket_time = {
-                if next_wakeup <= now {
-                    // no time, return to beginning of loop
-                    continue;
-                } else {
-                    // roughly until next_wakeup
-                    next_wakeup - now
-                }
-            };
-            
-            // set the timeout on the socket
-            self.proto_socket.set_read_timeout(Some(socket_time)).unwrap();
-            
-            // await the next message
-            match self.proto_socket.get_message() {
-                Ok((Message::HelloReq, source)) => {
-                    // send the source a HelloResp
-                    match self.proto_socket.send_message(&Message::HelloResp, source) {
-                        Ok(()) => {},
-                        Err(e) => {
-                            return Err(format!("Message send error: {:?}", e));
-                        }
-                    };
-                },
-                Ok((Message::PeerInfo(contents), source)) => {
-                    // got a PeerInfo packet 
-                    // ignore it unless it's coming from the holepuncher
-                    if source == self.holepuncher {
-                        // send a HelloReq to the peer, once.
-                        match self.proto_socket.send_message(&Message::HelloReq, contents.peer_addr) {
-                            Ok(()) => {},
-                            Err(e) => {
-                                return Err(format!("Message send error: {:?}", e));
-                            }
-                        };
-                    }
-                },
-                Ok((Message::Data(contents), source)) => {
-                    // got some data, return it
-                    // remove the timeout on the socket
-                    // TODO check data source?
-                    self.proto_socket.set_read_timeout(None).unwrap();
-                    return Ok(Some((source, contents.data)));
-                },
-                Ok((Message::LocalInterrupt, source)) if allow_interrupt => {
-                    // received a local interrupt and interrupts are allowed
-                    // check that the source is localhost. If yes, return Ok(None). Otherwise ignore.
-                    if source.ip().is_loopback() {
-                        self.proto_socket.set_read_timeout(None).unwrap();
-                        return Ok(None);
-                    } else {
-                        continue;
-                    }
-                },
-                Ok(_) => {
-                    // another message was received, ignore it
-                    continue;
-                },
-                Err(e) => {
-                    if e.is_fatal() {
-                        // fatal error, return
-                        return Err(format!("Fatal receive error: {:?}", e));
-                    } else {
-                        // nonfatal error, likely a timeout. Ignore and retry.
-                        continue;
-                    }
-                }
-            }
-        }
-    }
-}
\ No newline at end of file
+use std::net::{
+    SocketAddr,
+    Ipv4Addr,
+    Ipv6Addr,
+    ToSocketAddrs,
+};
+use std::time::{
+    Duration,
+    Instant,
+    SystemTime,
+};
+use std::collections::{
+    HashSet,
+    VecDeque,
+};
+use crate::messages::*;
+use crate::protocol_socket::*;
+use crate::error::{
+    RuphinError,
+    HandshakeStage,
+};
+use crate::event::{
+    Event,
+    MessageObserver,
+    DropReason,
+    DropObserver,
+    InterruptHandle,
+    HandshakeProgress,
+};
+use crate::jitter::jittered;
+use crate::clock::{
+    Clock,
+    deadline_after,
+    FOREVER,
+};
+use std::sync::Arc;
+#[cfg(feature = "encryption")]
+use crate::crypto::DataCipher;
+
+/// Tunable parameters of a PassiveClient
+#[derive(Debug, Clone)]
+pub struct PassiveClientConfig {
+    /// Interval at which keepalives are sent to the server. Must be non-zero. Default is 10 seconds.
+    /// If the server proposes a shorter interval when answering the client's HelloReqs, that one is used instead.
+    pub keepalive_interval: Duration,
+    /// Fraction by which each keepalive interval is randomly lengthened or shortened, so that peers which
+    /// started at the same time don't all send their keepalives at once. Must be in [0, 1). Default is 0 (no jitter).
+    pub keepalive_jitter: f64,
+    /// Number of keepalive intervals without hearing from the server after which it is considered gone.
+    /// Must be at least 2. Default is 3.
+    pub server_timeout_keepalives: u32,
+    /// Pre-shared key of the holepuncher, if it requires authentication.
+    /// PeerInfos are then only accepted with a valid MAC under this key. Default is None.
+    #[cfg(feature = "auth")]
+    pub auth_key: Option<Vec<u8>>,
+    /// Local address to bind the socket to, e.g. "[::]:0" for dual-stack. With a dual-stack socket,
+    /// IPv4 peers show up as IPv4-mapped IPv6 addresses, so the holepuncher must be given in that form too.
+    /// Default is None: the unspecified address of the holepuncher's family (0.0.0.0 or ::) and an arbitrary port.
+    pub bind_addr: Option<SocketAddr>,
+    /// Time after which connecting to the server is given up. Default is 10 seconds.
+    pub handshake_timeout: Duration,
+    /// Time to wait for a response to an individual handshake message.
+    /// Must be non-zero and not longer than handshake_timeout. Default is 500 ms.
+    pub message_timeout: Duration,
+    /// Minimal time between retransmissions of handshake messages. Default is 400 ms.
+    pub retry_interval: Duration,
+    /// Retransmissions of the Join start retry_interval apart, and each gap is twice as long as the previous one,
+    /// up to this. Set it to retry_interval for fixed spacing. Must not be shorter than retry_interval. Default is 3.2 seconds.
+    pub max_retry_interval: Duration,
+    /// Number of HelloReqs sent to the server before joining the session again (or falling back to the relay,
+    /// see relay_fallback). Must be at least 1. Default is 3.
+    pub hello_retries: u32,
+    /// If set, the first HelloReq to the server is sent with this IP TTL, low enough to open a mapping
+    /// in the client's own NAT without reaching the server, followed by HelloReqs with the normal TTL.
+    /// Must be non-zero. Default is None.
+    pub initial_hello_ttl: Option<u32>,
+    /// Key under which datagrams to and from the peer are encrypted. Both peers must use the same key.
+    /// Default is None (no encryption).
+    #[cfg(feature = "encryption")]
+    pub encryption_key: Option<[u8; 32]>,
+    /// Time after which send_reliable gives up waiting for an acknowledgement. Default is 5 seconds.
+    pub reliable_timeout: Duration,
+    /// Time between retransmissions of an unacknowledged reliable datagram. Must be non-zero. Default is 200 ms.
+    pub reliable_retry_interval: Duration,
+    /// If set, once this many reliable datagrams in a row go unacknowledged, the server is considered lost and
+    /// wait_for_event returns Ok(Event::PeerLost) once, until the server acknowledges one again. In chatty sessions
+    /// this notices a vanished server sooner than server_timeout_keepalives. Must be at least 1. Default is None.
+    pub max_unacked_reliable: Option<u32>,
+    /// Connect the socket to the server once the handshake completes, so that the OS drops datagrams from anyone else.
+    /// The holepuncher can no longer reach the client then. Ignored when relaying. Default is false.
+    pub connect_to_server: bool,
+    /// If the server cannot be reached directly, fall back to relaying all traffic through the holepuncher.
+    /// The holepuncher must have relaying enabled. Default is false.
+    pub relay_fallback: bool,
+    /// Application-level ID sent to the server with every HelloReq, so that it can address this client by it
+    /// (see PassiveServer::send_to_client). At most MAX_HANDLE_SIZE bytes. Default is empty (no handle).
+    pub handle: Vec<u8>,
+    /// Number of datagrams PassiveClient::wait_for_event picks up from the socket in one go when several arrive at once.
+    /// The first is returned right away, the rest on the following calls, so that bursts don't overflow the OS receive buffer
+    /// while the application handles them. 1 returns each datagram as soon as it's received. Default is 32.
+    pub max_queued_data: usize,
+    /// Time to live of the datagrams sent with send_datagram. Receivers drop them once it has passed,
+    /// for real-time data where a late datagram is worse than a lost one. Requires the peers' clocks to be roughly in sync,
+    /// and the fragments of a large datagram expire along with it. Default is None (datagrams never expire).
+    pub data_ttl: Option<Duration>,
+    /// If set, the client also sends its keepalives to the holepuncher, and wait_for_event returns
+    /// Ok(Event::HolepuncherTimedOut) once if nothing is heard from the holepuncher for this long.
+    /// Must be longer than keepalive_interval. Has no effect with connect_to_server, since the holepuncher can't reach the client then.
+    /// Default is None (the holepuncher isn't watched).
+    pub holepuncher_timeout: Option<Duration>,
+    /// Announce the client's local addresses to the server along with the Join, so that a server on the same LAN
+    /// can reach it even if the NAT doesn't forward traffic between two of its own hosts. This reveals the addresses
+    /// of the client's network interfaces to the holepuncher and the server. Candidates announced by the server are tried either way. Default is false.
+    pub gather_candidates: bool,
+    /// If set, the client sends the holepuncher a Join for its session this often once connected, so that a holepuncher
+    /// which restarted and lost its sessions learns of the pairing again once the server has registered anew, e.g. to
+    /// tell the client where the server migrated to. Each Join also makes the holepuncher send both peers a PeerInfo.
+    /// Must be non-zero. Has no effect with connect_to_server, since the holepuncher can't reach the client then.
+    /// Default is None (the session is only joined once).
+    pub rejoin_interval: Option<Duration>,
+    /// Find the server on the LAN instead of through a holepuncher: the holepuncher addresses given are broadcast
+    /// addresses (e.g. 192.168.1.255:4000), to which the Joins are broadcast, and a server of the session listening
+    /// on that port with PassiveServerConfig::answer_lan_joins answers with its own address. The server which answered
+    /// then stands in for the holepuncher, e.g. reconnect asks it directly. Any host on the LAN can answer,
+    /// so use an auth_key to be sure of the server. relay_fallback has no effect. Default is false.
+    pub lan_discovery: bool,
+}
+
+impl Default for PassiveClientConfig {
+    fn default() -> Self {
+        Self {
+            keepalive_interval: Duration::from_secs(10),
+            keepalive_jitter: 0.0,
+            server_timeout_keepalives: 3,
+            bind_addr: None,
+            #[cfg(feature = "auth")]
+            auth_key: None,
+            handshake_timeout: Duration::from_secs(10),
+            message_timeout: Duration::from_millis(500),
+            retry_interval: Duration::from_millis(400),
+            max_retry_interval: Duration::from_millis(3200),
+            initial_hello_ttl: None,
+            reliable_timeout: Duration::from_secs(5),
+            reliable_retry_interval: Duration::from_millis(200),
+            max_unacked_reliable: None,
+            #[cfg(feature = "encryption")]
+            encryption_key: None,
+            hello_retries: 3,
+            connect_to_server: false,
+            relay_fallback: false,
+            handle: Vec::new(),
+            max_queued_data: 32,
+            data_ttl: None,
+            holepuncher_timeout: None,
+            gather_candidates: false,
+            rejoin_interval: None,
+            lan_discovery: false,
+        }
+    }
+}
+
+impl PassiveClientConfig {
+    // Checks that the configured values make sense.
+    fn validate(&self) -> Result<(), RuphinError> {
+        if self.keepalive_interval.is_zero() {
+            return Err(RuphinError::InvalidConfig("keepalive_interval must be non-zero"));
+        }
+        if !(0.0..1.0).contains(&self.keepalive_jitter) {
+            return Err(RuphinError::InvalidConfig("keepalive_jitter must be at least 0 and less than 1"));
+        }
+        if self.server_timeout_keepalives < 2 {
+            return Err(RuphinError::InvalidConfig("server_timeout_keepalives must be at least 2"));
+        }
+        if self.message_timeout.is_zero() {
+            return Err(RuphinError::InvalidConfig("message_timeout must be non-zero"));
+        }
+        if self.message_timeout > self.handshake_timeout {
+            return Err(RuphinError::InvalidConfig("message_timeout must not be longer than handshake_timeout"));
+        }
+        if self.max_retry_interval < self.retry_interval {
+            return Err(RuphinError::InvalidConfig("max_retry_interval must not be shorter than retry_interval"));
+        }
+        if self.hello_retries == 0 {
+            return Err(RuphinError::InvalidConfig("hello_retries must be at least 1"));
+        }
+        if self.reliable_retry_interval.is_zero() {
+            return Err(RuphinError::InvalidConfig("reliable_retry_interval must be non-zero"));
+        }
+        if self.max_unacked_reliable == Some(0) {
+            return Err(RuphinError::InvalidConfig("max_unacked_reliable must be at least 1"));
+        }
+        if self.initial_hello_ttl == Some(0) {
+            return Err(RuphinError::InvalidConfig("initial_hello_ttl must be non-zero"));
+        }
+        if self.handle.len() > MAX_HANDLE_SIZE {
+            return Err(RuphinError::InvalidConfig("handle must be at most MAX_HANDLE_SIZE bytes"));
+        }
+        if self.max_queued_data == 0 {
+            return Err(RuphinError::InvalidConfig("max_queued_data must be at least 1"));
+        }
+        if self.rejoin_interval == Some(Duration::ZERO) {
+            return Err(RuphinError::InvalidConfig("rejoin_interval must be non-zero"));
+        }
+        if self.data_ttl == Some(Duration::ZERO) {
+            return Err(RuphinError::InvalidConfig("data_ttl must be non-zero"));
+        }
+        if let Some(holepuncher_timeout) = self.holepuncher_timeout {
+            if holepuncher_timeout <= self.keepalive_interval {
+                return Err(RuphinError::InvalidConfig("holepuncher_timeout must be longer than keepalive_interval"));
+            }
+        }
+        return Ok(());
+    }
+
+    // MAC to attach to Join and Query messages of the given session, made at the current time.
+    // It covers the candidates of a Join, Queries have none. Empty unless a key is configured.
+    #[cfg(feature = "auth")]
+    fn session_mac(&self, msg_type: u16, session_id: &[u8], candidates: &[SocketAddr]) -> Vec<u8> {
+        match &self.auth_key {
+            Some(key) => crate::auth::session_mac(key, msg_type, session_id, &[], candidates, std::time::SystemTime::now()),
+            None => Vec::new(),
+        }
+    }
+    
+    #[cfg(not(feature = "auth"))]
+    fn session_mac(&self, _msg_type: u16, _session_id: &[u8], _candidates: &[SocketAddr]) -> Vec<u8> {
+        Vec::new()
+    }
+
+    // Checks that a PeerInfo comes from a holepuncher with our key. Always true unless a key is configured.
+    #[cfg(feature = "auth")]
+    fn is_authentic_peer_info(&self, contents: &PeerInfoContents) -> bool {
+        match &self.auth_key {
+            Some(key) => crate::auth::verify_peer_info_mac(key, &contents.session_id, contents.peer_addr, &contents.candidates, &contents.mac),
+            None => true,
+        }
+    }
+    
+    #[cfg(not(feature = "auth"))]
+    fn is_authentic_peer_info(&self, _contents: &PeerInfoContents) -> bool {
+        true
+    }
+}
+
+/// a client connects to a single server.
+pub struct PassiveClient { 
+    /// Underlying socket
+    proto_socket: ProtocolSocket,
+    /// Address of the holepuncher the session is registered with
+    holepuncher: SocketAddr,
+    /// Address of the server the client is connected to
+    server: SocketAddr,
+    /// ID of the session
+    session_id: Vec<u8>,
+    /// Whether messages to the server are relayed through the holepuncher
+    relayed: bool,
+    /// Whether the socket is connected to the server
+    connected: bool,
+    /// Keepalive interval. Default is 10 seconds.
+    keepalive_interval: Duration,
+    /// Keepalive interval the server proposed in its last HelloResp, if any
+    server_keepalive_interval: Option<Duration>,
+    /// Fraction by which keepalive intervals are randomized
+    keepalive_jitter: f64,
+    /// Time after which the client should send a keepalive to the server it's connected to.
+    next_keepalive_at: Instant,
+    /// Whether keepalives are paused, see pause_keepalives
+    keepalives_paused: bool,
+    /// Number of keepalive intervals after which the server is considered gone if nothing is heard from it
+    server_timeout_keepalives: u32,
+    /// Time after which the client should join the session at the holepuncher again, if it does so periodically
+    next_rejoin_at: Instant,
+    /// Last time a message was received from the server
+    last_heard_from_server: Instant,
+    /// Whether the server timing out has already been reported
+    server_timed_out: bool,
+    /// Last time a message was received from the holepuncher
+    last_heard_from_holepuncher: Instant,
+    /// Whether the holepuncher timing out has already been reported
+    holepuncher_timed_out: bool,
+    /// Puts fragmented datagrams back together
+    reassembler: Reassembler,
+    /// Sequence numbers of the reliable datagrams recently received from the server, to drop retransmissions
+    duplicate_filter: DuplicateFilter,
+    /// Message ID for the next fragmented datagram sent
+    next_message_id: u32,
+    /// Nonce for the next Ping sent
+    next_nonce: u32,
+    /// Sequence number of the next reliable datagram sent
+    next_seq: u32,
+    /// Time after which sending a reliable datagram is given up
+    reliable_timeout: Duration,
+    /// Time between retransmissions of a reliable datagram
+    reliable_retry_interval: Duration,
+    /// Number of reliable datagrams in a row the server hasn't acknowledged
+    unacked_reliable: u32,
+    /// Events which happened while the client was busy with something else, e.g. measuring the RTT
+    pending_events: VecDeque<Event>,
+    /// Encrypts and decrypts datagrams, if a key is configured
+    #[cfg(feature = "encryption")]
+    cipher: Option<DataCipher>,
+    /// Callback invoked with every received message, if set
+    observer: Option<MessageObserver>,
+    /// Callback invoked with every received message which is ignored, if set
+    unhandled_observer: Option<MessageObserver>,
+    /// Callback invoked with the reason for every dropped datagram, if set
+    drop_observer: Option<DropObserver>,
+    /// Source of the current time for keepalives and timeouts
+    clock: Arc<dyn Clock>,
+    /// Configuration the client was created with, used to join the session again
+    config: PassiveClientConfig,
+}
+
+impl PassiveClient {
+    /// Connects to the server registered under the given session, using the default configuration.
+    pub fn new(holepuncher: impl ToSocketAddrs, session_id: Vec<u8>)
+        -> Result<Self, RuphinError> {
+        Self::with_config(holepuncher, session_id, PassiveClientConfig::default())
+    }
+
+    /// Connects to the server registered under the given session.
+    /// The holepuncher's address can be a host name, e.g. "holepuncher.example.com:4000". The session is looked up at all
+    /// the addresses it resolves to, one address family at a time in the order the resolver prefers,
+    /// moving on to the next family if the holepuncher can't be reached through the first.
+    /// Returns Err(RuphinError::Resolve) if the name can't be resolved, and Err(RuphinError::NoUsableAddress)
+    /// if it only resolves to addresses of another family than bind_addr.
+    pub fn with_config(holepuncher: impl ToSocketAddrs, session_id: Vec<u8>, config: PassiveClientConfig)
+        -> Result<Self, RuphinError> {
+        let mut families = resolve_by_family(holepuncher, config.bind_addr)?.into_iter().peekable();
+        loop {
+            // there's at least one family, and the last one's result is returned
+            let holepunchers = families.next().unwrap();
+            match Self::with_config_multi(holepunchers, session_id.clone(), config.clone()) {
+                Err(e) if e.is_unreachable() && families.peek().is_some() => {
+                    continue;
+                },
+                result => {
+                    return result;
+                }
+            }
+        }
+    }
+    
+    /// Connects to the server registered under the given session at any of the given holepunchers,
+    /// using the default configuration.
+    pub fn new_multi(holepunchers: Vec<SocketAddr>, session_id: Vec<u8>)
+        -> Result<Self, RuphinError> {
+        Self::with_config_multi(holepunchers, session_id, PassiveClientConfig::default())
+    }
+    
+    /// Connects to the server registered under the given session at any of the given holepunchers.
+    /// The session is looked up at all of them at once, and the first one to answer with the server's address is used.
+    /// Returns Err(RuphinError::SessionNotFound) only if none of the holepunchers know the session.
+    /// The holepunchers must all be of the same address family, unless bind_addr is set to a dual-stack address.
+    pub fn with_config_multi(holepunchers: Vec<SocketAddr>, session_id: Vec<u8>, config: PassiveClientConfig)
+        -> Result<Self, RuphinError> {
+        Self::with_progress(holepunchers, session_id, config, |_| {})
+    }
+    
+    /// Like with_config_multi, but reports each step of the handshake to the given callback, e.g. to show it in a UI.
+    /// The callback is invoked on the calling thread, before this returns.
+    pub fn with_progress<F>(holepunchers: Vec<SocketAddr>, session_id: Vec<u8>, config: PassiveClientConfig, mut progress: F)
+        -> Result<Self, RuphinError> where F: FnMut(HandshakeProgress) {
+        config.validate()?;
+        if holepunchers.is_empty() {
+            return Err(RuphinError::InvalidConfig("at least one holepuncher is required"));
+        }
+        
+        // bind a protocol socket to the configured address, or to an arbitrary port that can reach the holepunchers
+        let bind_addr = match config.bind_addr {
+            Some(bind_addr) => bind_addr,
+            None if holepunchers[0].is_ipv6() => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+            None => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        };
+        let sock = match ProtocolSocket::bind(bind_addr.to_string()) {
+            Ok(sock) => sock,
+            Err(e) => {
+                return Err(RuphinError::Bind(e));
+            }
+        };
+        return Self::start(sock, holepunchers, session_id, config, &mut progress);
+    }
+    
+    /// Like with_config_multi, but on an already bound socket instead of one bound to config.bind_addr,
+    /// e.g. one on top of a transport::MemoryTransport for tests.
+    pub fn with_socket(sock: ProtocolSocket, holepunchers: Vec<SocketAddr>, session_id: Vec<u8>, config: PassiveClientConfig)
+        -> Result<Self, RuphinError> {
+        config.validate()?;
+        if holepunchers.is_empty() {
+            return Err(RuphinError::InvalidConfig("at least one holepuncher is required"));
+        }
+        return Self::start(sock, holepunchers, session_id, config, &mut |_| {});
+    }
+    
+    // Performs the handshake on a bound socket and constructs the client.
+    fn start(sock: ProtocolSocket, holepunchers: Vec<SocketAddr>, session_id: Vec<u8>, config: PassiveClientConfig,
+        progress: &mut dyn FnMut(HandshakeProgress)) -> Result<Self, RuphinError> {
+        if config.lan_discovery {
+            if let Err(e) = sock.set_broadcast(true) {
+                return Err(RuphinError::Io(e));
+            }
+        }
+        let (holepuncher, server, relayed, server_keepalive_interval) =
+            Self::handshake(&sock, &holepunchers, &session_id, &config, progress)?;
+        if config.connect_to_server && !relayed {
+            if let Err(e) = sock.connect(server) {
+                return Err(RuphinError::Io(e));
+            }
+        }
+        // construct a passive client and return it
+        let mut client = Self::connected(sock, holepuncher, server, session_id, &config, relayed);
+        client.adopt_keepalive_interval(server_keepalive_interval);
+        return Ok(client);
+    }
+    
+    /// Asks the holepuncher for the metadata the server of the given session registered it with, without joining it.
+    /// Useful for picking one of several servers. Returns empty metadata if the server didn't set any,
+    /// Err(RuphinError::SessionNotFound) if the session doesn't exist, and Err(RuphinError::Timeout) if the
+    /// holepuncher didn't answer within handshake_timeout.
+    pub fn query_session(holepuncher: SocketAddr, session_id: &[u8], config: &PassiveClientConfig)
+        -> Result<Vec<u8>, RuphinError> {
+        config.validate()?;
+        let bind_addr = match config.bind_addr {
+            Some(bind_addr) => bind_addr,
+            None if holepuncher.is_ipv6() => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+            None => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        };
+        let sock = match ProtocolSocket::bind(bind_addr.to_string()) {
+            Ok(sock) => sock,
+            Err(e) => {
+                return Err(RuphinError::Bind(e));
+            }
+        };
+        sock.set_read_timeout(Some(config.message_timeout)).unwrap();
+        
+        let end_time = deadline_after(Instant::now(), config.handshake_timeout);
+        // every attempt gets a fresh MAC, since the holepuncher only accepts one for a while
+        let request = || Message::Query(QueryContents {
+            session_id: session_id.to_vec(),
+            mac: config.session_mac(QUERY, session_id, &[]),
+        });
+        // send the Query right away, and again every retry_interval until the holepuncher answers
+        let mut next_retry_at = Instant::now();
+        while Instant::now() < end_time {
+            if Instant::now() >= next_retry_at {
+                match sock.send_message(&request(), holepuncher) {
+                    Ok(()) => {
+                        next_retry_at = deadline_after(Instant::now(), config.retry_interval);
+                    },
+                    Err(e) if !e.is_fatal() => {
+                        // transient error, try again on the next iteration
+                    },
+                    Err(e) => {
+                        return Err(RuphinError::from(e));
+                    }
+                };
+            }
+            
+            let (response, source) = match sock.get_message() {
+                Ok(received) => received,
+                Err(e) if e.is_fatal() => {
+                    return Err(RuphinError::from(e));
+                },
+                Err(_) => {
+                    // nonfatal error, likely a timeout. Ignore and retry.
+                    continue;
+                }
+            };
+            if source != holepuncher {
+                // not from the holepuncher, ignore it
+                continue;
+            }
+            match response {
+                Message::QueryResult(contents) if contents.session_id == session_id => {
+                    return Ok(contents.metadata);
+                },
+                Message::SessionNotFound(contents) if contents.session_id == session_id => {
+                    return Err(RuphinError::SessionNotFound);
+                },
+                _ => {
+                    // some other message arrived, ignore it
+                    continue;
+                }
+            }
+        }
+        return Err(RuphinError::Timeout);
+    }
+    
+    // Joins the session at the holepunchers and performs the HelloReq/HelloResp handshake with the server.
+    // Returns the holepuncher which answered, the server's address and whether the server has to be reached through the relay.
+    // Each step is reported to progress.
+    fn handshake(sock: &ProtocolSocket, holepunchers: &[SocketAddr], session_id: &[u8], config: &PassiveClientConfig,
+        progress: &mut dyn FnMut(HandshakeProgress)) -> Result<(SocketAddr, SocketAddr, bool, Option<Duration>), RuphinError> {
+        // Timeout behaviour (defaults in parentheses):
+        // Up to handshake_timeout for the session (10 seconds)
+        // individual message timeout = message_timeout (500 ms)
+        // minimal inter-message time = retry_interval (400 ms), doubling for Joins up to max_retry_interval (3.2 seconds)
+        // Retry the HelloReq/HelloResp handshake up to hello_retries times (3)
+        let total_timeout = config.handshake_timeout;
+        let indiv_timeout = config.message_timeout;
+        let inter_message_time = config.retry_interval;
+        let num_hello_retries = config.hello_retries;
+        // retries and timeouts follow the socket's clock, which is only not real time for in-memory transports
+        let clock = sock.clock();
+        
+        // deadline after which the attempt to create a server is considered failed
+        let end_time = deadline_after(clock.now(), total_timeout);
+        // Set the protocol socket's message timeout (will be undone after the function returns)
+        sock.set_read_timeout(Some(indiv_timeout)).unwrap();
+        
+        // We will send a Join message to the holepuncher.
+        // We will then wait for either a PeerInfo or SessionNotFound message.
+        // If we got a PeerInfo message, we will try to send a HelloReq to that peer and expect a HelloResp back.
+        // Once that is done, we've established our session and we're done.
+        // construct the message for the holepuncher
+        let candidates = match holepunchers.first() {
+            Some(holepuncher) if config.gather_candidates => sock.local_candidates(*holepuncher),
+            _ => Vec::new(),
+        };
+        // every attempt gets a fresh MAC, since the holepuncher only accepts one for a while
+        let request = || Message::Join(JoinContents {
+            session_id: session_id.to_vec(),
+            candidates: candidates.clone(),
+            mac: config.session_mac(JOIN, session_id, &candidates),
+        });
+        // and the HelloReq for the server, identifying us by our handle
+        let hello = Message::HelloReq(HelloReqContents {
+            handle: config.handle.clone(),
+        });
+        
+        // send the request to every holepuncher initially
+        for holepuncher in holepunchers {
+            match sock.send_message(&request(), *holepuncher) {
+                Ok(()) => {},
+                Err(e) if !e.is_fatal() => {
+                    // transient error, the retry loop will send it again
+                },
+                Err(e) => {
+                    return Err(RuphinError::from(e));
+                }
+            };
+        }
+        let mut num_joins = 1;
+        progress(HandshakeProgress::SentJoin { attempt: num_joins });
+        // schedule the earliest time for the next attempt, backing off exponentially
+        let mut join_retry_gap = inter_message_time;
+        let mut next_retry_at = deadline_after(clock.now(), join_retry_gap);
+        // how far we got, reported if the handshake times out
+        let mut stage = HandshakeStage::WaitingForPeerInfo;
+        // holepunchers which don't know the session
+        let mut not_found_at = HashSet::new();
+        
+        // enter a retry loop
+        'join_loop: while clock.now() < end_time {
+            // retry the Join message at the holepunchers which might still know the session
+            if clock.now() > next_retry_at {
+                for holepuncher in holepunchers {
+                    if not_found_at.contains(holepuncher) {
+                        continue;
+                    }
+                    match sock.send_message(&request(), *holepuncher) {
+                        Ok(()) => {},
+                        Err(e) if !e.is_fatal() => {
+                            // transient error, try again on the next retry
+                        },
+                        Err(e) => {
+                            return Err(RuphinError::from(e));
+                        }
+                    };
+                }
+                num_joins += 1;
+                progress(HandshakeProgress::SentJoin { attempt: num_joins });
+                join_retry_gap = join_retry_gap.saturating_mul(2).min(config.max_retry_interval);
+                next_retry_at = deadline_after(clock.now(), join_retry_gap);
+            }
+            
+            // Wait for a response. This will either succeed, timeout, or fatally fail.
+            let (response, source) = match sock.get_message() {
+                Ok((ack, source)) => (ack, source),
+                Err(e) => {
+                    if e.is_fatal() {
+                        // fatal error, return
+                        return Err(RuphinError::from(e));
+                    } else {
+                        // nonfatal error, ignore and retry
+                        continue 'join_loop;
+                    }
+                },
+            };
+            
+            // check the response type
+            match response {
+                Message::PeerInfo(contents) => {
+                    // got the info of another peer
+                    if !holepunchers.contains(&source) && !config.lan_discovery {
+                        // message is not from a holepuncher, ignore it
+                        continue 'join_loop;
+                    }
+                    if contents.session_id != session_id {
+                        // the address of a peer in some other session, ignore it
+                        continue 'join_loop;
+                    }
+                    if !config.is_authentic_peer_info(&contents) {
+                        // forged by someone who saw our Join, ignore it
+                        continue 'join_loop;
+                    }
+                    let peer_addr = contents.peer_addr;
+                    // a dual-stack holepuncher may hand out an address of the other family
+                    let peer_addr = match sock.reachable_addr(peer_addr) {
+                        Some(addr) => addr,
+                        None => {
+                            sock.set_read_timeout(None).unwrap();
+                            return Err(RuphinError::AddressFamilyMismatch(peer_addr));
+                        }
+                    };
+                    // The server may also be reachable at its candidate addresses, e.g. if it's on our LAN.
+                    // HelloReqs go to all of them, and the first to answer becomes the server's address.
+                    let mut targets = vec![peer_addr];
+                    for candidate in &contents.candidates {
+                        if let Some(candidate) = sock.reachable_addr(*candidate) {
+                            if !targets.contains(&candidate) {
+                                targets.push(candidate);
+                            }
+                        }
+                    }
+                    // stick with the holepuncher which answered
+                    let holepuncher = source;
+                    stage = HandshakeStage::WaitingForHelloResp;
+                    progress(HandshakeProgress::GotPeerInfo {
+                        holepuncher,
+                        server: peer_addr,
+                    });
+                    
+                    // start trying the HelloReq/HelloResp handshake
+                    // repeatedly send a HelloReq to the other peer and await a HelloResp
+                    // count how many HelloReq's we've already sent
+                    let mut num_attempts = 0;
+                    match config.initial_hello_ttl {
+                        Some(ttl) => {
+                            // open our NAT with a HelloReq that won't reach the peer, not counted as an attempt
+                            Self::send_hellos(sock, &hello, &targets, Some(ttl))?;
+                        },
+                        None => {
+                            num_attempts += 1;
+                            if Self::send_hellos(sock, &hello, &targets, None)? {
+                                progress(HandshakeProgress::SentHelloReq { attempt: num_attempts });
+                            }
+                        }
+                    }
+                    // schedule the next time when we can retry a HelloReq
+                    // after a low-TTL HelloReq, follow up with a normal one right away
+                    let mut next_hello_retry_at = if num_attempts == 0 {
+                        clock.now()
+                    } else {
+                        deadline_after(clock.now(), inter_message_time)
+                    };
+                    
+                    // retry loop
+                    'hello_loop: while num_attempts < num_hello_retries && clock.now() < end_time {
+                        // is it time to retry the HelloReq?
+                        if clock.now() >= next_hello_retry_at {
+                            // on a transient error, try again on the next iteration
+                            if Self::send_hellos(sock, &hello, &targets, None)? {
+                                // reschedule the next hello retry and count up the attempts
+                                next_hello_retry_at = deadline_after(clock.now(), inter_message_time);
+                                num_attempts += 1;
+                                progress(HandshakeProgress::SentHelloReq { attempt: num_attempts });
+                            }
+                        }
+                        
+                        // wait for a message
+                        match sock.get_message() {
+                            Ok((Message::HelloResp(contents), source)) => {
+                                if !targets.contains(&source) {
+                                    // wrong source, ignore
+                                    continue 'hello_loop;
+                                }
+                                // a HelloResp arrived, we're done
+                                
+                                // remove the timeout on the socket
+                                sock.set_read_timeout(None).unwrap();
+                                progress(HandshakeProgress::Connected);
+                                return Ok((holepuncher, source, false, contents.keepalive_interval));
+                            },
+                            Ok(_) => {
+                                // some other message arrived, ignore it
+                                continue 'hello_loop;
+                            }
+                            Err(e) => {
+                                if e.is_fatal() {
+                                    // fatal error, return
+                                    return Err(RuphinError::from(e));
+                                } else {
+                                    // nonfatal error, likely a timeout. Ignore and retry.
+                                    continue 'hello_loop;
+                                }
+                            }
+                        };
+                    }
+                    // couldn't get a HelloResp
+                    if config.relay_fallback && !config.lan_discovery {
+                        // talk to the server through the holepuncher instead
+                        sock.set_read_timeout(None).unwrap();
+                        progress(HandshakeProgress::Relayed);
+                        return Ok((holepuncher, peer_addr, true, None));
+                    }
+                    // retry the join
+                    continue 'join_loop;
+                },
+                Message::SessionNotFound(contents) => {
+                    if contents.session_id != session_id || !holepunchers.contains(&source) {
+                        // wrong session ID or not from a holepuncher, ignore
+                        continue 'join_loop;
+                    }
+                    // session not found at this holepuncher, return once none of them know it
+                    not_found_at.insert(source);
+                    if holepunchers.iter().all(|holepuncher| not_found_at.contains(holepuncher)) {
+                        return Err(RuphinError::SessionNotFound);
+                    }
+                    continue 'join_loop;
+                },
+                _ => {
+                    // some other message arrived, ignore it and retry
+                    continue 'join_loop;
+                }
+            }
+        }
+        
+        // timeout, could not register session
+        return Err(RuphinError::HandshakeTimeout {
+            stage,
+        });
+    }
+    
+    // Sends the HelloReq to each of the server's addresses, with the given IP TTL if any.
+    // Returns true if it could be sent to at least one of them, false if all sends failed with transient errors.
+    fn send_hellos(sock: &ProtocolSocket, hello: &Message, targets: &[SocketAddr], ttl: Option<u32>) -> Result<bool, RuphinError> {
+        let mut sent = false;
+        for target in targets {
+            let result = match ttl {
+                Some(ttl) => sock.send_message_with_ttl(hello, *target, ttl),
+                None => sock.send_message(hello, *target),
+            };
+            match result {
+                Ok(()) => {
+                    sent = true;
+                },
+                Err(e) if !e.is_fatal() => {
+                    // transient error, e.g. an unroutable candidate
+                },
+                Err(e) => {
+                    return Err(RuphinError::from(e));
+                }
+            }
+        }
+        return Ok(sent);
+    }
+    
+    // Constructs a client which completed the handshake with the server.
+    fn connected(proto_socket: ProtocolSocket, holepuncher: SocketAddr, server: SocketAddr, session_id: Vec<u8>,
+        config: &PassiveClientConfig, relayed: bool) -> Self {
+        let clock = proto_socket.clock();
+        let now = clock.now();
+        Self {
+            proto_socket,
+            holepuncher,
+            server,
+            session_id,
+            relayed,
+            connected: config.connect_to_server && !relayed,
+            keepalive_interval: config.keepalive_interval,
+            server_keepalive_interval: None,
+            keepalive_jitter: config.keepalive_jitter,
+            next_keepalive_at: deadline_after(now, jittered(config.keepalive_interval, config.keepalive_jitter)),
+            server_timeout_keepalives: config.server_timeout_keepalives,
+            next_rejoin_at: deadline_after(now, config.rejoin_interval.unwrap_or_default()),
+            last_heard_from_server: now,
+            server_timed_out: false,
+            last_heard_from_holepuncher: now,
+            holepuncher_timed_out: false,
+            reassembler: Reassembler::new(FRAGMENT_TIMEOUT),
+            duplicate_filter: DuplicateFilter::new(),
+            next_message_id: 0,
+            // start from an arbitrary value so nonces differ between clients
+            next_nonce: match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+                Ok(d) => d.subsec_nanos(),
+                Err(_) => 0,
+            },
+            next_seq: 0,
+            reliable_timeout: config.reliable_timeout,
+            reliable_retry_interval: config.reliable_retry_interval,
+            unacked_reliable: 0,
+            keepalives_paused: false,
+            pending_events: VecDeque::new(),
+            #[cfg(feature = "encryption")]
+            cipher: config.encryption_key.as_ref().map(DataCipher::new),
+            observer: None,
+            unhandled_observer: None,
+            drop_observer: None,
+            clock,
+            config: config.clone(),
+        }
+    }
+    
+    // Queues received data to be returned by wait_for_event.
+    // Returns true if there is room for more, in which case the datagrams already waiting on the socket are picked up too.
+    fn queue_data(&mut self, event: Event) -> bool {
+        self.pending_events.push_back(event);
+        let queued = self.pending_events.iter().filter(|event| matches!(event, Event::Data(..))).count();
+        return queued < self.config.max_queued_data;
+    }
+    
+    // Sends a message to a peer. Messages to the server go through the holepuncher if the client is in relay mode.
+    fn send_to_peer(&self, msg: &Message, to: SocketAddr) -> Result<(), SendError> {
+        if self.connected && to == self.server {
+            return self.proto_socket.send_message_connected(msg);
+        }
+        if !self.relayed || to != self.server {
+            return self.proto_socket.send_message(msg, to);
+        }
+        let payload = match msg.serialize() {
+            Ok(bytes) => bytes,
+            Err(()) => {
+                return Err(SendError::serialization(msg));
+            }
+        };
+        let relayed = Message::RelayData(RelayDataContents {
+            session_id: self.session_id.clone(),
+            peer_addr: self.server,
+            payload,
+        });
+        return self.proto_socket.send_message(&relayed, self.holepuncher);
+    }
+    
+    // Unwraps a message relayed by the holepuncher, so that it can be handled as if the server had sent it directly.
+    // Other messages are returned as they are.
+    fn unwrap_relayed(&self, msg: Message, source: SocketAddr) -> (Message, SocketAddr) {
+        if let Message::RelayData(contents) = &msg {
+            if self.relayed && source == self.holepuncher && contents.session_id == self.session_id {
+                if let Ok(inner) = Message::deserialize(&contents.payload) {
+                    return (inner, contents.peer_addr);
+                }
+            }
+        }
+        return (msg, source);
+    }
+    
+    // Receives the next message, unwrapping relayed messages and noting when the server was last heard from.
+    // The observer, if any, is shown the message.
+    fn receive(&mut self) -> Result<(Message, SocketAddr), ReceiveError> {
+        let (msg, source) = match self.proto_socket.get_message_with_source() {
+            Ok((msg, source)) => {
+                if source == self.holepuncher {
+                    self.last_heard_from_holepuncher = self.clock.now();
+                    self.holepuncher_timed_out = false;
+                }
+                self.unwrap_relayed(msg, source)
+            },
+            Err((e, Some(source))) => {
+                // a datagram arrived, but it isn't a message
+                self.dropped(DropReason::Malformed, source);
+                return Err(e);
+            },
+            Err((e, None)) => {
+                return Err(e);
+            }
+        };
+        if source == self.server {
+            self.last_heard_from_server = self.clock.now();
+            self.server_timed_out = false;
+        }
+        if let Some(observer) = &mut self.observer {
+            observer(&msg, source);
+        }
+        return Ok((msg, source));
+    }
+    
+    /// Sets a callback which is invoked with every message received while waiting for data or measuring the RTT,
+    /// including control messages which are handled internally or ignored. Relayed messages are passed unwrapped.
+    pub fn set_observer<F>(&mut self, observer: F) where F: FnMut(&Message, SocketAddr) + Send + 'static {
+        self.observer = Some(Box::new(observer));
+    }
+    
+    /// Removes the callback set by set_observer.
+    pub fn clear_observer(&mut self) {
+        self.observer = None;
+    }
+    
+    /// Sets a callback which is invoked with every message wait_for_event ignores because it doesn't expect it,
+    /// e.g. to count or log unexpected traffic. Messages which fail to deserialize are not passed to it.
+    pub fn set_unhandled_observer<F>(&mut self, observer: F) where F: FnMut(&Message, SocketAddr) + Send + 'static {
+        self.unhandled_observer = Some(Box::new(observer));
+    }
+    
+    /// Removes the callback set by set_unhandled_observer.
+    pub fn clear_unhandled_observer(&mut self) {
+        self.unhandled_observer = None;
+    }
+    
+    /// Sets a callback which is invoked with the reason and source of every datagram the client drops,
+    /// including ones which fail to deserialize, e.g. to find out why a connection doesn't work.
+    /// With the "tracing" feature, drops are also logged as debug events.
+    pub fn set_drop_observer<F>(&mut self, observer: F) where F: FnMut(DropReason, SocketAddr) + Send + 'static {
+        self.drop_observer = Some(Box::new(observer));
+    }
+    
+    /// Removes the callback set by set_drop_observer.
+    pub fn clear_drop_observer(&mut self) {
+        self.drop_observer = None;
+    }
+    
+    /// Replaces the clock which keepalives and timeouts follow, e.g. with a clock::MockClock in tests.
+    /// The timeouts passed to the client's methods are still measured in real time. Timers which are already running
+    /// keep their deadlines, so the new clock should go on from the current time, as a new MockClock does.
+    pub fn set_clock<C>(&mut self, clock: C) where C: Clock + 'static {
+        self.clock = Arc::new(clock);
+    }
+    
+    // Reports a datagram from the given source which is dropped for the given reason.
+    fn dropped(&mut self, reason: DropReason, source: SocketAddr) {
+        trace_event!(debug, source = %source, reason = %reason, "client dropped datagram");
+        if let Some(observer) = &mut self.drop_observer {
+            observer(reason, source);
+        }
+    }
+    
+    // Returns true if datagrams from the given source are accepted: they must come from the server.
+    // Relayed datagrams are unwrapped by receive() and show the server as their source.
+    fn is_data_source(&self, source: SocketAddr) -> bool {
+        return source == self.server;
+    }
+    
+    // Returns true if the client talks to the server through the holepuncher, because hole punching failed.
+    pub fn is_relayed(&self) -> bool {
+        self.relayed
+    }
+    
+    // Sends a datagram through the protocol socket to the given target
+    // Datagrams larger than MAX_DATA_SIZE are split into fragments, which the receiver puts back together.
+    pub fn send_datagram(&mut self, to: SocketAddr, data: impl Into<Payload>) -> Result<(), RuphinError> {
+        let data: Payload = self.seal(data.into());
+        if data.len() <= MAX_DATA_SIZE {
+            let msg = Message::Data(DataContents {
+                data,
+                expires_at: self.config.data_ttl.map(DataContents::deadline_after),
+            });
+            
+            match self.send_to_peer(&msg, to) {
+                Ok(()) => {
+                    return Ok(());
+                },
+                Err(e) => {
+                    return Err(RuphinError::from(e));
+                }
+            }
+        }
+        
+        // too large for a single message, send it in fragments
+        let fragments = match fragment(&data, self.next_message_id, self.config.data_ttl.map(DataContents::deadline_after)) {
+            Ok(fragments) => fragments,
+            Err(()) => {
+                return Err(RuphinError::DatagramTooLarge {
+                    size: data.len(),
+                    max: MAX_FRAGMENTED_DATA_SIZE,
+                });
+            }
+        };
+        self.next_message_id = self.next_message_id.wrapping_add(1);
+        for msg in fragments {
+            match self.send_to_peer(&msg, to) {
+                Ok(()) => {},
+                Err(e) => {
+                    return Err(RuphinError::from(e));
+                }
+            }
+        }
+        return Ok(());
+    }
+    
+    /// Sets the IP TTL of packets sent from now on.
+    pub fn set_ttl(&self, ttl: u32) -> Result<(), RuphinError> {
+        match self.proto_socket.set_ttl(ttl) {
+            Ok(()) => {
+                return Ok(());
+            },
+            Err(e) => {
+                return Err(RuphinError::Io(e));
+            }
+        }
+    }
+    
+    /// Returns the IP TTL of sent packets.
+    pub fn ttl(&self) -> Result<u32, RuphinError> {
+        match self.proto_socket.ttl() {
+            Ok(ttl) => {
+                return Ok(ttl);
+            },
+            Err(e) => {
+                return Err(RuphinError::Io(e));
+            }
+        }
+    }
+    
+    // Encrypts an outgoing datagram, if encryption is enabled.
+    fn seal<P>(&self, data: P) -> P where P: AsRef<[u8]> + From<Vec<u8>> {
+        #[cfg(feature = "encryption")]
+        if let Some(cipher) = &self.cipher {
+            return P::from(cipher.encrypt(data.as_ref()));
+        }
+        return data;
+    }
+    
+    // Decrypts an incoming datagram, if encryption is enabled. A datagram which fails to decrypt
+    // was tampered with or encrypted under another key, it is reported as dropped and None is returned.
+    #[cfg(feature = "encryption")]
+    fn open(&mut self, data: Vec<u8>, source: SocketAddr) -> Option<Vec<u8>> {
+        let cipher = match &self.cipher {
+            Some(cipher) => cipher,
+            None => {
+                return Some(data);
+            }
+        };
+        match cipher.decrypt(&data) {
+            Some(plaintext) => {
+                return Some(plaintext);
+            },
+            None => {
+                self.dropped(DropReason::Undecryptable, source);
+                return None;
+            }
+        }
+    }
+    
+    #[cfg(not(feature = "encryption"))]
+    fn open(&mut self, data: Vec<u8>, _source: SocketAddr) -> Option<Vec<u8>> {
+        Some(data)
+    }
+    
+    /// Returns a snapshot of the socket's traffic counters.
+    pub fn stats(&self) -> SocketStats {
+        self.proto_socket.stats()
+    }
+    
+    /// Discards every datagram queued on the socket and every event not returned by wait_for_event yet,
+    /// e.g. to get rid of stale keepalives and Pongs after a long pause. Does not block.
+    /// Returns the number of datagrams discarded from the socket.
+    pub fn drain(&mut self) -> Result<usize, RuphinError> {
+        self.pending_events.clear();
+        match self.proto_socket.drain() {
+            Ok(discarded) => {
+                return Ok(discarded);
+            },
+            Err(e) => {
+                return Err(RuphinError::Io(e));
+            }
+        }
+    }
+    
+    /// Returns the local address the socket is bound to. The IP is unspecified (0.0.0.0) unless bound to a specific interface.
+    pub fn local_addr(&self) -> Result<SocketAddr, RuphinError> {
+        match self.proto_socket.local_addr() {
+            Ok(addr) => {
+                return Ok(addr);
+            },
+            Err(e) => {
+                return Err(RuphinError::Io(e));
+            }
+        }
+    }
+    
+    /// Returns a handle which other threads can use to interrupt wait_for_data and wait_for_event with allow_interrupt set.
+    /// If the client's socket is connected to the server (see connect_to_server), the OS drops the interrupts.
+    pub fn interrupt_handle(&self) -> Result<InterruptHandle, RuphinError> {
+        return Ok(InterruptHandle::new(self.local_addr()?));
+    }
+    
+    // Get the listening port of the socket.
+    // Returns Err if the local address cannot be obtained.
+    pub fn get_port(&self) -> Result<u16, ()> {
+        self.proto_socket.get_port()
+    }
+    
+    // Returns the IP address + port of the server this client is connected to
+    pub fn get_server(&self) -> SocketAddr {
+        self.server
+    }
+    
+    /// Returns the last time a message was received from the holepuncher, or the client was connected if none was since.
+    /// The holepuncher is only asked for answers if holepuncher_timeout is configured.
+    pub fn holepuncher_last_seen(&self) -> Instant {
+        self.last_heard_from_holepuncher
+    }
+    
+    // Returns the time at which the holepuncher is considered gone, if it is watched and hasn't been reported as gone yet.
+    fn holepuncher_deadline(&self) -> Option<Instant> {
+        match self.config.holepuncher_timeout {
+            Some(holepuncher_timeout) if !self.holepuncher_timed_out && !self.connected && !self.keepalives_paused => {
+                return Some(deadline_after(self.last_heard_from_holepuncher, holepuncher_timeout));
+            },
+            _ => {
+                return None;
+            }
+        }
+    }
+    
+    // Returns when the session is next joined again, if it is joined periodically.
+    fn rejoin_deadline(&self) -> Option<Instant> {
+        if self.config.rejoin_interval.is_some() && !self.connected && !self.keepalives_paused {
+            return Some(self.next_rejoin_at);
+        }
+        return None;
+    }
+    
+    // Sends the holepuncher a Join for our session, like the one of the handshake.
+    fn send_rejoin(&self) -> Result<(), SendError> {
+        let candidates = if self.config.gather_candidates {
+            self.proto_socket.local_candidates(self.holepuncher)
+        } else {
+            Vec::new()
+        };
+        let request = Message::Join(JoinContents {
+            session_id: self.session_id.clone(),
+            mac: self.config.session_mac(JOIN, &self.session_id, &candidates),
+            candidates,
+        });
+        return self.proto_socket.send_message(&request, self.holepuncher);
+    }
+    
+    // Sends a keepalive (HelloReq) to the server, and to the holepuncher if it is watched.
+    // Transient errors are ignored, the next keepalive is sent as scheduled.
+    fn send_keepalive(&mut self) -> Result<(), RuphinError> {
+        let msg = Message::HelloReq(HelloReqContents {
+            handle: self.config.handle.clone(),
+        });
+        let addr = self.server;
+        
+        match self.send_to_peer(&msg, addr) {
+            Ok(()) => {},
+            Err(e) if !e.is_fatal() => {
+                // transient error, skip this keepalive, the next one is sent as scheduled
+            },
+            Err(e) => {
+                return Err(RuphinError::from(e));
+            }
+        };
+        // if the holepuncher is watched, ask it for a HelloResp as well
+        if self.config.holepuncher_timeout.is_some() && !self.connected {
+            match self.proto_socket.send_message(&msg, self.holepuncher) {
+                Ok(()) => {},
+                Err(e) if !e.is_fatal() => {
+                    // transient error, the next keepalive asks again
+                },
+                Err(e) => {
+                    return Err(RuphinError::from(e));
+                }
+            };
+        }
+        return Ok(());
+    }
+    
+    /// Stops sending keepalives without tearing down the connection, e.g. while the application is in the background.
+    /// Nothing is sent to the server or the holepuncher until resume_keepalives is called, so the NAT mapping
+    /// may expire in the meantime. Since the server isn't expected to answer, it isn't reported as timed out while paused,
+    /// neither is the holepuncher, and periodic rejoins are suspended too. Received data is still handed out.
+    pub fn pause_keepalives(&mut self) {
+        self.keepalives_paused = true;
+    }
+    
+    /// Restarts the keepalives stopped by pause_keepalives. One keepalive is sent right away to refresh the NAT mapping,
+    /// and the server and holepuncher timeouts start over from now. Does nothing if keepalives aren't paused.
+    pub fn resume_keepalives(&mut self) -> Result<(), RuphinError> {
+        if !self.keepalives_paused {
+            return Ok(());
+        }
+        self.keepalives_paused = false;
+        let now = self.clock.now();
+        self.last_heard_from_server = now;
+        self.last_heard_from_holepuncher = now;
+        self.send_keepalive()?;
+        let now = self.clock.now();
+        self.next_keepalive_at = deadline_after(now, jittered(self.keepalive_interval(), self.keepalive_jitter));
+        self.next_rejoin_at = deadline_after(now, self.config.rejoin_interval.unwrap_or_default());
+        return Ok(());
+    }
+    
+    /// Returns whether keepalives are paused, see pause_keepalives.
+    pub fn keepalives_paused(&self) -> bool {
+        self.keepalives_paused
+    }
+    
+    // Returns the ID of the session this client joined
+    pub fn get_session_id(&self) -> &[u8] {
+        &self.session_id
+    }
+    
+    /// Changes the keepalive interval. The next keepalive is rescheduled to one interval from now.
+    /// Returns Err(RuphinError::InvalidConfig) if the interval is zero.
+    pub fn set_keepalive_interval(&mut self, interval: Duration) -> Result<(), RuphinError> {
+        if interval.is_zero() {
+            return Err(RuphinError::InvalidConfig("keepalive_interval must be non-zero"));
+        }
+        self.keepalive_interval = interval;
+        self.next_keepalive_at = deadline_after(self.clock.now(), jittered(self.keepalive_interval(), self.keepalive_jitter));
+        return Ok(());
+    }
+    
+    /// Returns the interval at which keepalives are sent: the configured one, or the one the server proposed if that is shorter.
+    pub fn keepalive_interval(&self) -> Duration {
+        match self.server_keepalive_interval {
+            Some(proposed) if proposed < self.keepalive_interval => proposed,
+            _ => self.keepalive_interval,
+        }
+    }
+    
+    // Takes note of the keepalive interval proposed in a HelloResp from the server, and reschedules the next keepalive
+    // if the interval in use changes. Servers which don't propose one leave the previous proposal in place.
+    fn adopt_keepalive_interval(&mut self, proposed: Option<Duration>) {
+        if proposed.is_none() || proposed == self.server_keepalive_interval {
+            return;
+        }
+        let before = self.keepalive_interval();
+        self.server_keepalive_interval = proposed;
+        if self.keepalive_interval() != before {
+            self.next_keepalive_at = deadline_after(self.clock.now(), jittered(self.keepalive_interval(), self.keepalive_jitter));
+        }
+    }
+    
+    /// Tells the server that this client is leaving the session.
+    pub fn disconnect(&mut self) -> Result<(), RuphinError> {
+        let msg = Message::Goodbye(GoodbyeContents {
+            session_id: self.session_id.clone(),
+        });
+        match self.send_to_peer(&msg, self.server) {
+            Ok(()) => {
+                return Ok(());
+            },
+            Err(e) => {
+                return Err(RuphinError::from(e));
+            }
+        }
+    }
+    
+    /// Joins the session again and repeats the handshake with its server, e.g. after the server timed out or restarted.
+    /// The session is looked up at the holepuncher which answered the first time, through the same socket,
+    /// so the client's NAT mapping is kept. Datagrams which arrive in the meantime are dropped.
+    /// Not possible if the socket is connected to the server (see connect_to_server), since it can't reach the holepuncher.
+    pub fn reconnect(&mut self) -> Result<(), RuphinError> {
+        if self.connected {
+            return Err(RuphinError::InvalidConfig("cannot reconnect a client whose socket is connected to the server"));
+        }
+        let result = Self::handshake(&self.proto_socket, &[self.holepuncher], &self.session_id, &self.config, &mut |_| {});
+        let (holepuncher, server, relayed, server_keepalive_interval) = match result {
+            Ok(x) => x,
+            Err(e) => {
+                self.proto_socket.set_read_timeout(None).unwrap();
+                return Err(e);
+            }
+        };
+        if self.config.connect_to_server && !relayed {
+            if let Err(e) = self.proto_socket.connect(server) {
+                return Err(RuphinError::Io(e));
+            }
+            self.connected = true;
+        }
+        self.holepuncher = holepuncher;
+        self.server = server;
+        self.relayed = relayed;
+        self.last_heard_from_server = self.clock.now();
+        self.server_timed_out = false;
+        self.last_heard_from_holepuncher = self.clock.now();
+        self.holepuncher_timed_out = false;
+        // the server may be a new one with other settings
+        self.server_keepalive_interval = None;
+        self.adopt_keepalive_interval(server_keepalive_interval);
+        self.next_keepalive_at = deadline_after(self.clock.now(), jittered(self.keepalive_interval(), self.keepalive_jitter));
+        self.next_rejoin_at = deadline_after(self.clock.now(), self.config.rejoin_interval.unwrap_or_default());
+        // fragments of the old connection will never be completed
+        self.reassembler = Reassembler::new(FRAGMENT_TIMEOUT);
+        // and a new server counts its reliable datagrams from scratch
+        self.duplicate_filter = DuplicateFilter::new();
+        self.unacked_reliable = 0;
+        return Ok(());
+    }
+    
+    // Handles a PeerInfo received after the handshake. It is ignored unless it comes from the holepuncher and is for our session.
+    // A PeerInfo with another address than the server's means the session migrated there, so the client follows it.
+    fn handle_peer_info(&mut self, contents: PeerInfoContents, source: SocketAddr) -> Result<(), RuphinError> {
+        if source != self.holepuncher {
+            self.dropped(DropReason::WrongSource, source);
+            return Ok(());
+        }
+        if contents.session_id != self.session_id {
+            self.dropped(DropReason::WrongSession, source);
+            return Ok(());
+        }
+        if !self.config.is_authentic_peer_info(&contents) {
+            self.dropped(DropReason::Unauthenticated, source);
+            return Ok(());
+        }
+        let peer_addr = match self.proto_socket.reachable_addr(contents.peer_addr) {
+            Some(peer_addr) => peer_addr,
+            None => {
+                // can't talk to the new server from this socket anyway
+                return Ok(());
+            }
+        };
+        if peer_addr != self.server {
+            self.server = peer_addr;
+            // give the new server a full timeout to come up
+            self.last_heard_from_server = self.clock.now();
+            self.server_timed_out = false;
+            // fragments from the old server will never be completed
+            self.reassembler = Reassembler::new(FRAGMENT_TIMEOUT);
+            self.duplicate_filter = DuplicateFilter::new();
+            self.unacked_reliable = 0;
+        }
+        // send a HelloReq to the peer, once.
+        match self.proto_socket.send_message(&Message::HelloReq(HelloReqContents {
+            handle: self.config.handle.clone(),
+        }), peer_addr) {
+            Ok(()) => {},
+            Err(e) if !e.is_fatal() => {
+                // transient error, the peer's own HelloReqs can still open the path
+            },
+            Err(e) => {
+                return Err(RuphinError::from(e));
+            }
+        };
+        return Ok(());
+    }
+    
+    // Acknowledges a reliable datagram from the server. Every copy is acknowledged, since the acknowledgement
+    // of an earlier one may have been lost, but only the first one is returned to be delivered.
+    fn accept_reliable(&mut self, contents: ReliableDataContents, source: SocketAddr) -> Result<Option<Vec<u8>>, RuphinError> {
+        let ack = Message::DataAck(DataAckContents {
+            seq: contents.seq,
+        });
+        match self.send_to_peer(&ack, source) {
+            Ok(()) => {},
+            Err(e) if !e.is_fatal() => {
+                // transient error, the server will retransmit
+            },
+            Err(e) => {
+                return Err(RuphinError::from(e));
+            }
+        };
+        if !self.duplicate_filter.insert(source, contents.seq) {
+            return Ok(None);
+        }
+        return Ok(self.open(contents.data, source));
+    }
+    
+    // Handles a message which arrived while the client was busy with something else, e.g. measuring the RTT.
+    // Peers are answered right away, datagrams are kept for the next wait_for_event, the session is followed if it migrates,
+    // and everything else is ignored.
+    fn handle_while_busy(&mut self, msg: Message, source: SocketAddr) -> Result<(), RuphinError> {
+        match msg {
+            Message::HelloReq(_) => {
+                // send the source a HelloResp
+                match self.send_to_peer(&Message::HelloResp(HelloRespContents::default()), source) {
+                    Ok(()) => {},
+                    Err(e) if !e.is_fatal() => {
+                        // transient error, the peer will ask again
+                    },
+                    Err(e) => {
+                        return Err(RuphinError::from(e));
+                    }
+                };
+            },
+            Message::Data(_) | Message::ReliableData(_) | Message::DataFragment(_) if !self.is_data_source(source) => {
+                // only the server sends us data
+                self.dropped(DropReason::UnknownPeer, source);
+            },
+            Message::Data(contents) if contents.is_expired() => {
+                // too late to be of use, drop it
+                self.dropped(DropReason::Expired, source);
+            },
+            Message::Data(contents) => {
+                // keep the data for the next wait_for_event
+                if let Some(data) = self.open(contents.into_vec(), source) {
+                    self.pending_events.push_back(Event::Data(source, data));
+                }
+            },
+            Message::ReliableData(contents) => {
+                if let Some(data) = self.accept_reliable(contents, source)? {
+                    self.pending_events.push_back(Event::Data(source, data));
+                }
+            },
+            Message::DataFragment(contents) if contents.is_expired() => {
+                self.dropped(DropReason::Expired, source);
+            },
+            Message::DataFragment(contents) => {
+                if let Some(data) = self.reassembler.insert(source, contents, self.clock.now()).and_then(|data| self.open(data, source)) {
+                    self.pending_events.push_back(Event::Data(source, data));
+                }
+            },
+            Message::PeerInfo(contents) => {
+                self.handle_peer_info(contents, source)?;
+            },
+            Message::HelloResp(contents) if source == self.server => {
+                self.adopt_keepalive_interval(contents.keepalive_interval);
+            },
+            Message::HelloResp(_) if source == self.holepuncher => {
+                // answer to a keepalive, already noted by receive()
+            },
+            _ => {
+                // another message was received, ignore it
+                self.dropped(DropReason::UnexpectedMessage, source);
+            }
+        }
+        return Ok(());
+    }
+    
+    /// Sends a datagram to the server and waits until the server acknowledges it, retransmitting it in the meantime.
+    /// The server delivers the datagram only once, however often it is retransmitted.
+    /// Returns Err(RuphinError::Timeout) if no acknowledgement arrives within reliable_timeout;
+    /// the server may or may not have received the datagram in that case.
+    /// Reliable datagrams are not fragmented, so they can be at most MAX_DATA_SIZE bytes large.
+    /// Datagrams received in the meantime are kept and returned by subsequent calls to wait_for_event or wait_for_data.
+    pub fn send_reliable(&mut self, data: Vec<u8>) -> Result<(), RuphinError> {
+        let data = self.seal(data);
+        if data.len() > MAX_DATA_SIZE {
+            return Err(RuphinError::DatagramTooLarge {
+                size: data.len(),
+                max: MAX_DATA_SIZE,
+            });
+        }
+        let seq = self.next_seq;
+        self.next_seq = self.next_seq.wrapping_add(1);
+        let msg = Message::ReliableData(ReliableDataContents {
+            seq,
+            data,
+        });
+        
+        let return_at = deadline_after(Instant::now(), self.reliable_timeout);
+        let mut next_retry_at = Instant::now();
+        loop {
+            let mut now = Instant::now();
+            if now >= return_at {
+                self.proto_socket.set_read_timeout(None).unwrap();
+                self.unacked_reliable += 1;
+                if Some(self.unacked_reliable) == self.config.max_unacked_reliable {
+                    // the server stopped acknowledging, it's most likely gone
+                    self.pending_events.push_back(Event::PeerLost(self.server));
+                }
+                return Err(RuphinError::Timeout);
+            }
+            
+            // (re)transmit the datagram
+            if now >= next_retry_at {
+                match self.send_to_peer(&msg, self.server) {
+                    Ok(()) => {},
+                    Err(e) if !e.is_fatal() => {
+                        // transient error, the next retransmission will try again
+                    },
+                    Err(e) => {
+                        return Err(RuphinError::from(e));
+                    }
+                };
+                now = Instant::now();
+                next_retry_at = deadline_after(now, self.reliable_retry_interval);
+            }
+            
+            // wait for the acknowledgement until the next retransmission is due
+            let next_wakeup = next_retry_at.min(return_at);
+            if next_wakeup <= now {
+                continue;
+            }
+            self.proto_socket.set_read_timeout(Some(next_wakeup - now)).unwrap();
+            
+            match self.receive() {
+                Ok((Message::DataAck(contents), source)) if source == self.server && contents.seq == seq => {
+                    self.proto_socket.set_read_timeout(None).unwrap();
+                    self.unacked_reliable = 0;
+                    return Ok(());
+                },
+                Ok((msg, source)) => {
+                    self.handle_while_busy(msg, source)?;
+                },
+                Err(e) => {
+                    if e.is_fatal() {
+                        // fatal error, return
+                        return Err(RuphinError::from(e));
+                    } else {
+                        // nonfatal error, likely a timeout. Ignore and retry.
+                        continue;
+                    }
+                }
+            }
+        }
+    }
+    
+    /// Measures the round-trip time to the server by sending it a Ping and waiting for the matching Pong.
+    /// Returns Err(RuphinError::Timeout) if no matching Pong arrives within the timeout.
+    /// Datagrams received in the meantime are kept and returned by subsequent calls to wait_for_event or wait_for_data.
+    pub fn measure_rtt(&mut self, timeout: Duration) -> Result<Duration, RuphinError> {
+        let nonce = self.next_nonce;
+        self.next_nonce = self.next_nonce.wrapping_add(1);
+        
+        let sent_at = Instant::now();
+        let return_at = deadline_after(sent_at, timeout);
+        match self.send_to_peer(&Message::Ping(PingContents { nonce }), self.server) {
+            Ok(()) => {},
+            Err(e) => {
+                return Err(RuphinError::from(e));
+            }
+        };
+        
+        loop {
+            let now = Instant::now();
+            if now >= return_at {
+                self.proto_socket.set_read_timeout(None).unwrap();
+                return Err(RuphinError::Timeout);
+            }
+            self.proto_socket.set_read_timeout(Some(return_at - now)).unwrap();
+            
+            let received = self.receive();
+            match received {
+                Ok((Message::Pong(contents), source)) => {
+                    // Pongs with a different nonce are answers to earlier probes, ignore them
+                    if source == self.server && contents.nonce == nonce {
+                        self.proto_socket.set_read_timeout(None).unwrap();
+                        return Ok(sent_at.elapsed());
+                    }
+                },
+                Ok((msg, source)) => {
+                    self.handle_while_busy(msg, source)?;
+                },
+                Err(e) => {
+                    if e.is_fatal() {
+                        // fatal error, return
+                        return Err(RuphinError::from(e));
+                    } else {
+                        // nonfatal error, likely a timeout. Ignore and retry.
+                        continue;
+                    }
+                }
+            }
+        }
+    }
+    
+    /// Sends a datagram to the server and waits for the next datagram from the server, e.g. its answer to a question.
+    /// Keepalives are handled in the meantime. Datagrams from anyone else and other events are kept and returned
+    /// by subsequent calls to wait_for_event or wait_for_data. Datagrams are unordered, so if several requests
+    /// are in flight, the answer may belong to an earlier one.
+    /// Returns Err(RuphinError::Timeout) if the server doesn't send anything within the timeout,
+    /// and Err(RuphinError::PeerTimedOut) if it stops responding to keepalives.
+    pub fn request(&mut self, data: Vec<u8>, timeout: Duration) -> Result<Vec<u8>, RuphinError> {
+        let server = self.server;
+        self.send_datagram(server, data)?;
+        
+        let return_at = deadline_after(Instant::now(), timeout);
+        // events which aren't the answer, handed back to pending_events in order once we're done
+        let mut kept = VecDeque::new();
+        let result = loop {
+            let remaining = return_at.saturating_duration_since(Instant::now());
+            match self.wait_for_event(Some(remaining), false) {
+                Ok(Event::Data(source, data)) if source == server => {
+                    break Ok(data);
+                },
+                Ok(Event::Timeout) => {
+                    break Err(RuphinError::Timeout);
+                },
+                Ok(Event::PeerTimedOut(source)) | Ok(Event::PeerLost(source)) if source == server => {
+                    break Err(RuphinError::PeerTimedOut);
+                },
+                Ok(event) => {
+                    kept.push_back(event);
+                },
+                Err(e) => {
+                    break Err(e);
+                }
+            }
+        };
+        // events queued up while waiting came in after the kept ones
+        kept.append(&mut self.pending_events);
+        self.pending_events = kept;
+        return result;
+    }
+    
+    /// Serve messages on the socket until you get a datagram from someone.
+    /// This method should be called regularly to ensure keepalives are sent, connection requests answered, etc.
+    /// If no data is received after a specified timeout, it returns Ok(None).
+    /// If a timeout of None is specified, this function will not return until it has data.
+    /// An exception to this is: If allow_interrupt is true, the function will return if it receives a LocalInterrupt message from localhost, again with Ok(None).
+    /// If the server stops responding to keepalives, or is lost (see max_unacked_reliable), it returns Err(RuphinError::PeerTimedOut).
+    /// Datagrams the server sent with send_reliable are acknowledged as they arrive.
+    /// Other events are dropped; use wait_for_event to see them.
+    pub fn wait_for_data(&mut self, timeout: Option<Duration>, allow_interrupt: bool) -> Result<Option<(SocketAddr, Vec<u8>)>, RuphinError> {
+        let return_at = timeout.map(|timeout| deadline_after(Instant::now(), timeout));
+        loop {
+            let remaining = return_at.map(|return_at| return_at.saturating_duration_since(Instant::now()));
+            match self.wait_for_event(remaining, allow_interrupt)? {
+                Event::Data(source, data) => {
+                    return Ok(Some((source, data)));
+                },
+                Event::Interrupted | Event::Timeout => {
+                    return Ok(None);
+                },
+                Event::PeerTimedOut(_) | Event::PeerLost(_) => {
+                    return Err(RuphinError::PeerTimedOut);
+                },
+                _ => {
+                    // not interested in other events
+                    continue;
+                }
+            }
+        }
+    }
+    
+    /// Serve messages on the socket until something happens: a datagram arrives, the server times out, etc.
+    /// This method should be called regularly to ensure keepalives are sent, connection requests answered, etc.
+    /// If nothing happens within the specified timeout, it returns Ok(Event::Timeout).
+    /// If a timeout of None is specified, this function will not return until there is an event.
+    /// A timeout too large to be added to the current time, such as Duration::MAX, is as good as None.
+    /// If allow_interrupt is true, the function returns Ok(Event::Interrupted) if it receives a LocalInterrupt message from localhost.
+    /// If nothing is heard from the server for server_timeout_keepalives keepalive intervals, it returns Ok(Event::PeerTimedOut) once.
+    /// Likewise, if holepuncher_timeout is configured and passes without hearing from the holepuncher, it returns Ok(Event::HolepuncherTimedOut) once.
+    pub fn wait_for_event(&mut self, timeout: Option<Duration>, allow_interrupt: bool) -> Result<Event, RuphinError> {
+        // this is the time when the function should return, in real time
+        let return_at = timeout.map(|timeout| deadline_after(Instant::now(), timeout));
+        // whether the last read found nothing to receive. Only then the function times out,
+        // so that even with a zero timeout, the messages already queued on the socket are handled.
+        let mut drained = false;
+        // whether received data is being queued up while more is waiting on the socket
+        let mut collecting_data = false;
+        
+        // await messages in a loop
+        loop {
+            // Represents the current time on the clock, which the keepalives and timeouts follow.
+            // Measured before instances of being used if there was a syscall or I/O operation since it was last measured.
+            let mut now = self.clock.now();
+            
+            // drop fragmented datagrams that will never be completed
+            self.reassembler.evict_expired(now);
+            
+            // hand out events which happened earlier first, once the data waiting on the socket has been picked up
+            if !collecting_data {
+                if let Some(event) = self.pending_events.pop_front() {
+                    self.proto_socket.set_read_timeout(None).unwrap();
+                    return Ok(event);
+                }
+            }
+            
+            // Is it time to send a keepalive?
+            if now > self.next_keepalive_at {
+                // while paused, the schedule keeps running, but nothing is sent
+                if !self.keepalives_paused {
+                    self.send_keepalive()?;
+                    // We did an I/O operation, so re-measure the current time.
+                    now = self.clock.now();
+                }
+                
+                // schedule the next keepalive
+                self.next_keepalive_at = deadline_after(now, jittered(self.keepalive_interval(), self.keepalive_jitter));
+            }
+            
+            // Is it time to join the session again?
+            let rejoin_deadline = self.rejoin_deadline();
+            if let Some(rejoin_deadline) = rejoin_deadline {
+                if now > rejoin_deadline {
+                    match self.send_rejoin() {
+                        Ok(()) => {},
+                        Err(e) if !e.is_fatal() => {
+                            // transient error, the next one will do
+                        },
+                        Err(e) => {
+                            return Err(RuphinError::from(e));
+                        }
+                    };
+                    now = self.clock.now();
+                    self.next_rejoin_at = deadline_after(now, self.config.rejoin_interval.unwrap_or_default());
+                }
+            }
+            let rejoin_deadline = self.rejoin_deadline();
+            
+            // Has the server gone silent?
+            let server_timeout = self.keepalive_interval().checked_mul(self.server_timeout_keepalives).unwrap_or(FOREVER);
+            let server_deadline = deadline_after(self.last_heard_from_server, server_timeout);
+            let server_watched = !self.server_timed_out && !self.keepalives_paused;
+            if server_watched && now > server_deadline {
+                self.server_timed_out = true;
+                self.proto_socket.set_read_timeout(None).unwrap();
+                return Ok(Event::PeerTimedOut(self.server));
+            }
+            
+            // Has the holepuncher gone silent?
+            let holepuncher_deadline = self.holepuncher_deadline();
+            if let Some(holepuncher_deadline) = holepuncher_deadline {
+                if now > holepuncher_deadline {
+                    self.holepuncher_timed_out = true;
+                    self.proto_socket.set_read_timeout(None).unwrap();
+                    return Ok(Event::HolepuncherTimedOut(self.holepuncher));
+                }
+            }
+            
+            // Is it time to return?
+            if let Some(return_at) = return_at {
+                if Instant::now() > return_at && drained {
+                    self.proto_socket.set_read_timeout(None).unwrap();
+                    return Ok(Event::Timeout);
+                }
+            }
+            
+            // determine the next wakeup time on the clock: when it's time for the next keepalive
+            let next_wakeup = self.next_keepalive_at;
+            // also wake up in time to notice the server going silent
+            let next_wakeup = if server_watched && server_deadline < next_wakeup {
+                server_deadline
+            } else {
+                next_wakeup
+            };
+            let next_wakeup = match holepuncher_deadline {
+                Some(holepuncher_deadline) if holepuncher_deadline < next_wakeup => holepuncher_deadline,
+                _ => next_wakeup,
+            };
+            let next_wakeup = match rejoin_deadline {
+                Some(rejoin_deadline) if rejoin_deadline < next_wakeup => rejoin_deadline,
+                _ => next_wakeup,
+            };
+            // Return before then if the timeout runs out first
+            let wait = match return_at {
+                Some(return_at) => next_wakeup.saturating_duration_since(now).min(return_at.saturating_duration_since(Instant::now())),
+                None => next_wakeup.saturating_duration_since(now),
+            };
+            
+            // determine how much time we give the socket to wait for messages
+            let socket_time = {
+                if wait.is_zero() || collecting_data {
+                    // no time, only pick up a message if one is queued
+                    None
+                } else {
+                    // roughly until the next wakeup
+                    Some(wait)
+                }
+            };
+            
+            // set the timeout on the socket
+            match socket_time {
+                Some(socket_time) => self.proto_socket.set_read_timeout(Some(socket_time)).unwrap(),
+                None => self.proto_socket.set_nonblocking(true).unwrap(),
+            };
+            
+            // await the next message
+            let received = self.receive();
+            if socket_time.is_none() {
+                self.proto_socket.set_nonblocking(false).unwrap();
+            }
+            drained = match &received {
+                Err(e) => e.is_timeout(),
+                Ok(_) => false,
+            };
+            if drained {
+                collecting_data = false;
+            }
+            match received {
+                Ok((Message::HelloReq(_), source)) => {
+                    // send the source a HelloResp
+                    match self.send_to_peer(&Message::HelloResp(HelloRespContents::default()), source) {
+                        Ok(()) => {},
+                        Err(e) if !e.is_fatal() => {
+                            // transient error, the peer will ask again
+                        },
+                        Err(e) => {
+                            return Err(RuphinError::from(e));
+                        }
+                    };
+                },
+                Ok((Message::HelloResp(contents), source)) if source == self.server => {
+                    // the server answered a keepalive, and may have changed its keepalive interval since
+                    self.adopt_keepalive_interval(contents.keepalive_interval);
+                },
+                Ok((Message::HelloResp(_), source)) if source == self.holepuncher => {
+                    // the holepuncher answered a keepalive, already noted by receive()
+                },
+                Ok((Message::PeerInfo(contents), source)) => {
+                    self.handle_peer_info(contents, source)?;
+                },
+                Ok((Message::SessionNotFound(contents), source)) if source == self.holepuncher && contents.session_id == self.session_id => {
+                    // answer to a periodic Join while the server hasn't registered with the holepuncher (again) yet,
+                    // a later Join will find it
+                },
+                Ok((Message::Data(_), source)) | Ok((Message::ReliableData(_), source)) | Ok((Message::DataFragment(_), source))
+                    if !self.is_data_source(source) => {
+                    // data from anyone but the server, e.g. a spoofed datagram, drop it
+                    self.dropped(DropReason::UnknownPeer, source);
+                },
+                Ok((Message::Data(contents), source)) if contents.is_expired() => {
+                    // the data sat in a queue or relay for too long and is of no use anymore, drop it
+                    self.dropped(DropReason::Expired, source);
+                },
+                Ok((Message::Data(contents), source)) => {
+                    // got some data, queue it up to be returned
+                    if let Some(data) = self.open(contents.into_vec(), source) {
+                        collecting_data = self.queue_data(Event::Data(source, data));
+                    }
+                },
+                Ok((Message::ReliableData(contents), source)) => {
+                    // got a datagram the server wants acknowledged, queue it up unless it's a retransmission
+                    if let Some(data) = self.accept_reliable(contents, source)? {
+                        collecting_data = self.queue_data(Event::Data(source, data));
+                    }
+                },
+                Ok((Message::DataFragment(contents), source)) if contents.is_expired() => {
+                    // a piece of a datagram which is of no use anymore, drop it
+                    self.dropped(DropReason::Expired, source);
+                },
+                Ok((Message::DataFragment(contents), source)) => {
+                    // got a piece of a larger datagram, return it once it's complete
+                    if let Some(data) = self.reassembler.insert(source, contents, self.clock.now()).and_then(|data| self.open(data, source)) {
+                        collecting_data = self.queue_data(Event::Data(source, data));
+                    }
+                },
+                Ok((Message::LocalInterrupt, source)) if allow_interrupt => {
+                    // received a local interrupt and interrupts are allowed
+                    // check that the source is localhost. If yes, return Ok(Event::Interrupted). Otherwise ignore.
+                    if source.ip().is_loopback() {
+                        self.proto_socket.set_read_timeout(None).unwrap();
+                        return Ok(Event::Interrupted);
+                    } else {
+                        self.dropped(DropReason::WrongSource, source);
+                        continue;
+                    }
+                },
+                Ok((msg, source)) => {
+                    // another message was received, ignore it
+                    self.dropped(DropReason::UnexpectedMessage, source);
+                    if let Some(observer) = &mut self.unhandled_observer {
+                        observer(&msg, source);
+                    }
+                    continue;
+                },
+                Err(e) => {
+                    if e.is_fatal() {
+                        // fatal error, return
+                        return Err(RuphinError::from(e));
+                    } else {
+                        // nonfatal error, likely a timeout. Ignore and retry.
+                        continue;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The socket can be registered with an external poller (epoll, kqueue, etc.) instead of blocking in wait_for_event.
+/// Once it is readable, call wait_for_event with a timeout of zero: it handles the queued messages without blocking,
+/// and returns Event::Timeout once there are none left. It still has to be called regularly to send keepalives.
+#[cfg(unix)]
+impl std::os::unix::io::AsRawFd for PassiveClient {
+    fn as_raw_fd(&self) -> std::os::unix::io::RawFd {
+        self.proto_socket.as_raw_fd()
+    }
+}
+
+/// See the AsRawFd implementation on Unix.
+#[cfg(windows)]
+impl std::os::windows::io::AsRawSocket for PassiveClient {
+    fn as_raw_socket(&self) -> std::os::windows::io::RawSocket {
+        self.proto_socket.as_raw_socket()
+    }
+}
@@ -1,191 +1,1595 @@
-use std::net::SocketAddr;
-use std::time::{
-    Duration,
-    Instant,
-};
-use std::collections::HashMap;
-use crate::messages::*;
-use crate::protocol_socket::*;
-
-/// Holepuncher's storage of sessions
-// TODO complete this!
-pub struct SessionStore {
-    storage: HashMap<Vec<u8>, SocketAddr>,
-}
-
-impl SessionStore {
-    pub fn new() -> Self {
-        Self {
-            storage: HashMap::new(),
-        }
-    }
-    
-    pub fn insert(&mut self, session_id: Vec<u8>, addr: SocketAddr) {
-        self.storage.insert(session_id, addr);
-    }
-    
-    pub fn get(&self, session_id: &Vec<u8>) -> Option<SocketAddr> {
-        match self.storage.get(session_id) {
-            None => None,
-            Some(sock_ref) => Some(*sock_ref),
-        }
-    }
-}
-
-/// a holepuncher helps connect servers and clients
-pub struct PassiveHolepuncher { 
-    /// Underlying socket
-    proto_socket: ProtocolSocket,
-    /// Storage structure for sessions
-    session_store: SessionStore,
-}
-
-impl PassiveHolepuncher {
-    pub fn new(listen_addr: &str) -> Result<Self, String> {
-        // bind a protocol socket
-        let proto_socket = match ProtocolSocket::bind(listen_addr) {
-            Ok(sock) => sock,
-            Err(e) => {
-                return Err(format!("Bind error: {:?}", e));
-            }
-        };
-        
-        // holepuncher is ready
-        return Ok(Self {
-            proto_socket,
-            session_store: SessionStore::new(),
-        });
-    }
-    
-    // Get the listening port of the socket.
-    // Returns Err if the local address cannot be obtained.
-    pub fn get_port(&self) -> Result<u16, ()> {
-        self.proto_socket.get_port()
-    }
-    
-    /// Serve as a holepuncher on the socket.
-    /// If time = Some(x), the method returns after a duration of x.
-    /// The method also returns upon receiving a LocalInterrupt from localhost, if allow_interrupt is true.
-    /// Returns Ok(()) normally, or Err(description) if some error occurred.
-    pub fn serve(&mut self, time: Option<Duration>, allow_interrupt: bool) -> Result<(), String> {
-        // Represents the current time.
-        // Measured before instances of being used if there was a syscall or I/O operation since it was last measured.
-        let mut now = Instant::now();
-        
-        // this is the time when the function should return
-        let return_at = match time {
-            None => None,
-            Some(time) => Some(now + time),
-        };
-        
-        // await messages in a loop
-        loop {
-            // Re-measure the time since there might've been an I/O operation before that.
-            now = Instant::now();
-            
-            // determine how long the socket should wait
-            let socket_time = if let Some(return_at) = return_at {
-                // check if we should actually return now
-                if now >= return_at {
-                    self.proto_socket.set_read_timeout(None).unwrap();
-                    return Ok(());
-                }
-                // otherwise, the socket should wait for return_at - now at most
-                Some(return_at - now)
-            } else {
-                // no return time is specified, so the socket will wait indefinitely.
-                None
-            };
-            
-            // set the timeout on the socket
-            self.proto_socket.set_read_timeout(socket_time).unwrap();
-            
-            // await the next message
-            match self.proto_socket.get_message() {
-                Ok((Message::HelloReq, source)) => {
-                    // send the source a HelloResp
-                    match self.proto_socket.send_message(&Message::HelloResp, source) {
-                        Ok(()) => {},
-                        Err(e) => {
-                            return Err(format!("Message send error: {:?}", e));
-                        }
-                    };
-                },
-                Ok((Message::LocalInterrupt, source)) if allow_interrupt => {
-                    // received a local interrupt and interrupts are allowed
-                    // check that the source is localhost. If yes, return Ok(None). Otherwise ignore.
-                    if source.ip().is_loopback() {
-                        self.proto_socket.set_read_timeout(None).unwrap();
-                        return Ok(());
-                    } else {
-                        continue;
-                    }
-                },
-                Ok((Message::Register(contents), source)) => {
-                    // add a session to the list of sessions
-                    self.session_store.insert(contents.session_id.clone(), source);
-                    // respond with a RegisterAck
-                    let response = Message::RegisterAck(RegisterAckContents {
-                        session_id: contents.session_id,
-                    });
-                    match self.proto_socket.send_message(&response, source) {
-                        Ok(()) => {},
-                        Err(e) => {
-                            return Err(format!("Message send error: {:?}", e));
-                        }
-                    };
-                },
-                Ok((Message::Join(contents), source)) => {
-                    if let Some(server) = self.session_store.get(&contents.session_id) {
-                        // session found, send the requester the address of the session initiator
-                        let response = Message::PeerInfo(PeerInfoContents {
-                            peer_addr: server,
-                        });
-                        match self.proto_socket.send_message(&response, source) {
-                            Ok(()) => {},
-                            Err(e) => {
-                                return Err(format!("Message send error: {:?}", e));
-                            }
-                        };
-                        
-                        // also send the session initiator the address of the client
-                        let response = Message::PeerInfo(PeerInfoContents {
-                            peer_addr: source,
-                        });
-                        match self.proto_socket.send_message(&response, server) {
-                            Ok(()) => {},
-                            Err(e) => {
-                                return Err(format!("Message send error: {:?}", e));
-                            }
-                        };
-                    } else {
-                        // send the source a SessionNotFound error
-                        // respond with a RegisterAck
-                        let response = Message::SessionNotFound(SessionNotFoundContents {
-                            session_id: contents.session_id,
-                        });
-                        match self.proto_socket.send_message(&response, source) {
-                            Ok(()) => {},
-                            Err(e) => {
-                                return Err(format!("Message send error: {:?}", e));
-                            }
-                        };
-                    }
-                },
-                Ok(_) => {
-                    // another message was received, ignore it
-                    continue;
-                },
-                Err(e) => {
-                    if e.is_fatal() {
-                        // fatal error, return
-                        return Err(format!("Fatal receive error: {:?}", e));
-                    } else {
-                        // nonfatal error, likely a timeout. Ignore and retry.
-                        continue;
-                    }
-                }
-            }
-        }
-    }
-}
\ No newline at end of file
+use std::net::{
+    SocketAddr,
+    IpAddr,
+    ToSocketAddrs,
+};
+use std::time::{
+    Duration,
+    Instant,
+};
+use std::collections::{
+    BTreeMap,
+    HashMap,
+    HashSet,
+};
+use std::sync::{
+    Arc,
+    Mutex,
+    RwLock,
+};
+use std::sync::atomic::{
+    AtomicBool,
+    Ordering,
+};
+use crate::messages::*;
+use crate::protocol_socket::*;
+use crate::error::RuphinError;
+use crate::clock::{
+    Clock,
+    SystemClock,
+    deadline_after,
+};
+use crate::event::{
+    InterruptHandle,
+    MessageObserver,
+    DropReason,
+    DropObserver,
+};
+
+/// How often the holepuncher checks for expired sessions
+const EVICTION_INTERVAL: Duration = Duration::from_secs(1);
+/// How often the holepuncher checks its probe socket, if it has one
+const PROBE_POLL_INTERVAL: Duration = Duration::from_millis(20);
+/// Longest a holepuncher waits on its socket at once while clones serve it too. The read timeout belongs to the socket,
+/// so another thread may have set a longer one than this one's deadlines allow by the time it waits.
+const SHARED_POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// Number of joined clients remembered per session, to relay their messages and to tell them about it when the session migrates
+const MAX_REMEMBERED_CLIENTS: usize = 1024;
+
+/// Limit on the number of Register/Join messages accepted from a single IP address
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    /// Number of messages allowed per `per`. Also the size of the burst allowed after a quiet period.
+    pub max_requests: u32,
+    /// Time window over which max_requests are allowed
+    pub per: Duration,
+}
+
+/// Token bucket of a single source IP
+struct TokenBucket {
+    tokens: f64,
+    last_refill_at: Instant,
+}
+
+/// Per-source-IP token bucket rate limiter
+struct RateLimiter {
+    limit: RateLimit,
+    buckets: HashMap<IpAddr, TokenBucket>,
+}
+
+impl RateLimiter {
+    fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            buckets: HashMap::new(),
+        }
+    }
+    
+    // Number of tokens a bucket regains per second
+    fn refill_rate(&self) -> f64 {
+        f64::from(self.limit.max_requests) / self.limit.per.as_secs_f64()
+    }
+    
+    // Takes a token from the source's bucket. Returns false if the bucket is empty.
+    fn allow(&mut self, source: IpAddr) -> bool {
+        let capacity = f64::from(self.limit.max_requests);
+        let refill_rate = self.refill_rate();
+        let now = Instant::now();
+        let bucket = self.buckets.entry(source).or_insert(TokenBucket {
+            tokens: capacity,
+            last_refill_at: now,
+        });
+        
+        // refill the bucket for the time that passed since the last request
+        let elapsed = now.duration_since(bucket.last_refill_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * refill_rate).min(capacity);
+        bucket.last_refill_at = now;
+        
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return true;
+        }
+        return false;
+    }
+    
+    // Forgets sources whose buckets would be full again, they are indistinguishable from new sources.
+    fn prune(&mut self) {
+        let capacity = f64::from(self.limit.max_requests);
+        let refill_rate = self.refill_rate();
+        self.buckets.retain(|_, bucket| {
+            bucket.tokens + bucket.last_refill_at.elapsed().as_secs_f64() * refill_rate < capacity
+        });
+    }
+}
+
+/// Tunable parameters of a PassiveHolepuncher
+#[derive(Debug, Clone)]
+pub struct PassiveHolepuncherConfig {
+    /// Sessions which are not refreshed by a Register within this time are dropped. Default is 30 seconds.
+    pub session_ttl: Duration,
+    /// Maximum number of sessions. Registrations of new sessions beyond it are rejected,
+    /// refreshing existing sessions still works. Default is None (unlimited).
+    pub max_sessions: Option<usize>,
+    /// Approximate memory the sessions may take, in bytes. Beyond it, the sessions refreshed least recently are dropped
+    /// to make room (see InMemorySessionStore::set_max_bytes). Only applies to the default session store, limit a store
+    /// passed to with_session_store yourself. Default is None (unlimited).
+    pub max_store_bytes: Option<usize>,
+    /// Register and Join messages from a source IP beyond this rate are silently dropped.
+    /// Default is None (unlimited).
+    pub rate_limit: Option<RateLimit>,
+    /// Whether to relay messages between the peers of a session when they cannot reach each other directly.
+    /// Only messages between the server and clients which joined its session are relayed, and at most
+    /// MAX_REMEMBERED_CLIENTS clients per session are remembered. Relaying costs the holepuncher bandwidth. Default is false.
+    pub relay_enabled: bool,
+    /// Pre-shared key which Register, Join and Query messages must be authenticated with.
+    /// Messages without a valid MAC are dropped, as are messages whose MAC was made more than auth::MAX_MAC_AGE ago
+    /// or was already seen from another source, so that captured messages can't be replayed.
+    /// PeerInfos are authenticated with it too, so that peers can tell them from forged ones.
+    /// Default is None (no authentication).
+    #[cfg(feature = "auth")]
+    pub auth_key: Option<Vec<u8>>,
+    /// Second address to listen on, from which peers can learn how their NAT treats traffic from another port
+    /// (see diagnostics::detect_nat_type). Only AddrReqs are answered there. Default is None.
+    pub probe_addr: Option<SocketAddr>,
+    /// Size of the socket's OS receive buffer, so that bursts of requests aren't dropped under load.
+    /// The OS may clamp it, e.g. Linux to net.core.rmem_max. Must be non-zero. Default is None (the OS default).
+    pub recv_buffer_size: Option<usize>,
+    /// Size of the socket's OS send buffer. The OS may clamp it, e.g. Linux to net.core.wmem_max.
+    /// Must be non-zero. Default is None (the OS default).
+    pub send_buffer_size: Option<usize>,
+}
+
+impl Default for PassiveHolepuncherConfig {
+    fn default() -> Self {
+        Self {
+            session_ttl: Duration::from_secs(30),
+            max_sessions: None,
+            max_store_bytes: None,
+            rate_limit: None,
+            relay_enabled: false,
+            #[cfg(feature = "auth")]
+            auth_key: None,
+            probe_addr: None,
+            recv_buffer_size: None,
+            send_buffer_size: None,
+        }
+    }
+}
+
+impl PassiveHolepuncherConfig {
+    // Checks that the configured values make sense.
+    fn validate(&self) -> Result<(), RuphinError> {
+        if let Some(limit) = self.rate_limit {
+            if limit.max_requests == 0 || limit.per.is_zero() {
+                return Err(RuphinError::InvalidConfig("rate_limit must allow at least one request per non-zero duration"));
+            }
+        }
+        if self.recv_buffer_size == Some(0) {
+            return Err(RuphinError::InvalidConfig("recv_buffer_size must be non-zero"));
+        }
+        if self.send_buffer_size == Some(0) {
+            return Err(RuphinError::InvalidConfig("send_buffer_size must be non-zero"));
+        }
+        return Ok(());
+    }
+}
+
+/// Holepuncher's storage of sessions.
+/// Implement this to back the holepuncher with your own storage, e.g. one shared between several holepunchers.
+pub trait SessionStore {
+    /// Inserts a session, or refreshes it if it already exists.
+    fn insert(&mut self, session_id: &[u8], addr: SocketAddr);
+    /// Returns the address the session was registered from, if it exists.
+    fn get(&self, session_id: &[u8]) -> Option<SocketAddr>;
+    /// Removes a session, returning the address it was registered from (if it existed).
+    fn remove(&mut self, session_id: &[u8]) -> Option<SocketAddr>;
+    /// Drops all sessions which have not been refreshed within the given TTL.
+    fn evict_expired(&mut self, ttl: Duration);
+    /// Returns the number of stored sessions.
+    fn len(&self) -> usize;
+    /// Returns the ID of every stored session and the address it was registered from.
+    fn sessions(&self) -> Vec<(Vec<u8>, SocketAddr)>;
+    /// Returns true if no sessions are stored.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Replaces the metadata of an existing session. Does nothing if the session doesn't exist.
+    /// Stores which don't keep metadata can leave this out, Queries are then answered with empty metadata.
+    fn set_metadata(&mut self, _session_id: &[u8], _metadata: &[u8]) {}
+    /// Returns the metadata of a session, if it exists and has any.
+    fn get_metadata(&self, _session_id: &[u8]) -> Option<Vec<u8>> {
+        None
+    }
+    /// Replaces the candidate addresses of an existing session. Does nothing if the session doesn't exist.
+    /// Stores which don't keep candidates can leave this out, clients then only learn the registered address.
+    fn set_candidates(&mut self, _session_id: &[u8], _candidates: &[SocketAddr]) {}
+    /// Returns the candidate addresses of a session, or an empty list if it doesn't exist or has none.
+    fn get_candidates(&self, _session_id: &[u8]) -> Vec<SocketAddr> {
+        Vec::new()
+    }
+    /// Returns the approximate memory taken by the stored sessions, in bytes.
+    /// Stores which don't keep track of it can leave this out, it then returns 0.
+    fn approx_bytes(&self) -> usize {
+        0
+    }
+    /// Registers a session from the given address, with its metadata and candidates, unless another address owns it
+    /// or it is new and the store already holds max_sessions sessions. Refreshes the session if the address owns it.
+    /// The holepuncher registers sessions only through this. The default implementation checks and stores in separate calls;
+    /// stores shared between threads do both at once, so that two Registers handled at the same time can't both claim
+    /// the same new session or the last free slot.
+    fn try_register(&mut self, session_id: &[u8], addr: SocketAddr, metadata: &[u8], candidates: &[SocketAddr],
+        max_sessions: Option<usize>) -> Registration {
+        let is_new = match self.get(session_id) {
+            Some(owner) if owner != addr => {
+                return Registration::Taken;
+            },
+            Some(_) => false,
+            None => true,
+        };
+        if is_new && max_sessions.is_some_and(|max_sessions| self.len() >= max_sessions) {
+            return Registration::Full;
+        }
+        self.insert(session_id, addr);
+        self.set_metadata(session_id, metadata);
+        self.set_candidates(session_id, candidates);
+        return Registration::Accepted { new: is_new };
+    }
+}
+
+/// Outcome of SessionStore::try_register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Registration {
+    /// The session was created, or refreshed by the address which owns it
+    Accepted { new: bool },
+    /// The session is owned by another address
+    Taken,
+    /// The session is new, but the store holds max_sessions sessions already
+    Full,
+}
+
+// Bookkeeping counted towards every session's footprint besides its contents: the stored entry, the key's Vec,
+// and the entry in the refresh order
+const SESSION_OVERHEAD: usize = std::mem::size_of::<StoredSession>() + std::mem::size_of::<Vec<u8>>()
+    + std::mem::size_of::<(u64, Vec<u8>)>();
+
+// A session kept by InMemorySessionStore
+struct StoredSession {
+    /// Address of the server
+    addr: SocketAddr,
+    /// Last time the session was registered or refreshed
+    last_seen: Instant,
+    /// Order of the last registration or refresh among all sessions, to find the least recently refreshed one
+    last_refresh: u64,
+    /// Metadata the server registered the session with
+    metadata: Vec<u8>,
+    /// Candidate addresses of the server
+    candidates: Vec<SocketAddr>,
+}
+
+impl StoredSession {
+    // Approximate memory taken by the session, including its ID, which is kept twice.
+    fn approx_bytes(&self, session_id: &[u8]) -> usize {
+        SESSION_OVERHEAD + 2 * session_id.len() + self.metadata.len() + self.candidates.len() * std::mem::size_of::<SocketAddr>()
+    }
+}
+
+/// Default session storage, keeping the sessions in memory
+// TODO complete this!
+pub struct InMemorySessionStore {
+    /// The stored sessions, by ID
+    storage: HashMap<Vec<u8>, StoredSession>,
+    /// Source of the current time for aging sessions
+    clock: Arc<dyn Clock>,
+    /// Number of registrations and refreshes so far, ordering them
+    refreshes: u64,
+    /// IDs of the sessions by their last_refresh, least recently refreshed first
+    by_refresh: BTreeMap<u64, Vec<u8>>,
+    /// Approximate memory taken by all sessions
+    bytes: usize,
+    /// Memory the sessions may take before the least recently refreshed ones are dropped, if limited
+    max_bytes: Option<usize>,
+}
+
+impl InMemorySessionStore {
+    pub fn new() -> Self {
+        Self::with_clock(SystemClock)
+    }
+
+    /// Creates a store whose sessions age by the given clock instead of real time, e.g. a clock::MockClock in tests.
+    pub fn with_clock<C>(clock: C) -> Self where C: Clock + 'static {
+        Self {
+            storage: HashMap::new(),
+            clock: Arc::new(clock),
+            refreshes: 0,
+            by_refresh: BTreeMap::new(),
+            bytes: 0,
+            max_bytes: None,
+        }
+    }
+
+    /// Limits the approximate memory taken by the sessions (see SessionStore::approx_bytes). Whenever a session is
+    /// registered or updated beyond it, the sessions refreshed least recently are dropped until the rest fit.
+    /// The session being registered or updated is never dropped, even if it doesn't fit on its own. None removes the limit.
+    pub fn set_max_bytes(&mut self, max_bytes: Option<usize>) {
+        self.max_bytes = max_bytes;
+        self.enforce_max_bytes(None);
+    }
+
+    /// Like get, but borrows the address instead of copying it.
+    pub fn get_ref(&self, session_id: &[u8]) -> Option<&SocketAddr> {
+        match self.storage.get(session_id) {
+            None => None,
+            Some(session) => Some(&session.addr),
+        }
+    }
+
+    // Drops the sessions refreshed least recently, except for the given one, until the rest fit into max_bytes.
+    fn enforce_max_bytes(&mut self, keep: Option<&[u8]>) {
+        let max_bytes = match self.max_bytes {
+            Some(max_bytes) => max_bytes,
+            None => {
+                return;
+            }
+        };
+        while self.bytes > max_bytes {
+            // the kept session is skipped at most once, so this takes a couple of steps rather than a scan
+            let oldest = self.by_refresh.values()
+                .find(|session_id| Some(session_id.as_slice()) != keep)
+                .cloned();
+            match oldest {
+                Some(session_id) => {
+                    self.remove(&session_id);
+                },
+                None => {
+                    // only the kept session is left
+                    return;
+                }
+            }
+        }
+    }
+}
+
+impl Default for InMemorySessionStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SessionStore for InMemorySessionStore {
+    fn insert(&mut self, session_id: &[u8], addr: SocketAddr) {
+        self.refreshes += 1;
+        // refreshing an existing session (the common case, keepalives) doesn't need to copy the ID
+        match self.storage.get_mut(session_id) {
+            Some(session) => {
+                session.addr = addr;
+                session.last_seen = self.clock.now();
+                // move the ID to the end of the refresh order
+                if let Some(key) = self.by_refresh.remove(&session.last_refresh) {
+                    self.by_refresh.insert(self.refreshes, key);
+                }
+                session.last_refresh = self.refreshes;
+            },
+            None => {
+                let session = StoredSession {
+                    addr,
+                    last_seen: self.clock.now(),
+                    last_refresh: self.refreshes,
+                    metadata: Vec::new(),
+                    candidates: Vec::new(),
+                };
+                self.bytes += session.approx_bytes(session_id);
+                self.by_refresh.insert(self.refreshes, session_id.to_vec());
+                self.storage.insert(session_id.to_vec(), session);
+                self.enforce_max_bytes(Some(session_id));
+            },
+        }
+    }
+    
+    fn get(&self, session_id: &[u8]) -> Option<SocketAddr> {
+        self.storage.get(session_id).map(|session| session.addr)
+    }
+    
+    fn remove(&mut self, session_id: &[u8]) -> Option<SocketAddr> {
+        match self.storage.remove(session_id) {
+            None => None,
+            Some(session) => {
+                self.bytes -= session.approx_bytes(session_id);
+                self.by_refresh.remove(&session.last_refresh);
+                Some(session.addr)
+            },
+        }
+    }
+    
+    fn evict_expired(&mut self, ttl: Duration) {
+        let now = self.clock.now();
+        let bytes = &mut self.bytes;
+        let by_refresh = &mut self.by_refresh;
+        self.storage.retain(|session_id, session| {
+            let keep = now.saturating_duration_since(session.last_seen) <= ttl;
+            if !keep {
+                *bytes -= session.approx_bytes(session_id);
+                by_refresh.remove(&session.last_refresh);
+            }
+            keep
+        });
+    }
+    
+    fn len(&self) -> usize {
+        self.storage.len()
+    }
+    
+    fn sessions(&self) -> Vec<(Vec<u8>, SocketAddr)> {
+        self.storage.iter()
+            .map(|(session_id, session)| (session_id.clone(), session.addr))
+            .collect()
+    }
+    
+    fn set_metadata(&mut self, session_id: &[u8], metadata: &[u8]) {
+        // keepalives usually carry the same metadata again, don't reallocate for them
+        if let Some(session) = self.storage.get_mut(session_id) {
+            if session.metadata != metadata {
+                self.bytes = self.bytes - session.metadata.len() + metadata.len();
+                session.metadata = metadata.to_vec();
+                self.enforce_max_bytes(Some(session_id));
+            }
+        }
+    }
+    
+    fn get_metadata(&self, session_id: &[u8]) -> Option<Vec<u8>> {
+        match self.storage.get(session_id) {
+            Some(session) if !session.metadata.is_empty() => Some(session.metadata.clone()),
+            _ => None,
+        }
+    }
+    
+    fn set_candidates(&mut self, session_id: &[u8], candidates: &[SocketAddr]) {
+        if let Some(session) = self.storage.get_mut(session_id) {
+            if session.candidates != candidates {
+                self.bytes = self.bytes - std::mem::size_of_val(session.candidates.as_slice()) + std::mem::size_of_val(candidates);
+                session.candidates = candidates.to_vec();
+                self.enforce_max_bytes(Some(session_id));
+            }
+        }
+    }
+    
+    fn get_candidates(&self, session_id: &[u8]) -> Vec<SocketAddr> {
+        match self.storage.get(session_id) {
+            Some(session) => session.candidates.clone(),
+            None => Vec::new(),
+        }
+    }
+    
+    fn approx_bytes(&self) -> usize {
+        self.bytes
+    }
+}
+
+/// A store shared between threads, e.g. to list the sessions of a holepuncher while another thread runs serve().
+/// Keep a clone of the Arc and pass the other one to PassiveHolepuncher::with_session_store.
+impl<S: SessionStore> SessionStore for Arc<Mutex<S>> {
+    fn insert(&mut self, session_id: &[u8], addr: SocketAddr) {
+        self.lock().unwrap().insert(session_id, addr)
+    }
+    
+    fn get(&self, session_id: &[u8]) -> Option<SocketAddr> {
+        self.lock().unwrap().get(session_id)
+    }
+    
+    fn remove(&mut self, session_id: &[u8]) -> Option<SocketAddr> {
+        self.lock().unwrap().remove(session_id)
+    }
+    
+    fn evict_expired(&mut self, ttl: Duration) {
+        self.lock().unwrap().evict_expired(ttl)
+    }
+    
+    fn len(&self) -> usize {
+        self.lock().unwrap().len()
+    }
+    
+    fn sessions(&self) -> Vec<(Vec<u8>, SocketAddr)> {
+        self.lock().unwrap().sessions()
+    }
+    
+    fn set_metadata(&mut self, session_id: &[u8], metadata: &[u8]) {
+        self.lock().unwrap().set_metadata(session_id, metadata)
+    }
+    
+    fn get_metadata(&self, session_id: &[u8]) -> Option<Vec<u8>> {
+        self.lock().unwrap().get_metadata(session_id)
+    }
+    
+    fn set_candidates(&mut self, session_id: &[u8], candidates: &[SocketAddr]) {
+        self.lock().unwrap().set_candidates(session_id, candidates)
+    }
+    
+    fn get_candidates(&self, session_id: &[u8]) -> Vec<SocketAddr> {
+        self.lock().unwrap().get_candidates(session_id)
+    }
+    
+    fn approx_bytes(&self) -> usize {
+        self.lock().unwrap().approx_bytes()
+    }
+    
+    fn try_register(&mut self, session_id: &[u8], addr: SocketAddr, metadata: &[u8], candidates: &[SocketAddr],
+        max_sessions: Option<usize>) -> Registration {
+        self.lock().unwrap().try_register(session_id, addr, metadata, candidates, max_sessions)
+    }
+}
+
+/// Like the Arc<Mutex<_>> store, but lookups (Joins and Queries) don't block each other.
+/// Useful when several threads serve the same holepuncher, see PassiveHolepuncher::try_clone.
+impl<S: SessionStore> SessionStore for Arc<RwLock<S>> {
+    fn insert(&mut self, session_id: &[u8], addr: SocketAddr) {
+        self.write().unwrap().insert(session_id, addr)
+    }
+    
+    fn get(&self, session_id: &[u8]) -> Option<SocketAddr> {
+        self.read().unwrap().get(session_id)
+    }
+    
+    fn remove(&mut self, session_id: &[u8]) -> Option<SocketAddr> {
+        self.write().unwrap().remove(session_id)
+    }
+    
+    fn evict_expired(&mut self, ttl: Duration) {
+        self.write().unwrap().evict_expired(ttl)
+    }
+    
+    fn len(&self) -> usize {
+        self.read().unwrap().len()
+    }
+    
+    fn sessions(&self) -> Vec<(Vec<u8>, SocketAddr)> {
+        self.read().unwrap().sessions()
+    }
+    
+    fn set_metadata(&mut self, session_id: &[u8], metadata: &[u8]) {
+        self.write().unwrap().set_metadata(session_id, metadata)
+    }
+    
+    fn get_metadata(&self, session_id: &[u8]) -> Option<Vec<u8>> {
+        self.read().unwrap().get_metadata(session_id)
+    }
+    
+    fn set_candidates(&mut self, session_id: &[u8], candidates: &[SocketAddr]) {
+        self.write().unwrap().set_candidates(session_id, candidates)
+    }
+    
+    fn get_candidates(&self, session_id: &[u8]) -> Vec<SocketAddr> {
+        self.read().unwrap().get_candidates(session_id)
+    }
+    
+    fn approx_bytes(&self) -> usize {
+        self.read().unwrap().approx_bytes()
+    }
+    
+    fn try_register(&mut self, session_id: &[u8], addr: SocketAddr, metadata: &[u8], candidates: &[SocketAddr],
+        max_sessions: Option<usize>) -> Registration {
+        self.write().unwrap().try_register(session_id, addr, metadata, candidates, max_sessions)
+    }
+}
+
+/// A session store whose clones all refer to the same sessions, so that it can back several holepuncher threads.
+/// Implemented for Arc<Mutex<_>> and Arc<RwLock<_>> around any store. Implementing it for a store
+/// whose clones are independent copies would make sessions registered on one thread unknown to the others.
+pub trait SharedSessionStore: SessionStore + Clone {}
+
+impl<S: SessionStore> SharedSessionStore for Arc<Mutex<S>> {}
+
+impl<S: SessionStore> SharedSessionStore for Arc<RwLock<S>> {}
+
+// MACs of recently accepted messages, with the source each came from and when it was accepted
+#[cfg(feature = "auth")]
+type RecentMacs = Arc<Mutex<HashMap<Vec<u8>, (SocketAddr, Instant)>>>;
+
+/// a holepuncher helps connect servers and clients
+pub struct PassiveHolepuncher<S: SessionStore = InMemorySessionStore> { 
+    /// Underlying socket
+    proto_socket: ProtocolSocket,
+    /// Socket listening on the probe address, if configured
+    probe_socket: Option<ProtocolSocket>,
+    /// Storage structure for sessions
+    session_store: S,
+    /// Time to live of sessions which are not refreshed
+    session_ttl: Duration,
+    /// Maximum number of sessions, if limited
+    max_sessions: Option<usize>,
+    /// Rate limiter for Register and Join messages, if enabled. Shared with clones of the holepuncher.
+    rate_limiter: Option<Arc<Mutex<RateLimiter>>>,
+    /// Whether RelayData messages are forwarded
+    relay_enabled: bool,
+    /// Time after which expired sessions should be dropped from the session store
+    next_eviction_at: Instant,
+    /// Whether clones serve the same socket, sharing its read timeout. Shared with the clones.
+    shared_socket: Arc<AtomicBool>,
+    /// Pre-shared key for authenticating Register and Join messages, if required
+    #[cfg(feature = "auth")]
+    auth_key: Option<Vec<u8>>,
+    /// MACs of recently accepted Register, Join and Query messages, with the source they came from and when,
+    /// to reject replays of them from elsewhere. Shared with clones of the holepuncher.
+    #[cfg(feature = "auth")]
+    recent_macs: RecentMacs,
+    /// Callback invoked with every received message which is ignored, if set
+    unhandled_observer: Option<MessageObserver>,
+    /// Callback invoked with the reason for every dropped datagram, if set
+    drop_observer: Option<DropObserver>,
+    /// Clients which joined each session, the only ones whose messages are relayed to and from its server,
+    /// and which are sent the new address if the session migrates. Shared with clones of the holepuncher.
+    session_clients: Arc<Mutex<HashMap<Vec<u8>, HashSet<SocketAddr>>>>,
+    /// Source of the current time for evicting expired sessions
+    clock: Arc<dyn Clock>,
+}
+
+impl PassiveHolepuncher {
+    /// Creates a holepuncher listening on the given address, using the default configuration.
+    pub fn new(listen_addr: impl ToSocketAddrs) -> Result<Self, RuphinError> {
+        Self::with_config(listen_addr, PassiveHolepuncherConfig::default())
+    }
+    
+    /// Creates a holepuncher listening on the given address.
+    pub fn with_config(listen_addr: impl ToSocketAddrs, config: PassiveHolepuncherConfig) -> Result<Self, RuphinError> {
+        let mut session_store = InMemorySessionStore::new();
+        session_store.set_max_bytes(config.max_store_bytes);
+        Self::with_session_store(listen_addr, config, session_store)
+    }
+    
+    /// Returns a builder for configuring a holepuncher step by step.
+    pub fn builder() -> PassiveHolepuncherBuilder {
+        PassiveHolepuncherBuilder::new()
+    }
+}
+
+/// Builds a PassiveHolepuncher. Settings which are not set keep their defaults.
+#[derive(Debug, Clone)]
+pub struct PassiveHolepuncherBuilder {
+    listen_addr: String,
+    config: PassiveHolepuncherConfig,
+}
+
+impl PassiveHolepuncherBuilder {
+    /// Creates a builder which listens on 0.0.0.0:0 with the default configuration.
+    pub fn new() -> Self {
+        Self {
+            listen_addr: String::from("0.0.0.0:0"),
+            config: PassiveHolepuncherConfig::default(),
+        }
+    }
+    
+    /// Address to listen on.
+    pub fn bind(mut self, listen_addr: &str) -> Self {
+        self.listen_addr = String::from(listen_addr);
+        self
+    }
+    
+    /// See PassiveHolepuncherConfig::session_ttl.
+    pub fn session_ttl(mut self, session_ttl: Duration) -> Self {
+        self.config.session_ttl = session_ttl;
+        self
+    }
+    
+    /// See PassiveHolepuncherConfig::max_sessions.
+    pub fn max_sessions(mut self, max_sessions: usize) -> Self {
+        self.config.max_sessions = Some(max_sessions);
+        self
+    }
+    
+    /// See PassiveHolepuncherConfig::max_store_bytes.
+    pub fn max_store_bytes(mut self, max_store_bytes: usize) -> Self {
+        self.config.max_store_bytes = Some(max_store_bytes);
+        self
+    }
+    
+    /// See PassiveHolepuncherConfig::rate_limit.
+    pub fn rate_limit(mut self, max_requests: u32, per: Duration) -> Self {
+        self.config.rate_limit = Some(RateLimit {
+            max_requests,
+            per,
+        });
+        self
+    }
+    
+    /// See PassiveHolepuncherConfig::relay_enabled.
+    pub fn relay_enabled(mut self, relay_enabled: bool) -> Self {
+        self.config.relay_enabled = relay_enabled;
+        self
+    }
+    
+    /// See PassiveHolepuncherConfig::probe_addr.
+    pub fn probe_addr(mut self, probe_addr: SocketAddr) -> Self {
+        self.config.probe_addr = Some(probe_addr);
+        self
+    }
+    
+    /// See PassiveHolepuncherConfig::recv_buffer_size.
+    pub fn recv_buffer_size(mut self, size: usize) -> Self {
+        self.config.recv_buffer_size = Some(size);
+        self
+    }
+    
+    /// See PassiveHolepuncherConfig::send_buffer_size.
+    pub fn send_buffer_size(mut self, size: usize) -> Self {
+        self.config.send_buffer_size = Some(size);
+        self
+    }
+    
+    /// Validates the configuration and binds the holepuncher.
+    pub fn build(self) -> Result<PassiveHolepuncher, RuphinError> {
+        PassiveHolepuncher::with_config(&self.listen_addr, self.config)
+    }
+}
+
+impl Default for PassiveHolepuncherBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S: SharedSessionStore> PassiveHolepuncher<S> {
+    /// Returns a second holepuncher serving the same socket and sessions, to run serve() on another thread.
+    /// Each datagram is received by exactly one of the threads waiting on the socket, whichever the OS picks,
+    /// so every thread answers a share of the traffic. They share the session store, the rate limiter,
+    /// the probe socket and the traffic counters; callbacks set with set_unhandled_observer or set_drop_observer are not copied.
+    /// Expired sessions are evicted by whichever thread gets to it first.
+    /// An InterruptHandle only stops the thread which happens to receive the LocalInterrupt,
+    /// so send one per thread to stop them all.
+    ///
+    /// Concurrency: each thread keeps its own deadlines. The read timeout belongs to the shared socket, so once cloned,
+    /// a holepuncher wakes up at least every 50 ms to check them, and leaves the timeout alone when serve() returns.
+    /// A Register is checked against the session's owner and max_sessions and stored in one step with
+    /// SessionStore::try_register, under the store's lock, so two threads can't both claim the same new session
+    /// or the last free slot. Other messages look the store up without holding its lock in between:
+    /// a Join handled while another thread handles a Migrate of its session may still get the old address.
+    pub fn try_clone(&self) -> Result<Self, RuphinError> {
+        let proto_socket = match self.proto_socket.try_clone() {
+            Ok(sock) => sock,
+            Err(e) => {
+                return Err(RuphinError::Io(e));
+            }
+        };
+        let probe_socket = match &self.probe_socket {
+            Some(probe_socket) => match probe_socket.try_clone() {
+                Ok(sock) => Some(sock),
+                Err(e) => {
+                    return Err(RuphinError::Io(e));
+                }
+            },
+            None => None,
+        };
+        self.shared_socket.store(true, Ordering::Relaxed);
+        return Ok(Self {
+            proto_socket,
+            probe_socket,
+            session_store: self.session_store.clone(),
+            session_ttl: self.session_ttl,
+            max_sessions: self.max_sessions,
+            relay_enabled: self.relay_enabled,
+            rate_limiter: self.rate_limiter.clone(),
+            next_eviction_at: self.next_eviction_at,
+            shared_socket: self.shared_socket.clone(),
+            #[cfg(feature = "auth")]
+            auth_key: self.auth_key.clone(),
+            #[cfg(feature = "auth")]
+            recent_macs: self.recent_macs.clone(),
+            unhandled_observer: None,
+            drop_observer: None,
+            session_clients: self.session_clients.clone(),
+            clock: self.clock.clone(),
+        });
+    }
+}
+
+impl<S: SessionStore> PassiveHolepuncher<S> {
+    /// Creates a holepuncher listening on the given address, which keeps its sessions in the given store.
+    /// Like the client and server, the address can be given as a host name, e.g. "localhost:4000".
+    /// The socket is bound to the first address it resolves to which can be bound.
+    pub fn with_session_store(listen_addr: impl ToSocketAddrs, config: PassiveHolepuncherConfig, session_store: S) -> Result<Self, RuphinError> {
+        config.validate()?;
+        
+        let listen_addrs: Vec<SocketAddr> = match listen_addr.to_socket_addrs() {
+            Ok(addrs) => addrs.collect(),
+            Err(e) => {
+                return Err(RuphinError::Resolve(e));
+            }
+        };
+        if listen_addrs.is_empty() {
+            return Err(RuphinError::NoUsableAddress);
+        }
+        // bind a protocol socket
+        let proto_socket = match ProtocolSocket::bind(&listen_addrs[..]) {
+            Ok(sock) => sock,
+            Err(e) => {
+                return Err(RuphinError::Bind(e));
+            }
+        };
+        return Self::with_socket(proto_socket, config, session_store);
+    }
+    
+    /// Creates a holepuncher serving on an already bound socket, e.g. one on top of a transport::MemoryTransport for tests.
+    /// The probe socket, if configured, is still bound to a real UDP port.
+    pub fn with_socket(proto_socket: ProtocolSocket, config: PassiveHolepuncherConfig, session_store: S) -> Result<Self, RuphinError> {
+        config.validate()?;
+        
+        if let Some(size) = config.recv_buffer_size {
+            if let Err(e) = proto_socket.set_recv_buffer_size(size) {
+                return Err(RuphinError::Io(e));
+            }
+        }
+        if let Some(size) = config.send_buffer_size {
+            if let Err(e) = proto_socket.set_send_buffer_size(size) {
+                return Err(RuphinError::Io(e));
+            }
+        }
+        
+        // The probe socket is only ever polled, so it's non-blocking for good.
+        // Toggling it around each read would race with other threads serving a clone of the holepuncher.
+        let probe_socket = match config.probe_addr {
+            Some(probe_addr) => match ProtocolSocket::bind(probe_addr.to_string()) {
+                Ok(sock) => {
+                    if let Err(e) = sock.set_nonblocking(true) {
+                        return Err(RuphinError::Io(e));
+                    }
+                    Some(sock)
+                },
+                Err(e) => {
+                    return Err(RuphinError::Bind(e));
+                }
+            },
+            None => None,
+        };
+        
+        // holepuncher is ready
+        let clock = proto_socket.clock();
+        return Ok(Self {
+            proto_socket,
+            probe_socket,
+            session_store,
+            session_ttl: config.session_ttl,
+            max_sessions: config.max_sessions,
+            relay_enabled: config.relay_enabled,
+            rate_limiter: config.rate_limit.map(|limit| Arc::new(Mutex::new(RateLimiter::new(limit)))),
+            next_eviction_at: clock.now() + EVICTION_INTERVAL,
+            shared_socket: Arc::new(AtomicBool::new(false)),
+            #[cfg(feature = "auth")]
+            auth_key: config.auth_key,
+            #[cfg(feature = "auth")]
+            recent_macs: Arc::new(Mutex::new(HashMap::new())),
+            unhandled_observer: None,
+            drop_observer: None,
+            session_clients: Arc::new(Mutex::new(HashMap::new())),
+            clock,
+        });
+    }
+    
+    /// Returns a snapshot of the socket's traffic counters.
+    pub fn stats(&self) -> SocketStats {
+        self.proto_socket.stats()
+    }
+    
+    /// Returns the local address the socket is bound to. The IP is unspecified (0.0.0.0) unless bound to a specific interface.
+    pub fn local_addr(&self) -> Result<SocketAddr, RuphinError> {
+        match self.proto_socket.local_addr() {
+            Ok(addr) => {
+                return Ok(addr);
+            },
+            Err(e) => {
+                return Err(RuphinError::Io(e));
+            }
+        }
+    }
+    
+    /// Sets a callback which is invoked with every message serve ignores because it doesn't expect it,
+    /// e.g. to count or log unexpected traffic. Messages which fail to deserialize are not passed to it.
+    pub fn set_unhandled_observer<F>(&mut self, observer: F) where F: FnMut(&Message, SocketAddr) + Send + 'static {
+        self.unhandled_observer = Some(Box::new(observer));
+    }
+    
+    /// Removes the callback set by set_unhandled_observer.
+    pub fn clear_unhandled_observer(&mut self) {
+        self.unhandled_observer = None;
+    }
+    
+    /// Sets a callback which is invoked with the reason and source of every datagram serve drops,
+    /// including ones which fail to deserialize, e.g. to find out why peers can't register or join.
+    pub fn set_drop_observer<F>(&mut self, observer: F) where F: FnMut(DropReason, SocketAddr) + Send + 'static {
+        self.drop_observer = Some(Box::new(observer));
+    }
+    
+    /// Removes the callback set by set_drop_observer.
+    pub fn clear_drop_observer(&mut self) {
+        self.drop_observer = None;
+    }
+    
+    /// Replaces the clock by which it is decided when to evict expired sessions, e.g. with a clock::MockClock in tests.
+    /// The sessions themselves age by the session store's clock, see InMemorySessionStore::with_clock.
+    /// The time passed to serve is still measured in real time.
+    pub fn set_clock<C>(&mut self, clock: C) where C: Clock + 'static {
+        self.clock = Arc::new(clock);
+    }
+    
+    // Reports a datagram from the given source which is dropped for the given reason.
+    fn dropped(&mut self, reason: DropReason, source: SocketAddr) {
+        trace_event!(debug, source = %source, reason = %reason, "dropped datagram");
+        if let Some(observer) = &mut self.drop_observer {
+            observer(reason, source);
+        }
+    }
+    
+    /// Returns a handle which other threads can use to interrupt serve with allow_interrupt set.
+    pub fn interrupt_handle(&self) -> Result<InterruptHandle, RuphinError> {
+        return Ok(InterruptHandle::new(self.local_addr()?));
+    }
+    
+    // Get the listening port of the socket.
+    // Returns Err if the local address cannot be obtained.
+    pub fn get_port(&self) -> Result<u16, ()> {
+        self.proto_socket.get_port()
+    }
+    
+    /// Returns the ID and server address of every active session.
+    /// serve() borrows the holepuncher mutably, so to call this while it runs on another thread,
+    /// back the holepuncher with an Arc<Mutex<_>> store and list the sessions through that instead.
+    pub fn active_sessions(&self) -> Vec<(Vec<u8>, SocketAddr)> {
+        self.session_store.sessions()
+    }
+    
+    /// Returns the number of active sessions.
+    pub fn session_count(&self) -> usize {
+        self.session_store.len()
+    }
+    
+    // Checks the MAC of a Register/Join/Query over its session ID, metadata and candidates, if authentication is required.
+    // A MAC is only accepted from one source: further copies from it are retransmissions, copies from elsewhere replays.
+    #[cfg(feature = "auth")]
+    fn is_authentic(&self, msg_type: u16, session_id: &[u8], metadata: &[u8], candidates: &[SocketAddr], mac: &[u8], source: SocketAddr) -> bool {
+        let key = match &self.auth_key {
+            Some(key) => key,
+            None => {
+                return true;
+            }
+        };
+        if !crate::auth::verify_session_mac(key, msg_type, session_id, metadata, candidates, mac, std::time::SystemTime::now()) {
+            return false;
+        }
+        let mut recent_macs = self.recent_macs.lock().unwrap();
+        match recent_macs.get(mac) {
+            Some((first_source, _)) => {
+                return *first_source == source;
+            },
+            None => {
+                recent_macs.insert(mac.to_vec(), (source, self.clock.now()));
+                return true;
+            }
+        }
+    }
+    
+    #[cfg(not(feature = "auth"))]
+    fn is_authentic(&self, _msg_type: u16, _session_id: &[u8], _metadata: &[u8], _candidates: &[SocketAddr], _mac: &[u8], _source: SocketAddr) -> bool {
+        true
+    }
+    
+    // Forgets the MACs which are too old to be accepted anymore anyway. A MAC made up to MAX_MAC_AGE ahead
+    // of our clock stays acceptable for twice that long.
+    #[cfg(feature = "auth")]
+    fn prune_recent_macs(&self, now: Instant) {
+        self.recent_macs.lock().unwrap().retain(|_, (_, seen_at)| now.saturating_duration_since(*seen_at) <= 2 * crate::auth::MAX_MAC_AGE);
+    }
+    
+    #[cfg(not(feature = "auth"))]
+    fn prune_recent_macs(&self, _now: Instant) {}
+    // MAC to attach to a PeerInfo, so that the peers can check that it comes from us. Empty unless a key is configured.
+    #[cfg(feature = "auth")]
+    fn peer_info_mac(&self, session_id: &[u8], peer_addr: SocketAddr, candidates: &[SocketAddr]) -> Vec<u8> {
+        match &self.auth_key {
+            Some(key) => crate::auth::peer_info_mac(key, session_id, peer_addr, candidates),
+            None => Vec::new(),
+        }
+    }
+    
+    #[cfg(not(feature = "auth"))]
+    fn peer_info_mac(&self, _session_id: &[u8], _peer_addr: SocketAddr, _candidates: &[SocketAddr]) -> Vec<u8> {
+        Vec::new()
+    }
+    
+    // Checks the MAC of a Migrate, if authentication is required.
+    #[cfg(feature = "auth")]
+    fn is_authentic_migrate(&self, contents: &MigrateContents) -> bool {
+        match &self.auth_key {
+            Some(key) => crate::auth::verify_migrate_mac(key, &contents.session_id, contents.new_addr, &contents.mac),
+            None => true,
+        }
+    }
+    
+    #[cfg(not(feature = "auth"))]
+    fn is_authentic_migrate(&self, _contents: &MigrateContents) -> bool {
+        true
+    }
+    
+    // Takes the read timeout off the socket before serve() returns, unless clones may still be waiting on it:
+    // without a timeout, they would miss their deadlines until the next datagram arrives.
+    fn clear_read_timeout(&self) {
+        if !self.shared_socket.load(Ordering::Relaxed) {
+            self.proto_socket.set_read_timeout(None).unwrap();
+        }
+    }
+    
+    // Checks (and counts) a Register/Join from the given source against the rate limit.
+    fn is_within_rate_limit(&mut self, source: SocketAddr) -> bool {
+        match &self.rate_limiter {
+            Some(rate_limiter) => rate_limiter.lock().unwrap().allow(source.ip()),
+            None => true,
+        }
+    }
+    
+    // Remembers a client which joined a session, unless the session already has too many remembered.
+    fn remember_client(&self, session_id: &[u8], client: SocketAddr) {
+        let mut session_clients = self.session_clients.lock().unwrap();
+        match session_clients.get_mut(session_id) {
+            Some(clients) => {
+                if clients.len() < MAX_REMEMBERED_CLIENTS {
+                    clients.insert(client);
+                }
+            },
+            None => {
+                session_clients.insert(session_id.to_vec(), HashSet::from([client]));
+            }
+        }
+    }
+    
+    // Returns true if the client joined the session.
+    fn is_session_client(&self, session_id: &[u8], client: SocketAddr) -> bool {
+        match self.session_clients.lock().unwrap().get(session_id) {
+            Some(clients) => clients.contains(&client),
+            None => false,
+        }
+    }
+    
+    // Moves a session to its new address and tells the session's clients about it.
+    // Nothing is sent to the new address, which anyone registering a session could name: the new server learns
+    // of the clients from their keepalives instead, which they send to it once they follow the PeerInfo.
+    fn migrate(&mut self, contents: MigrateContents) -> Result<(), RuphinError> {
+        self.session_store.insert(&contents.session_id, contents.new_addr);
+        // the old server's candidates don't lead to the new one
+        self.session_store.set_candidates(&contents.session_id, &[]);
+        let clients: Vec<SocketAddr> = match self.session_clients.lock().unwrap().get(&contents.session_id) {
+            Some(clients) => clients.iter().copied().collect(),
+            None => Vec::new(),
+        };
+        for client in clients {
+            let response = Message::PeerInfo(PeerInfoContents {
+                session_id: contents.session_id.clone(),
+                peer_addr: contents.new_addr,
+                // the new server announces its candidates with its next Register
+                candidates: Vec::new(),
+                mac: self.peer_info_mac(&contents.session_id, contents.new_addr, &[]),
+            });
+            match self.proto_socket.send_message(&response, client) {
+                Ok(()) => {},
+                Err(e) if !e.is_fatal() => {
+                    // transient error, this client stays with the old address until it rejoins or times out
+                },
+                Err(e) => {
+                    return Err(RuphinError::from(e));
+                }
+            };
+        }
+        return Ok(());
+    }
+    
+    // Answers an AddrReq which arrived on the main socket or, if from_probe is set, on the probe socket.
+    fn answer_addr_req(&mut self, contents: AddrReqContents, source: SocketAddr, from_probe: bool) -> Result<(), RuphinError> {
+        // answer from the socket the request arrived on, or from the other one if asked to
+        let sock = match &self.probe_socket {
+            Some(probe_socket) if from_probe != contents.change_port => probe_socket,
+            None if contents.change_port => {
+                // no other port to answer from; staying silent is more honest than answering from this one
+                self.dropped(DropReason::UnexpectedMessage, source);
+                return Ok(());
+            },
+            _ => &self.proto_socket,
+        };
+        let response = Message::AddrResp(AddrRespContents {
+            mapped_addr: source,
+            probe_port: match &self.probe_socket {
+                Some(probe_socket) => probe_socket.get_port().ok(),
+                None => None,
+            },
+        });
+        trace_event!(trace, source = %source, change_port = contents.change_port, "answering AddrReq");
+        match sock.send_message(&response, source) {
+            Ok(()) => {},
+            Err(e) if !e.is_fatal() => {
+                // transient error, the peer will ask again
+            },
+            Err(e) => {
+                return Err(RuphinError::from(e));
+            }
+        };
+        return Ok(());
+    }
+    
+    // Answers the AddrReqs waiting on the probe socket, if there is one.
+    fn serve_probes(&mut self) -> Result<(), RuphinError> {
+        loop {
+            let received = match &self.probe_socket {
+                Some(probe_socket) => probe_socket.get_message_with_source(),
+                None => {
+                    return Ok(());
+                }
+            };
+            match received {
+                Ok((Message::AddrReq(contents), source)) => {
+                    self.answer_addr_req(contents, source, true)?;
+                },
+                Ok((_, source)) => {
+                    // only AddrReqs are answered on the probe port
+                    self.dropped(DropReason::UnexpectedMessage, source);
+                    continue;
+                },
+                Err((e, _)) if e.is_timeout() => {
+                    // nothing left to read
+                    return Ok(());
+                },
+                Err((e, source)) if !e.is_fatal() => {
+                    if let Some(source) = source {
+                        self.dropped(DropReason::Malformed, source);
+                    }
+                    continue;
+                },
+                Err((e, _)) => {
+                    return Err(RuphinError::from(e));
+                }
+            }
+        }
+    }
+    
+    /// Serve as a holepuncher on the socket.
+    /// If time = Some(x), the method returns after a duration of x. Durations too large to be added to the current time,
+    /// such as Duration::MAX, are treated like None.
+    /// The method also returns upon receiving a LocalInterrupt from localhost, if allow_interrupt is true.
+    /// Returns Ok(()) normally, or Err(description) if some error occurred.
+    pub fn serve(&mut self, time: Option<Duration>, allow_interrupt: bool) -> Result<(), RuphinError> {
+        // this is the time when the function should return, in real time
+        let return_at = time.map(|time| deadline_after(Instant::now(), time));
+        
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!("holepuncher_serve", port = ?self.get_port().ok()).entered();
+        
+        // await messages in a loop
+        loop {
+            // Represents the current time on the clock, which the eviction of expired sessions follows.
+            // Measured anew since there might've been an I/O operation before that.
+            let now = self.clock.now();
+            
+            // Is it time to drop expired sessions?
+            if now >= self.next_eviction_at {
+                self.session_store.evict_expired(self.session_ttl);
+                self.session_clients.lock().unwrap().retain(|session_id, _| self.session_store.get(session_id).is_some());
+                trace_event!(trace, sessions = self.session_store.len(), "evicted expired sessions");
+                if let Some(rate_limiter) = &self.rate_limiter {
+                    rate_limiter.lock().unwrap().prune();
+                }
+                self.prune_recent_macs(now);
+                self.next_eviction_at = now + EVICTION_INTERVAL;
+            }
+            
+            // answer whatever arrived on the probe socket in the meantime
+            self.serve_probes()?;
+            
+            // determine how long the socket should wait
+            let until_eviction = self.next_eviction_at.saturating_duration_since(now);
+            let socket_time = if let Some(return_at) = return_at {
+                // check if we should actually return now
+                let real_now = Instant::now();
+                if real_now >= return_at {
+                    self.clear_read_timeout();
+                    return Ok(());
+                }
+                // otherwise, the socket should wait until return_at or the next eviction, whichever comes first
+                Some((return_at - real_now).min(until_eviction))
+            } else {
+                // no return time is specified, so the socket will wait until the next eviction.
+                Some(until_eviction)
+            };
+            // with a probe socket, wake up often enough to check it
+            let socket_time = match (socket_time, &self.probe_socket) {
+                (Some(socket_time), Some(_)) => Some(socket_time.min(PROBE_POLL_INTERVAL)),
+                (socket_time, _) => socket_time,
+            };
+            // with clones waiting on the socket as well, wake up often enough to keep our own deadlines
+            let socket_time = match socket_time {
+                Some(socket_time) if self.shared_socket.load(Ordering::Relaxed) => Some(socket_time.min(SHARED_POLL_INTERVAL)),
+                socket_time => socket_time,
+            };
+            
+            // set the timeout on the socket
+            self.proto_socket.set_read_timeout(socket_time).unwrap();
+            
+            // await the next message
+            match self.proto_socket.get_message_with_source() {
+                Ok((Message::HelloReq(_), source)) => {
+                    trace_event!(trace, source = %source, "answering HelloReq");
+                    // send the source a HelloResp
+                    match self.proto_socket.send_message(&Message::HelloResp(HelloRespContents::default()), source) {
+                        Ok(()) => {},
+                        Err(e) if !e.is_fatal() => {
+                            // transient error, the peer will ask again
+                        },
+                        Err(e) => {
+                            return Err(RuphinError::from(e));
+                        }
+                    };
+                },
+                Ok((Message::AddrReq(contents), source)) => {
+                    self.answer_addr_req(contents, source, false)?;
+                },
+                Ok((Message::LocalInterrupt, source)) if allow_interrupt => {
+                    // received a local interrupt and interrupts are allowed
+                    // check that the source is localhost. If yes, return Ok(None). Otherwise ignore.
+                    if source.ip().is_loopback() {
+                        trace_event!(debug, source = %source, "interrupted");
+                        self.clear_read_timeout();
+                        return Ok(());
+                    } else {
+                        self.dropped(DropReason::WrongSource, source);
+                        continue;
+                    }
+                },
+                Ok((Message::Register(_), source)) | Ok((Message::Join(_), source)) | Ok((Message::Query(_), source))
+                    | Ok((Message::Migrate(_), source)) if !self.is_within_rate_limit(source) => {
+                    // too many requests from this source, drop the message
+                    self.dropped(DropReason::RateLimited, source);
+                    continue;
+                },
+                Ok((Message::Register(contents), source)) if !self.is_authentic(REGISTER, &contents.session_id, &contents.metadata, &contents.candidates, &contents.mac, source) => {
+                    // not authenticated with the pre-shared key, drop the message
+                    self.dropped(DropReason::Unauthenticated, source);
+                    continue;
+                },
+                Ok((Message::Join(contents), source)) if !self.is_authentic(JOIN, &contents.session_id, &[], &contents.candidates, &contents.mac, source) => {
+                    // not authenticated with the pre-shared key, drop the message
+                    self.dropped(DropReason::Unauthenticated, source);
+                    continue;
+                },
+                Ok((Message::Query(contents), source)) if !self.is_authentic(QUERY, &contents.session_id, &[], &[], &contents.mac, source) => {
+                    // not authenticated with the pre-shared key, drop the message
+                    self.dropped(DropReason::Unauthenticated, source);
+                    continue;
+                },
+                Ok((Message::Migrate(contents), source)) if !self.is_authentic_migrate(&contents) => {
+                    // not authenticated with the pre-shared key, drop the message
+                    self.dropped(DropReason::Unauthenticated, source);
+                    continue;
+                },
+                Ok((Message::Register(contents), source)) => {
+                    // an existing session may only be refreshed by its owner, a new one is allowed only if there's room for it
+                    // a server whose public address changed has to wait for its old session to expire
+                    let registration = self.session_store.try_register(&contents.session_id, source, &contents.metadata,
+                        &contents.candidates, self.max_sessions);
+                    let response = match registration {
+                        Registration::Taken => {
+                            // someone else owns this session, don't let them redirect its clients
+                            trace_event!(warn, session_id = ?contents.session_id, source = %source, "rejected Register for a session owned by another peer");
+                            Message::RegisterRejected(RegisterRejectedContents {
+                                session_id: contents.session_id,
+                            })
+                        },
+                        Registration::Full => {
+                            // reject the registration
+                            trace_event!(warn, session_id = ?contents.session_id, source = %source, "rejected Register, holepuncher is full");
+                            Message::RegisterRejected(RegisterRejectedContents {
+                                session_id: contents.session_id,
+                            })
+                        },
+                        Registration::Accepted { .. } => {
+                            trace_event!(debug, session_id = ?contents.session_id, source = %source, ?registration, "registered session");
+                            // respond with a RegisterAck
+                            Message::RegisterAck(RegisterAckContents {
+                                session_id: contents.session_id,
+                                mapped_addr: Some(source),
+                            })
+                        }
+                    };
+                    match self.proto_socket.send_message(&response, source) {
+                        Ok(()) => {},
+                        Err(e) if !e.is_fatal() => {
+                            // transient error, the server registers again
+                        },
+                        Err(e) => {
+                            return Err(RuphinError::from(e));
+                        }
+                    };
+                },
+                Ok((Message::Join(contents), source)) => {
+                    if let Some(server) = self.session_store.get(&contents.session_id) {
+                        trace_event!(debug, session_id = ?contents.session_id, source = %source, server = %server,
+                            "session found, sending PeerInfo to both peers");
+                        // session found, send the requester the addresses of the session initiator
+                        let candidates = self.session_store.get_candidates(&contents.session_id);
+                        let response = Message::PeerInfo(PeerInfoContents {
+                            session_id: contents.session_id.clone(),
+                            peer_addr: server,
+                            mac: self.peer_info_mac(&contents.session_id, server, &candidates),
+                            candidates,
+                        });
+                        match self.proto_socket.send_message(&response, source) {
+                            Ok(()) => {},
+                            Err(e) if !e.is_fatal() => {
+                                // transient error, the client joins again
+                            },
+                            Err(e) => {
+                                return Err(RuphinError::from(e));
+                            }
+                        };
+                        
+                        // also send the session initiator the addresses of the client
+                        let response = Message::PeerInfo(PeerInfoContents {
+                            session_id: contents.session_id.clone(),
+                            peer_addr: source,
+                            mac: self.peer_info_mac(&contents.session_id, source, &contents.candidates),
+                            candidates: contents.candidates.clone(),
+                        });
+                        match self.proto_socket.send_message(&response, server) {
+                            Ok(()) => {},
+                            Err(e) if !e.is_fatal() => {
+                                // transient error, the client's next Join tells the server again
+                            },
+                            Err(e) => {
+                                return Err(RuphinError::from(e));
+                            }
+                        };
+                        self.remember_client(&contents.session_id, source);
+                    } else {
+                        // send the source a SessionNotFound error
+                        trace_event!(debug, session_id = ?contents.session_id, source = %source, "session not found");
+                        let response = Message::SessionNotFound(SessionNotFoundContents {
+                            session_id: contents.session_id,
+                        });
+                        match self.proto_socket.send_message(&response, source) {
+                            Ok(()) => {},
+                            Err(e) if !e.is_fatal() => {
+                                // transient error, the client joins again
+                            },
+                            Err(e) => {
+                                return Err(RuphinError::from(e));
+                            }
+                        };
+                    }
+                },
+                Ok((Message::Query(contents), source)) => {
+                    // answer with the session's metadata, without telling anyone about the requester
+                    let response = if self.session_store.get(&contents.session_id).is_some() {
+                        trace_event!(debug, session_id = ?contents.session_id, source = %source, "answering Query");
+                        Message::QueryResult(QueryResultContents {
+                            metadata: self.session_store.get_metadata(&contents.session_id).unwrap_or_default(),
+                            session_id: contents.session_id,
+                        })
+                    } else {
+                        trace_event!(debug, session_id = ?contents.session_id, source = %source, "session not found");
+                        Message::SessionNotFound(SessionNotFoundContents {
+                            session_id: contents.session_id,
+                        })
+                    };
+                    match self.proto_socket.send_message(&response, source) {
+                        Ok(()) => {},
+                        Err(e) if !e.is_fatal() => {
+                            // transient error, the client queries again
+                        },
+                        Err(e) => {
+                            return Err(RuphinError::from(e));
+                        }
+                    };
+                },
+                Ok((Message::RelayData(contents), source)) if self.relay_enabled => {
+                    // relay the message within the session it's addressed to
+                    let server = match self.session_store.get(&contents.session_id) {
+                        Some(server) => server,
+                        None => {
+                            // unknown session, drop it
+                            self.dropped(DropReason::WrongSession, source);
+                            continue;
+                        }
+                    };
+                    // only between the server and the clients which joined its session,
+                    // so that nobody can have the holepuncher send datagrams to arbitrary addresses
+                    let (dest, origin) = if source == server && self.is_session_client(&contents.session_id, contents.peer_addr) {
+                        // from the server to one of its clients
+                        (contents.peer_addr, server)
+                    } else if source != server && self.is_session_client(&contents.session_id, source) {
+                        // from a client to the server
+                        (server, source)
+                    } else {
+                        self.dropped(DropReason::UnknownPeer, source);
+                        continue;
+                    };
+                    if Message::peek_expires_at(&contents.payload).is_some_and(is_past) {
+                        // a datagram which is of no use anymore, don't spend the bandwidth on it
+                        self.dropped(DropReason::Expired, source);
+                        continue;
+                    }
+                    trace_event!(trace, session_id = ?contents.session_id, source = %source, dest = %dest, "relaying message");
+                    let relayed = Message::RelayData(RelayDataContents {
+                        session_id: contents.session_id,
+                        peer_addr: origin,
+                        payload: contents.payload,
+                    });
+                    match self.proto_socket.send_message(&relayed, dest) {
+                        Ok(()) => {},
+                        Err(e) if !e.is_fatal() => {
+                            // transient error, the relayed datagram is lost like any other
+                        },
+                        Err(e) => {
+                            return Err(RuphinError::from(e));
+                        }
+                    };
+                },
+                Ok((Message::Goodbye(contents), source)) => {
+                    // the server is closing its session
+                    // only the peer that registered the session may remove it
+                    if self.session_store.get(&contents.session_id) == Some(source) {
+                        trace_event!(debug, session_id = ?contents.session_id, source = %source, "removed session");
+                        self.session_store.remove(&contents.session_id);
+                        self.session_clients.lock().unwrap().remove(&contents.session_id);
+                    } else {
+                        self.dropped(DropReason::WrongSource, source);
+                    }
+                },
+                Ok((Message::Migrate(contents), source)) => {
+                    // only the peer that registered the session may move it
+                    if self.session_store.get(&contents.session_id) == Some(source) {
+                        trace_event!(debug, session_id = ?contents.session_id, source = %source, new_addr = %contents.new_addr, "migrated session");
+                        self.migrate(contents)?;
+                    } else {
+                        self.dropped(DropReason::WrongSource, source);
+                    }
+                },
+                Ok((msg, source)) => {
+                    // another message was received, ignore it
+                    self.dropped(DropReason::UnexpectedMessage, source);
+                    if let Some(observer) = &mut self.unhandled_observer {
+                        observer(&msg, source);
+                    }
+                    continue;
+                },
+                Err((e, source)) => {
+                    if e.is_fatal() {
+                        // fatal error, return
+                        trace_event!(error, error = ?e, "fatal receive error");
+                        return Err(RuphinError::from(e));
+                    } else {
+                        // nonfatal error, likely a timeout or a corrupted packet. Ignore and retry.
+                        if let Some(source) = source {
+                            self.dropped(DropReason::Malformed, source);
+                        }
+                        continue;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    #[test]
+    fn eviction_drops_only_expired_sessions() {
+        let clock = MockClock::new();
+        let mut store = InMemorySessionStore::with_clock(clock.clone());
+        let addr: SocketAddr = "10.0.0.1:1000".parse().unwrap();
+        store.insert(b"stale", addr);
+        store.insert(b"fresh", addr);
+        clock.advance(Duration::from_secs(50));
+        // a keepalive Register refreshes the session
+        store.insert(b"fresh", addr);
+        clock.advance(Duration::from_secs(20));
+
+        store.evict_expired(Duration::from_secs(60));
+        assert_eq!(store.get(b"stale"), None);
+        assert_eq!(store.get(b"fresh"), Some(addr));
+    }
+
+    #[test]
+    fn remove_returns_the_stored_address() {
+        let mut store = InMemorySessionStore::new();
+        let addr: SocketAddr = "10.0.0.1:1000".parse().unwrap();
+        assert!(store.is_empty());
+        store.insert(b"session", addr);
+        assert_eq!(store.len(), 1);
+
+        assert_eq!(store.remove(b"session"), Some(addr));
+        assert_eq!(store.get(b"session"), None);
+        assert_eq!(store.remove(b"session"), None);
+        assert!(store.is_empty());
+    }
+
+    #[test]
+    fn sessions_are_looked_up_by_slice() {
+        let mut store = InMemorySessionStore::new();
+        let addr: SocketAddr = "10.0.0.1:1000".parse().unwrap();
+        store.insert(&[1, 2, 3], addr);
+        assert_eq!(store.get(&[1, 2, 3]), Some(addr));
+        assert_eq!(store.get_ref(&[1, 2, 3]), Some(&addr));
+        assert_eq!(store.get_ref(&[1, 2]), None);
+    }
+
+    #[test]
+    fn least_recently_refreshed_sessions_make_room() {
+        let mut store = InMemorySessionStore::new();
+        let addr: SocketAddr = "10.0.0.1:1000".parse().unwrap();
+        store.insert(b"one", addr);
+        let per_session = store.approx_bytes();
+        assert!(per_session > 3);
+        store.set_max_bytes(Some(per_session * 3));
+        store.insert(b"two", addr);
+        store.insert(b"thr", addr);
+        // refreshing one leaves two the least recently refreshed
+        store.insert(b"one", addr);
+        store.insert(b"fou", addr);
+        assert_eq!(store.len(), 3);
+        assert_eq!(store.get(b"two"), None);
+        assert_eq!(store.get(b"one"), Some(addr));
+
+        // metadata counts too, the session it's set on stays
+        store.set_metadata(b"fou", &[0; 1000]);
+        assert_eq!(store.len(), 1);
+        assert_eq!(store.get(b"fou"), Some(addr));
+    }
+
+    #[test]
+    fn many_sessions_are_dropped_in_refresh_order() {
+        let clock = MockClock::new();
+        let mut store = InMemorySessionStore::with_clock(clock.clone());
+        let addr: SocketAddr = "10.0.0.1:1000".parse().unwrap();
+        store.insert(&0u32.to_be_bytes(), addr);
+        let per_session = store.approx_bytes();
+        store.remove(&0u32.to_be_bytes());
+        // sessions which are removed or expire leave the order too
+        store.insert(b"expiring", addr);
+        store.insert(b"removed", addr);
+        store.remove(b"removed");
+        clock.advance(Duration::from_secs(61));
+        store.evict_expired(Duration::from_secs(60));
+        store.set_max_bytes(Some(per_session * 10));
+        for i in 0..10_000u32 {
+            store.insert(&i.to_be_bytes(), addr);
+            // keep refreshing the first one
+            store.insert(&0u32.to_be_bytes(), addr);
+        }
+        let mut kept: Vec<u32> = store.sessions().into_iter()
+            .map(|(session_id, _)| u32::from_be_bytes(session_id.try_into().unwrap()))
+            .collect();
+        kept.sort();
+        assert_eq!(kept, [0].into_iter().chain(9991..10_000).collect::<Vec<u32>>());
+        assert_eq!(store.approx_bytes(), per_session * 10);
+    }
+
+    #[test]
+    fn approx_bytes_are_given_back_when_sessions_go() {
+        let clock = MockClock::new();
+        let mut store = InMemorySessionStore::with_clock(clock.clone());
+        let addr: SocketAddr = "10.0.0.1:1000".parse().unwrap();
+        store.insert(b"removed", addr);
+        store.set_metadata(b"removed", b"metadata");
+        store.remove(b"removed");
+        assert_eq!(store.approx_bytes(), 0);
+
+        store.insert(b"expired", addr);
+        store.set_candidates(b"expired", &[addr]);
+        clock.advance(Duration::from_secs(61));
+        store.evict_expired(Duration::from_secs(60));
+        assert_eq!(store.approx_bytes(), 0);
+    }
+}
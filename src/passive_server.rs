@@ -1,284 +1,1603 @@
-use std::net::SocketAddr;
-use std::time::{
-    Duration,
-    Instant,
-};
-use crate::messages::*;
-use crate::protocol_socket::*;
-
-/// a server maintains and serves on a session
-pub struct PassiveServer { 
-    /// Underlying socket
-    proto_socket: ProtocolSocket,
-    /// Address of the holepuncher the session is registered with
-    holepuncher: SocketAddr,
-    /// ID of the session
-    session_id: Vec<u8>,
-    /// Keepalive interval. Default is 10 seconds.
-    keepalive_interval: Duration,
-    /// Time after which the server should send a keepalive to the holepuncher.
-    next_keepalive_at: Instant,
-}
-
-impl PassiveServer {
-    pub fn new(holepuncher: SocketAddr, session_id: Vec<u8>)
-        -> Result<Self, String> {
-        // bind a protocol socket to 0.0.0.0:0
-        let sock = match ProtocolSocket::bind("0.0.0.0:0") {
-            Ok(sock) => sock,
-            Err(e) => {
-                return Err(format!("Socket bind error: {:?}", e));
-            }
-        };
-        
-        // Timeout behaviour:
-        // Up to 10 seconds for the session
-        // individual message timeout = 500 ms
-        // minimal inter-message time = 400 ms
-        // 3 Hello retries
-        let total_timeout = Duration::from_secs(10);
-        let indiv_timeout = Duration::from_millis(500);
-        let inter_message_time = Duration::from_millis(400);
-        
-        // deadline after which the attempt to create a server is considered failed
-        let end_time = Instant::now() + total_timeout;
-        // Set the protocol socket's message timeout (will be undone after the function returns)
-        sock.set_read_timeout(Some(indiv_timeout)).unwrap();
-        
-        // Now we will send a Register to the holepuncher, and expect a RegisterAck back.
-        let request = Message::Register(RegisterContents {
-            session_id: session_id.clone(),
-        });
-        
-        // send the request initially
-        match sock.send_message(&request, holepuncher) {
-            Ok(()) => {},
-            Err(e) => {
-                return Err(format!("Message send error: {:?}", e));
-            }
-        };
-        // earliest time after which the next retry will be sent
-        let mut next_retry_at = Instant::now() + inter_message_time;
-        
-        // enter a retry loop
-        while Instant::now() < end_time {
-            // if we're past the next_retry_at deadline, retry sending the Register and reset the next_retry_at deadline
-            if Instant::now() > next_retry_at {
-                match sock.send_message(&request, holepuncher) {
-                    Ok(()) => {
-                        // reset the next_retry_at deadline
-                        next_retry_at = Instant::now() + inter_message_time
-                    },
-                    Err(e) => {
-                        return Err(format!("Message send error: {:?}", e));
-                    }
-                };
-            }
-            
-            // Wait for a response. This will either succeed, timeout, or fail fatally.
-            let (ack, source) = match sock.get_message() {
-                Ok((ack, source)) => (ack, source),
-                Err(e) => {
-                    if e.is_fatal() {
-                        // fatal error, return
-                        return Err(format!("Fatal receive error: {:?}", e));
-                    } else {
-                        // nonfatal error, ignore and retry
-                        continue;
-                    }
-                },
-            };
-            
-            // We got a message. What is it?
-            if let Message::RegisterAck(RegisterAckContents {
-                session_id: returned_session_id
-            }) = ack {
-                // it's a session register acknowledgement
-                if source != holepuncher {
-                    // message is not from the holepuncher, ignore it
-                    continue;
-                }
-                if returned_session_id != session_id {
-                    // message has a wrong session ID, ignore it
-                    continue;
-                }
-                // our session was registered successfully!
-                // remove the timeout on the socket
-                sock.set_read_timeout(None).unwrap();
-                // construct an Endpoint and return it
-                return Ok(Self {
-                    proto_socket: sock,
-                    holepuncher,
-                    session_id,
-                    keepalive_interval: Duration::from_secs(10),
-                    next_keepalive_at: Instant::now() + Duration::from_secs(10),
-                });
-            } else {
-                // some other message arrived, ignore it and retry
-                continue;
-            }
-        }
-        
-        // timeout, could not register session
-        return Err(format!("Timed out trying to register the session."));
-    }
-    
-    // Get the listening port of the socket.
-    // Returns Err if the local address cannot be obtained.
-    pub fn get_port(&self) -> Result<u16, ()> {
-        self.proto_socket.get_port()
-    }
-    
-    // Sends a datagram through the protocol socket to the given target
-    pub fn send_datagram(&mut self, to: SocketAddr, data: Vec<u8>) -> Result<(), String> {
-        let msg = Message::Data(DataContents {
-            data,
-        });
-        
-        match self.proto_socket.send_message(&msg, to) {
-            Ok(()) => {
-                return Ok(());
-            },
-            Err(e) => {
-                return Err(format!("Message send error: {:?}", e));
-            }
-        }
-    }
-    
-    /// Serve messages on the socket until you get a datagram from someone.
-    /// This method should be called regularly to ensure keepalives are sent, connection requests answered, etc.
-    /// If no data is received after a specified timeout, it returns Ok(None).
-    /// If a timeout of None is specified, this function will not return until it has data.
-    /// An exception to this is: If allow_interrupt is true, the function will return if it receives a LocalInterrupt message from localhost, again with Ok(None).
-    pub fn wait_for_data(&mut self, timeout: Option<Duration>, allow_interrupt: bool) -> Result<Option<(SocketAddr, Vec<u8>)>, String> {
-        // Represents the current time.
-        // Measured before instances of being used if there was a syscall or I/O operation since it was last measured.
-        let mut now = Instant::now();
-        
-        // this is the time when the function should return
-        let return_at = match timeout {
-            None => None,
-            Some(timeout) => Some(now + timeout),
-        };
-        
-        // await messages in a loop
-        loop {
-            // Re-measure the time since there might've been an I/O operation before that.
-            now = Instant::now();
-            
-            // Is it time to send a keepalive?
-            if now > self.next_keepalive_at {
-                // send a keepalive (Register for my session) to the holepuncher
-                let msg = Message::Register(RegisterContents {
-                    session_id: self.session_id.clone()
-                });
-                let addr = self.holepuncher;
-                
-                // TODO we can track the time since the last RegisterAck to see if the holepuncher is still online?
-                match self.proto_socket.send_message(&msg, addr) {
-                    Ok(()) => {},
-                    Err(e) => {
-                        return Err(format!("Message send error: {:?}", e));
-                    }
-                };
-                // We did an I/O operation, so re-measure the current time.
-                now = Instant::now();
-                
-                // schedule the next keepalive
-                self.next_keepalive_at = now + self.keepalive_interval;
-            }
-            
-            // Is it time to return?
-            if let Some(return_at) = return_at {
-                if now > return_at {
-                    self.proto_socket.set_read_timeout(None).unwrap();
-                    return Ok(None);
-                }
-            }
-            
-            // determine the next wakeup time
-            let next_wakeup = if let Some(return_at) = return_at {
-                if return_at > self.next_keepalive_at {
-                    // Have to first do a keepalive
-                    self.next_keepalive_at
-                } else {
-                    // Return before it's time for the keepalive
-                    return_at
-                }
-            } else {
-                // no return time; wake up when it's time for the next keepalive
-                self.next_keepalive_at 
-            };
-            
-            // determine how much time we give the socket to wait for messages
-            let socket_time = {
-                if next_wakeup <= now {
-                    // no time, return to beginning of loop
-                    continue;
-                } else {
-                    // roughly until next_wakeup
-                    next_wakeup - now
-                }
-            };
-            
-            // set the timeout on the socket
-            self.proto_socket.set_read_timeout(Some(socket_time)).unwrap();
-            
-            // await the next message
-            match self.proto_socket.get_message() {
-                Ok((Message::HelloReq, source)) => {
-                    // send the source a HelloResp
-                    match self.proto_socket.send_message(&Message::HelloResp, source) {
-                        Ok(()) => {},
-                        Err(e) => {
-                            return Err(format!("Message send error: {:?}", e));
-                        }
-                    };
-                },
-                Ok((Message::PeerInfo(contents), source)) => {
-                    // got a PeerInfo packet 
-                    // ignore it unless it's coming from the holepuncher
-                    if source == self.holepuncher {
-                        // send a HelloReq to the peer, once.
-                        match self.proto_socket.send_message(&Message::HelloReq, contents.peer_addr) {
-                            Ok(()) => {},
-                            Err(e) => {
-                                return Err(format!("Message send error: {:?}", e));
-                            }
-                        };
-                    }
-                },
-                Ok((Message::Data(contents), source)) => {
-                    // got some data, return it
-                    // remove the timeout on the socket
-                    // TODO check data source?
-                    self.proto_socket.set_read_timeout(None).unwrap();
-                    return Ok(Some((source, contents.data)));
-                },
-                Ok((Message::LocalInterrupt, source)) if allow_interrupt => {
-                    // received a local interrupt and interrupts are allowed
-                    // check that the source is localhost. If yes, return Ok(None). Otherwise ignore.
-                    if source.ip().is_loopback() {
-                        self.proto_socket.set_read_timeout(None).unwrap();
-                        return Ok(None);
-                    } else {
-                        continue;
-                    }
-                },
-                Ok(_) => {
-                    // another message was received, ignore it
-                    continue;
-                },
-                Err(e) => {
-                    if e.is_fatal() {
-                        // fatal error, return
-                        return Err(format!("Fatal receive error: {:?}", e));
-                    } else {
-                        // nonfatal error, likely a timeout. Ignore and retry.
-                        continue;
-                    }
-                }
-            }
-        }
-    }
-}
\ No newline at end of file
+use std::net::{
+    SocketAddr,
+    Ipv4Addr,
+    Ipv6Addr,
+    ToSocketAddrs,
+};
+use std::time::{
+    Duration,
+    Instant,
+};
+use std::collections::{
+    HashMap,
+    HashSet,
+    VecDeque,
+};
+use crate::messages::*;
+use crate::protocol_socket::*;
+use crate::error::{
+    RuphinError,
+    HandshakeStage,
+};
+use crate::event::{
+    Event,
+    MessageObserver,
+    DropReason,
+    DropObserver,
+    InterruptHandle,
+};
+use crate::jitter::jittered;
+use crate::clock::{
+    Clock,
+    deadline_after,
+};
+use std::sync::Arc;
+#[cfg(feature = "encryption")]
+use crate::crypto::DataCipher;
+
+/// Tunable parameters of a PassiveServer, also used by MultiSessionServer
+#[derive(Debug, Clone)]
+pub struct PassiveServerConfig {
+    /// Interval at which keepalives are sent to the holepuncher. Must be non-zero. Default is 10 seconds.
+    pub keepalive_interval: Duration,
+    /// Fraction by which each keepalive interval is randomly lengthened or shortened, so that peers which
+    /// started at the same time don't all send their keepalives at once. Must be in [0, 1). Default is 0 (no jitter).
+    pub keepalive_jitter: f64,
+    /// Pre-shared key of the holepuncher, if it requires authentication. The Registers (and keepalives) are then signed
+    /// with it, and PeerInfos, as well as LAN Joins with answer_lan_joins, are only accepted with a valid MAC under it.
+    /// Default is None.
+    #[cfg(feature = "auth")]
+    pub auth_key: Option<Vec<u8>>,
+    /// Local address to bind the socket to, e.g. "[::]:0" for dual-stack. With a dual-stack socket,
+    /// IPv4 peers show up as IPv4-mapped IPv6 addresses, so the holepuncher must be given in that form too.
+    /// Default is None: the unspecified address of the holepuncher's family (0.0.0.0 or ::) and an arbitrary port.
+    pub bind_addr: Option<SocketAddr>,
+    /// Time after which registering the session is given up. Default is 10 seconds.
+    pub handshake_timeout: Duration,
+    /// Time to wait for a response to an individual handshake message.
+    /// Must be non-zero and not longer than handshake_timeout. Default is 500 ms.
+    pub message_timeout: Duration,
+    /// Minimal time between retransmissions of handshake messages. Default is 400 ms.
+    pub retry_interval: Duration,
+    /// Retransmissions of the Register start retry_interval apart, and each gap is twice as long as the previous one,
+    /// up to this. Set it to retry_interval for fixed spacing. Must not be shorter than retry_interval. Default is 3.2 seconds.
+    pub max_retry_interval: Duration,
+    /// Number of Registers sent before registering is given up, even if handshake_timeout hasn't passed yet.
+    /// The last one gets as long to be answered as the gap before it. Must be at least 1.
+    /// Default is None (only handshake_timeout applies).
+    pub register_retries: Option<u32>,
+    /// If set, the HelloReq sent to a newly joined client is sent with this IP TTL, low enough to open
+    /// a mapping in the server's own NAT without reaching the client. The client's HelloReqs then get through.
+    /// Must be non-zero. Default is None.
+    pub initial_hello_ttl: Option<u32>,
+    /// Key under which datagrams to and from the peer are encrypted. Both peers must use the same key.
+    /// Default is None (no encryption).
+    #[cfg(feature = "encryption")]
+    pub encryption_key: Option<[u8; 32]>,
+    /// Clients which haven't sent a keepalive within this time are no longer considered connected.
+    /// Must be non-zero. Default is 30 seconds.
+    pub client_timeout: Duration,
+    /// Time for which sending to a client is retried while the OS send buffer is full, instead of failing right away.
+    /// Default is zero (no retries).
+    pub send_retry_budget: Duration,
+    /// Opaque data describing the session (e.g. capacity or game mode), which clients can query before joining.
+    /// At most MAX_METADATA_SIZE bytes. Default is empty.
+    pub metadata: Vec<u8>,
+    /// Number of datagrams PassiveServer::wait_for_event picks up from the socket in one go when several arrive at once.
+    /// The first is returned right away, the rest on the following calls, so that bursts don't overflow the OS receive buffer
+    /// while the application handles them. 1 returns each datagram as soon as it's received. Default is 32.
+    /// Has no effect on a MultiSessionServer, which always returns each datagram as soon as it's received.
+    pub max_queued_data: usize,
+    /// Time to live of the datagrams sent with send_datagram and broadcast. Receivers drop them once it has passed,
+    /// for real-time data where a late datagram is worse than a lost one. Requires the peers' clocks to be roughly in sync,
+    /// and the fragments of a large datagram expire along with it. Default is None (datagrams never expire).
+    pub data_ttl: Option<Duration>,
+    /// If set, PassiveServer::wait_for_event returns Ok(Event::HolepuncherTimedOut) once if nothing, not even a RegisterAck
+    /// for a keepalive, is heard from the holepuncher for this long. Must be longer than keepalive_interval.
+    /// MultiSessionServer::wait_for_data returns Ok(None) instead, see MultiSessionServer::holepuncher_timed_out.
+    /// Default is None (the holepuncher isn't watched).
+    pub holepuncher_timeout: Option<Duration>,
+    /// Announce the server's local addresses (those of its network interfaces, up to MAX_CANDIDATES) to the holepuncher
+    /// along with its Registers, so that clients on the same LAN can reach it even if the NAT doesn't forward traffic
+    /// between two of its own hosts. This reveals the local addresses to the holepuncher and every client. Candidates announced by clients are tried either way. Default is false.
+    pub gather_candidates: bool,
+    /// Time after which PassiveServer::send_reliable gives up waiting for an acknowledgement. Default is 5 seconds.
+    pub reliable_timeout: Duration,
+    /// Time between retransmissions of an unacknowledged reliable datagram. Must be non-zero. Default is 200 ms.
+    pub reliable_retry_interval: Duration,
+    /// If set, once this many reliable datagrams in a row sent to a client go unacknowledged, the client is forgotten
+    /// and wait_for_event returns Ok(Event::PeerLost) for it. In chatty sessions this notices a vanished client sooner
+    /// than client_timeout. Must be at least 1. Default is None.
+    pub max_unacked_reliable: Option<u32>,
+    /// Answer Joins for the session which clients broadcast on the LAN (see PassiveClientConfig::lan_discovery)
+    /// with the server's address on the LAN, so that they can connect without going through the holepuncher.
+    /// The clients broadcast to a fixed port, so bind_addr should be set to it. With an auth_key, only Joins with
+    /// a valid MAC are answered. Always on for servers created with PassiveServer::lan_only. A MultiSessionServer answers
+    /// Joins for any of its sessions. Default is false.
+    pub answer_lan_joins: bool,
+}
+
+impl Default for PassiveServerConfig {
+    fn default() -> Self {
+        Self {
+            keepalive_interval: Duration::from_secs(10),
+            keepalive_jitter: 0.0,
+            bind_addr: None,
+            #[cfg(feature = "auth")]
+            auth_key: None,
+            handshake_timeout: Duration::from_secs(10),
+            message_timeout: Duration::from_millis(500),
+            retry_interval: Duration::from_millis(400),
+            max_retry_interval: Duration::from_millis(3200),
+            register_retries: None,
+            initial_hello_ttl: None,
+            #[cfg(feature = "encryption")]
+            encryption_key: None,
+            client_timeout: Duration::from_secs(30),
+            send_retry_budget: Duration::ZERO,
+            metadata: Vec::new(),
+            max_queued_data: 32,
+            data_ttl: None,
+            holepuncher_timeout: None,
+            gather_candidates: false,
+            reliable_timeout: Duration::from_secs(5),
+            reliable_retry_interval: Duration::from_millis(200),
+            max_unacked_reliable: None,
+            answer_lan_joins: false,
+        }
+    }
+}
+
+impl PassiveServerConfig {
+    // Checks that the configured values make sense.
+    pub(crate) fn validate(&self) -> Result<(), RuphinError> {
+        if self.keepalive_interval.is_zero() {
+            return Err(RuphinError::InvalidConfig("keepalive_interval must be non-zero"));
+        }
+        if !(0.0..1.0).contains(&self.keepalive_jitter) {
+            return Err(RuphinError::InvalidConfig("keepalive_jitter must be at least 0 and less than 1"));
+        }
+        if self.client_timeout.is_zero() {
+            return Err(RuphinError::InvalidConfig("client_timeout must be non-zero"));
+        }
+        if self.message_timeout.is_zero() {
+            return Err(RuphinError::InvalidConfig("message_timeout must be non-zero"));
+        }
+        if self.message_timeout > self.handshake_timeout {
+            return Err(RuphinError::InvalidConfig("message_timeout must not be longer than handshake_timeout"));
+        }
+        if self.max_retry_interval < self.retry_interval {
+            return Err(RuphinError::InvalidConfig("max_retry_interval must not be shorter than retry_interval"));
+        }
+        if self.register_retries == Some(0) {
+            return Err(RuphinError::InvalidConfig("register_retries must be at least 1"));
+        }
+        if self.initial_hello_ttl == Some(0) {
+            return Err(RuphinError::InvalidConfig("initial_hello_ttl must be non-zero"));
+        }
+        if self.metadata.len() > MAX_METADATA_SIZE {
+            return Err(RuphinError::InvalidConfig("metadata must be at most MAX_METADATA_SIZE bytes"));
+        }
+        if self.max_queued_data == 0 {
+            return Err(RuphinError::InvalidConfig("max_queued_data must be at least 1"));
+        }
+        if self.reliable_retry_interval.is_zero() {
+            return Err(RuphinError::InvalidConfig("reliable_retry_interval must be non-zero"));
+        }
+        if self.max_unacked_reliable == Some(0) {
+            return Err(RuphinError::InvalidConfig("max_unacked_reliable must be at least 1"));
+        }
+        if self.data_ttl == Some(Duration::ZERO) {
+            return Err(RuphinError::InvalidConfig("data_ttl must be non-zero"));
+        }
+        if let Some(holepuncher_timeout) = self.holepuncher_timeout {
+            if holepuncher_timeout <= self.keepalive_interval {
+                return Err(RuphinError::InvalidConfig("holepuncher_timeout must be longer than keepalive_interval"));
+            }
+        }
+        return Ok(());
+    }
+
+    // MAC to attach to a Register of the given session with our metadata and the given candidates, made at the current time.
+    // Empty unless a key is configured.
+    #[cfg(feature = "auth")]
+    pub(crate) fn register_mac(&self, session_id: &[u8], candidates: &[SocketAddr]) -> Vec<u8> {
+        match &self.auth_key {
+            Some(key) => crate::auth::session_mac(key, REGISTER, session_id, &self.metadata, candidates, std::time::SystemTime::now()),
+            None => Vec::new(),
+        }
+    }
+    
+    #[cfg(not(feature = "auth"))]
+    pub(crate) fn register_mac(&self, _session_id: &[u8], _candidates: &[SocketAddr]) -> Vec<u8> {
+        Vec::new()
+    }
+
+    // Checks that a PeerInfo comes from a holepuncher with our key. Always true unless a key is configured.
+    #[cfg(feature = "auth")]
+    pub(crate) fn is_authentic_peer_info(&self, contents: &PeerInfoContents) -> bool {
+        match &self.auth_key {
+            Some(key) => crate::auth::verify_peer_info_mac(key, &contents.session_id, contents.peer_addr, &contents.candidates, &contents.mac),
+            None => true,
+        }
+    }
+    
+    #[cfg(not(feature = "auth"))]
+    pub(crate) fn is_authentic_peer_info(&self, _contents: &PeerInfoContents) -> bool {
+        true
+    }
+
+    // Checks that a Join broadcast on the LAN was made with our key. Always true unless a key is configured.
+    #[cfg(feature = "auth")]
+    pub(crate) fn is_authentic_join(&self, contents: &JoinContents) -> bool {
+        match &self.auth_key {
+            Some(key) => crate::auth::verify_session_mac(key, JOIN, &contents.session_id, &[], &contents.candidates, &contents.mac, std::time::SystemTime::now()),
+            None => true,
+        }
+    }
+
+    #[cfg(not(feature = "auth"))]
+    pub(crate) fn is_authentic_join(&self, _contents: &JoinContents) -> bool {
+        true
+    }
+
+    // MAC vouching for our address in the PeerInfo answering a LAN Join for the given session, like the holepuncher's.
+    // Empty unless a key is configured.
+    #[cfg(feature = "auth")]
+    pub(crate) fn peer_info_mac(&self, session_id: &[u8], peer_addr: SocketAddr) -> Vec<u8> {
+        match &self.auth_key {
+            Some(key) => crate::auth::peer_info_mac(key, session_id, peer_addr, &[]),
+            None => Vec::new(),
+        }
+    }
+
+    #[cfg(not(feature = "auth"))]
+    pub(crate) fn peer_info_mac(&self, _session_id: &[u8], _peer_addr: SocketAddr) -> Vec<u8> {
+        Vec::new()
+    }
+}
+
+/// a server maintains and serves on a session
+pub struct PassiveServer { 
+    /// Underlying socket
+    proto_socket: ProtocolSocket,
+    /// Address of the holepuncher the session is registered with
+    holepuncher: SocketAddr,
+    /// ID of the session
+    session_id: Vec<u8>,
+    /// Authenticates the keepalive Registers and checks PeerInfos, and holds the metadata sent along with every keepalive
+    config: PassiveServerConfig,
+    /// Local addresses announced to the holepuncher along with every keepalive
+    candidates: Vec<SocketAddr>,
+    /// Whether the session was removed from the holepuncher
+    deregistered: bool,
+    /// Whether the server runs without a holepuncher, see PassiveServer::lan_only
+    lan_only: bool,
+    /// Public address of the server as last reported by the holepuncher
+    external_addr: Option<SocketAddr>,
+    /// Keepalive interval. Default is 10 seconds.
+    keepalive_interval: Duration,
+    /// Fraction by which keepalive intervals are randomized
+    keepalive_jitter: f64,
+    /// Time after which the server should send a keepalive to the holepuncher.
+    next_keepalive_at: Instant,
+    /// Puts fragmented datagrams back together
+    reassembler: Reassembler,
+    /// Message ID for the next fragmented datagram sent
+    next_message_id: u32,
+    /// Recognizes retransmitted reliable datagrams
+    duplicate_filter: DuplicateFilter,
+    /// Clients which completed the HelloReq/HelloResp exchange, and the last time each was heard from
+    clients: HashMap<SocketAddr, Instant>,
+    /// Time after which a silent client is no longer considered connected
+    client_timeout: Duration,
+    /// Clients which talk to the server through the holepuncher's relay
+    relayed_clients: HashSet<SocketAddr>,
+    /// Address of each client which identified itself with a handle in its HelloReqs
+    handles: HashMap<Vec<u8>, SocketAddr>,
+    /// Time for which sends are retried while the OS send buffer is full
+    send_retry_budget: Duration,
+    /// IP TTL of the HelloReq sent to newly joined clients, if lowered
+    initial_hello_ttl: Option<u32>,
+    /// Events which have not been returned by wait_for_event yet
+    pending_events: VecDeque<Event>,
+    /// Number of datagrams wait_for_event picks up from the socket in one go
+    max_queued_data: usize,
+    /// Messages which arrived while send_reliable waited for an acknowledgement, to be handled by wait_for_event
+    deferred: VecDeque<(Message, SocketAddr)>,
+    /// Sequence number of the next reliable datagram sent
+    next_seq: u32,
+    /// Time after which sending a reliable datagram is given up
+    reliable_timeout: Duration,
+    /// Time between retransmissions of a reliable datagram
+    reliable_retry_interval: Duration,
+    /// Number of reliable datagrams in a row each client hasn't acknowledged, for the clients which left some unacknowledged
+    unacked_reliable: HashMap<SocketAddr, u32>,
+    /// Number of unacknowledged reliable datagrams in a row after which a client is considered lost, if any
+    max_unacked_reliable: Option<u32>,
+    /// Whether Joins broadcast by clients on the LAN are answered
+    answer_lan_joins: bool,
+    /// Time to live of the datagrams sent, if they expire
+    data_ttl: Option<Duration>,
+    /// Time after which the holepuncher is considered gone if nothing is heard from it, if it is watched
+    holepuncher_timeout: Option<Duration>,
+    /// Last time a message was received from the holepuncher
+    last_heard_from_holepuncher: Instant,
+    /// Whether the holepuncher timing out has already been reported
+    holepuncher_timed_out: bool,
+    /// Encrypts and decrypts datagrams, if a key is configured
+    #[cfg(feature = "encryption")]
+    cipher: Option<DataCipher>,
+    /// Callback invoked with every received message, if set
+    observer: Option<MessageObserver>,
+    /// Callback invoked with every received message which is ignored, if set
+    unhandled_observer: Option<MessageObserver>,
+    /// Callback invoked with the reason for every dropped datagram, if set
+    drop_observer: Option<DropObserver>,
+    /// Source of the current time for keepalives and timeouts
+    clock: Arc<dyn Clock>,
+}
+
+impl PassiveServer {
+    /// Registers a session with the holepuncher, using the default configuration.
+    pub fn new(holepuncher: impl ToSocketAddrs, session_id: Vec<u8>)
+        -> Result<Self, RuphinError> {
+        Self::with_config(holepuncher, session_id, PassiveServerConfig::default())
+    }
+    
+    /// Registers a session with the holepuncher.
+    /// The holepuncher's address can be a host name, e.g. "holepuncher.example.com:4000". The addresses it resolves to
+    /// are tried one after the other, in the order the resolver prefers, until one of them acknowledges the session.
+    /// Returns Err(RuphinError::Resolve) if the name can't be resolved, and Err(RuphinError::NoUsableAddress)
+    /// if it only resolves to addresses of another family than bind_addr.
+    pub fn with_config(holepuncher: impl ToSocketAddrs, session_id: Vec<u8>, config: PassiveServerConfig)
+        -> Result<Self, RuphinError> {
+        config.validate()?;
+        let mut holepunchers = resolve_by_family(holepuncher, config.bind_addr)?.into_iter().flatten().peekable();
+        loop {
+            // there's at least one address, and the last one's result is returned
+            let holepuncher = holepunchers.next().unwrap();
+            match Self::register_at(holepuncher, session_id.clone(), config.clone()) {
+                Err(e) if e.is_unreachable() && holepunchers.peek().is_some() => {
+                    continue;
+                },
+                result => {
+                    return result;
+                }
+            }
+        }
+    }
+    
+    /// Serves the session on the LAN only, without a holepuncher: clients with PassiveClientConfig::lan_discovery
+    /// find the server by broadcasting Joins to config.bind_addr, which is therefore required.
+    /// Nothing is sent until a client asks, so this returns right away. Since there is no holepuncher,
+    /// disconnect and migrate fail, and clients behind another NAT can't connect.
+    pub fn lan_only(session_id: Vec<u8>, config: PassiveServerConfig) -> Result<Self, RuphinError> {
+        config.validate()?;
+        let bind_addr = match config.bind_addr {
+            Some(bind_addr) => bind_addr,
+            None => {
+                return Err(RuphinError::InvalidConfig("bind_addr must be set for clients to find a LAN-only server"));
+            }
+        };
+        let sock = match ProtocolSocket::bind(bind_addr.to_string()) {
+            Ok(sock) => sock,
+            Err(e) => {
+                return Err(RuphinError::Bind(e));
+            }
+        };
+        // no messages ever come from the unspecified address, so none are mistaken for the holepuncher's
+        let no_holepuncher = if bind_addr.is_ipv6() {
+            SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))
+        } else {
+            SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
+        };
+        return Ok(Self::serving(sock, no_holepuncher, session_id, &config, Vec::new(), None, true));
+    }
+    
+    // Binds a socket and registers the session with the holepuncher at the given address.
+    fn register_at(holepuncher: SocketAddr, session_id: Vec<u8>, config: PassiveServerConfig)
+        -> Result<Self, RuphinError> {
+        // bind a protocol socket to the configured address, or to an arbitrary port that can reach the holepuncher
+        let bind_addr = match config.bind_addr {
+            Some(bind_addr) => bind_addr,
+            None if holepuncher.is_ipv6() => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+            None => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        };
+        let sock = match ProtocolSocket::bind(bind_addr.to_string()) {
+            Ok(sock) => sock,
+            Err(e) => {
+                return Err(RuphinError::Bind(e));
+            }
+        };
+        return Self::with_socket(sock, holepuncher, session_id, config);
+    }
+    
+    /// Like with_config, but on an already bound socket instead of one bound to config.bind_addr,
+    /// e.g. one on top of a transport::MemoryTransport for tests.
+    pub fn with_socket(sock: ProtocolSocket, holepuncher: SocketAddr, session_id: Vec<u8>, config: PassiveServerConfig)
+        -> Result<Self, RuphinError> {
+        config.validate()?;
+        
+        // Timeout behaviour (defaults in parentheses):
+        // Up to handshake_timeout for the session (10 seconds)
+        // individual message timeout = message_timeout (500 ms)
+        // minimal inter-message time = retry_interval (400 ms), doubling up to max_retry_interval (3.2 seconds)
+        // up to register_retries Registers (unlimited)
+        let total_timeout = config.handshake_timeout;
+        let indiv_timeout = config.message_timeout;
+        let inter_message_time = config.retry_interval;
+        // retries and timeouts follow the socket's clock, which is only not real time for in-memory transports
+        let clock = sock.clock();
+        
+        // deadline after which the attempt to create a server is considered failed
+        let end_time = deadline_after(clock.now(), total_timeout);
+        // Set the protocol socket's message timeout (will be undone after the function returns)
+        sock.set_read_timeout(Some(indiv_timeout)).unwrap();
+        
+        // Now we will send a Register to the holepuncher, and expect a RegisterAck back.
+        let candidates = if config.gather_candidates {
+            sock.local_candidates(holepuncher)
+        } else {
+            Vec::new()
+        };
+        // every attempt gets a fresh MAC, since the holepuncher only accepts one for a while
+        let request = || Message::Register(RegisterContents {
+            session_id: session_id.clone(),
+            metadata: config.metadata.clone(),
+            candidates: candidates.clone(),
+            mac: config.register_mac(&session_id, &candidates),
+        });
+        
+        // send the request initially
+        let mut num_registers = 0;
+        match sock.send_message(&request(), holepuncher) {
+            Ok(()) => {
+                num_registers += 1;
+            },
+            Err(e) if !e.is_fatal() => {
+                // transient error, the retry loop will send it again
+            },
+            Err(e) => {
+                return Err(RuphinError::from(e));
+            }
+        };
+        // earliest time after which the next retry will be sent, backing off exponentially
+        let mut retry_gap = inter_message_time;
+        let mut next_retry_at = deadline_after(clock.now(), retry_gap);
+        
+        // enter a retry loop
+        while clock.now() < end_time {
+            // if we're past the next_retry_at deadline, retry sending the Register and reset the next_retry_at deadline
+            if clock.now() > next_retry_at {
+                if let Some(max_registers) = config.register_retries {
+                    if num_registers >= max_registers {
+                        // the last Register went unanswered, give up
+                        break;
+                    }
+                }
+                match sock.send_message(&request(), holepuncher) {
+                    Ok(()) => {
+                        num_registers += 1;
+                        // reset the next_retry_at deadline, further out than the last time
+                        retry_gap = retry_gap.saturating_mul(2).min(config.max_retry_interval);
+                        next_retry_at = deadline_after(clock.now(), retry_gap);
+                    },
+                    Err(e) if !e.is_fatal() => {
+                        // transient error, try again on the next iteration
+                    },
+                    Err(e) => {
+                        return Err(RuphinError::from(e));
+                    }
+                };
+            }
+            
+            // Wait for a response. This will either succeed, timeout, or fail fatally.
+            let (ack, source) = match sock.get_message() {
+                Ok((ack, source)) => (ack, source),
+                Err(e) => {
+                    if e.is_fatal() {
+                        // fatal error, return
+                        return Err(RuphinError::from(e));
+                    } else {
+                        // nonfatal error, ignore and retry
+                        continue;
+                    }
+                },
+            };
+            
+            // We got a message. What is it?
+            if let Message::RegisterAck(RegisterAckContents {
+                session_id: returned_session_id,
+                mapped_addr,
+            }) = ack {
+                // it's a session register acknowledgement
+                if source != holepuncher {
+                    // message is not from the holepuncher, ignore it
+                    continue;
+                }
+                if returned_session_id != session_id {
+                    // message has a wrong session ID, ignore it
+                    continue;
+                }
+                // our session was registered successfully!
+                // remove the timeout on the socket
+                sock.set_read_timeout(None).unwrap();
+                // construct an Endpoint and return it
+                return Ok(Self::serving(sock, holepuncher, session_id, &config, candidates, mapped_addr, false));
+            } else if let Message::RegisterRejected(RegisterRejectedContents {
+                session_id: returned_session_id
+            }) = ack {
+                // the holepuncher refused our session
+                if source != holepuncher || returned_session_id != session_id {
+                    // not meant for us, ignore it
+                    continue;
+                }
+                return Err(RuphinError::RegisterRejected);
+            } else {
+                // some other message arrived, ignore it and retry
+                continue;
+            }
+        }
+        
+        // timeout, could not register session
+        return Err(RuphinError::HandshakeTimeout {
+            stage: HandshakeStage::WaitingForRegisterAck,
+        });
+    }
+    
+    // Puts together a server for the session on the socket, once it's registered with the holepuncher,
+    // or, if lan_only is set, without a holepuncher to keep the session alive at.
+    fn serving(sock: ProtocolSocket, holepuncher: SocketAddr, session_id: Vec<u8>, config: &PassiveServerConfig,
+        candidates: Vec<SocketAddr>, external_addr: Option<SocketAddr>, lan_only: bool) -> Self {
+        let clock = sock.clock();
+        let now = clock.now();
+        Self {
+            proto_socket: sock,
+            holepuncher,
+            config: config.clone(),
+            candidates,
+            deregistered: false,
+            lan_only,
+            external_addr,
+            session_id,
+            keepalive_interval: config.keepalive_interval,
+            keepalive_jitter: config.keepalive_jitter,
+            next_keepalive_at: deadline_after(now, jittered(config.keepalive_interval, config.keepalive_jitter)),
+            reassembler: Reassembler::new(FRAGMENT_TIMEOUT),
+            next_message_id: 0,
+            duplicate_filter: DuplicateFilter::new(),
+            clients: HashMap::new(),
+            relayed_clients: HashSet::new(),
+            handles: HashMap::new(),
+            client_timeout: config.client_timeout,
+            send_retry_budget: config.send_retry_budget,
+            initial_hello_ttl: config.initial_hello_ttl,
+            pending_events: VecDeque::new(),
+            max_queued_data: config.max_queued_data,
+            deferred: VecDeque::new(),
+            next_seq: 0,
+            reliable_timeout: config.reliable_timeout,
+            reliable_retry_interval: config.reliable_retry_interval,
+            unacked_reliable: HashMap::new(),
+            max_unacked_reliable: config.max_unacked_reliable,
+            // Joins are the only way to find a LAN-only server
+            answer_lan_joins: config.answer_lan_joins || lan_only,
+            data_ttl: config.data_ttl,
+            holepuncher_timeout: config.holepuncher_timeout,
+            last_heard_from_holepuncher: now,
+            holepuncher_timed_out: false,
+            #[cfg(feature = "encryption")]
+            cipher: config.encryption_key.as_ref().map(DataCipher::new),
+            observer: None,
+            unhandled_observer: None,
+            drop_observer: None,
+            clock,
+        }
+    }
+    
+    /// Sets the IP TTL of packets sent from now on.
+    pub fn set_ttl(&self, ttl: u32) -> Result<(), RuphinError> {
+        match self.proto_socket.set_ttl(ttl) {
+            Ok(()) => {
+                return Ok(());
+            },
+            Err(e) => {
+                return Err(RuphinError::Io(e));
+            }
+        }
+    }
+    
+    /// Returns the IP TTL of sent packets.
+    pub fn ttl(&self) -> Result<u32, RuphinError> {
+        match self.proto_socket.ttl() {
+            Ok(ttl) => {
+                return Ok(ttl);
+            },
+            Err(e) => {
+                return Err(RuphinError::Io(e));
+            }
+        }
+    }
+    
+    // Encrypts an outgoing datagram, if encryption is enabled.
+    fn seal<P>(&self, data: P) -> P where P: AsRef<[u8]> + From<Vec<u8>> {
+        #[cfg(feature = "encryption")]
+        if let Some(cipher) = &self.cipher {
+            return P::from(cipher.encrypt(data.as_ref()));
+        }
+        return data;
+    }
+    
+    // Decrypts an incoming datagram, if encryption is enabled. A datagram which fails to decrypt
+    // was tampered with or encrypted under another key, it is reported as dropped and None is returned.
+    #[cfg(feature = "encryption")]
+    fn open(&mut self, data: Vec<u8>, source: SocketAddr) -> Option<Vec<u8>> {
+        let cipher = match &self.cipher {
+            Some(cipher) => cipher,
+            None => {
+                return Some(data);
+            }
+        };
+        match cipher.decrypt(&data) {
+            Some(plaintext) => {
+                return Some(plaintext);
+            },
+            None => {
+                self.dropped(DropReason::Undecryptable, source);
+                return None;
+            }
+        }
+    }
+    
+    #[cfg(not(feature = "encryption"))]
+    fn open(&mut self, data: Vec<u8>, _source: SocketAddr) -> Option<Vec<u8>> {
+        Some(data)
+    }
+    
+    /// Returns a snapshot of the socket's traffic counters.
+    pub fn stats(&self) -> SocketStats {
+        self.proto_socket.stats()
+    }
+    
+    /// Discards every datagram queued on the socket and every event not returned by wait_for_event yet,
+    /// e.g. to get rid of stale data after a long pause. Does not block.
+    /// Returns the number of datagrams discarded from the socket.
+    pub fn drain(&mut self) -> Result<usize, RuphinError> {
+        self.pending_events.clear();
+        match self.proto_socket.drain() {
+            Ok(discarded) => {
+                return Ok(discarded);
+            },
+            Err(e) => {
+                return Err(RuphinError::Io(e));
+            }
+        }
+    }
+    
+    /// Returns the local address the socket is bound to. The IP is unspecified (0.0.0.0) unless bound to a specific interface.
+    pub fn local_addr(&self) -> Result<SocketAddr, RuphinError> {
+        match self.proto_socket.local_addr() {
+            Ok(addr) => {
+                return Ok(addr);
+            },
+            Err(e) => {
+                return Err(RuphinError::Io(e));
+            }
+        }
+    }
+    
+    /// Replaces the metadata of the session, e.g. when the number of free slots changes.
+    /// The holepuncher learns about it with the next keepalive.
+    pub fn set_metadata(&mut self, metadata: Vec<u8>) -> Result<(), RuphinError> {
+        if metadata.len() > MAX_METADATA_SIZE {
+            return Err(RuphinError::InvalidConfig("metadata must be at most MAX_METADATA_SIZE bytes"));
+        }
+        self.config.metadata = metadata;
+        return Ok(());
+    }
+    
+    /// Returns the public address of the server, as seen by the holepuncher.
+    /// Updated whenever a keepalive is acknowledged. None if the holepuncher doesn't report it.
+    pub fn external_addr(&self) -> Option<SocketAddr> {
+        return self.external_addr;
+    }
+    
+    /// Returns the last time a message was received from the holepuncher, or the session was registered if none was since.
+    /// Keepalives are acknowledged by the holepuncher, so this normally lags by at most keepalive_interval.
+    pub fn holepuncher_last_seen(&self) -> Instant {
+        return self.last_heard_from_holepuncher;
+    }
+    
+    // Returns whether the session is kept alive at the holepuncher: it was registered there, and not removed since.
+    fn is_registered(&self) -> bool {
+        return !self.lan_only && !self.deregistered;
+    }
+    
+    // Returns the time at which the holepuncher is considered gone, if it is watched and hasn't been reported as gone yet.
+    // After disconnecting, the holepuncher has no reason to talk to the server anymore.
+    fn holepuncher_deadline(&self) -> Option<Instant> {
+        match self.holepuncher_timeout {
+            Some(holepuncher_timeout) if !self.holepuncher_timed_out && self.is_registered() => {
+                return Some(deadline_after(self.last_heard_from_holepuncher, holepuncher_timeout));
+            },
+            _ => {
+                return None;
+            }
+        }
+    }
+    
+    /// Returns a handle which other threads can use to interrupt wait_for_data and wait_for_event with allow_interrupt set.
+    pub fn interrupt_handle(&self) -> Result<InterruptHandle, RuphinError> {
+        return Ok(InterruptHandle::new(self.local_addr()?));
+    }
+    
+    // Get the listening port of the socket.
+    // Returns Err if the local address cannot be obtained.
+    pub fn get_port(&self) -> Result<u16, ()> {
+        self.proto_socket.get_port()
+    }
+    
+    // Wraps a message to a relayed client into a RelayData message for the holepuncher.
+    fn wrap_relayed(&self, payload: Vec<u8>, to: SocketAddr) -> Message {
+        Message::RelayData(RelayDataContents {
+            session_id: self.session_id.clone(),
+            peer_addr: to,
+            payload,
+        })
+    }
+    
+    // Sends a message to a client. Messages to relayed clients go through the holepuncher.
+    fn send_to_peer(&self, msg: &Message, to: SocketAddr) -> Result<(), SendError> {
+        if !self.relayed_clients.contains(&to) {
+            return self.proto_socket.send_message_blocking(msg, to, self.send_retry_budget);
+        }
+        let payload = match msg.serialize() {
+            Ok(bytes) => bytes,
+            Err(()) => {
+                return Err(SendError::serialization(msg));
+            }
+        };
+        return self.proto_socket.send_message_blocking(&self.wrap_relayed(payload, to), self.holepuncher, self.send_retry_budget);
+    }
+    
+    // Unwraps a message relayed by the holepuncher, so that it can be handled as if the client had sent it directly.
+    // Other messages are returned as they are. Like any other peer, a relayed one only becomes a client
+    // through the HelloReq/HelloResp handshake.
+    fn unwrap_relayed(&mut self, msg: Message, source: SocketAddr) -> (Message, SocketAddr) {
+        if let Message::RelayData(contents) = &msg {
+            if source == self.holepuncher && contents.session_id == self.session_id {
+                if let Ok(inner) = Message::deserialize(&contents.payload) {
+                    // remember to answer this peer through the relay as well
+                    self.relayed_clients.insert(contents.peer_addr);
+                    return (inner, contents.peer_addr);
+                }
+            }
+        }
+        return (msg, source);
+    }
+    
+    // Queues received data to be returned by wait_for_event.
+    // Returns true if there is room for more, in which case the datagrams already waiting on the socket are picked up too.
+    fn queue_data(&mut self, event: Event) -> bool {
+        self.pending_events.push_back(event);
+        let queued = self.pending_events.iter().filter(|event| matches!(event, Event::Data(..))).count();
+        return queued < self.max_queued_data;
+    }
+    
+    // MAC to attach to a Migrate to the given address. Empty unless a key is configured.
+    #[cfg(feature = "auth")]
+    fn migrate_mac(&self, new_addr: SocketAddr) -> Vec<u8> {
+        match &self.config.auth_key {
+            Some(key) => crate::auth::migrate_mac(key, &self.session_id, new_addr),
+            None => Vec::new(),
+        }
+    }
+    
+    #[cfg(not(feature = "auth"))]
+    fn migrate_mac(&self, _new_addr: SocketAddr) -> Vec<u8> {
+        Vec::new()
+    }
+    
+    // Records that a client was heard from. Clients heard from for the first time are reported as connected.
+    fn touch_client(&mut self, addr: SocketAddr) {
+        if self.clients.insert(addr, self.clock.now()).is_none() {
+            self.pending_events.push_back(Event::PeerConnected(addr));
+        }
+    }
+    
+    // Forgets clients that stopped sending keepalives and reports them as timed out.
+    fn prune_clients(&mut self) {
+        let client_timeout = self.client_timeout;
+        let now = self.clock.now();
+        let timed_out: Vec<SocketAddr> = self.clients.iter()
+            .filter(|(_, last_seen)| now.saturating_duration_since(**last_seen) > client_timeout)
+            .map(|(addr, _)| *addr)
+            .collect();
+        for addr in timed_out {
+            self.forget_client(addr);
+            self.pending_events.push_back(Event::PeerTimedOut(addr));
+        }
+        // and peers which talked through the relay without ever completing the handshake
+        let clients = &self.clients;
+        self.relayed_clients.retain(|addr| clients.contains_key(addr));
+    }
+    
+    // Drops everything known about a client.
+    fn forget_client(&mut self, addr: SocketAddr) {
+        self.clients.remove(&addr);
+        self.relayed_clients.remove(&addr);
+        self.duplicate_filter.forget(addr);
+        self.unacked_reliable.remove(&addr);
+        self.handles.retain(|_, client| *client != addr);
+    }
+    
+    // Receives the next message, unwrapping relayed messages. The observer, if any, is shown the message.
+    fn receive(&mut self) -> Result<(Message, SocketAddr), ReceiveError> {
+        let (msg, source) = match self.proto_socket.get_message_with_source() {
+            Ok((msg, source)) => {
+                if source == self.holepuncher {
+                    self.last_heard_from_holepuncher = self.clock.now();
+                    self.holepuncher_timed_out = false;
+                }
+                self.unwrap_relayed(msg, source)
+            },
+            Err((e, Some(source))) => {
+                // a datagram arrived, but it isn't a message
+                self.dropped(DropReason::Malformed, source);
+                return Err(e);
+            },
+            Err((e, None)) => {
+                return Err(e);
+            }
+        };
+        if let Some(observer) = &mut self.observer {
+            observer(&msg, source);
+        }
+        return Ok((msg, source));
+    }
+    
+    /// Sets a callback which is invoked with every message received while waiting for data,
+    /// including control messages which are handled internally or ignored. Relayed messages are passed unwrapped.
+    pub fn set_observer<F>(&mut self, observer: F) where F: FnMut(&Message, SocketAddr) + Send + 'static {
+        self.observer = Some(Box::new(observer));
+    }
+    
+    /// Removes the callback set by set_observer.
+    pub fn clear_observer(&mut self) {
+        self.observer = None;
+    }
+    
+    /// Sets a callback which is invoked with every message wait_for_event ignores because it doesn't expect it,
+    /// e.g. to count or log unexpected traffic. This includes data from peers which are not connected clients. Messages which fail to deserialize are not passed to it.
+    pub fn set_unhandled_observer<F>(&mut self, observer: F) where F: FnMut(&Message, SocketAddr) + Send + 'static {
+        self.unhandled_observer = Some(Box::new(observer));
+    }
+    
+    /// Removes the callback set by set_unhandled_observer.
+    pub fn clear_unhandled_observer(&mut self) {
+        self.unhandled_observer = None;
+    }
+    
+    /// Sets a callback which is invoked with the reason and source of every datagram the server drops,
+    /// including ones which fail to deserialize, e.g. to find out why clients can't connect.
+    /// With the "tracing" feature, drops are also logged as debug events.
+    pub fn set_drop_observer<F>(&mut self, observer: F) where F: FnMut(DropReason, SocketAddr) + Send + 'static {
+        self.drop_observer = Some(Box::new(observer));
+    }
+    
+    /// Removes the callback set by set_drop_observer.
+    pub fn clear_drop_observer(&mut self) {
+        self.drop_observer = None;
+    }
+    
+    /// Replaces the clock which keepalives and timeouts follow, e.g. with a clock::MockClock in tests.
+    /// The timeouts passed to the server's methods are still measured in real time. Timers which are already running
+    /// keep their deadlines, so the new clock should go on from the current time, as a new MockClock does.
+    pub fn set_clock<C>(&mut self, clock: C) where C: Clock + 'static {
+        self.clock = Arc::new(clock);
+    }
+    
+    // Reports a datagram from the given source which is dropped for the given reason.
+    fn dropped(&mut self, reason: DropReason, source: SocketAddr) {
+        trace_event!(debug, source = %source, reason = %reason, "server dropped datagram");
+        if let Some(observer) = &mut self.drop_observer {
+            observer(reason, source);
+        }
+    }
+    
+    // Returns the ID of the session this server is registered under
+    pub fn get_session_id(&self) -> &[u8] {
+        &self.session_id
+    }
+    
+    /// Returns the clients which completed the handshake and have been heard from within the client timeout.
+    pub fn connected_clients(&self) -> Vec<SocketAddr> {
+        let now = self.clock.now();
+        self.clients.iter()
+            .filter(|(_, last_seen)| now.saturating_duration_since(**last_seen) <= self.client_timeout)
+            .map(|(addr, _)| *addr)
+            .collect()
+    }
+    
+    /// Returns the address of the client which identified itself with the given handle (see PassiveClientConfig::handle).
+    /// If several clients claim the same handle, the last one heard from wins.
+    pub fn client_by_handle(&self, handle: &[u8]) -> Option<SocketAddr> {
+        return self.handles.get(handle).copied();
+    }
+    
+    /// Sends a datagram to the client which identified itself with the given handle, like send_datagram.
+    /// Returns Err(RuphinError::UnknownHandle) if no connected client has that handle.
+    pub fn send_to_client(&mut self, handle: &[u8], data: Vec<u8>) -> Result<(), RuphinError> {
+        match self.client_by_handle(handle) {
+            Some(addr) => {
+                return self.send_datagram(addr, data);
+            },
+            None => {
+                return Err(RuphinError::UnknownHandle);
+            }
+        }
+    }
+    
+    /// Forgets the clients which haven't been heard from for longer than idle_for, and returns their addresses,
+    /// e.g. to clean up their state. No PeerTimedOut events are reported for them.
+    /// An evicted client which is heard from again is reported as connected again.
+    pub fn evict_idle(&mut self, idle_for: Duration) -> Vec<SocketAddr> {
+        let now = self.clock.now();
+        let idle: Vec<SocketAddr> = self.clients.iter()
+            .filter(|(_, last_seen)| now.saturating_duration_since(**last_seen) > idle_for)
+            .map(|(addr, _)| *addr)
+            .collect();
+        for addr in &idle {
+            self.forget_client(*addr);
+        }
+        return idle;
+    }
+    
+    // Sends a datagram through the protocol socket to the given target
+    // Datagrams larger than MAX_DATA_SIZE are split into fragments, which the receiver puts back together.
+    // With the bytes feature, clones of one bytes::Bytes can be sent to many clients without copying the payload,
+    // unless it is encrypted or fragmented.
+    pub fn send_datagram(&mut self, to: SocketAddr, data: impl Into<Payload>) -> Result<(), RuphinError> {
+        let data: Payload = self.seal(data.into());
+        if data.len() <= MAX_DATA_SIZE {
+            let msg = Message::Data(DataContents {
+                data,
+                expires_at: self.data_ttl.map(DataContents::deadline_after),
+            });
+            
+            match self.send_to_peer(&msg, to) {
+                Ok(()) => {
+                    return Ok(());
+                },
+                Err(e) => {
+                    return Err(RuphinError::from(e));
+                }
+            }
+        }
+        
+        // too large for a single message, send it in fragments
+        let fragments = match fragment(&data, self.next_message_id, self.data_ttl.map(DataContents::deadline_after)) {
+            Ok(fragments) => fragments,
+            Err(()) => {
+                return Err(RuphinError::DatagramTooLarge {
+                    size: data.len(),
+                    max: MAX_FRAGMENTED_DATA_SIZE,
+                });
+            }
+        };
+        self.next_message_id = self.next_message_id.wrapping_add(1);
+        for msg in fragments {
+            match self.send_to_peer(&msg, to) {
+                Ok(()) => {},
+                Err(e) => {
+                    return Err(RuphinError::from(e));
+                }
+            }
+        }
+        return Ok(());
+    }
+    
+    /// Sends a datagram to the given client and waits until the client acknowledges it, retransmitting it in the meantime.
+    /// The client delivers the datagram only once, however often it is retransmitted.
+    /// Returns Err(RuphinError::Timeout) if no acknowledgement arrives within reliable_timeout;
+    /// the client may or may not have received the datagram in that case.
+    /// Reliable datagrams are not fragmented, so they can be at most MAX_DATA_SIZE bytes large.
+    /// Messages received in the meantime are kept and handled by subsequent calls to wait_for_event or wait_for_data.
+    pub fn send_reliable(&mut self, to: SocketAddr, data: Vec<u8>) -> Result<(), RuphinError> {
+        let data = self.seal(data);
+        if data.len() > MAX_DATA_SIZE {
+            return Err(RuphinError::DatagramTooLarge {
+                size: data.len(),
+                max: MAX_DATA_SIZE,
+            });
+        }
+        let seq = self.next_seq;
+        self.next_seq = self.next_seq.wrapping_add(1);
+        let msg = Message::ReliableData(ReliableDataContents {
+            seq,
+            data,
+        });
+        
+        let return_at = deadline_after(Instant::now(), self.reliable_timeout);
+        let mut next_retry_at = Instant::now();
+        loop {
+            let mut now = Instant::now();
+            if now >= return_at {
+                self.proto_socket.set_read_timeout(None).unwrap();
+                let unacked = self.unacked_reliable.entry(to).or_insert(0);
+                *unacked += 1;
+                if Some(*unacked) == self.max_unacked_reliable {
+                    // the client stopped acknowledging, it's most likely gone
+                    self.forget_client(to);
+                    self.pending_events.push_back(Event::PeerLost(to));
+                }
+                return Err(RuphinError::Timeout);
+            }
+            
+            // (re)transmit the datagram
+            if now >= next_retry_at {
+                match self.send_to_peer(&msg, to) {
+                    Ok(()) => {},
+                    Err(e) if !e.is_fatal() => {
+                        // transient error, the next retransmission will try again
+                    },
+                    Err(e) => {
+                        return Err(RuphinError::from(e));
+                    }
+                };
+                now = Instant::now();
+                next_retry_at = deadline_after(now, self.reliable_retry_interval);
+            }
+            
+            // wait for the acknowledgement until the next retransmission is due
+            let next_wakeup = next_retry_at.min(return_at);
+            if next_wakeup <= now {
+                continue;
+            }
+            self.proto_socket.set_read_timeout(Some(next_wakeup - now)).unwrap();
+            
+            match self.receive() {
+                Ok((Message::DataAck(contents), source)) if source == to && contents.seq == seq => {
+                    self.proto_socket.set_read_timeout(None).unwrap();
+                    self.unacked_reliable.remove(&to);
+                    return Ok(());
+                },
+                Ok((msg, source)) => {
+                    // keep everything else for wait_for_event
+                    self.deferred.push_back((msg, source));
+                },
+                Err(e) => {
+                    if e.is_fatal() {
+                        // fatal error, return
+                        return Err(RuphinError::from(e));
+                    } else {
+                        // nonfatal error, likely a timeout. Ignore and retry.
+                        continue;
+                    }
+                }
+            }
+        }
+    }
+    
+    /// Sends a datagram to every connected client.
+    /// The datagram is serialized only once and the same bytes are sent to each client.
+    /// With the bytes feature, a bytes::Bytes holding it is passed on without being copied.
+    /// A client the datagram can't be sent to doesn't keep it from the others: transient errors such as a full
+    /// send buffer are skipped, and the first fatal error is returned once every client has been tried.
+    pub fn broadcast(&mut self, data: impl Into<Payload>) -> Result<(), RuphinError> {
+        let data: Payload = self.seal(data.into());
+        let messages = if data.len() <= MAX_DATA_SIZE {
+            vec![Message::Data(DataContents {
+                data,
+                expires_at: self.data_ttl.map(DataContents::deadline_after),
+            })]
+        } else {
+            let fragments = match fragment(&data, self.next_message_id, self.data_ttl.map(DataContents::deadline_after)) {
+                Ok(fragments) => fragments,
+                Err(()) => {
+                    return Err(RuphinError::DatagramTooLarge {
+                        size: data.len(),
+                        max: MAX_FRAGMENTED_DATA_SIZE,
+                    });
+                }
+            };
+            self.next_message_id = self.next_message_id.wrapping_add(1);
+            fragments
+        };
+        
+        let mut serialized = Vec::with_capacity(messages.len());
+        for msg in messages {
+            match msg.serialize() {
+                Ok(bytes) => serialized.push(bytes),
+                Err(()) => {
+                    return Err(RuphinError::Serialization);
+                }
+            }
+        }
+        
+        let mut first_error = None;
+        // the wrapper for relayed clients, reused for each of them
+        let mut relay_buf = Vec::new();
+        for client in self.connected_clients() {
+            for bytes in &serialized {
+                let result = if self.relayed_clients.contains(&client) {
+                    // relayed clients get the same bytes, wrapped for the holepuncher
+                    if Message::serialize_relayed_into(&self.session_id, client, bytes, &mut relay_buf).is_err() {
+                        return Err(RuphinError::Serialization);
+                    }
+                    self.proto_socket.send_bytes_blocking(&relay_buf, self.holepuncher, self.send_retry_budget)
+                } else {
+                    self.proto_socket.send_bytes_blocking(bytes, client, self.send_retry_budget)
+                };
+                match result {
+                    Ok(()) => {},
+                    Err(e) if !e.is_fatal() => {
+                        // transient error, this client misses the datagram like any lost one
+                        break;
+                    },
+                    Err(e) => {
+                        // give up on this client, but not on the others
+                        if first_error.is_none() {
+                            first_error = Some(RuphinError::from(e));
+                        }
+                        break;
+                    }
+                }
+            }
+        }
+        match first_error {
+            Some(e) => {
+                return Err(e);
+            },
+            None => {
+                return Ok(());
+            }
+        }
+    }
+    
+    /// Changes the keepalive interval. The next keepalive is rescheduled to one interval from now.
+    /// Returns Err(RuphinError::InvalidConfig) if the interval is zero.
+    pub fn set_keepalive_interval(&mut self, interval: Duration) -> Result<(), RuphinError> {
+        if interval.is_zero() {
+            return Err(RuphinError::InvalidConfig("keepalive_interval must be non-zero"));
+        }
+        self.keepalive_interval = interval;
+        self.next_keepalive_at = deadline_after(self.clock.now(), jittered(interval, self.keepalive_jitter));
+        return Ok(());
+    }
+    
+    /// Tells the holepuncher to forget about this server's session.
+    /// Clients will no longer be able to join it; clients which are already connected are unaffected.
+    /// No more keepalives are sent to the holepuncher afterwards.
+    pub fn disconnect(&mut self) -> Result<(), RuphinError> {
+        let msg = Message::Goodbye(GoodbyeContents {
+            session_id: self.session_id.clone(),
+        });
+        match self.proto_socket.send_message(&msg, self.holepuncher) {
+            Ok(()) => {
+                self.deregistered = true;
+                return Ok(());
+            },
+            Err(e) => {
+                return Err(RuphinError::from(e));
+            }
+        }
+    }
+    
+    /// Hands the session over to a server at another address, e.g. the next process of a rolling deploy.
+    /// The holepuncher sends the new address to the clients which joined the session, and they switch over to it
+    /// without joining again (except for clients with connect_to_server set, which the holepuncher can't reach). The new server has to register the session from exactly new_addr afterwards,
+    /// so new_addr is its public address, e.g. a fixed port on a host which is not behind a NAT.
+    /// Until it is up, clients keep sending keepalives to new_addr and time out if it takes too long.
+    /// The holepuncher sends nothing to new_addr: the new server learns of the clients from these keepalives.
+    /// Like disconnect, the Migrate is sent once, and no more keepalives are sent to the holepuncher afterwards.
+    /// If it is lost, the session stays with this server and the new server's registration is rejected.
+    pub fn migrate(&mut self, new_addr: SocketAddr) -> Result<(), RuphinError> {
+        let msg = Message::Migrate(MigrateContents {
+            session_id: self.session_id.clone(),
+            new_addr,
+            mac: self.migrate_mac(new_addr),
+        });
+        match self.proto_socket.send_message(&msg, self.holepuncher) {
+            Ok(()) => {
+                self.deregistered = true;
+                return Ok(());
+            },
+            Err(e) => {
+                return Err(RuphinError::from(e));
+            }
+        }
+    }
+    
+    /// Removes the session from the holepuncher and closes the server.
+    /// Dropping the server does the same, but can't report errors.
+    pub fn shutdown(mut self) -> Result<(), RuphinError> {
+        return self.disconnect();
+    }
+    
+    /// Serve messages on the socket until you get a datagram from someone.
+    /// This method should be called regularly to ensure keepalives are sent, connection requests answered, etc.
+    /// If no data is received after a specified timeout, it returns Ok(None).
+    /// If a timeout of None is specified, this function will not return until it has data.
+    /// An exception to this is: If allow_interrupt is true, the function will return if it receives a LocalInterrupt message from localhost, again with Ok(None).
+    /// Events other than data are dropped; use wait_for_event to see them.
+    pub fn wait_for_data(&mut self, timeout: Option<Duration>, allow_interrupt: bool) -> Result<Option<(SocketAddr, Vec<u8>)>, RuphinError> {
+        let return_at = timeout.map(|timeout| deadline_after(Instant::now(), timeout));
+        loop {
+            let remaining = return_at.map(|return_at| return_at.saturating_duration_since(Instant::now()));
+            match self.wait_for_event(remaining, allow_interrupt)? {
+                Event::Data(source, data) => {
+                    return Ok(Some((source, data)));
+                },
+                Event::Interrupted | Event::Timeout => {
+                    return Ok(None);
+                },
+                _ => {
+                    // not interested in other events
+                    continue;
+                }
+            }
+        }
+    }
+    
+    /// Serve messages on the socket until something happens: a datagram arrives, a client connects or times out, etc.
+    /// This method should be called regularly to ensure keepalives are sent, connection requests answered, etc.
+    /// If nothing happens within the specified timeout, it returns Ok(Event::Timeout).
+    /// If a timeout of None is specified, this function will not return until there is an event.
+    /// A timeout too large to be added to the current time, such as Duration::MAX, is as good as None.
+    /// If allow_interrupt is true, the function returns Ok(Event::Interrupted) if it receives a LocalInterrupt message from localhost.
+    /// If holepuncher_timeout is configured and passes without hearing from the holepuncher, it returns Ok(Event::HolepuncherTimedOut) once.
+    pub fn wait_for_event(&mut self, timeout: Option<Duration>, allow_interrupt: bool) -> Result<Event, RuphinError> {
+        // this is the time when the function should return, in real time
+        let return_at = timeout.map(|timeout| deadline_after(Instant::now(), timeout));
+        // whether the last read found nothing to receive. Only then the function times out,
+        // so that even with a zero timeout, the messages already queued on the socket are handled.
+        let mut drained = false;
+        // whether received data is being queued up while more is waiting on the socket
+        let mut collecting_data = false;
+        
+        // await messages in a loop
+        loop {
+            // Represents the current time on the clock, which the keepalives and timeouts follow.
+            // Measured before instances of being used if there was a syscall or I/O operation since it was last measured.
+            let mut now = self.clock.now();
+            
+            // drop fragmented datagrams that will never be completed
+            self.reassembler.evict_expired(now);
+            // forget clients that stopped sending keepalives
+            self.prune_clients();
+            
+            // hand out events which happened earlier first, once the data waiting on the socket has been picked up
+            if !collecting_data {
+                if let Some(event) = self.pending_events.pop_front() {
+                    self.proto_socket.set_read_timeout(None).unwrap();
+                    return Ok(event);
+                }
+            }
+            
+            // Is it time to send a keepalive?
+            // After disconnecting, no keepalives are sent, they would register the session again.
+            if now > self.next_keepalive_at && self.is_registered() {
+                // send a keepalive (Register for my session) to the holepuncher
+                let msg = Message::Register(RegisterContents {
+                    session_id: self.session_id.clone(),
+                    metadata: self.config.metadata.clone(),
+                    candidates: self.candidates.clone(),
+                    mac: self.config.register_mac(&self.session_id, &self.candidates),
+                });
+                let addr = self.holepuncher;
+                
+                match self.proto_socket.send_message(&msg, addr) {
+                    Ok(()) => {},
+                    Err(e) if !e.is_fatal() => {
+                        // transient error, skip this keepalive, the next one is sent as scheduled
+                    },
+                    Err(e) => {
+                        return Err(RuphinError::from(e));
+                    }
+                };
+                // We did an I/O operation, so re-measure the current time.
+                now = self.clock.now();
+                
+                // schedule the next keepalive
+                self.next_keepalive_at = deadline_after(now, jittered(self.keepalive_interval, self.keepalive_jitter));
+            }
+            
+            // Has the holepuncher stopped acknowledging keepalives?
+            let holepuncher_deadline = self.holepuncher_deadline();
+            if let Some(holepuncher_deadline) = holepuncher_deadline {
+                if now > holepuncher_deadline {
+                    self.holepuncher_timed_out = true;
+                    self.proto_socket.set_read_timeout(None).unwrap();
+                    return Ok(Event::HolepuncherTimedOut(self.holepuncher));
+                }
+            }
+            
+            // Is it time to return?
+            if let Some(return_at) = return_at {
+                if Instant::now() > return_at && drained {
+                    self.proto_socket.set_read_timeout(None).unwrap();
+                    return Ok(Event::Timeout);
+                }
+            }
+            
+            // determine the next wakeup time on the clock: when it's time for the next keepalive
+            let next_wakeup = self.next_keepalive_at;
+            // also wake up in time to notice the holepuncher going silent
+            let next_wakeup = match holepuncher_deadline {
+                Some(holepuncher_deadline) if holepuncher_deadline < next_wakeup => holepuncher_deadline,
+                _ => next_wakeup,
+            };
+            // Return before then if the timeout runs out first
+            let wait = match return_at {
+                Some(return_at) => next_wakeup.saturating_duration_since(now).min(return_at.saturating_duration_since(Instant::now())),
+                None => next_wakeup.saturating_duration_since(now),
+            };
+            
+            // determine how much time we give the socket to wait for messages
+            let socket_time = {
+                if wait.is_zero() || collecting_data {
+                    // no time, only pick up a message if one is queued
+                    None
+                } else {
+                    // roughly until the next wakeup
+                    Some(wait)
+                }
+            };
+            
+            // set the timeout on the socket
+            match socket_time {
+                Some(socket_time) => self.proto_socket.set_read_timeout(Some(socket_time)).unwrap(),
+                None => self.proto_socket.set_nonblocking(true).unwrap(),
+            };
+            
+            // await the next message, handling the ones which arrived during send_reliable first
+            let received = match self.deferred.pop_front() {
+                Some(deferred) => Ok(deferred),
+                None => self.receive(),
+            };
+            if socket_time.is_none() {
+                self.proto_socket.set_nonblocking(false).unwrap();
+            }
+            drained = match &received {
+                Err(e) => e.is_timeout(),
+                Ok(_) => false,
+            };
+            if drained {
+                collecting_data = false;
+            }
+            match received {
+                Ok((Message::HelloReq(contents), source)) => {
+                    // a client is connecting or sending a keepalive
+                    if source != self.holepuncher {
+                        self.touch_client(source);
+                        if !contents.handle.is_empty() {
+                            self.handles.insert(contents.handle, source);
+                        }
+                    }
+                    // send the source a HelloResp, proposing our keepalive interval
+                    let resp = Message::HelloResp(HelloRespContents::proposing(self.keepalive_interval));
+                    match self.send_to_peer(&resp, source) {
+                        Ok(()) => {},
+                        Err(e) if !e.is_fatal() => {
+                            // transient error, the peer will ask again
+                        },
+                        Err(e) => {
+                            return Err(RuphinError::from(e));
+                        }
+                    };
+                },
+                Ok((Message::HelloResp(_), source)) => {
+                    // a client answered our HelloReq
+                    if source != self.holepuncher {
+                        self.touch_client(source);
+                    }
+                },
+                Ok((Message::Goodbye(contents), source)) => {
+                    // a client is leaving the session
+                    if contents.session_id != self.session_id {
+                        self.dropped(DropReason::WrongSession, source);
+                    } else if !self.clients.contains_key(&source) {
+                        self.dropped(DropReason::UnknownPeer, source);
+                    } else {
+                        self.forget_client(source);
+                        self.proto_socket.set_read_timeout(None).unwrap();
+                        return Ok(Event::PeerDisconnected(source));
+                    }
+                },
+                Ok((Message::Ping(contents), source)) => {
+                    // answer with a Pong carrying the same nonce
+                    let response = Message::Pong(PongContents {
+                        nonce: contents.nonce,
+                    });
+                    match self.send_to_peer(&response, source) {
+                        Ok(()) => {},
+                        Err(e) if !e.is_fatal() => {
+                            // transient error, the peer will ask again
+                        },
+                        Err(e) => {
+                            return Err(RuphinError::from(e));
+                        }
+                    };
+                },
+                Ok((Message::PeerInfo(_), source)) if source != self.holepuncher => {
+                    // PeerInfos are only trusted from the holepuncher
+                    self.dropped(DropReason::WrongSource, source);
+                },
+                Ok((Message::PeerInfo(contents), source)) if contents.session_id != self.session_id => {
+                    self.dropped(DropReason::WrongSession, source);
+                },
+                Ok((Message::PeerInfo(contents), source)) if !self.config.is_authentic_peer_info(&contents) => {
+                    self.dropped(DropReason::Unauthenticated, source);
+                },
+                Ok((Message::PeerInfo(contents), _)) => {
+                    // a client joined our session, send a HelloReq to each of its addresses, once.
+                    // a dual-stack holepuncher may hand out an address of the other family.
+                    // If we can't reach it, the client's HelloReqs may still reach us, or it falls back to the relay.
+                    let addrs = std::iter::once(contents.peer_addr).chain(contents.candidates);
+                    for peer_addr in addrs.filter_map(|addr| self.proto_socket.reachable_addr(addr)) {
+                        let result = match self.initial_hello_ttl {
+                            Some(ttl) => self.proto_socket.send_message_with_ttl(&Message::HelloReq(HelloReqContents::default()), peer_addr, ttl),
+                            None => self.proto_socket.send_message(&Message::HelloReq(HelloReqContents::default()), peer_addr),
+                        };
+                        match result {
+                            Ok(()) => {},
+                            Err(e) if !e.is_fatal() => {
+                                // transient error, the peer's own HelloReqs can still open the path
+                            },
+                            Err(e) => {
+                                return Err(RuphinError::from(e));
+                            }
+                        };
+                    }
+                },
+                Ok((Message::Join(contents), source)) if self.answer_lan_joins && contents.session_id != self.session_id => {
+                    // looking for another server on the LAN
+                    self.dropped(DropReason::WrongSession, source);
+                },
+                Ok((Message::Join(contents), source)) if self.answer_lan_joins && !self.config.is_authentic_join(&contents) => {
+                    self.dropped(DropReason::Unauthenticated, source);
+                },
+                Ok((Message::Join(_), source)) if self.answer_lan_joins => {
+                    // a client on the LAN is looking for us, tell it our address on the interface which reaches it.
+                    // It goes on with the HelloReq/HelloResp handshake as if the holepuncher had answered.
+                    if let Some(addr) = self.proto_socket.routed_addr(source) {
+                        let info = Message::PeerInfo(PeerInfoContents {
+                            session_id: self.session_id.clone(),
+                            peer_addr: addr,
+                            candidates: Vec::new(),
+                            mac: self.config.peer_info_mac(&self.session_id, addr),
+                        });
+                        match self.proto_socket.send_message(&info, source) {
+                            Ok(()) => {},
+                            Err(e) if !e.is_fatal() => {
+                                // transient error, the client broadcasts again
+                            },
+                            Err(e) => {
+                                return Err(RuphinError::from(e));
+                            }
+                        };
+                    }
+                },
+                Ok((Message::RegisterAck(_), source)) if source != self.holepuncher => {
+                    self.dropped(DropReason::WrongSource, source);
+                },
+                Ok((Message::RegisterAck(contents), source)) if contents.session_id != self.session_id => {
+                    self.dropped(DropReason::WrongSession, source);
+                },
+                Ok((Message::RegisterAck(contents), _)) => {
+                    // a keepalive was acknowledged, our public address may have changed since the last one
+                    if contents.mapped_addr.is_some() {
+                        self.external_addr = contents.mapped_addr;
+                    }
+                },
+                Ok((msg, source)) if matches!(msg, Message::Data(_) | Message::ReliableData(_) | Message::DataFragment(_))
+                    && !self.clients.contains_key(&source) => {
+                    // data from a peer which never completed the HelloReq/HelloResp handshake,
+                    // e.g. a client of an earlier session on the same port. Drop it.
+                    self.dropped(DropReason::UnknownPeer, source);
+                    if let Some(observer) = &mut self.unhandled_observer {
+                        observer(&msg, source);
+                    }
+                },
+                Ok((Message::Data(contents), source)) if contents.is_expired() => {
+                    // the data sat in a queue or relay for too long and is of no use anymore, drop it
+                    self.dropped(DropReason::Expired, source);
+                },
+                Ok((Message::Data(contents), source)) => {
+                    // got some data from a connected client, queue it up to be returned
+                    if let Some(data) = self.open(contents.into_vec(), source) {
+                        collecting_data = self.queue_data(Event::Data(source, data));
+                    }
+                },
+                Ok((Message::ReliableData(contents), source)) => {
+                    // acknowledge every copy, the acknowledgement of an earlier one may have been lost
+                    let ack = Message::DataAck(DataAckContents {
+                        seq: contents.seq,
+                    });
+                    match self.send_to_peer(&ack, source) {
+                        Ok(()) => {},
+                        Err(e) if !e.is_fatal() => {
+                            // transient error, the peer will retransmit
+                        },
+                        Err(e) => {
+                            return Err(RuphinError::from(e));
+                        }
+                    };
+                    // but return only the first one
+                    if self.duplicate_filter.insert(source, contents.seq) {
+                        if let Some(data) = self.open(contents.data, source) {
+                            collecting_data = self.queue_data(Event::Data(source, data));
+                        }
+                    }
+                },
+                Ok((Message::DataFragment(contents), source)) if contents.is_expired() => {
+                    // a piece of a datagram which is of no use anymore, drop it
+                    self.dropped(DropReason::Expired, source);
+                },
+                Ok((Message::DataFragment(contents), source)) => {
+                    // got a piece of a larger datagram, return it once it's complete
+                    if let Some(data) = self.reassembler.insert(source, contents, self.clock.now()).and_then(|data| self.open(data, source)) {
+                        collecting_data = self.queue_data(Event::Data(source, data));
+                    }
+                },
+                Ok((Message::LocalInterrupt, source)) if allow_interrupt => {
+                    // received a local interrupt and interrupts are allowed
+                    // check that the source is localhost. If yes, return Ok(Event::Interrupted). Otherwise ignore.
+                    if source.ip().is_loopback() {
+                        self.proto_socket.set_read_timeout(None).unwrap();
+                        return Ok(Event::Interrupted);
+                    } else {
+                        self.dropped(DropReason::WrongSource, source);
+                        continue;
+                    }
+                },
+                Ok((msg, source)) => {
+                    // another message was received, ignore it
+                    self.dropped(DropReason::UnexpectedMessage, source);
+                    if let Some(observer) = &mut self.unhandled_observer {
+                        observer(&msg, source);
+                    }
+                    continue;
+                },
+                Err(e) => {
+                    if e.is_fatal() {
+                        // fatal error, return
+                        return Err(RuphinError::from(e));
+                    } else {
+                        // nonfatal error, likely a timeout. Ignore and retry.
+                        continue;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Drop for PassiveServer {
+    fn drop(&mut self) {
+        if self.is_registered() {
+            // best effort, the session expires at the holepuncher eventually anyway
+            let _ = self.disconnect();
+        }
+    }
+}
+
+/// The socket can be registered with an external poller (epoll, kqueue, etc.) instead of blocking in wait_for_event.
+/// Once it is readable, call wait_for_event with a timeout of zero: it handles the queued messages without blocking,
+/// and returns Event::Timeout once there are none left. It still has to be called regularly to send keepalives.
+#[cfg(unix)]
+impl std::os::unix::io::AsRawFd for PassiveServer {
+    fn as_raw_fd(&self) -> std::os::unix::io::RawFd {
+        self.proto_socket.as_raw_fd()
+    }
+}
+
+/// See the AsRawFd implementation on Unix.
+#[cfg(windows)]
+impl std::os::windows::io::AsRawSocket for PassiveServer {
+    fn as_raw_socket(&self) -> std::os::windows::io::RawSocket {
+        self.proto_socket.as_raw_socket()
+    }
+}
@@ -1,101 +1,696 @@
-use std::net::{
-    UdpSocket,
-    SocketAddr,
-};
-use std::time::Duration;
+use std::net::{
+    IpAddr,
+    UdpSocket,
+    SocketAddr,
+    ToSocketAddrs,
+};
+use std::time::{
+    Duration,
+    Instant,
+};
+use std::sync::atomic::{
+    AtomicBool,
+    AtomicU64,
+    Ordering,
+};
+use std::sync::{
+    Arc,
+    Mutex,
+};
 use crate::messages::*;
-use std::io::ErrorKind;
-
-pub struct ProtocolSocket {
-    udp_sock: UdpSocket,
-}
-
+use crate::transport::Transport;
+use crate::error::RuphinError;
+use crate::clock::{
+    deadline_after,
+    Clock,
+    SystemClock,
+};
+use std::io::ErrorKind;
+
+// time to wait before retrying a send which failed because the OS send buffer was full
+const SEND_RETRY_DELAY: Duration = Duration::from_millis(1);
+
+// A ProtocolSocket can be shared between threads. get_message reads into a buffer owned by the socket,
+// one more byte than the largest message, so that oversized datagrams can be told apart from truncated ones.
+// While one thread is receiving into it, other threads receive into a temporary buffer instead of waiting.
+pub struct ProtocolSocket {
+    transport: Box<dyn Transport>,
+    // shared with clones of the socket, so that the stats cover all of them
+    counters: Arc<Counters>,
+    // whether the socket was put into non-blocking mode with set_nonblocking, shared with clones like the mode itself
+    nonblocking: Arc<AtomicBool>,
+    recv_buf: Mutex<Vec<u8>>,
+    send_buf: Mutex<Vec<u8>>,
+}
+
+/// Snapshot of a socket's traffic counters
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SocketStats {
+    /// Messages sent successfully
+    pub messages_sent: u64,
+    /// Messages received and deserialized successfully
+    pub messages_received: u64,
+    /// Bytes sent, including headers and checksums
+    pub bytes_sent: u64,
+    /// Bytes received, including datagrams which could not be deserialized
+    pub bytes_received: u64,
+    /// Received datagrams which were not valid messages
+    pub deserialization_failures: u64,
+}
+
+// live counters behind SocketStats
+#[derive(Default)]
+struct Counters {
+    messages_sent: AtomicU64,
+    messages_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    deserialization_failures: AtomicU64,
+}
+
+// Resolves the address of a holepuncher, e.g. a host name and port.
+// The addresses are grouped by address family, in the order the resolver prefers them, since a socket can only talk to one family.
+// Addresses of the other family than bind_addr, if given, are left out.
+pub(crate) fn resolve_by_family(addr: impl ToSocketAddrs, bind_addr: Option<SocketAddr>)
+    -> Result<Vec<Vec<SocketAddr>>, RuphinError> {
+    let addrs = match addr.to_socket_addrs() {
+        Ok(addrs) => addrs,
+        Err(e) => {
+            return Err(RuphinError::Resolve(e));
+        }
+    };
+    let mut families: Vec<Vec<SocketAddr>> = Vec::new();
+    for addr in addrs {
+        if let Some(bind_addr) = bind_addr {
+            if bind_addr.is_ipv6() != addr.is_ipv6() {
+                continue;
+            }
+        }
+        match families.iter_mut().find(|family| family[0].is_ipv6() == addr.is_ipv6()) {
+            Some(family) => family.push(addr),
+            None => families.push(vec![addr]),
+        }
+    }
+    if families.is_empty() {
+        return Err(RuphinError::NoUsableAddress);
+    }
+    return Ok(families);
+}
+
 // generic error type for ProtocolSocket send errors
-#[derive(Debug)]
-pub enum SendError {
-    SerializationFailed,
-    IO(std::io::Error),
-    IncompleteSend(usize),
-}
-
+#[derive(Debug)]
+pub enum SendError {
+    SerializationFailed,
+    // the message carries more data than fits into it, see Message::oversized_payload
+    MessageTooLarge { size: usize, max: usize },
+    IO(std::io::Error),
+    IncompleteSend(usize),
+}
+
 // generic error type for ProtocolSocket receive errors
-#[derive(Debug)]
-pub enum ReceiveError {
-    DeserializationFailed,
-    IO(std::io::Error),
-}
-
-impl ReceiveError {
-    pub fn is_fatal(&self) -> bool {
-        if let ReceiveError::IO(io_err) = self {
-            return match io_err.kind() {
-                ErrorKind::WouldBlock => false,
-                ErrorKind::TimedOut => false,
-                ErrorKind::Interrupted => false,
-                _ => true,
-            };
-        }
-        return true;
-    }
-}
-
-impl ProtocolSocket {
-    pub fn bind(bind_addr: &str) -> Result<Self, std::io::Error> {
-        let udp_sock = UdpSocket::bind(bind_addr)?;
-        Ok(Self {
-            udp_sock,
-        })
-    }
-
-    pub fn get_message(&self) -> Result<(Message, SocketAddr), ReceiveError> {
-        let mut buf = [0u8; 65536];
-
-        let (size, source) = match self.udp_sock.recv_from(&mut buf) {
-            Ok(x) => x,
-            Err(e) => {
-                return Err(ReceiveError::IO(e));
-            }
-        };
-
-        let msg = match Message::deserialize(&buf[0..size]) {
-            Ok(msg) => msg,
-            Err(()) => {
-                return Err(ReceiveError::DeserializationFailed);
-            }
-        };
-
-        return Ok((msg, source));
-    }
-
-    pub fn send_message(&self, msg: &Message, dest: SocketAddr) -> Result<(), SendError>{
-        let bytes = match msg.serialize() {
-            Ok(data) => data,
-            Err(_) => return Err(SendError::SerializationFailed),
-        };
-
-        match self.udp_sock.send_to(&bytes[..], dest) {
-            Ok(num_bytes) if num_bytes == bytes.len() => {
-                return Ok(());
-            },
-            Err(e) => {
-                return Err(SendError::IO(e));
-            },
-            Ok(n) => {
-                return Err(SendError::IncompleteSend(n));
-            },
-        };
-    }
-    
-    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), std::io::Error>  {
-        self.udp_sock.set_read_timeout(timeout)
-    }
-    
-    // Get the listening port of the socket.
-    // Returns Err if the local address cannot be obtained.
-    pub fn get_port(&self) -> Result<u16, ()> {
-        match self.udp_sock.local_addr() {
-            Ok(addr) => Ok(addr.port()),
-            Err(_) => Err(()),
-        }
-    }
-}
\ No newline at end of file
+#[derive(Debug)]
+pub enum ReceiveError {
+    DeserializationFailed,
+    ChecksumMismatch,
+    BadMagic,
+    // the datagram did not fit into the receive buffer
+    BufferTooSmall,
+    // the datagram is larger than any valid message
+    TooLarge,
+    IO(std::io::Error),
+}
+
+impl SendError {
+    // The error for a message which could not be serialized: MessageTooLarge if its payload is too large,
+    // SerializationFailed for anything else (e.g. an overlong session ID)
+    pub fn serialization(msg: &Message) -> Self {
+        match msg.oversized_payload() {
+            Some((size, max)) => {
+                return SendError::MessageTooLarge { size, max };
+            },
+            None => {
+                return SendError::SerializationFailed;
+            }
+        }
+    }
+
+    // Transient errors which are worth retrying the send for
+    pub fn is_fatal(&self) -> bool {
+        match self {
+            SendError::IO(io_err) => {
+                return !matches!(io_err.kind(), ErrorKind::WouldBlock | ErrorKind::Interrupted);
+            },
+            _ => true,
+        }
+    }
+}
+
+impl ReceiveError {
+    // Nothing arrived before the read timeout, or nothing was queued on a non-blocking socket
+    pub fn is_timeout(&self) -> bool {
+        match self {
+            ReceiveError::IO(io_err) => {
+                return matches!(io_err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut);
+            },
+            _ => false,
+        }
+    }
+
+    pub fn is_fatal(&self) -> bool {
+        match self {
+            ReceiveError::IO(io_err) => {
+                return match io_err.kind() {
+                    ErrorKind::WouldBlock => false,
+                    ErrorKind::TimedOut => false,
+                    ErrorKind::Interrupted => false,
+                    // An ICMP "port unreachable" answering an earlier send, reported by the next receive:
+                    // on Windows for any UDP socket (WSAECONNRESET), elsewhere for connected ones.
+                    // It concerns that one peer, not the socket.
+                    ErrorKind::ConnectionReset => false,
+                    ErrorKind::ConnectionRefused => false,
+                    _ => true,
+                };
+            },
+            // a corrupted or foreign datagram only affects itself, keep going
+            ReceiveError::ChecksumMismatch => false,
+            ReceiveError::BadMagic => false,
+            ReceiveError::TooLarge => false,
+            _ => true,
+        }
+    }
+}
+
+impl ProtocolSocket {
+    pub fn bind(bind_addr: impl ToSocketAddrs) -> Result<Self, std::io::Error> {
+        let udp_sock = UdpSocket::bind(bind_addr)?;
+        Ok(Self::with_transport(udp_sock))
+    }
+
+    // Creates a protocol socket on top of the given transport, e.g. a MemoryTransport for tests.
+    pub fn with_transport<T: Transport + 'static>(transport: T) -> Self {
+        Self {
+            transport: Box::new(transport),
+            counters: Arc::new(Counters::default()),
+            nonblocking: Arc::new(AtomicBool::new(false)),
+            recv_buf: Mutex::new(vec![0u8; MAX_MESSAGE_SIZE + 1]),
+            send_buf: Mutex::new(Vec::with_capacity(MAX_MESSAGE_SIZE)),
+        }
+    }
+
+    // Returns a second handle to the same socket, e.g. for receiving on several threads.
+    // Socket options such as the read timeout are shared, the traffic counters too.
+    pub fn try_clone(&self) -> Result<Self, std::io::Error> {
+        Ok(Self {
+            transport: self.transport.try_clone()?,
+            counters: self.counters.clone(),
+            nonblocking: self.nonblocking.clone(),
+            recv_buf: Mutex::new(vec![0u8; MAX_MESSAGE_SIZE + 1]),
+            send_buf: Mutex::new(Vec::with_capacity(MAX_MESSAGE_SIZE)),
+        })
+    }
+
+    pub fn get_message(&self) -> Result<(Message, SocketAddr), ReceiveError> {
+        return self.get_message_with_source().map_err(|(e, _)| e);
+    }
+
+    // Receives a message into a caller-provided buffer, so that it can be reused across calls.
+    // Returns ReceiveError::BufferTooSmall if the datagram does not fit into the buffer.
+    pub fn get_message_into(&self, buf: &mut [u8]) -> Result<(Message, SocketAddr), ReceiveError> {
+        return self.receive_into(buf).map_err(|(e, _)| e);
+    }
+
+    // Like get_message, but a datagram which can't be deserialized also comes with the address it arrived from,
+    // so that the drop can be reported.
+    pub(crate) fn get_message_with_source(&self) -> Result<(Message, SocketAddr), (ReceiveError, Option<SocketAddr>)> {
+        return self.with_recv_buf(|buf| self.receive_into(buf));
+    }
+
+    // Like get_message, but also accepts datagrams carrying several messages (see send_batch), and returns all of them.
+    // A datagram carrying a single message is returned as a batch of one.
+    pub fn get_messages(&self) -> Result<(Vec<Message>, SocketAddr), ReceiveError> {
+        return self.with_recv_buf(|buf| {
+            let (size, source) = self.receive_datagram(buf)?;
+            let msgs = match Message::deserialize_batch(&buf[0..size]) {
+                Ok(msgs) => msgs,
+                Err(e) => {
+                    return Err((self.deserialization_failed(e), Some(source)));
+                }
+            };
+            self.counters.messages_received.fetch_add(msgs.len() as u64, Ordering::Relaxed);
+            return Ok((msgs, source));
+        }).map_err(|(e, _)| e);
+    }
+
+    // Runs f with the socket's receive buffer.
+    fn with_recv_buf<T, F>(&self, f: F) -> T where F: FnOnce(&mut [u8]) -> T {
+        match self.recv_buf.try_lock() {
+            Ok(mut buf) => {
+                return f(&mut buf);
+            },
+            Err(_) => {
+                // another thread is receiving, don't wait for it
+                let mut buf = vec![0u8; MAX_MESSAGE_SIZE + 1];
+                return f(&mut buf);
+            }
+        }
+    }
+
+    // Receives a message into the buffer. Errors come with the source of the datagram, if one was received.
+    fn receive_into(&self, buf: &mut [u8]) -> Result<(Message, SocketAddr), (ReceiveError, Option<SocketAddr>)> {
+        let (size, source) = self.receive_datagram(buf)?;
+        let msg = match Message::deserialize(&buf[0..size]) {
+            Ok(msg) => msg,
+            Err(e) => {
+                return Err((self.deserialization_failed(e), Some(source)));
+            }
+        };
+        self.counters.messages_received.fetch_add(1, Ordering::Relaxed);
+
+        return Ok((msg, source));
+    }
+
+    // Receives a datagram into the buffer, returning its size and source. Truncated datagrams are rejected.
+    fn receive_datagram(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr), (ReceiveError, Option<SocketAddr>)> {
+        if buf.len() < HEADER_SIZE + CHECKSUM_SIZE {
+            // no message can fit into this buffer
+            return Err((ReceiveError::BufferTooSmall, None));
+        }
+
+        let (size, source) = match self.transport.recv_from(buf) {
+            Ok(x) => x,
+            Err(e) => {
+                return Err((ReceiveError::IO(e), None));
+            }
+        };
+        self.counters.bytes_received.fetch_add(size as u64, Ordering::Relaxed);
+
+        // the OS silently truncates datagrams which don't fit, but the header tells us the real size
+        if size == buf.len() && buf[0..MAGIC.len()] == MAGIC {
+            let declared_len = (usize::from(buf[2]) << 8) | usize::from(buf[3]);
+            if declared_len > MAX_MESSAGE_SIZE {
+                self.counters.deserialization_failures.fetch_add(1, Ordering::Relaxed);
+                return Err((ReceiveError::TooLarge, Some(source)));
+            }
+            if declared_len > size {
+                self.counters.deserialization_failures.fetch_add(1, Ordering::Relaxed);
+                return Err((ReceiveError::BufferTooSmall, Some(source)));
+            }
+        }
+        return Ok((size, source));
+    }
+
+    // Counts a datagram which could not be deserialized, and returns the matching error.
+    fn deserialization_failed(&self, e: DeserializeError) -> ReceiveError {
+        self.counters.deserialization_failures.fetch_add(1, Ordering::Relaxed);
+        return match e {
+            DeserializeError::BadMagic => ReceiveError::BadMagic,
+            DeserializeError::ChecksumMismatch => ReceiveError::ChecksumMismatch,
+            DeserializeError::Malformed => ReceiveError::DeserializationFailed,
+            DeserializeError::TooLarge => ReceiveError::TooLarge,
+        };
+    }
+
+    // Runs f with the socket in non-blocking mode, then puts it back into the mode it was in before.
+    fn without_blocking<T, F>(&self, f: F) -> Result<T, std::io::Error> where F: FnOnce() -> T {
+        if self.nonblocking.load(Ordering::Relaxed) {
+            return Ok(f());
+        }
+        self.transport.set_nonblocking(true)?;
+        let result = f();
+        self.transport.set_nonblocking(false)?;
+        return Ok(result);
+    }
+
+    // Receives a message if one is waiting, without blocking.
+    // Returns Ok(None) if there is nothing to read. The read timeout and the blocking mode of the socket are left untouched.
+    pub fn try_get_message(&self) -> Result<Option<(Message, SocketAddr)>, ReceiveError> {
+        let result = match self.without_blocking(|| self.get_message()) {
+            Ok(result) => result,
+            Err(e) => {
+                return Err(ReceiveError::IO(e));
+            }
+        };
+        match result {
+            Ok(received) => {
+                return Ok(Some(received));
+            },
+            Err(ReceiveError::IO(e)) if e.kind() == ErrorKind::WouldBlock => {
+                return Ok(None);
+            },
+            Err(e) => {
+                return Err(e);
+            }
+        }
+    }
+
+    // Reads and discards every datagram currently queued on the socket, without blocking.
+    // Returns the number of datagrams discarded. The blocking mode of the socket is left untouched.
+    pub fn drain(&self) -> Result<usize, std::io::Error> {
+        return self.without_blocking(|| self.discard_queued())?;
+    }
+
+    // Reads and discards datagrams until the socket reports WouldBlock. The socket must be non-blocking.
+    fn discard_queued(&self) -> Result<usize, std::io::Error> {
+        let mut buf = [0u8; MAX_MESSAGE_SIZE + 1];
+        let mut discarded = 0;
+        loop {
+            match self.transport.recv_from(&mut buf) {
+                Ok((size, _)) => {
+                    self.counters.bytes_received.fetch_add(size as u64, Ordering::Relaxed);
+                    discarded += 1;
+                },
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    return Ok(discarded);
+                },
+                Err(e) if e.kind() == ErrorKind::Interrupted => {
+                    continue;
+                },
+                Err(e) => {
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    // Serializes a message into the socket's scratch buffer and passes the bytes to send.
+    // If another thread is using the scratch buffer, a temporary one is used instead.
+    fn send_serialized<F>(&self, msg: &Message, send: F) -> Result<(), SendError>
+        where F: FnOnce(&[u8]) -> Result<(), SendError> {
+        let mut temp_buf;
+        let mut guard = self.send_buf.try_lock();
+        let buf = match &mut guard {
+            Ok(buf) => &mut **buf,
+            Err(_) => {
+                temp_buf = Vec::new();
+                &mut temp_buf
+            }
+        };
+        if msg.serialize_into(buf).is_err() {
+            return Err(SendError::serialization(msg));
+        }
+        return send(&buf[..]);
+    }
+
+    pub fn send_message(&self, msg: &Message, dest: SocketAddr) -> Result<(), SendError>{
+        return self.send_serialized(msg, |bytes| self.send_bytes(bytes, dest));
+    }
+    
+    // Sends several messages in a single datagram (see Message::serialize_batch), e.g. a Register and a Ping
+    // to save a round trip. The receiver has to use get_messages, get_message rejects such datagrams.
+    pub fn send_batch(&self, msgs: &[Message], dest: SocketAddr) -> Result<(), SendError> {
+        match Message::serialize_batch(msgs) {
+            Ok(bytes) => {
+                return self.send_bytes(&bytes, dest);
+            },
+            Err(()) => {
+                return Err(SendError::SerializationFailed);
+            }
+        }
+    }
+    
+    // Sends an already serialized message.
+    // Useful for sending the same message to several destinations without serializing it each time.
+    pub fn send_bytes(&self, bytes: &[u8], dest: SocketAddr) -> Result<(), SendError> {
+        match self.transport.send_to(bytes, dest) {
+            Ok(num_bytes) if num_bytes == bytes.len() => {
+                self.counters.messages_sent.fetch_add(1, Ordering::Relaxed);
+                self.counters.bytes_sent.fetch_add(num_bytes as u64, Ordering::Relaxed);
+                return Ok(());
+            },
+            Err(e) => {
+                return Err(SendError::IO(e));
+            },
+            Ok(n) => {
+                return Err(SendError::IncompleteSend(n));
+            },
+        };
+    }
+    
+    // Sends a message like send_message, but if the OS send buffer is full (WouldBlock),
+    // waits briefly and retries until the budget is used up. A zero budget sends only once.
+    pub fn send_message_blocking(&self, msg: &Message, dest: SocketAddr, budget: Duration) -> Result<(), SendError> {
+        return self.send_serialized(msg, |bytes| self.send_bytes_blocking(bytes, dest, budget));
+    }
+    
+    // Sends an already serialized message, retrying while the OS send buffer is full like send_message_blocking.
+    pub fn send_bytes_blocking(&self, bytes: &[u8], dest: SocketAddr, budget: Duration) -> Result<(), SendError> {
+        let give_up_at = deadline_after(Instant::now(), budget);
+        loop {
+            match self.send_bytes(bytes, dest) {
+                Err(SendError::IO(e)) if e.kind() == ErrorKind::WouldBlock && Instant::now() < give_up_at => {
+                    // give the OS some time to drain the send buffer
+                    std::thread::sleep(SEND_RETRY_DELAY.min(give_up_at.saturating_duration_since(Instant::now())));
+                },
+                result => {
+                    return result;
+                }
+            }
+        }
+    }
+    
+    // Connects the socket to a single peer. Afterwards the OS drops datagrams from anyone else,
+    // and send_message_connected can be used instead of send_message.
+    pub fn connect(&self, peer: SocketAddr) -> Result<(), std::io::Error> {
+        self.transport.connect(peer)
+    }
+    
+    // Sends a message to the peer the socket is connected to.
+    pub fn send_message_connected(&self, msg: &Message) -> Result<(), SendError> {
+        return self.send_serialized(msg, |bytes| {
+            match self.transport.send(bytes) {
+                Ok(num_bytes) if num_bytes == bytes.len() => {
+                    self.counters.messages_sent.fetch_add(1, Ordering::Relaxed);
+                    self.counters.bytes_sent.fetch_add(num_bytes as u64, Ordering::Relaxed);
+                    return Ok(());
+                },
+                Err(e) => {
+                    return Err(SendError::IO(e));
+                },
+                Ok(n) => {
+                    return Err(SendError::IncompleteSend(n));
+                },
+            };
+        });
+    }
+    
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), std::io::Error>  {
+        self.transport.set_read_timeout(timeout)
+    }
+    
+    // Puts the socket into or out of non-blocking mode. In non-blocking mode, reads fail with WouldBlock
+    // instead of waiting, regardless of the read timeout.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> Result<(), std::io::Error> {
+        self.transport.set_nonblocking(nonblocking)?;
+        self.nonblocking.store(nonblocking, Ordering::Relaxed);
+        return Ok(());
+    }
+    
+    // Sets the IP TTL of outgoing packets.
+    // For IPv4 sockets only; dual-stack and IPv6 sockets may reject it.
+    pub fn set_ttl(&self, ttl: u32) -> Result<(), std::io::Error> {
+        self.transport.set_ttl(ttl)
+    }
+    
+    // Returns the IP TTL of outgoing packets.
+    pub fn ttl(&self) -> Result<u32, std::io::Error> {
+        self.transport.ttl()
+    }
+    
+    // Allows or forbids sending to broadcast addresses, e.g. to find peers on the LAN.
+    pub fn set_broadcast(&self, broadcast: bool) -> Result<(), std::io::Error> {
+        self.transport.set_broadcast(broadcast)
+    }
+    
+    // Sets the size of the OS receive buffer, so that bursts of datagrams aren't dropped while the socket isn't read.
+    // The OS may clamp the size (on Linux to net.core.rmem_max) or round it up, e.g. Linux doubles it for bookkeeping,
+    // so read it back with recv_buffer_size to see what was granted.
+    pub fn set_recv_buffer_size(&self, size: usize) -> Result<(), std::io::Error> {
+        self.transport.set_recv_buffer_size(size)
+    }
+    
+    // Returns the size of the OS receive buffer.
+    pub fn recv_buffer_size(&self) -> Result<usize, std::io::Error> {
+        self.transport.recv_buffer_size()
+    }
+    
+    // Sets the size of the OS send buffer. Like set_recv_buffer_size, the OS may clamp or round it
+    // (on Linux to net.core.wmem_max).
+    pub fn set_send_buffer_size(&self, size: usize) -> Result<(), std::io::Error> {
+        self.transport.set_send_buffer_size(size)
+    }
+    
+    // Returns the size of the OS send buffer.
+    pub fn send_buffer_size(&self) -> Result<usize, std::io::Error> {
+        self.transport.send_buffer_size()
+    }
+    
+    // Sends a message with the given TTL, restoring the socket's previous TTL afterwards.
+    // A low TTL lets the packet open a mapping in the local NAT without reaching the peer.
+    pub fn send_message_with_ttl(&self, msg: &Message, dest: SocketAddr, ttl: u32) -> Result<(), SendError> {
+        let previous_ttl = match self.transport.ttl() {
+            Ok(ttl) => ttl,
+            Err(e) => {
+                return Err(SendError::IO(e));
+            }
+        };
+        if let Err(e) = self.transport.set_ttl(ttl) {
+            return Err(SendError::IO(e));
+        }
+        let result = self.send_message(msg, dest);
+        if let Err(e) = self.transport.set_ttl(previous_ttl) {
+            return Err(SendError::IO(e));
+        }
+        return result;
+    }
+    
+    // Returns a snapshot of the traffic counters.
+    pub fn stats(&self) -> SocketStats {
+        SocketStats {
+            messages_sent: self.counters.messages_sent.load(Ordering::Relaxed),
+            messages_received: self.counters.messages_received.load(Ordering::Relaxed),
+            bytes_sent: self.counters.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.counters.bytes_received.load(Ordering::Relaxed),
+            deserialization_failures: self.counters.deserialization_failures.load(Ordering::Relaxed),
+        }
+    }
+    
+    // Returns the given address in the form this socket can send to, or None if it is of another address family.
+    // IPv4 addresses are mapped into IPv6 (::ffff:a.b.c.d) for sockets bound to [::], which are dual-stack on most systems,
+    // and IPv4-mapped IPv6 addresses are unmapped for IPv4 sockets.
+    pub fn reachable_addr(&self, addr: SocketAddr) -> Option<SocketAddr> {
+        let local_addr = match self.local_addr() {
+            Ok(local_addr) => local_addr,
+            Err(_) => {
+                // can't tell, let the send fail if it has to
+                return Some(addr);
+            }
+        };
+        match (local_addr, addr) {
+            (SocketAddr::V4(_), SocketAddr::V4(_)) | (SocketAddr::V6(_), SocketAddr::V6(_)) => {
+                return Some(addr);
+            },
+            (SocketAddr::V6(local), SocketAddr::V4(v4)) if local.ip().is_unspecified() => {
+                return Some(SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port()));
+            },
+            (SocketAddr::V4(_), SocketAddr::V6(v6)) => {
+                match v6.ip().to_ipv4_mapped() {
+                    Some(v4) => {
+                        return Some(SocketAddr::new(v4.into(), v6.port()));
+                    },
+                    None => {
+                        return None;
+                    }
+                }
+            },
+            _ => {
+                // an IPv6 socket bound to a specific address can't send IPv4
+                return None;
+            }
+        }
+    }
+    
+    // Returns the address the socket is bound to.
+    pub fn local_addr(&self) -> Result<SocketAddr, std::io::Error> {
+        self.transport.local_addr()
+    }
+    
+    // Returns the clock the socket's read timeouts are measured on: the transport's, if it has one, or else the system clock.
+    // Clients, servers and holepunchers created on the socket take the time from it.
+    pub fn clock(&self) -> Arc<dyn Clock> {
+        match self.transport.clock() {
+            Some(clock) => clock,
+            None => Arc::new(SystemClock),
+        }
+    }
+    
+    // Get the listening port of the socket.
+    // Returns Err if the local address cannot be obtained.
+    pub fn get_port(&self) -> Result<u16, ()> {
+        match self.local_addr() {
+            Ok(addr) => Ok(addr.port()),
+            Err(_) => Err(()),
+        }
+    }
+    
+    // Returns the local address at which the given peer may reach this socket: the bound address if the socket is
+    // bound to a specific IP, otherwise the address of the interface which routes to the peer, even if that is loopback.
+    // Returns None if it can't be found out.
+    pub(crate) fn routed_addr(&self, peer: SocketAddr) -> Option<SocketAddr> {
+        let local_addr = match self.local_addr() {
+            Ok(local_addr) => local_addr,
+            Err(_) => {
+                return None;
+            }
+        };
+        if !local_addr.ip().is_unspecified() {
+            return Some(local_addr);
+        }
+        // a connected socket reveals the interface without sending anything
+        let unspecified = SocketAddr::new(local_addr.ip(), 0);
+        let routed = UdpSocket::bind(unspecified).and_then(|sock| {
+            sock.connect(peer)?;
+            sock.local_addr()
+        });
+        match routed {
+            Ok(addr) => {
+                return Some(SocketAddr::new(addr.ip(), local_addr.port()));
+            },
+            Err(_) => {
+                return None;
+            }
+        }
+    }
+    
+    // Returns the local addresses at which peers may reach this socket besides the one the holepuncher sees,
+    // e.g. from the same LAN: the bound address if the socket is bound to a specific IP, otherwise the addresses
+    // of the interfaces, starting with the one which routes to the holepuncher. Loopback and IPv6 link-local
+    // addresses are left out, as are addresses of the other IP version, and at most MAX_CANDIDATES are returned.
+    // Returns an empty list if no address can be found out.
+    pub(crate) fn local_candidates(&self, holepuncher: SocketAddr) -> Vec<SocketAddr> {
+        let local_addr = match self.local_addr() {
+            Ok(local_addr) => local_addr,
+            Err(_) => {
+                return Vec::new();
+            }
+        };
+        if !local_addr.ip().is_unspecified() {
+            return vec![local_addr];
+        }
+        let mut ips = Vec::new();
+        if let Some(addr) = self.routed_addr(holepuncher) {
+            ips.push(addr.ip());
+        }
+        if let Ok(interfaces) = if_addrs::get_if_addrs() {
+            ips.extend(interfaces.iter().map(|interface| interface.ip()));
+        }
+        let mut candidates: Vec<SocketAddr> = Vec::new();
+        for ip in ips {
+            let usable = match ip {
+                IpAddr::V4(ip) => local_addr.is_ipv4() && !ip.is_loopback() && !ip.is_unspecified(),
+                IpAddr::V6(ip) => local_addr.is_ipv6() && !ip.is_loopback() && !ip.is_unspecified() && !ip.is_unicast_link_local(),
+            };
+            let candidate = SocketAddr::new(ip, local_addr.port());
+            if usable && !candidates.contains(&candidate) && candidates.len() < MAX_CANDIDATES {
+                candidates.push(candidate);
+            }
+        }
+        return candidates;
+    }
+}
+
+// The underlying socket can be registered with an external poller (epoll, kqueue, etc.) to learn when it becomes readable.
+// Reading from or changing the socket through the raw handle bypasses the protocol and is not supported.
+#[cfg(unix)]
+impl std::os::unix::io::AsRawFd for ProtocolSocket {
+    fn as_raw_fd(&self) -> std::os::unix::io::RawFd {
+        // transports without an OS handle report an invalid one, which pollers reject
+        self.transport.raw_fd().unwrap_or(-1)
+    }
+}
+
+#[cfg(windows)]
+impl std::os::windows::io::AsRawSocket for ProtocolSocket {
+    fn as_raw_socket(&self) -> std::os::windows::io::RawSocket {
+        self.transport.raw_socket().unwrap_or(!0)
+    }
+}
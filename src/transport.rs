@@ -0,0 +1,537 @@
+use std::net::{
+    SocketAddr,
+    UdpSocket,
+};
+use std::time::{
+    Duration,
+    Instant,
+};
+use std::collections::{
+    HashMap,
+    HashSet,
+    VecDeque,
+};
+use std::sync::{
+    Arc,
+    Mutex,
+    Condvar,
+};
+use std::io::{
+    Error,
+    ErrorKind,
+};
+use socket2::SockRef;
+use crate::clock::{
+    deadline_after,
+    Clock,
+    MockClock,
+};
+
+/// What a ProtocolSocket sends and receives datagrams through.
+/// UdpSocket is the real implementation; MemoryNetwork provides an in-memory one for tests.
+/// The methods behave like their UdpSocket counterparts, including the error kinds:
+/// a read that times out or finds nothing on a non-blocking transport fails with WouldBlock or TimedOut.
+pub trait Transport: Send + Sync {
+    /// Sends a datagram to the given address, returning the number of bytes sent.
+    fn send_to(&self, buf: &[u8], dest: SocketAddr) -> Result<usize, Error>;
+    /// Receives a datagram, returning its size and source. Datagrams larger than buf are truncated.
+    fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr), Error>;
+    /// Restricts sending and receiving to a single peer.
+    fn connect(&self, peer: SocketAddr) -> Result<(), Error>;
+    /// Sends a datagram to the connected peer.
+    fn send(&self, buf: &[u8]) -> Result<usize, Error>;
+    /// Sets how long recv_from waits for a datagram. None waits forever.
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), Error>;
+    /// Puts the transport into or out of non-blocking mode.
+    fn set_nonblocking(&self, nonblocking: bool) -> Result<(), Error>;
+    /// Sets the IP TTL of outgoing datagrams.
+    fn set_ttl(&self, ttl: u32) -> Result<(), Error>;
+    /// Returns the IP TTL of outgoing datagrams.
+    fn ttl(&self) -> Result<u32, Error>;
+    /// Allows or forbids sending to broadcast addresses.
+    fn set_broadcast(&self, broadcast: bool) -> Result<(), Error>;
+    /// Sets the size of the OS buffer holding received datagrams until they are read.
+    fn set_recv_buffer_size(&self, size: usize) -> Result<(), Error>;
+    /// Returns the size of the OS receive buffer.
+    fn recv_buffer_size(&self) -> Result<usize, Error>;
+    /// Sets the size of the OS buffer holding outgoing datagrams until they are sent.
+    fn set_send_buffer_size(&self, size: usize) -> Result<(), Error>;
+    /// Returns the size of the OS send buffer.
+    fn send_buffer_size(&self) -> Result<usize, Error>;
+    /// Returns the address the transport is bound to.
+    fn local_addr(&self) -> Result<SocketAddr, Error>;
+    /// Returns a second handle to the same transport, sharing its options and received datagrams.
+    fn try_clone(&self) -> Result<Box<dyn Transport>, Error>;
+    /// Returns the clock read timeouts are measured on, if it isn't the system clock.
+    /// Clients, servers and holepunchers on the transport then take their time from it as well.
+    fn clock(&self) -> Option<Arc<dyn Clock>> {
+        None
+    }
+    /// Returns the OS handle for registering with an external poller, if the transport has one.
+    #[cfg(unix)]
+    fn raw_fd(&self) -> Option<std::os::unix::io::RawFd> {
+        None
+    }
+    /// Returns the OS handle for registering with an external poller, if the transport has one.
+    #[cfg(windows)]
+    fn raw_socket(&self) -> Option<std::os::windows::io::RawSocket> {
+        None
+    }
+}
+
+impl Transport for UdpSocket {
+    fn send_to(&self, buf: &[u8], dest: SocketAddr) -> Result<usize, Error> {
+        UdpSocket::send_to(self, buf, dest)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr), Error> {
+        UdpSocket::recv_from(self, buf)
+    }
+
+    fn connect(&self, peer: SocketAddr) -> Result<(), Error> {
+        UdpSocket::connect(self, peer)
+    }
+
+    fn send(&self, buf: &[u8]) -> Result<usize, Error> {
+        UdpSocket::send(self, buf)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), Error> {
+        UdpSocket::set_read_timeout(self, timeout)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> Result<(), Error> {
+        UdpSocket::set_nonblocking(self, nonblocking)
+    }
+
+    fn set_ttl(&self, ttl: u32) -> Result<(), Error> {
+        UdpSocket::set_ttl(self, ttl)
+    }
+
+    fn ttl(&self) -> Result<u32, Error> {
+        UdpSocket::ttl(self)
+    }
+
+    fn set_broadcast(&self, broadcast: bool) -> Result<(), Error> {
+        UdpSocket::set_broadcast(self, broadcast)
+    }
+
+    fn set_recv_buffer_size(&self, size: usize) -> Result<(), Error> {
+        SockRef::from(self).set_recv_buffer_size(size)
+    }
+
+    fn recv_buffer_size(&self) -> Result<usize, Error> {
+        SockRef::from(self).recv_buffer_size()
+    }
+
+    fn set_send_buffer_size(&self, size: usize) -> Result<(), Error> {
+        SockRef::from(self).set_send_buffer_size(size)
+    }
+
+    fn send_buffer_size(&self) -> Result<usize, Error> {
+        SockRef::from(self).send_buffer_size()
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr, Error> {
+        UdpSocket::local_addr(self)
+    }
+
+    fn try_clone(&self) -> Result<Box<dyn Transport>, Error> {
+        Ok(Box::new(UdpSocket::try_clone(self)?))
+    }
+
+    #[cfg(unix)]
+    fn raw_fd(&self) -> Option<std::os::unix::io::RawFd> {
+        Some(std::os::unix::io::AsRawFd::as_raw_fd(self))
+    }
+
+    #[cfg(windows)]
+    fn raw_socket(&self) -> Option<std::os::windows::io::RawSocket> {
+        Some(std::os::windows::io::AsRawSocket::as_raw_socket(self))
+    }
+}
+
+// Buffer size MemoryTransports report until another one is set
+const MEMORY_BUFFER_SIZE: usize = 212992;
+
+// First port handed out to transports bound to port 0
+const FIRST_EPHEMERAL_PORT: u16 = 49152;
+
+// Everything a MemoryNetwork knows, behind one lock
+struct NetworkState {
+    /// Datagrams waiting to be received at each bound address, with their source
+    queues: HashMap<SocketAddr, VecDeque<(Vec<u8>, SocketAddr)>>,
+    /// Fraction of datagrams which are dropped, between 0 and 1
+    loss_rate: f64,
+    /// State of the xorshift generator deciding which datagrams are dropped
+    rng_state: u64,
+    /// Number of datagrams dropped so far, whether lost or sent to an unbound address
+    dropped: u64,
+    /// Port handed out to the next transport bound to port 0
+    next_port: u16,
+    /// Clock read timeouts are measured on, if it isn't the system clock
+    clock: Option<MockClock>,
+    /// Bound addresses. The clock only skips ahead while reads are waiting at all of them.
+    receivers: HashSet<SocketAddr>,
+    /// Number of reads waiting at each address
+    waiting: HashMap<SocketAddr, usize>,
+    /// Deadlines on the clock of the waiting reads which have a timeout, one entry per read
+    deadlines: Vec<Instant>,
+}
+
+impl NetworkState {
+    // Returns the next pseudo-random number between 0 and 1.
+    fn next_random(&mut self) -> f64 {
+        let mut x = self.rng_state;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.rng_state = x;
+        return (x >> 11) as f64 / (1u64 << 53) as f64;
+    }
+
+    // Moves the clock on to the earliest deadline of the waiting reads if every receiver is waiting and has nothing
+    // queued, since nothing can happen before then. Returns true if the clock moved.
+    fn skip_idle_time(&mut self) -> bool {
+        let clock = match &self.clock {
+            Some(clock) => clock,
+            None => {
+                return false;
+            }
+        };
+        let idle = self.receivers.iter().all(|addr| {
+            self.waiting.get(addr).is_some_and(|waiting| *waiting > 0)
+                && self.queues.get(addr).is_none_or(|queue| queue.is_empty())
+        });
+        if !idle {
+            return false;
+        }
+        let now = clock.now();
+        match self.deadlines.iter().min() {
+            Some(earliest) if *earliest > now => {
+                clock.advance(*earliest - now);
+                return true;
+            },
+            _ => {
+                return false;
+            }
+        }
+    }
+}
+
+/// An in-memory network connecting MemoryTransports, for testing clients, servers and holepunchers
+/// without real sockets, e.g. under packet loss. Datagrams are delivered instantly and in order, unless dropped.
+/// There is no NAT: every transport is seen under the address it was bound to.
+/// Which datagrams are lost only depends on the seed and the order in which datagrams are sent.
+/// A network created with_clock runs on a MockClock instead of real time, see with_clock.
+/// Clones refer to the same network.
+#[derive(Clone)]
+pub struct MemoryNetwork {
+    shared: Arc<(Mutex<NetworkState>, Condvar)>,
+}
+
+impl MemoryNetwork {
+    /// Creates a network which delivers every datagram.
+    pub fn new() -> Self {
+        Self {
+            shared: Arc::new((Mutex::new(NetworkState {
+                queues: HashMap::new(),
+                loss_rate: 0.0,
+                rng_state: 1,
+                dropped: 0,
+                next_port: FIRST_EPHEMERAL_PORT,
+                clock: None,
+                receivers: HashSet::new(),
+                waiting: HashMap::new(),
+                deadlines: Vec::new(),
+            }), Condvar::new())),
+        }
+    }
+
+    /// Creates a network whose transports measure their read timeouts on the given clock, as do the clients, servers
+    /// and holepunchers on top of them. Whenever every bound transport is waiting for a datagram,
+    /// the clock skips ahead to the earliest read timeout, so retries and timeouts take no real time.
+    /// A transport whose owner is busy with something else, or never reads from it, holds the clock back;
+    /// reads then also time out after their timeout has passed in real time, while the clock stands still.
+    pub fn with_clock(clock: MockClock) -> Self {
+        let network = Self::new();
+        network.shared.0.lock().unwrap().clock = Some(clock);
+        return network;
+    }
+
+    /// Makes the network drop the given fraction of datagrams (0 to 1), chosen pseudo-randomly from the given seed.
+    pub fn set_loss(&self, loss_rate: f64, seed: u64) {
+        let mut state = self.shared.0.lock().unwrap();
+        state.loss_rate = loss_rate;
+        // xorshift gets stuck at 0
+        state.rng_state = seed.max(1);
+    }
+
+    /// Returns the number of datagrams dropped so far, whether lost or sent to an address nobody is bound to.
+    pub fn dropped(&self) -> u64 {
+        self.shared.0.lock().unwrap().dropped
+    }
+
+    /// Binds a transport to the given address. Port 0 picks a free port.
+    /// Fails with AddrInUse if another transport is bound to the address.
+    pub fn bind(&self, addr: SocketAddr) -> Result<MemoryTransport, Error> {
+        let mut state = self.shared.0.lock().unwrap();
+        let mut local_addr = addr;
+        if local_addr.port() == 0 {
+            // look for a free port, wrapping around into the ephemeral range
+            let mut tries = 0u32;
+            loop {
+                local_addr.set_port(state.next_port);
+                state.next_port = state.next_port.checked_add(1).unwrap_or(FIRST_EPHEMERAL_PORT);
+                if !state.queues.contains_key(&local_addr) {
+                    break;
+                }
+                tries += 1;
+                if tries > u16::MAX as u32 {
+                    return Err(Error::new(ErrorKind::AddrInUse, "no free port left"));
+                }
+            }
+        } else if state.queues.contains_key(&local_addr) {
+            return Err(Error::new(ErrorKind::AddrInUse, "address already bound"));
+        }
+        state.queues.insert(local_addr, VecDeque::new());
+        state.receivers.insert(local_addr);
+        return Ok(MemoryTransport {
+            binding: Arc::new(Binding {
+                network: self.clone(),
+                local_addr,
+                options: Mutex::new(TransportOptions {
+                    read_timeout: None,
+                    nonblocking: false,
+                    peer: None,
+                    ttl: 64,
+                    recv_buffer_size: MEMORY_BUFFER_SIZE,
+                    send_buffer_size: MEMORY_BUFFER_SIZE,
+                }),
+            }),
+        });
+    }
+
+    // Queues a datagram for the transport bound to dest, unless it is lost.
+    fn deliver(&self, data: &[u8], source: SocketAddr, dest: SocketAddr) {
+        let (lock, cvar) = &*self.shared;
+        let mut state = lock.lock().unwrap();
+        let lost = state.loss_rate > 0.0 && state.next_random() < state.loss_rate;
+        match state.queues.get_mut(&dest) {
+            Some(queue) if !lost => {
+                queue.push_back((data.to_vec(), source));
+                cvar.notify_all();
+            },
+            _ => {
+                // like UDP, the sender doesn't find out
+                state.dropped += 1;
+            }
+        }
+    }
+}
+
+impl Default for MemoryNetwork {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Options of a MemoryTransport, shared between its clones like a socket's options
+struct TransportOptions {
+    read_timeout: Option<Duration>,
+    nonblocking: bool,
+    peer: Option<SocketAddr>,
+    ttl: u32,
+    recv_buffer_size: usize,
+    send_buffer_size: usize,
+}
+
+// An address bound on a MemoryNetwork. Unbound when the last transport referring to it is dropped.
+struct Binding {
+    network: MemoryNetwork,
+    local_addr: SocketAddr,
+    options: Mutex<TransportOptions>,
+}
+
+impl Drop for Binding {
+    fn drop(&mut self) {
+        let (lock, cvar) = &*self.network.shared;
+        let mut state = lock.lock().unwrap();
+        state.queues.remove(&self.local_addr);
+        state.receivers.remove(&self.local_addr);
+        // the remaining receivers may be all waiting now
+        if state.skip_idle_time() {
+            cvar.notify_all();
+        }
+    }
+}
+
+/// Transport bound to an address on a MemoryNetwork, see MemoryNetwork::bind.
+/// Wrap it with ProtocolSocket::with_transport to use it for a client, server or holepuncher.
+pub struct MemoryTransport {
+    binding: Arc<Binding>,
+}
+
+impl Transport for MemoryTransport {
+    fn send_to(&self, buf: &[u8], dest: SocketAddr) -> Result<usize, Error> {
+        let peer = self.binding.options.lock().unwrap().peer;
+        if let Some(peer) = peer {
+            if peer != dest {
+                // like a connected UdpSocket
+                return Err(Error::new(ErrorKind::InvalidInput, "transport is connected to another address"));
+            }
+        }
+        self.binding.network.deliver(buf, self.binding.local_addr, dest);
+        return Ok(buf.len());
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr), Error> {
+        let (read_timeout, nonblocking, peer) = {
+            let options = self.binding.options.lock().unwrap();
+            (options.read_timeout, options.nonblocking, options.peer)
+        };
+        let (lock, cvar) = &*self.binding.network.shared;
+        let mut state = lock.lock().unwrap();
+        // the timeout runs out in real time, or earlier on the network's clock, if it has one
+        let real_deadline = read_timeout.map(|timeout| deadline_after(Instant::now(), timeout));
+        let clock = state.clock.clone();
+        let deadline = match (&clock, read_timeout) {
+            (Some(clock), Some(timeout)) => Some(deadline_after(clock.now(), timeout)),
+            _ => None,
+        };
+        loop {
+            let queue = match state.queues.get_mut(&self.binding.local_addr) {
+                Some(queue) => queue,
+                None => {
+                    return Err(Error::new(ErrorKind::NotConnected, "transport is not bound"));
+                }
+            };
+            while let Some((data, source)) = queue.pop_front() {
+                if peer.is_some() && peer != Some(source) {
+                    // a connected socket only hears from its peer
+                    continue;
+                }
+                let size = data.len().min(buf.len());
+                buf[..size].copy_from_slice(&data[..size]);
+                return Ok((size, source));
+            }
+            if nonblocking {
+                return Err(Error::new(ErrorKind::WouldBlock, "no datagram queued"));
+            }
+            let timed_out_on_clock = match (&clock, deadline) {
+                (Some(clock), Some(deadline)) => clock.now() >= deadline,
+                _ => false,
+            };
+            let now = Instant::now();
+            if timed_out_on_clock || real_deadline.is_some_and(|real_deadline| now >= real_deadline) {
+                return Err(Error::new(ErrorKind::WouldBlock, "read timed out"));
+            }
+            
+            // wait, letting the clock skip ahead if everyone else is waiting too
+            *state.waiting.entry(self.binding.local_addr).or_insert(0) += 1;
+            if let Some(deadline) = deadline {
+                state.deadlines.push(deadline);
+            }
+            if state.skip_idle_time() {
+                // this read may be the one which timed out, so check again before waiting
+                cvar.notify_all();
+            } else {
+                state = match real_deadline {
+                    Some(real_deadline) => cvar.wait_timeout(state, real_deadline - now).unwrap().0,
+                    None => cvar.wait(state).unwrap(),
+                };
+            }
+            if let Some(waiting) = state.waiting.get_mut(&self.binding.local_addr) {
+                *waiting -= 1;
+            }
+            if let Some(deadline) = deadline {
+                if let Some(at) = state.deadlines.iter().position(|waiting_until| *waiting_until == deadline) {
+                    state.deadlines.swap_remove(at);
+                }
+            }
+        }
+    }
+
+    fn connect(&self, peer: SocketAddr) -> Result<(), Error> {
+        self.binding.options.lock().unwrap().peer = Some(peer);
+        return Ok(());
+    }
+
+    fn send(&self, buf: &[u8]) -> Result<usize, Error> {
+        let peer = self.binding.options.lock().unwrap().peer;
+        match peer {
+            Some(peer) => {
+                return self.send_to(buf, peer);
+            },
+            None => {
+                return Err(Error::new(ErrorKind::NotConnected, "transport is not connected"));
+            }
+        }
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), Error> {
+        if timeout == Some(Duration::ZERO) {
+            // same as UdpSocket
+            return Err(Error::new(ErrorKind::InvalidInput, "cannot set a 0 duration timeout"));
+        }
+        self.binding.options.lock().unwrap().read_timeout = timeout;
+        return Ok(());
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> Result<(), Error> {
+        self.binding.options.lock().unwrap().nonblocking = nonblocking;
+        return Ok(());
+    }
+
+    fn set_ttl(&self, ttl: u32) -> Result<(), Error> {
+        // there are no hops to count, so the TTL is only remembered
+        self.binding.options.lock().unwrap().ttl = ttl;
+        return Ok(());
+    }
+
+    fn ttl(&self) -> Result<u32, Error> {
+        return Ok(self.binding.options.lock().unwrap().ttl);
+    }
+
+    fn set_broadcast(&self, _broadcast: bool) -> Result<(), Error> {
+        // there are no broadcast addresses, so there is nothing to allow
+        return Ok(());
+    }
+
+    fn set_recv_buffer_size(&self, size: usize) -> Result<(), Error> {
+        // queues are unbounded, so the size is only remembered
+        self.binding.options.lock().unwrap().recv_buffer_size = size;
+        return Ok(());
+    }
+
+    fn recv_buffer_size(&self) -> Result<usize, Error> {
+        return Ok(self.binding.options.lock().unwrap().recv_buffer_size);
+    }
+
+    fn set_send_buffer_size(&self, size: usize) -> Result<(), Error> {
+        // datagrams are delivered right away, so the size is only remembered
+        self.binding.options.lock().unwrap().send_buffer_size = size;
+        return Ok(());
+    }
+
+    fn send_buffer_size(&self) -> Result<usize, Error> {
+        return Ok(self.binding.options.lock().unwrap().send_buffer_size);
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr, Error> {
+        return Ok(self.binding.local_addr);
+    }
+
+    fn try_clone(&self) -> Result<Box<dyn Transport>, Error> {
+        return Ok(Box::new(MemoryTransport {
+            binding: self.binding.clone(),
+        }));
+    }
+
+    fn clock(&self) -> Option<Arc<dyn Clock>> {
+        match &self.binding.network.shared.0.lock().unwrap().clock {
+            Some(clock) => Some(Arc::new(clock.clone())),
+            None => None,
+        }
+    }
+}
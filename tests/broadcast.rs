@@ -0,0 +1,113 @@
+// Counts the allocations broadcasting and sending make. Kept in its own test binary, since it replaces the global allocator.
+#![cfg(feature = "std")]
+mod common;
+
+use std::alloc::{
+    GlobalAlloc,
+    Layout,
+    System,
+};
+use std::cell::Cell;
+use std::net::SocketAddr;
+use std::time::Duration;
+use ruphin::event::Event;
+use ruphin::messages::*;
+use ruphin::passive_server::*;
+use ruphin::protocol_socket::*;
+
+thread_local! {
+    // allocations made by the current thread
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    // bytes allocated by the current thread
+    static ALLOCATED_BYTES: Cell<usize> = const { Cell::new(0) };
+}
+
+struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // the counter can't be reached while the thread is being torn down
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        let _ = ALLOCATED_BYTES.try_with(|bytes| bytes.set(bytes.get() + layout.size()));
+        return System.alloc(layout);
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+// Connects fake clients until the server has the given number of them.
+fn connect_clients(server: &mut PassiveServer, clients: &mut Vec<ProtocolSocket>, client_count: usize) {
+    let server_addr = SocketAddr::from(([127, 0, 0, 1], server.get_port().unwrap()));
+    while clients.len() < client_count {
+        let client = ProtocolSocket::bind("127.0.0.1:0").unwrap();
+        client.send_message(&Message::HelloReq(HelloReqContents::default()), server_addr).unwrap();
+        clients.push(client);
+        while !matches!(server.wait_for_event(Some(Duration::from_secs(1)), false).unwrap(), Event::PeerConnected(_)) {}
+    }
+}
+
+// Connects fake clients until the server has the given number of them, and returns the allocations made by a broadcast.
+fn broadcast_allocations(server: &mut PassiveServer, clients: &mut Vec<ProtocolSocket>, client_count: usize) -> usize {
+    connect_clients(server, clients, client_count);
+    let before = ALLOCATIONS.with(|count| count.get());
+    server.broadcast(vec![9; 500]).unwrap();
+    return ALLOCATIONS.with(|count| count.get()) - before;
+}
+
+#[test]
+fn broadcast_shares_the_payload_between_clients() {
+    let holepuncher = common::spawn_holepuncher();
+    let mut server = PassiveServer::new(holepuncher, b"fan out".to_vec()).unwrap();
+    let mut clients = Vec::new();
+    let to_one = broadcast_allocations(&mut server, &mut clients, 1);
+    let to_many = broadcast_allocations(&mut server, &mut clients, 20);
+    // only the list of recipients grows, by a few reallocations, rather than one copy per client
+    assert!(to_many <= to_one + 6, "{} allocations for one client, {} for twenty", to_one, to_many);
+}
+
+#[cfg(feature = "bytes")]
+#[test]
+fn clones_of_a_payload_share_its_buffer() {
+    let holepuncher = common::spawn_holepuncher();
+    let mut server = PassiveServer::new(holepuncher, b"shared payload".to_vec()).unwrap();
+    let mut clients = Vec::new();
+    connect_clients(&mut server, &mut clients, 20);
+    let client_addrs: Vec<SocketAddr> = clients.iter()
+        .map(|client| SocketAddr::from(([127, 0, 0, 1], client.get_port().unwrap())))
+        .collect();
+
+    // xorshift noise, which the compression feature can't shrink
+    let mut state = 0x2545F491u32;
+    let payload = bytes::Bytes::from((0..1000).map(|_| {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        (state >> 24) as u8
+    }).collect::<Vec<u8>>());
+    let before = ALLOCATED_BYTES.with(|bytes| bytes.get());
+    let msg = Message::Data(DataContents { data: payload.clone(), expires_at: None });
+    msg.serialize().unwrap();
+    let serializing = ALLOCATED_BYTES.with(|bytes| bytes.get()) - before;
+    let before = ALLOCATED_BYTES.with(|bytes| bytes.get());
+    for addr in &client_addrs {
+        server.send_datagram(*addr, payload.clone()).unwrap();
+    }
+    let per_send = (ALLOCATED_BYTES.with(|bytes| bytes.get()) - before) / client_addrs.len();
+    // serializing allocates a fresh buffer for the message, and the compressor's scratch space with the compression feature.
+    // A send reuses its buffer, so it allocates less than that by at least the payload, unless it copies the payload.
+    assert!(per_send + payload.len() <= serializing, "{} bytes allocated per send, {} to serialize", per_send, serializing);
+    for client in &clients {
+        client.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        loop {
+            if let (Message::Data(contents), _) = client.get_message().unwrap() {
+                assert_eq!(contents.data, payload);
+                break;
+            }
+        }
+    }
+}
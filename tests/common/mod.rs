@@ -0,0 +1,181 @@
+// Helpers shared by the integration tests. Not every test file uses all of them.
+#![allow(dead_code)]
+
+use std::io::{
+    Error,
+    ErrorKind,
+};
+use std::net::{
+    SocketAddr,
+    UdpSocket,
+};
+use std::sync::Arc;
+use std::sync::atomic::{
+    AtomicUsize,
+    Ordering,
+};
+use std::thread::{
+    self,
+    JoinHandle,
+};
+use std::time::{
+    Duration,
+    Instant,
+};
+use ruphin::messages::*;
+use ruphin::protocol_socket::ProtocolSocket;
+use ruphin::passive_holepuncher::{
+    PassiveHolepuncher,
+    PassiveHolepuncherConfig,
+};
+use ruphin::passive_server::{
+    PassiveServer,
+    PassiveServerConfig,
+};
+use ruphin::transport::Transport;
+
+/// Starts a holepuncher on a loopback port, serving on another thread until the test process exits.
+pub fn spawn_holepuncher() -> SocketAddr {
+    return spawn_holepuncher_with(PassiveHolepuncherConfig::default());
+}
+
+/// Like spawn_holepuncher, with the given configuration.
+pub fn spawn_holepuncher_with(config: PassiveHolepuncherConfig) -> SocketAddr {
+    let mut holepuncher = PassiveHolepuncher::with_config("127.0.0.1:0", config).unwrap();
+    let addr = holepuncher.local_addr().unwrap();
+    thread::spawn(move || {
+        let _ = holepuncher.serve(None, false);
+    });
+    return addr;
+}
+
+/// Registers a server for the given session and serves it on another thread for the given time,
+/// so that clients can connect to it meanwhile. The thread hands the server back when done.
+pub fn spawn_server(holepuncher: SocketAddr, session_id: &[u8], serve_for: Duration) -> JoinHandle<PassiveServer> {
+    return spawn_server_with(holepuncher, session_id, PassiveServerConfig::default(), serve_for);
+}
+
+/// Like spawn_server, with the given configuration.
+pub fn spawn_server_with(holepuncher: SocketAddr, session_id: &[u8], config: PassiveServerConfig, serve_for: Duration)
+    -> JoinHandle<PassiveServer> {
+    let server = PassiveServer::with_config(holepuncher, session_id.to_vec(), config).unwrap();
+    return serve_in_background(server, serve_for);
+}
+
+/// Serves an existing server on another thread for the given time, handing it back when done.
+pub fn serve_in_background(mut server: PassiveServer, serve_for: Duration) -> JoinHandle<PassiveServer> {
+    return thread::spawn(move || {
+        let end = Instant::now() + serve_for;
+        while Instant::now() < end {
+            server.wait_for_event(Some(end.saturating_duration_since(Instant::now())), false).unwrap();
+        }
+        server
+    });
+}
+
+/// Polls the condition until it holds or the timeout passes, returning whether it held.
+pub fn eventually<F: FnMut() -> bool>(timeout: Duration, mut condition: F) -> bool {
+    let end = Instant::now() + timeout;
+    while Instant::now() < end {
+        if condition() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    return condition();
+}
+
+/// Registers the session at the holepuncher from a bare socket, which then stands in for a server
+/// whose behaviour the test controls. Its read timeout is set to a few seconds.
+pub fn register_fake_server(holepuncher: SocketAddr, session_id: &[u8]) -> ProtocolSocket {
+    let sock = ProtocolSocket::bind("127.0.0.1:0").unwrap();
+    sock.set_read_timeout(Some(Duration::from_secs(3))).unwrap();
+    let register = Message::Register(RegisterContents {
+        session_id: session_id.to_vec(),
+        metadata: Vec::new(),
+        candidates: Vec::new(),
+        mac: Vec::new(),
+    });
+    sock.send_message(&register, holepuncher).unwrap();
+    match sock.get_message().unwrap() {
+        (Message::RegisterAck(_), _) => {},
+        (other, _) => panic!("expected a RegisterAck, got {:?}", other),
+    }
+    return sock;
+}
+
+/// A UDP transport whose send buffer is full for the first few sends, as if a burst had just been sent.
+/// Loopback drains too fast for a real full send buffer to be reproduced reliably.
+pub struct CongestedTransport {
+    pub udp: UdpSocket,
+    /// How many more sends fail with WouldBlock
+    pub full_for: Arc<AtomicUsize>,
+}
+
+impl Transport for CongestedTransport {
+    fn send_to(&self, buf: &[u8], dest: SocketAddr) -> Result<usize, Error> {
+        match self.full_for.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |sends| sends.checked_sub(1)) {
+            Ok(_) => Err(Error::from(ErrorKind::WouldBlock)),
+            Err(_) => self.udp.send_to(buf, dest),
+        }
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr), Error> {
+        self.udp.recv_from(buf)
+    }
+
+    fn connect(&self, peer: SocketAddr) -> Result<(), Error> {
+        self.udp.connect(peer)
+    }
+
+    fn send(&self, buf: &[u8]) -> Result<usize, Error> {
+        self.udp.send(buf)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), Error> {
+        self.udp.set_read_timeout(timeout)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> Result<(), Error> {
+        self.udp.set_nonblocking(nonblocking)
+    }
+
+    fn set_ttl(&self, ttl: u32) -> Result<(), Error> {
+        self.udp.set_ttl(ttl)
+    }
+
+    fn ttl(&self) -> Result<u32, Error> {
+        self.udp.ttl()
+    }
+
+    fn set_broadcast(&self, broadcast: bool) -> Result<(), Error> {
+        self.udp.set_broadcast(broadcast)
+    }
+
+    fn set_recv_buffer_size(&self, size: usize) -> Result<(), Error> {
+        Transport::set_recv_buffer_size(&self.udp, size)
+    }
+
+    fn recv_buffer_size(&self) -> Result<usize, Error> {
+        Transport::recv_buffer_size(&self.udp)
+    }
+
+    fn set_send_buffer_size(&self, size: usize) -> Result<(), Error> {
+        Transport::set_send_buffer_size(&self.udp, size)
+    }
+
+    fn send_buffer_size(&self) -> Result<usize, Error> {
+        Transport::send_buffer_size(&self.udp)
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr, Error> {
+        self.udp.local_addr()
+    }
+
+    fn try_clone(&self) -> Result<Box<dyn Transport>, Error> {
+        return Ok(Box::new(CongestedTransport {
+            udp: self.udp.try_clone()?,
+            full_for: Arc::clone(&self.full_for),
+        }));
+    }
+}
@@ -0,0 +1,229 @@
+#![cfg(feature = "std")]
+
+mod common;
+
+use std::net::SocketAddr;
+use std::sync::{
+    Arc,
+    Mutex,
+};
+use std::thread;
+use std::time::{
+    Duration,
+    Instant,
+};
+use ruphin::event::DropReason;
+use ruphin::messages::*;
+use ruphin::multi_session_server::*;
+use ruphin::passive_client::*;
+use ruphin::passive_server::PassiveServerConfig;
+use ruphin::protocol_socket::ProtocolSocket;
+
+#[test]
+fn data_is_attributed_to_its_session() {
+    let holepuncher = common::spawn_holepuncher();
+    let mut server = MultiSessionServer::new(holepuncher, vec![b"first".to_vec(), b"second".to_vec()]).unwrap();
+    // echoes two datagrams, noting which session each came from
+    let server = thread::spawn(move || {
+        let mut received = Vec::new();
+        while received.len() < 2 {
+            match server.wait_for_data(Some(Duration::from_secs(5)), false).unwrap() {
+                Some((session_id, source, data)) => {
+                    server.send_datagram(source, data.clone()).unwrap();
+                    received.push((session_id, data));
+                },
+                None => break,
+            }
+        }
+        received
+    });
+
+    let mut first = PassiveClient::new(holepuncher, b"first".to_vec()).unwrap();
+    let mut second = PassiveClient::new(holepuncher, b"second".to_vec()).unwrap();
+    assert_eq!(first.get_server(), second.get_server());
+    for (client, data) in [(&mut first, &b"to first"[..]), (&mut second, &b"to second"[..])] {
+        let server_addr = client.get_server();
+        client.send_datagram(server_addr, data.to_vec()).unwrap();
+        assert_eq!(client.wait_for_data(Some(Duration::from_secs(3)), false).unwrap().unwrap().1, data);
+    }
+    let mut received = server.join().unwrap();
+    received.sort();
+    assert_eq!(received, vec![(b"first".to_vec(), b"to first".to_vec()), (b"second".to_vec(), b"to second".to_vec())]);
+}
+
+// Registers a server with a bare socket standing in for the holepuncher, which acknowledges the first Register only.
+// Returns the server, the stand-in, and the server's loopback address.
+fn register_with_stand_in(session_id: &[u8], config: PassiveServerConfig) -> (MultiSessionServer, ProtocolSocket, SocketAddr) {
+    let holepuncher = ProtocolSocket::bind("127.0.0.1:0").unwrap();
+    holepuncher.set_read_timeout(Some(Duration::from_secs(3))).unwrap();
+    let holepuncher_addr = holepuncher.local_addr().unwrap();
+    let registering = thread::spawn(move || loop {
+        if let (Message::Register(contents), source) = holepuncher.get_message().unwrap() {
+            let ack = Message::RegisterAck(RegisterAckContents { session_id: contents.session_id, mapped_addr: Some(source) });
+            holepuncher.send_message(&ack, source).unwrap();
+            return holepuncher;
+        }
+    });
+    let server = MultiSessionServer::with_config(holepuncher_addr, vec![session_id.to_vec()], config).unwrap();
+    let holepuncher = registering.join().unwrap();
+    let server_addr = SocketAddr::from(([127, 0, 0, 1], server.local_addr().unwrap().port()));
+    return (server, holepuncher, server_addr);
+}
+
+#[test]
+fn relayed_data_is_only_accepted_from_introduced_clients() {
+    // the stand-in relays to the server
+    let (mut server, holepuncher, server_addr) = register_with_stand_in(b"relayed", PassiveServerConfig::default());
+    let drops = Arc::new(Mutex::new(Vec::new()));
+    let recorded = Arc::clone(&drops);
+    server.set_drop_observer(move |reason, source| recorded.lock().unwrap().push((reason, source)));
+    let client = ProtocolSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let stranger = ProtocolSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+    // only the client is introduced to the session, and completes the handshake
+    let info = Message::PeerInfo(PeerInfoContents { session_id: b"relayed".to_vec(), peer_addr: client, candidates: vec![], mac: vec![] });
+    holepuncher.send_message(&info, server_addr).unwrap();
+    let data = |data: &[u8]| Message::Data(DataContents { data: Payload::from(data.to_vec()), expires_at: None });
+    let hello = Message::HelloReq(HelloReqContents::default());
+    for (peer_addr, msg) in [(stranger, data(b"stray")), (client, hello), (client, data(b"known"))] {
+        let payload = msg.serialize().unwrap();
+        let relayed = Message::RelayData(RelayDataContents { session_id: b"relayed".to_vec(), peer_addr, payload });
+        holepuncher.send_message(&relayed, server_addr).unwrap();
+    }
+    let received = server.wait_for_data(Some(Duration::from_secs(2)), false).unwrap();
+    assert_eq!(received, Some((b"relayed".to_vec(), client, b"known".to_vec())));
+    assert_eq!(*drops.lock().unwrap(), [(DropReason::UnknownPeer, stranger)]);
+}
+
+#[test]
+fn clients_are_admitted_after_the_handshake() {
+    let (mut server, holepuncher, server_addr) = register_with_stand_in(b"gated", PassiveServerConfig::default());
+    let drops = Arc::new(Mutex::new(Vec::new()));
+    let recorded = Arc::clone(&drops);
+    server.set_drop_observer(move |reason, _| recorded.lock().unwrap().push(reason));
+    let client = ProtocolSocket::bind("127.0.0.1:0").unwrap();
+    client.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+    let client_addr = client.local_addr().unwrap();
+    let hello = Message::HelloReq(HelloReqContents::default());
+    let data = Message::Data(DataContents { data: Payload::from(b"early".to_vec()), expires_at: None });
+
+    // before the holepuncher introduces the client, its HelloReqs go unanswered
+    client.send_message(&hello, server_addr).unwrap();
+    assert_eq!(server.wait_for_data(Some(Duration::from_millis(200)), false).unwrap(), None);
+    assert!(client.get_message().is_err());
+
+    // once introduced, its data is still dropped until it completes the handshake
+    let info = Message::PeerInfo(PeerInfoContents { session_id: b"gated".to_vec(), peer_addr: client_addr, candidates: vec![], mac: vec![] });
+    holepuncher.send_message(&info, server_addr).unwrap();
+    client.send_message(&data, server_addr).unwrap();
+    assert_eq!(server.wait_for_data(Some(Duration::from_millis(200)), false).unwrap(), None);
+    assert!(server.connected_clients(b"gated").is_empty());
+    assert_eq!(*drops.lock().unwrap(), [DropReason::UnknownPeer, DropReason::UnknownPeer]);
+
+    // the client got the server's HelloReq, and sends its own
+    assert!(matches!(client.get_message().unwrap().0, Message::HelloReq(_)));
+    client.send_message(&hello, server_addr).unwrap();
+    client.send_message(&data, server_addr).unwrap();
+    let received = server.wait_for_data(Some(Duration::from_secs(2)), false).unwrap();
+    assert_eq!(received, Some((b"gated".to_vec(), client_addr, b"early".to_vec())));
+    assert!(matches!(client.get_message().unwrap().0, Message::HelloResp(_)));
+    assert_eq!(server.connected_clients(b"gated"), [client_addr]);
+}
+
+#[test]
+fn register_retries_back_off() {
+    // a holepuncher which never answers, noting when each round of Registers arrived
+    let silent = ProtocolSocket::bind("127.0.0.1:0").unwrap();
+    let holepuncher = silent.local_addr().unwrap();
+    let listener = thread::spawn(move || {
+        silent.set_read_timeout(Some(Duration::from_millis(2500))).unwrap();
+        let mut arrivals = Vec::new();
+        while let Ok((Message::Register(_), _)) = silent.get_message() {
+            arrivals.push(Instant::now());
+        }
+        return arrivals.windows(2).map(|pair| pair[1] - pair[0]).collect::<Vec<_>>();
+    });
+    let config = PassiveServerConfig {
+        handshake_timeout: Duration::from_secs(2),
+        message_timeout: Duration::from_millis(20),
+        retry_interval: Duration::from_millis(100),
+        max_retry_interval: Duration::from_millis(800),
+        ..Default::default()
+    };
+    assert!(MultiSessionServer::with_config(holepuncher, vec![b"unanswered".to_vec()], config).is_err());
+    let gaps = listener.join().unwrap();
+    assert!(gaps.len() >= 4, "only {} retries", gaps.len());
+    for pair in gaps.windows(2).take(3) {
+        assert!(pair[1] > pair[0] + Duration::from_millis(50), "gaps {:?}", gaps);
+    }
+}
+
+#[test]
+fn holepuncher_which_stops_acking_is_detected() {
+    let config = PassiveServerConfig {
+        keepalive_interval: Duration::from_millis(100),
+        holepuncher_timeout: Some(Duration::from_millis(400)),
+        ..Default::default()
+    };
+    let (mut server, _holepuncher, _) = register_with_stand_in(b"abandoned", config);
+    let started = Instant::now();
+    assert_eq!(server.wait_for_data(Some(Duration::from_secs(2)), false).unwrap(), None);
+    assert!(started.elapsed() >= Duration::from_millis(300));
+    assert!(started.elapsed() < Duration::from_secs(2));
+    assert!(server.holepuncher_timed_out());
+    assert!(server.holepuncher_last_seen() <= started);
+    // reported once
+    let again = Instant::now();
+    assert_eq!(server.wait_for_data(Some(Duration::from_millis(300)), false).unwrap(), None);
+    assert!(again.elapsed() >= Duration::from_millis(300));
+}
+
+#[test]
+fn lan_joins_are_answered_for_any_of_the_sessions() {
+    let config = PassiveServerConfig {
+        answer_lan_joins: true,
+        ..Default::default()
+    };
+    let (mut server, _holepuncher, server_addr) = register_with_stand_in(b"on the lan", config);
+    let client = ProtocolSocket::bind("127.0.0.1:0").unwrap();
+    client.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+    let client_addr = client.local_addr().unwrap();
+    let join = |session_id: &[u8]| Message::Join(JoinContents { session_id: session_id.to_vec(), candidates: vec![], mac: vec![] });
+
+    // Joins for other sessions go unanswered
+    client.send_message(&join(b"elsewhere"), server_addr).unwrap();
+    client.send_message(&join(b"on the lan"), server_addr).unwrap();
+    assert_eq!(server.wait_for_data(Some(Duration::from_millis(200)), false).unwrap(), None);
+    match client.get_message().unwrap() {
+        (Message::PeerInfo(contents), _) => {
+            assert_eq!(contents.session_id, b"on the lan");
+            assert_eq!(contents.peer_addr, server_addr);
+        },
+        other => panic!("expected a PeerInfo, got {:?}", other),
+    }
+
+    // and the client goes on with the handshake as if the holepuncher had introduced it
+    client.send_message(&Message::HelloReq(HelloReqContents::default()), server_addr).unwrap();
+    client.send_message(&Message::Data(DataContents { data: Payload::from(b"hi".to_vec()), expires_at: None }), server_addr).unwrap();
+    let received = server.wait_for_data(Some(Duration::from_secs(2)), false).unwrap();
+    assert_eq!(received, Some((b"on the lan".to_vec(), client_addr, b"hi".to_vec())));
+    assert!(matches!(client.get_message().unwrap().0, Message::HelloResp(_)));
+}
+
+#[test]
+fn acknowledged_keepalives_are_not_dropped() {
+    let holepuncher = common::spawn_holepuncher();
+    let config = PassiveServerConfig {
+        keepalive_interval: Duration::from_millis(100),
+        holepuncher_timeout: Some(Duration::from_millis(500)),
+        ..Default::default()
+    };
+    let mut server = MultiSessionServer::with_config(holepuncher, vec![b"first".to_vec(), b"second".to_vec()], config).unwrap();
+    let drops = Arc::new(Mutex::new(Vec::new()));
+    let recorded = drops.clone();
+    server.set_drop_observer(move |reason, source| recorded.lock().unwrap().push((reason, source)));
+    // about ten keepalives per session, every one acknowledged by the holepuncher
+    assert_eq!(server.wait_for_data(Some(Duration::from_secs(1)), false).unwrap(), None);
+    assert!(!server.holepuncher_timed_out());
+    assert_eq!(*drops.lock().unwrap(), vec![]);
+}
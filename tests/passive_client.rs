@@ -0,0 +1,992 @@
+#![cfg(feature = "std")]
+
+mod common;
+
+use std::net::SocketAddr;
+use std::thread;
+use std::time::{
+    Duration,
+    Instant,
+};
+use ruphin::error::{
+    HandshakeStage,
+    RuphinError,
+};
+use ruphin::event::{
+    Event,
+    HandshakeProgress,
+};
+use ruphin::messages::*;
+use ruphin::passive_client::*;
+use ruphin::passive_holepuncher::*;
+use ruphin::passive_server::*;
+use ruphin::protocol_socket::*;
+
+#[test]
+fn session_id_matches_the_one_joined() {
+    let holepuncher = common::spawn_holepuncher();
+    let server = common::spawn_server(holepuncher, b"client session", Duration::from_secs(1));
+    let client = PassiveClient::new(holepuncher, b"client session".to_vec()).unwrap();
+    assert_eq!(client.get_session_id(), b"client session");
+    server.join().unwrap();
+}
+
+#[test]
+fn hello_retries_are_spaced_out() {
+    let holepuncher = common::spawn_holepuncher();
+    let server = common::register_fake_server(holepuncher, b"slow server");
+    // a slow peer, which only answers the third HelloReq
+    let answerer = thread::spawn(move || {
+        let mut arrivals = Vec::new();
+        while arrivals.len() < 3 {
+            if let (Message::HelloReq(_), source) = server.get_message().unwrap() {
+                arrivals.push(Instant::now());
+                if arrivals.len() == 3 {
+                    server.send_message(&Message::HelloResp(HelloRespContents::default()), source).unwrap();
+                }
+            }
+        }
+        arrivals
+    });
+    let config = PassiveClientConfig {
+        retry_interval: Duration::from_millis(200),
+        message_timeout: Duration::from_millis(50),
+        ..Default::default()
+    };
+    PassiveClient::with_config(holepuncher, b"slow server".to_vec(), config).unwrap();
+    let arrivals = answerer.join().unwrap();
+    for pair in arrivals.windows(2) {
+        assert!(pair[1] - pair[0] >= Duration::from_millis(180), "retried after {:?}", pair[1] - pair[0]);
+    }
+}
+
+#[test]
+fn message_timeout_is_waited_out_before_retrying() {
+    // a holepuncher which never answers
+    let holepuncher = ProtocolSocket::bind("127.0.0.1:0").unwrap();
+    holepuncher.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let holepuncher_addr = SocketAddr::from(([127, 0, 0, 1], holepuncher.get_port().unwrap()));
+    let config = PassiveClientConfig {
+        message_timeout: Duration::from_secs(2),
+        handshake_timeout: Duration::from_secs(3),
+        ..Default::default()
+    };
+    let client = thread::spawn(move || PassiveClient::with_config(holepuncher_addr, b"satellite".to_vec(), config));
+    let mut arrivals = Vec::new();
+    while arrivals.len() < 2 {
+        if let (Message::Join(_), _) = holepuncher.get_message().unwrap() {
+            arrivals.push(Instant::now());
+        }
+    }
+    assert!(arrivals[1] - arrivals[0] >= Duration::from_millis(1900), "retried after {:?}", arrivals[1] - arrivals[0]);
+    assert!(matches!(client.join().unwrap(), Err(RuphinError::HandshakeTimeout { .. })));
+}
+
+#[test]
+fn silent_server_times_out() {
+    let holepuncher = common::spawn_holepuncher();
+    let server = common::spawn_server(holepuncher, b"crashing server", Duration::from_millis(1500));
+    let config = PassiveClientConfig {
+        keepalive_interval: Duration::from_millis(200),
+        server_timeout_keepalives: 3,
+        ..Default::default()
+    };
+    let mut client = PassiveClient::with_config(holepuncher, b"crashing server".to_vec(), config).unwrap();
+    // the server answers the keepalives while it's up
+    assert!(matches!(client.wait_for_data(Some(Duration::from_secs(1)), false), Ok(None)));
+    server.join().unwrap();
+
+    let stopped_at = Instant::now();
+    assert!(matches!(client.wait_for_data(Some(Duration::from_secs(5)), false), Err(RuphinError::PeerTimedOut)));
+    // three keepalive intervals after the server was last heard from, give or take a keepalive
+    assert!(stopped_at.elapsed() < Duration::from_millis(900), "noticed after {:?}", stopped_at.elapsed());
+}
+
+#[test]
+fn ttl_is_applied_to_the_client_socket() {
+    let holepuncher = common::spawn_holepuncher();
+    let server = common::spawn_server(holepuncher, b"ttl", Duration::from_secs(1));
+    let config = PassiveClientConfig {
+        initial_hello_ttl: Some(1),
+        ..Default::default()
+    };
+    let client = PassiveClient::with_config(holepuncher, b"ttl".to_vec(), config).unwrap();
+    client.set_ttl(30).unwrap();
+    assert_eq!(client.ttl().unwrap(), 30);
+    server.join().unwrap();
+}
+
+#[test]
+fn unreachable_server_is_reached_through_the_relay() {
+    let config = PassiveHolepuncherConfig {
+        relay_enabled: true,
+        ..Default::default()
+    };
+    let holepuncher = common::spawn_holepuncher_with(config);
+    // a server which can't be reached directly: it ignores everything but relayed Data, which it echoes
+    let server = common::register_fake_server(holepuncher, b"fallback");
+    let server_addr = SocketAddr::from(([127, 0, 0, 1], server.get_port().unwrap()));
+    thread::spawn(move || {
+        while let Ok((msg, _)) = server.get_message() {
+            if let Message::RelayData(contents) = msg {
+                if let Ok(Message::Data(_)) = Message::deserialize(&contents.payload) {
+                    server.send_message(&Message::RelayData(contents), holepuncher).unwrap();
+                }
+            }
+        }
+    });
+    let config = PassiveClientConfig {
+        message_timeout: Duration::from_millis(50),
+        hello_retries: 2,
+        relay_fallback: true,
+        ..Default::default()
+    };
+    let mut client = PassiveClient::with_config(holepuncher, b"fallback".to_vec(), config).unwrap();
+    assert!(client.is_relayed());
+    assert_eq!(client.get_server(), server_addr);
+    client.send_datagram(server_addr, b"echo".to_vec()).unwrap();
+    assert_eq!(client.wait_for_data(Some(Duration::from_secs(2)), false).unwrap(), Some((server_addr, b"echo".to_vec())));
+}
+
+#[test]
+fn lost_reliable_datagrams_are_retransmitted() {
+    let holepuncher = common::spawn_holepuncher();
+    let server = common::register_fake_server(holepuncher, b"reliable");
+    let server = thread::spawn(move || {
+        let mut copies = 0;
+        loop {
+            match server.get_message().unwrap() {
+                (Message::HelloReq(_), source) => {
+                    server.send_message(&Message::HelloResp(HelloRespContents::default()), source).unwrap();
+                },
+                (Message::ReliableData(contents), source) => {
+                    // pretend the first copy was lost on the way
+                    copies += 1;
+                    if copies == 2 {
+                        server.send_message(&Message::DataAck(DataAckContents { seq: contents.seq }), source).unwrap();
+                        return contents.data;
+                    }
+                },
+                _ => continue,
+            }
+        }
+    });
+    let mut client = PassiveClient::new(holepuncher, b"reliable".to_vec()).unwrap();
+    client.send_reliable(b"level change".to_vec()).unwrap();
+    assert_eq!(server.join().unwrap(), b"level change");
+}
+
+#[test]
+fn peers_connect_over_ipv6() {
+    let mut holepuncher = PassiveHolepuncher::new("[::1]:0").unwrap();
+    let holepuncher_addr = holepuncher.local_addr().unwrap();
+    thread::spawn(move || {
+        let _ = holepuncher.serve(None, false);
+    });
+    let mut server = PassiveServer::new(holepuncher_addr, b"six".to_vec()).unwrap();
+    let server = thread::spawn(move || server.wait_for_data(Some(Duration::from_secs(3)), false).unwrap());
+
+    let mut client = PassiveClient::new(holepuncher_addr, b"six".to_vec()).unwrap();
+    let server_addr = client.get_server();
+    assert!(server_addr.is_ipv6());
+    client.send_datagram(server_addr, b"over v6".to_vec()).unwrap();
+    let (source, data) = server.join().unwrap().unwrap();
+    assert!(source.is_ipv6());
+    assert_eq!(data, b"over v6");
+}
+
+#[test]
+fn lowered_keepalive_interval_takes_effect_right_away() {
+    let holepuncher = common::spawn_holepuncher();
+    let server = common::register_fake_server(holepuncher, b"mobile");
+    server.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+    // answers keepalives for a while, noting when they arrive
+    let server = thread::spawn(move || {
+        let end = Instant::now() + Duration::from_millis(2500);
+        let mut arrivals = Vec::new();
+        while Instant::now() < end {
+            if let Ok((Message::HelloReq(_), source)) = server.get_message() {
+                arrivals.push(Instant::now());
+                server.send_message(&Message::HelloResp(HelloRespContents::default()), source).unwrap();
+            }
+        }
+        arrivals
+    });
+    let mut client = PassiveClient::new(holepuncher, b"mobile".to_vec()).unwrap();
+    assert!(matches!(client.set_keepalive_interval(Duration::ZERO), Err(RuphinError::InvalidConfig(_))));
+
+    let lowered_at = Instant::now();
+    client.set_keepalive_interval(Duration::from_millis(200)).unwrap();
+    assert!(client.wait_for_data(Some(Duration::from_secs(1)), false).unwrap().is_none());
+    // the default interval of 10 s would not have sent any in this time
+    let keepalives = server.join().unwrap().into_iter().filter(|arrival| *arrival > lowered_at).count();
+    assert!(keepalives >= 3, "only {} keepalives were sent", keepalives);
+}
+
+#[test]
+fn silent_holepuncher_times_out_waiting_for_peer_info() {
+    let holepuncher = ProtocolSocket::bind("127.0.0.1:0").unwrap();
+    let config = PassiveClientConfig {
+        handshake_timeout: Duration::from_millis(800),
+        ..Default::default()
+    };
+    let result = PassiveClient::with_config(holepuncher.local_addr().unwrap(), b"stalled".to_vec(), config);
+    assert!(matches!(result, Err(RuphinError::HandshakeTimeout { stage: HandshakeStage::WaitingForPeerInfo })));
+}
+
+// Starts a fake holepuncher on another thread, which answers every Join with a PeerInfo pointing at the given address,
+// for the given session. It serves for a couple of seconds.
+fn spawn_fake_holepuncher(session_id: &[u8], peer_addr: SocketAddr) -> SocketAddr {
+    return spawn_fake_holepuncher_with_candidates(session_id, peer_addr, Vec::new());
+}
+
+// Like spawn_fake_holepuncher, but the PeerInfo names the given candidates as well.
+fn spawn_fake_holepuncher_with_candidates(session_id: &[u8], peer_addr: SocketAddr, candidates: Vec<SocketAddr>) -> SocketAddr {
+    let holepuncher = ProtocolSocket::bind("127.0.0.1:0").unwrap();
+    holepuncher.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+    let holepuncher_addr = holepuncher.local_addr().unwrap();
+    let peer_info = Message::PeerInfo(PeerInfoContents {
+        session_id: session_id.to_vec(),
+        peer_addr,
+        candidates,
+        mac: Vec::new(),
+    });
+    thread::spawn(move || {
+        while let Ok((msg, source)) = holepuncher.get_message() {
+            if let Message::Join(_) = msg {
+                holepuncher.send_message(&peer_info, source).unwrap();
+            }
+        }
+    });
+    return holepuncher_addr;
+}
+
+#[test]
+fn silent_server_times_out_waiting_for_hello_resp() {
+    // the client is pointed at a server that never answers
+    let dead_server = ProtocolSocket::bind("127.0.0.1:0").unwrap();
+    let holepuncher_addr = spawn_fake_holepuncher(b"stalled", dead_server.local_addr().unwrap());
+    let config = PassiveClientConfig {
+        handshake_timeout: Duration::from_millis(800),
+        ..Default::default()
+    };
+    let result = PassiveClient::with_config(holepuncher_addr, b"stalled".to_vec(), config);
+    assert!(matches!(result, Err(RuphinError::HandshakeTimeout { stage: HandshakeStage::WaitingForHelloResp })));
+}
+
+#[test]
+fn server_is_reached_at_a_candidate_address() {
+    // the address the holepuncher saw is dead, only the candidate answers
+    let dead_server = ProtocolSocket::bind("127.0.0.1:0").unwrap();
+    let server = ProtocolSocket::bind("127.0.0.1:0").unwrap();
+    server.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+    let server_addr = server.local_addr().unwrap();
+    let holepuncher_addr = spawn_fake_holepuncher_with_candidates(b"candidate", dead_server.local_addr().unwrap(), vec![server_addr]);
+    let answering = thread::spawn(move || {
+        while let Ok((msg, source)) = server.get_message() {
+            if let Message::HelloReq(_) = msg {
+                server.send_message(&Message::HelloResp(HelloRespContents::default()), source).unwrap();
+                return;
+            }
+        }
+    });
+    let client = PassiveClient::new(holepuncher_addr, b"candidate".to_vec()).unwrap();
+    assert_eq!(client.get_server(), server_addr);
+    answering.join().unwrap();
+}
+
+#[test]
+fn server_which_stops_acking_is_lost() {
+    // a server which completes the handshake, but never acks
+    let server = ProtocolSocket::bind("127.0.0.1:0").unwrap();
+    server.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+    let server_addr = server.local_addr().unwrap();
+    let holepuncher_addr = spawn_fake_holepuncher(b"unacked", server_addr);
+    thread::spawn(move || {
+        while let Ok((msg, source)) = server.get_message() {
+            if let Message::HelloReq(_) = msg {
+                server.send_message(&Message::HelloResp(HelloRespContents::default()), source).unwrap();
+            }
+        }
+    });
+    let config = PassiveClientConfig {
+        reliable_timeout: Duration::from_millis(100),
+        max_unacked_reliable: Some(2),
+        ..Default::default()
+    };
+    let mut client = PassiveClient::with_config(holepuncher_addr, b"unacked".to_vec(), config).unwrap();
+    assert!(client.send_reliable(b"x".to_vec()).is_err());
+    assert_eq!(client.wait_for_event(Some(Duration::from_millis(50)), false).unwrap(), Event::Timeout);
+    assert!(client.send_reliable(b"x".to_vec()).is_err());
+    assert_eq!(client.wait_for_event(Some(Duration::from_millis(50)), false).unwrap(), Event::PeerLost(server_addr));
+    // reported once, not for every further send
+    assert!(client.send_reliable(b"x".to_vec()).is_err());
+    assert_eq!(client.wait_for_event(Some(Duration::from_millis(50)), false).unwrap(), Event::Timeout);
+}
+
+#[test]
+fn servers_are_found_by_broadcasting_on_the_lan() {
+    use std::sync::atomic::{
+        AtomicBool,
+        Ordering,
+    };
+    use std::sync::Arc;
+
+    let port = ProtocolSocket::bind("0.0.0.0:0").unwrap().local_addr().unwrap().port();
+    let server_config = PassiveServerConfig {
+        bind_addr: Some(SocketAddr::from(([0, 0, 0, 0], port))),
+        ..Default::default()
+    };
+    let mut server = PassiveServer::lan_only(b"lan".to_vec(), server_config).unwrap();
+    // there is no holepuncher to leave
+    assert!(server.disconnect().is_err());
+    let stop = Arc::new(AtomicBool::new(false));
+    let stopped = Arc::clone(&stop);
+    // a server echoing every datagram
+    let echoing = thread::spawn(move || {
+        while !stopped.load(Ordering::Relaxed) {
+            if let Event::Data(source, data) = server.wait_for_event(Some(Duration::from_millis(20)), false).unwrap() {
+                server.send_datagram(source, data).unwrap();
+            }
+        }
+    });
+
+    let mut config = PassiveClientConfig {
+        lan_discovery: true,
+        handshake_timeout: Duration::from_secs(3),
+        ..Default::default()
+    };
+    let broadcast = SocketAddr::from(([127, 255, 255, 255], port));
+    let mut client = PassiveClient::with_config(broadcast, b"lan".to_vec(), config.clone()).unwrap();
+    let server_addr = client.get_server();
+    assert_eq!(server_addr.port(), port);
+    client.send_datagram(server_addr, b"hey".to_vec()).unwrap();
+    match client.wait_for_data(Some(Duration::from_secs(2)), false).unwrap() {
+        Some((_, data)) => assert_eq!(data, b"hey"),
+        None => panic!("expected the echo, got nothing"),
+    }
+    // servers of other sessions don't answer
+    config.handshake_timeout = Duration::from_millis(800);
+    assert!(PassiveClient::with_config(broadcast, b"other".to_vec(), config).is_err());
+    stop.store(true, Ordering::Relaxed);
+    echoing.join().unwrap();
+}
+
+#[test]
+fn lan_only_servers_need_a_bind_addr() {
+    assert!(matches!(PassiveServer::lan_only(b"lan".to_vec(), PassiveServerConfig::default()), Err(RuphinError::InvalidConfig(_))));
+}
+
+#[test]
+fn first_answering_holepuncher_is_used() {
+    let silent = ProtocolSocket::bind("127.0.0.1:0").unwrap();
+    let unaware = common::spawn_holepuncher();
+    let holepuncher = common::spawn_holepuncher();
+    let server = common::spawn_server(holepuncher, b"redundant", Duration::from_millis(1500));
+    let holepunchers = vec![silent.local_addr().unwrap(), unaware, holepuncher];
+    // the SessionNotFound from the second one doesn't stop the third one from completing the handshake
+    let mut answered_by = None;
+    PassiveClient::with_progress(holepunchers, b"redundant".to_vec(), PassiveClientConfig::default(), |progress| {
+        if let HandshakeProgress::GotPeerInfo { holepuncher, .. } = progress {
+            answered_by = Some(holepuncher);
+        }
+    }).unwrap();
+    assert_eq!(answered_by, Some(holepuncher));
+    server.join().unwrap();
+}
+
+#[test]
+fn interrupt_wakes_a_blocked_client() {
+    let holepuncher = common::spawn_holepuncher();
+    let server = common::spawn_server(holepuncher, b"interrupted", Duration::from_secs(2));
+    let mut client = PassiveClient::new(holepuncher, b"interrupted".to_vec()).unwrap();
+    let handle = client.interrupt_handle().unwrap();
+    let interrupter = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(300));
+        handle.interrupt().unwrap();
+    });
+    let started = Instant::now();
+    assert!(client.wait_for_data(None, true).unwrap().is_none());
+    assert!(started.elapsed() < Duration::from_secs(2), "interrupted after {:?}", started.elapsed());
+    interrupter.join().unwrap();
+    server.join().unwrap();
+}
+
+#[test]
+fn peer_info_for_another_session_is_ignored() {
+    let server = ProtocolSocket::bind("127.0.0.1:0").unwrap();
+    let holepuncher = spawn_fake_holepuncher(b"another session", server.local_addr().unwrap());
+    let config = PassiveClientConfig {
+        handshake_timeout: Duration::from_millis(800),
+        ..Default::default()
+    };
+    let result = PassiveClient::with_config(holepuncher, b"my session".to_vec(), config);
+    assert!(matches!(result, Err(RuphinError::HandshakeTimeout { stage: HandshakeStage::WaitingForPeerInfo })));
+}
+
+#[test]
+fn reconnect_keeps_the_local_port() {
+    let holepuncher = common::spawn_holepuncher();
+    let old_server = common::spawn_server(holepuncher, b"restarting", Duration::from_millis(500));
+    let mut client = PassiveClient::new(holepuncher, b"restarting".to_vec()).unwrap();
+    let port = client.get_port().unwrap();
+    let old_server_addr = client.get_server();
+    // the server goes away and comes back on another port
+    drop(old_server.join().unwrap());
+    let new_server = common::spawn_server(holepuncher, b"restarting", Duration::from_secs(2));
+
+    client.reconnect().unwrap();
+    assert_eq!(client.get_port().unwrap(), port);
+    assert_ne!(client.get_server(), old_server_addr);
+    new_server.join().unwrap();
+}
+
+#[test]
+fn queries_return_the_metadata_registered_with() {
+    let holepuncher = common::spawn_holepuncher();
+    let config = PassiveServerConfig {
+        metadata: b"mode=ctf;slots=4".to_vec(),
+        ..Default::default()
+    };
+    let _with_metadata = PassiveServer::with_config(holepuncher, b"with metadata".to_vec(), config).unwrap();
+    let _without_metadata = PassiveServer::new(holepuncher, b"without metadata".to_vec()).unwrap();
+    let config = PassiveClientConfig::default();
+    assert_eq!(PassiveClient::query_session(holepuncher, b"with metadata", &config).unwrap(), b"mode=ctf;slots=4");
+    assert!(PassiveClient::query_session(holepuncher, b"without metadata", &config).unwrap().is_empty());
+    assert!(matches!(PassiveClient::query_session(holepuncher, b"no such session", &config), Err(RuphinError::SessionNotFound)));
+}
+
+#[test]
+fn oversized_metadata_is_refused() {
+    let holepuncher = common::spawn_holepuncher();
+    let config = PassiveServerConfig {
+        metadata: vec![0; MAX_METADATA_SIZE + 1],
+        ..Default::default()
+    };
+    assert!(matches!(PassiveServer::with_config(holepuncher, b"too much".to_vec(), config), Err(RuphinError::InvalidConfig(_))));
+}
+
+#[test]
+fn sockets_bind_to_the_configured_address() {
+    let holepuncher = common::spawn_holepuncher();
+    let config = PassiveServerConfig {
+        bind_addr: Some("127.0.0.1:0".parse().unwrap()),
+        ..Default::default()
+    };
+    let server = common::spawn_server_with(holepuncher, b"bound to loopback", config, Duration::from_secs(1));
+    let config = PassiveClientConfig {
+        bind_addr: Some("127.0.0.1:0".parse().unwrap()),
+        ..Default::default()
+    };
+    let client = PassiveClient::with_config(holepuncher, b"bound to loopback".to_vec(), config).unwrap();
+    assert!(client.local_addr().unwrap().ip().is_loopback());
+    assert!(server.join().unwrap().local_addr().unwrap().ip().is_loopback());
+}
+
+// Connects a client to a fake server registered at a real holepuncher, returning the client and the server's socket.
+// The server answers the handshake's HelloReq and nothing else, so later keepalives can be counted with count_keepalives.
+fn connect_to_fake_server(session_id: &[u8]) -> (PassiveClient, ProtocolSocket) {
+    let holepuncher = common::spawn_holepuncher();
+    let server = common::register_fake_server(holepuncher, session_id);
+    let answering = server.try_clone().unwrap();
+    let answerer = thread::spawn(move || {
+        while let Ok((msg, source)) = answering.get_message() {
+            if let Message::HelloReq(_) = msg {
+                answering.send_message(&Message::HelloResp(HelloRespContents::default()), source).unwrap();
+                return;
+            }
+        }
+    });
+    let client = PassiveClient::new(holepuncher, session_id.to_vec()).unwrap();
+    answerer.join().unwrap();
+    server.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+    return (client, server);
+}
+
+#[test]
+fn data_from_peers_other_than_the_server_is_dropped() {
+    use std::sync::{Arc, Mutex};
+    use ruphin::event::DropReason;
+
+    let (mut client, server) = connect_to_fake_server(b"filtered");
+    let drops = Arc::new(Mutex::new(Vec::new()));
+    let recorded = Arc::clone(&drops);
+    client.set_drop_observer(move |reason, source| recorded.lock().unwrap().push((reason, source)));
+    let client_addr = SocketAddr::new("127.0.0.1".parse().unwrap(), client.local_addr().unwrap().port());
+    let stranger = ProtocolSocket::bind("127.0.0.1:0").unwrap();
+    for (sock, data) in [(&stranger, b"stray"), (&server, b"known")] {
+        sock.send_message(&Message::Data(DataContents { data: Payload::from(data.to_vec()), expires_at: None }), client_addr).unwrap();
+    }
+    loop {
+        match client.wait_for_event(Some(Duration::from_secs(2)), false).unwrap() {
+            Event::Data(source, data) => {
+                assert_eq!(source, server.local_addr().unwrap());
+                assert_eq!(data, b"known");
+                break;
+            },
+            Event::Timeout => panic!("the server's datagram never arrived"),
+            _ => continue,
+        }
+    }
+    assert_eq!(*drops.lock().unwrap(), [(DropReason::UnknownPeer, stranger.local_addr().unwrap())]);
+}
+
+#[test]
+fn data_from_the_holepuncher_is_dropped() {
+    use std::sync::{Arc, Mutex};
+    use ruphin::event::DropReason;
+
+    let server = ProtocolSocket::bind("127.0.0.1:0").unwrap();
+    server.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+    let server_addr = server.local_addr().unwrap();
+    let holepuncher = ProtocolSocket::bind("127.0.0.1:0").unwrap();
+    holepuncher.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+    let holepuncher_addr = holepuncher.local_addr().unwrap();
+    // a fake holepuncher and server which introduce the client and hand back their sockets
+    let introducing = thread::spawn(move || {
+        while let Ok((msg, source)) = holepuncher.get_message() {
+            if let Message::Join(contents) = msg {
+                let peer_info = PeerInfoContents { session_id: contents.session_id, peer_addr: server_addr, candidates: Vec::new(), mac: Vec::new() };
+                holepuncher.send_message(&Message::PeerInfo(peer_info), source).unwrap();
+                break;
+            }
+        }
+        return holepuncher;
+    });
+    let answering = thread::spawn(move || {
+        while let Ok((msg, source)) = server.get_message() {
+            if let Message::HelloReq(_) = msg {
+                server.send_message(&Message::HelloResp(HelloRespContents::default()), source).unwrap();
+                break;
+            }
+        }
+        return server;
+    });
+    let mut client = PassiveClient::new(holepuncher_addr, b"injected".to_vec()).unwrap();
+    let holepuncher = introducing.join().unwrap();
+    let server = answering.join().unwrap();
+    let drops = Arc::new(Mutex::new(Vec::new()));
+    let recorded = Arc::clone(&drops);
+    client.set_drop_observer(move |reason, source| recorded.lock().unwrap().push((reason, source)));
+    let client_addr = SocketAddr::new("127.0.0.1".parse().unwrap(), client.local_addr().unwrap().port());
+    // the holepuncher only ever relays data wrapped in RelayData, plain Data from it is injected
+    for (sock, data) in [(&holepuncher, b"stray"), (&server, b"known")] {
+        sock.send_message(&Message::Data(DataContents { data: Payload::from(data.to_vec()), expires_at: None }), client_addr).unwrap();
+    }
+    loop {
+        match client.wait_for_event(Some(Duration::from_secs(2)), false).unwrap() {
+            Event::Data(source, data) => {
+                assert_eq!(source, server_addr);
+                assert_eq!(data, b"known");
+                break;
+            },
+            Event::Timeout => panic!("the server's datagram never arrived"),
+            _ => continue,
+        }
+    }
+    assert_eq!(*drops.lock().unwrap(), [(DropReason::UnknownPeer, holepuncher_addr)]);
+}
+
+#[test]
+fn progress_is_reported_through_the_handshake() {
+    // a server which only answers the second HelloReq
+    let server = ProtocolSocket::bind("127.0.0.1:0").unwrap();
+    server.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+    let holepuncher = spawn_fake_holepuncher(b"progress", server.local_addr().unwrap());
+    let answerer = thread::spawn(move || {
+        let mut hellos = 0;
+        while hellos < 2 {
+            if let (Message::HelloReq(_), source) = server.get_message().unwrap() {
+                hellos += 1;
+                if hellos == 2 {
+                    server.send_message(&Message::HelloResp(HelloRespContents::default()), source).unwrap();
+                }
+            }
+        }
+    });
+    let config = PassiveClientConfig {
+        retry_interval: Duration::from_millis(50),
+        message_timeout: Duration::from_millis(50),
+        ..Default::default()
+    };
+    let mut stages = Vec::new();
+    PassiveClient::with_progress(vec![holepuncher], b"progress".to_vec(), config, |progress| stages.push(progress.to_string())).unwrap();
+    assert_eq!(stages, ["sent Join", "got PeerInfo", "sent HelloReq", "sent HelloReq attempt 2", "connected"]);
+    answerer.join().unwrap();
+}
+
+#[test]
+fn ipv4_peer_is_refused_by_an_ipv6_only_client() {
+    // a holepuncher on IPv6 which points the client at an IPv4 server
+    let holepuncher = ProtocolSocket::bind("[::1]:0").unwrap();
+    holepuncher.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+    let holepuncher_addr = holepuncher.local_addr().unwrap();
+    let answerer = thread::spawn(move || {
+        if let (Message::Join(join), source) = holepuncher.get_message().unwrap() {
+            let peer_info = PeerInfoContents {
+                session_id: join.session_id,
+                peer_addr: "127.0.0.1:5000".parse().unwrap(),
+                candidates: Vec::new(),
+                mac: Vec::new(),
+            };
+            holepuncher.send_message(&Message::PeerInfo(peer_info), source).unwrap();
+        }
+    });
+    let config = PassiveClientConfig {
+        bind_addr: Some("[::1]:0".parse().unwrap()),
+        ..Default::default()
+    };
+    let result = PassiveClient::with_config(holepuncher_addr, b"mixed families".to_vec(), config);
+    assert!(matches!(result, Err(RuphinError::AddressFamilyMismatch(addr)) if addr == "127.0.0.1:5000".parse().unwrap()));
+    answerer.join().unwrap();
+}
+
+#[cfg(feature = "auth")]
+#[test]
+fn peer_info_without_a_valid_mac_is_ignored() {
+    // an injected PeerInfo, once unsigned and once with a made up MAC
+    let holepuncher = ProtocolSocket::bind("127.0.0.1:0").unwrap();
+    holepuncher.set_read_timeout(Some(Duration::from_millis(1500))).unwrap();
+    let holepuncher_addr = holepuncher.local_addr().unwrap();
+    thread::spawn(move || {
+        while let Ok((msg, source)) = holepuncher.get_message() {
+            if let Message::Join(join) = msg {
+                for mac in [Vec::new(), vec![0; 32]] {
+                    let peer_info = PeerInfoContents {
+                        session_id: join.session_id.clone(),
+                        peer_addr: "127.0.0.1:9".parse().unwrap(),
+                        candidates: Vec::new(),
+                        mac,
+                    };
+                    holepuncher.send_message(&Message::PeerInfo(peer_info), source).unwrap();
+                }
+            }
+        }
+    });
+    let config = PassiveClientConfig {
+        auth_key: Some(b"shared key".to_vec()),
+        handshake_timeout: Duration::from_secs(1),
+        ..Default::default()
+    };
+    let result = PassiveClient::with_config(holepuncher_addr, b"injected".to_vec(), config);
+    assert!(matches!(result, Err(RuphinError::HandshakeTimeout { stage: HandshakeStage::WaitingForPeerInfo })), "{:?}", result.err());
+}
+
+#[test]
+fn requests_return_the_servers_answer() {
+    // a mock server which echoes every datagram, except for the ones saying "quiet"
+    let server = ProtocolSocket::bind("127.0.0.1:0").unwrap();
+    server.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+    let holepuncher = spawn_fake_holepuncher(b"echo", server.local_addr().unwrap());
+    thread::spawn(move || {
+        while let Ok((msg, source)) = server.get_message() {
+            match msg {
+                Message::HelloReq(_) => {
+                    server.send_message(&Message::HelloResp(HelloRespContents::default()), source).unwrap();
+                },
+                Message::Data(contents) if contents.data != b"quiet"[..] => {
+                    server.send_message(&Message::Data(contents), source).unwrap();
+                },
+                _ => continue,
+            }
+        }
+    });
+    let mut client = PassiveClient::new(holepuncher, b"echo".to_vec()).unwrap();
+    assert_eq!(client.request(b"hi".to_vec(), Duration::from_secs(1)).unwrap(), b"hi");
+    assert!(matches!(client.request(b"quiet".to_vec(), Duration::from_millis(300)), Err(RuphinError::Timeout)));
+    assert_eq!(client.request(b"again".to_vec(), Duration::from_secs(1)).unwrap(), b"again");
+}
+
+#[test]
+fn clients_follow_a_migrated_session() {
+    let holepuncher = common::spawn_holepuncher();
+    let mut old_server = PassiveServer::new(holepuncher, b"migrating".to_vec()).unwrap();
+    let config = PassiveClientConfig {
+        keepalive_interval: Duration::from_millis(200),
+        server_timeout_keepalives: 20,
+        ..Default::default()
+    };
+    let connecting = thread::spawn(move || PassiveClient::with_config(holepuncher, b"migrating".to_vec(), config).unwrap());
+    while !connecting.is_finished() {
+        old_server.wait_for_event(Some(Duration::from_millis(50)), false).unwrap();
+    }
+    let mut client = connecting.join().unwrap();
+    let old_addr = client.get_server();
+
+    // stands in for the next server, which isn't up yet
+    let new_server = ProtocolSocket::bind("127.0.0.1:0").unwrap();
+    new_server.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+    let new_addr = new_server.local_addr().unwrap();
+    old_server.migrate(new_addr).unwrap();
+    client.wait_for_event(Some(Duration::from_millis(500)), false).unwrap();
+    assert_eq!(client.get_server(), new_addr);
+    assert_ne!(old_addr, new_addr);
+
+    // the keepalives go to the new address
+    let mut hellos = 0;
+    let end = Instant::now() + Duration::from_secs(1);
+    while Instant::now() < end {
+        client.wait_for_event(Some(Duration::from_millis(100)), false).unwrap();
+        while let Ok((msg, _)) = new_server.get_message() {
+            if let Message::HelloReq(_) = msg {
+                hellos += 1;
+            }
+        }
+    }
+    assert!(hellos >= 2, "only {} keepalives arrived", hellos);
+
+    // the holepuncher lets the next server take the session over from the new address
+    drop(new_server);
+    let config = PassiveServerConfig {
+        bind_addr: Some(new_addr),
+        ..Default::default()
+    };
+    let mut next_server = PassiveServer::with_config(holepuncher, b"migrating".to_vec(), config).unwrap();
+    let keeping_alive = thread::spawn(move || {
+        for _ in 0..10 {
+            client.wait_for_event(Some(Duration::from_millis(100)), false).unwrap();
+        }
+    });
+    assert!(common::eventually(Duration::from_secs(1), || {
+        matches!(next_server.wait_for_event(Some(Duration::from_millis(100)), false), Ok(Event::PeerConnected(_)))
+    }));
+    keeping_alive.join().unwrap();
+}
+
+#[test]
+fn live_holepuncher_is_not_reported_as_timed_out() {
+    let holepuncher = common::spawn_holepuncher();
+    let config = PassiveServerConfig {
+        keepalive_interval: Duration::from_millis(100),
+        holepuncher_timeout: Some(Duration::from_millis(400)),
+        ..Default::default()
+    };
+    let server = common::spawn_server_with(holepuncher, b"watched", config, Duration::from_millis(1500));
+    let config = PassiveClientConfig {
+        keepalive_interval: Duration::from_millis(100),
+        holepuncher_timeout: Some(Duration::from_millis(400)),
+        ..Default::default()
+    };
+    let mut client = PassiveClient::with_config(holepuncher, b"watched".to_vec(), config).unwrap();
+    let started = Instant::now();
+    while started.elapsed() < Duration::from_secs(1) {
+        let event = client.wait_for_event(Some(Duration::from_millis(100)), false).unwrap();
+        assert!(!matches!(event, Event::HolepuncherTimedOut(_)));
+    }
+    assert!(client.holepuncher_last_seen() > started);
+    let server = server.join().unwrap();
+    assert!(server.holepuncher_last_seen() > started);
+}
+
+#[test]
+fn holepuncher_can_be_given_by_hostname() {
+    let port = common::spawn_holepuncher().port();
+    let mut server = PassiveServer::new(("localhost", port), b"by hostname".to_vec()).unwrap();
+    let connecting = thread::spawn(move || PassiveClient::new(format!("localhost:{}", port), b"by hostname".to_vec()).unwrap());
+    while !connecting.is_finished() {
+        server.wait_for_event(Some(Duration::from_millis(50)), false).unwrap();
+    }
+    connecting.join().unwrap();
+}
+
+#[test]
+fn unresolvable_holepuncher_is_an_error() {
+    assert!(matches!(PassiveClient::new("no-such-host.invalid:1", b"nowhere".to_vec()), Err(RuphinError::Resolve(_))));
+    // resolves, but only to addresses the socket can't reach
+    let config = PassiveServerConfig {
+        bind_addr: Some("[::1]:0".parse().unwrap()),
+        ..Default::default()
+    };
+    assert!(matches!(PassiveServer::with_config("127.0.0.1:1", b"nowhere".to_vec(), config), Err(RuphinError::NoUsableAddress)));
+}
+
+#[test]
+fn shorter_keepalive_interval_of_the_server_is_adopted() {
+    let holepuncher = common::spawn_holepuncher();
+    let config = PassiveServerConfig {
+        keepalive_interval: Duration::from_secs(5),
+        ..Default::default()
+    };
+    let mut server = PassiveServer::with_config(holepuncher, b"negotiated".to_vec(), config).unwrap();
+    // the client's own interval is shorter in the second round
+    for (client_interval, expected) in [(Duration::from_secs(10), Duration::from_secs(5)), (Duration::from_secs(2), Duration::from_secs(2))] {
+        let config = PassiveClientConfig {
+            keepalive_interval: client_interval,
+            ..Default::default()
+        };
+        let connecting = thread::spawn(move || PassiveClient::with_config(holepuncher, b"negotiated".to_vec(), config).unwrap());
+        while !connecting.is_finished() {
+            server.wait_for_event(Some(Duration::from_millis(20)), false).unwrap();
+        }
+        assert_eq!(connecting.join().unwrap().keepalive_interval(), expected);
+    }
+}
+
+#[test]
+fn keepalives_are_sent_at_the_proposed_interval() {
+    let holepuncher = common::spawn_holepuncher();
+    // a server proposing 250 ms, which records when keepalives arrive
+    let server = common::register_fake_server(holepuncher, b"proposed");
+    let answerer = thread::spawn(move || {
+        server.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        let mut hellos = 0;
+        let end = Instant::now() + Duration::from_millis(2500);
+        while Instant::now() < end {
+            if let Ok((Message::HelloReq(_), source)) = server.get_message() {
+                hellos += 1;
+                server.send_message(&Message::HelloResp(HelloRespContents::proposing(Duration::from_millis(250))), source).unwrap();
+            }
+        }
+        return hellos;
+    });
+    let mut client = PassiveClient::new(holepuncher, b"proposed".to_vec()).unwrap();
+    assert_eq!(client.keepalive_interval(), Duration::from_millis(250));
+    let end = Instant::now() + Duration::from_secs(2);
+    while Instant::now() < end {
+        client.wait_for_event(Some(Duration::from_millis(50)), false).unwrap();
+    }
+    // the handshake's HelloReq, then one every 250 ms rather than none within the default 10 s
+    let hellos = answerer.join().unwrap();
+    assert!(hellos >= 7, "only {} HelloReqs", hellos);
+}
+
+// Counts the keepalives which reached the fake server by now.
+fn count_keepalives(server: &ProtocolSocket) -> usize {
+    let mut count = 0;
+    while let Ok((msg, _)) = server.get_message() {
+        if let Message::HelloReq(_) = msg {
+            count += 1;
+        }
+    }
+    return count;
+}
+
+#[test]
+fn keepalives_follow_the_clock() {
+    use ruphin::clock::MockClock;
+
+    let (mut client, server) = connect_to_fake_server(b"mock clock");
+    let clock = MockClock::new();
+    client.set_clock(clock.clone());
+
+    client.wait_for_event(Some(Duration::ZERO), false).unwrap();
+    assert_eq!(count_keepalives(&server), 0);
+    clock.advance(Duration::from_secs(9));
+    client.wait_for_event(Some(Duration::ZERO), false).unwrap();
+    assert_eq!(count_keepalives(&server), 0);
+    // past the default 10 s keepalive interval, without waiting for it
+    clock.advance(Duration::from_secs(2));
+    client.wait_for_event(Some(Duration::ZERO), false).unwrap();
+    assert_eq!(count_keepalives(&server), 1);
+    // a clock standing still doesn't keep a timed call from returning
+    client.wait_for_event(Some(Duration::from_millis(50)), false).unwrap();
+}
+
+#[test]
+fn paused_keepalives_are_not_sent() {
+    use ruphin::clock::MockClock;
+
+    let (mut client, server) = connect_to_fake_server(b"paused");
+    let clock = MockClock::new();
+    client.set_clock(clock.clone());
+    client.pause_keepalives();
+    // long past every keepalive and the server timeout, the server isn't reported as timed out either
+    for _ in 0..10 {
+        clock.advance(Duration::from_secs(11));
+        let event = client.wait_for_event(Some(Duration::ZERO), false).unwrap();
+        assert_eq!(event, Event::Timeout);
+    }
+    assert_eq!(count_keepalives(&server), 0);
+
+    // resuming sends one right away, then they follow the interval again
+    client.resume_keepalives().unwrap();
+    assert_eq!(count_keepalives(&server), 1);
+    assert_eq!(client.wait_for_event(Some(Duration::ZERO), false).unwrap(), Event::Timeout);
+    clock.advance(Duration::from_secs(11));
+    client.wait_for_event(Some(Duration::ZERO), false).unwrap();
+    assert_eq!(count_keepalives(&server), 1);
+}
+
+#[test]
+fn huge_intervals_and_timeouts_dont_overflow() {
+    let holepuncher = common::spawn_holepuncher();
+    let server = common::spawn_server(holepuncher, b"forever", Duration::from_secs(1));
+    let config = PassiveClientConfig {
+        keepalive_interval: Duration::from_secs(u64::MAX / 2),
+        keepalive_jitter: 0.5,
+        server_timeout_keepalives: u32::MAX,
+        rejoin_interval: Some(Duration::MAX),
+        holepuncher_timeout: Some(Duration::MAX),
+        data_ttl: Some(Duration::MAX),
+        ..Default::default()
+    };
+    let mut client = PassiveClient::with_config(holepuncher, b"forever".to_vec(), config).unwrap();
+    client.send_datagram(client.get_server(), b"data".to_vec()).unwrap();
+    assert_eq!(client.wait_for_event(Some(Duration::from_millis(100)), false).unwrap(), Event::Timeout);
+    client.measure_rtt(Duration::MAX).unwrap();
+    server.join().unwrap();
+}
+
+#[test]
+fn client_drops_are_reported_with_their_reason() {
+    use ruphin::event::DropReason;
+    use std::sync::{
+        Arc,
+        Mutex,
+    };
+
+    let holepuncher = common::spawn_holepuncher();
+    let server = common::spawn_server(holepuncher, b"client drops", Duration::from_secs(1));
+    let mut client = PassiveClient::new(holepuncher, b"client drops".to_vec()).unwrap();
+    let drops = Arc::new(Mutex::new(Vec::new()));
+    let recorded = Arc::clone(&drops);
+    client.set_drop_observer(move |reason, _| recorded.lock().unwrap().push(reason));
+    let client_addr = SocketAddr::from(([127, 0, 0, 1], client.get_port().unwrap()));
+    let stranger = ProtocolSocket::bind("127.0.0.1:0").unwrap();
+    let peer_info = PeerInfoContents { session_id: b"client drops".to_vec(), peer_addr: client_addr, candidates: vec![], mac: vec![] };
+    stranger.send_message(&Message::PeerInfo(peer_info), client_addr).unwrap();
+    let register = RegisterContents { session_id: b"other".to_vec(), metadata: vec![], candidates: vec![], mac: vec![] };
+    stranger.send_message(&Message::Register(register), client_addr).unwrap();
+    std::net::UdpSocket::bind("127.0.0.1:0").unwrap().send_to(b"not a message", client_addr).unwrap();
+    for _ in 0..5 {
+        client.wait_for_event(Some(Duration::from_millis(30)), false).unwrap();
+    }
+    assert_eq!(*drops.lock().unwrap(), [DropReason::WrongSource, DropReason::UnexpectedMessage, DropReason::Malformed]);
+    server.join().unwrap();
+}
+
+#[test]
+fn single_hello_retry_falls_back_to_joining_again() {
+    let holepuncher = common::spawn_holepuncher();
+    // a server which never answers, and records the PeerInfos and HelloReqs reaching it
+    let server = common::register_fake_server(holepuncher, b"one hello");
+    let recorder = thread::spawn(move || {
+        server.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        let (mut peer_infos, mut hellos) = (0, 0);
+        let end = Instant::now() + Duration::from_millis(2500);
+        while Instant::now() < end {
+            match server.get_message() {
+                Ok((Message::PeerInfo(_), _)) => peer_infos += 1,
+                Ok((Message::HelloReq(_), _)) => hellos += 1,
+                _ => continue,
+            }
+        }
+        return (peer_infos, hellos);
+    });
+    let config = PassiveClientConfig {
+        hello_retries: 1,
+        handshake_timeout: Duration::from_secs(2),
+        ..Default::default()
+    };
+    assert!(PassiveClient::with_config(holepuncher, b"one hello".to_vec(), config).is_err());
+    // one HelloReq per Join, each of which makes the holepuncher send the server a PeerInfo.
+    // The HelloReq can overtake the PeerInfo, so only the counts are compared.
+    let (peer_infos, hellos) = recorder.join().unwrap();
+    assert!(hellos >= 2 && hellos <= peer_infos, "{} HelloReqs for {} PeerInfos", hellos, peer_infos);
+
+    let config = PassiveClientConfig {
+        hello_retries: 0,
+        ..Default::default()
+    };
+    assert!(matches!(PassiveClient::with_config(holepuncher, b"one hello".to_vec(), config), Err(RuphinError::InvalidConfig(_))));
+}
@@ -0,0 +1,748 @@
+#![cfg(feature = "std")]
+
+mod common;
+
+use std::net::SocketAddr;
+use std::sync::{
+    Arc,
+    Mutex,
+    RwLock,
+};
+use std::thread;
+use std::time::{
+    Duration,
+    Instant,
+};
+use ruphin::diagnostics::{
+    check_session,
+    detect_nat_type,
+    ping_holepuncher,
+    NatType,
+};
+use ruphin::error::RuphinError;
+use ruphin::messages::*;
+use ruphin::passive_client::*;
+use ruphin::passive_holepuncher::*;
+use ruphin::passive_server::*;
+use ruphin::protocol_socket::*;
+
+// Starts a holepuncher keeping its sessions in the given store, which the test can then inspect.
+fn spawn_with_store(store: Arc<Mutex<InMemorySessionStore>>, config: PassiveHolepuncherConfig) -> SocketAddr {
+    let mut holepuncher = PassiveHolepuncher::with_session_store("127.0.0.1:0", config, store).unwrap();
+    let addr = holepuncher.local_addr().unwrap();
+    thread::spawn(move || {
+        let _ = holepuncher.serve(None, false);
+    });
+    return addr;
+}
+
+#[test]
+fn goodbye_removes_the_session() {
+    let store = Arc::new(Mutex::new(InMemorySessionStore::new()));
+    let holepuncher = spawn_with_store(Arc::clone(&store), PassiveHolepuncherConfig::default());
+    let mut server = PassiveServer::new(holepuncher, b"goodbye".to_vec()).unwrap();
+    assert!(store.get(b"goodbye").is_some());
+
+    server.disconnect().unwrap();
+    assert!(common::eventually(Duration::from_secs(1), || store.get(b"goodbye").is_none()));
+    assert!(matches!(PassiveClient::new(holepuncher, b"goodbye".to_vec()), Err(RuphinError::SessionNotFound)));
+}
+
+#[test]
+fn registrations_beyond_max_sessions_are_rejected() {
+    let config = PassiveHolepuncherConfig {
+        max_sessions: Some(2),
+        ..Default::default()
+    };
+    let holepuncher = common::spawn_holepuncher_with(config);
+    let _first = PassiveServer::new(holepuncher, b"first".to_vec()).unwrap();
+    let _second = PassiveServer::new(holepuncher, b"second".to_vec()).unwrap();
+    assert!(matches!(PassiveServer::new(holepuncher, b"third".to_vec()), Err(RuphinError::RegisterRejected)));
+    for session_id in [&b"first"[..], &b"second"[..]] {
+        assert!(check_session(holepuncher, session_id, Duration::from_secs(1)).unwrap());
+    }
+}
+
+// Sends the holepuncher a Join for a session it doesn't know, which it answers with a SessionNotFound.
+fn send_join(sock: &ProtocolSocket, holepuncher: SocketAddr) {
+    let join = Message::Join(JoinContents {
+        session_id: b"unknown".to_vec(),
+        candidates: Vec::new(),
+        mac: Vec::new(),
+    });
+    sock.send_message(&join, holepuncher).unwrap();
+}
+
+// Counts the answers the socket receives until it has been quiet for a while.
+fn count_answers(sock: &ProtocolSocket) -> usize {
+    sock.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+    let mut answers = 0;
+    while sock.get_message().is_ok() {
+        answers += 1;
+    }
+    return answers;
+}
+
+#[test]
+fn joins_beyond_the_rate_limit_are_dropped() {
+    let config = PassiveHolepuncherConfig {
+        rate_limit: Some(RateLimit { max_requests: 5, per: Duration::from_secs(10) }),
+        ..Default::default()
+    };
+    let holepuncher = common::spawn_holepuncher_with(config);
+    let flooder = ProtocolSocket::bind("127.0.0.1:0").unwrap();
+    for _ in 0..100 {
+        send_join(&flooder, holepuncher);
+    }
+    assert_eq!(count_answers(&flooder), 5);
+
+    // another address has its own budget
+    let other = ProtocolSocket::bind("127.0.0.2:0").unwrap();
+    send_join(&other, holepuncher);
+    assert_eq!(count_answers(&other), 1);
+}
+
+#[test]
+fn sessions_cannot_be_taken_over() {
+    let store = Arc::new(Mutex::new(InMemorySessionStore::new()));
+    let holepuncher = spawn_with_store(Arc::clone(&store), PassiveHolepuncherConfig::default());
+    let config = PassiveServerConfig {
+        keepalive_interval: Duration::from_millis(100),
+        ..Default::default()
+    };
+    let mut owner = PassiveServer::with_config(holepuncher, b"owned".to_vec(), config).unwrap();
+    let owner_port = owner.get_port().unwrap();
+    assert!(matches!(PassiveServer::new(holepuncher, b"owned".to_vec()), Err(RuphinError::RegisterRejected)));
+
+    // the owner's keepalives still refresh the session
+    assert!(matches!(owner.wait_for_data(Some(Duration::from_millis(400)), false), Ok(None)));
+    assert_eq!(store.get(b"owned").unwrap().port(), owner_port);
+    assert!(matches!(PassiveServer::new(holepuncher, b"owned".to_vec()), Err(RuphinError::RegisterRejected)));
+}
+
+#[test]
+fn active_sessions_lists_every_registration() {
+    let store = Arc::new(Mutex::new(InMemorySessionStore::new()));
+    let mut holepuncher = PassiveHolepuncher::with_session_store("127.0.0.1:0", PassiveHolepuncherConfig::default(), Arc::clone(&store)).unwrap();
+    let addr = SocketAddr::from(([127, 0, 0, 1], holepuncher.get_port().unwrap()));
+    let serving = thread::spawn(move || {
+        holepuncher.serve(Some(Duration::from_millis(500)), false).unwrap();
+        holepuncher
+    });
+    let _first = PassiveServer::new(addr, b"first".to_vec()).unwrap();
+    let _second = PassiveServer::new(addr, b"second".to_vec()).unwrap();
+    // while serve runs, the sessions are listed through the shared store
+    let mut listed: Vec<Vec<u8>> = store.sessions().into_iter().map(|(session_id, _)| session_id).collect();
+    listed.sort();
+    assert_eq!(listed, vec![b"first".to_vec(), b"second".to_vec()]);
+
+    let holepuncher = serving.join().unwrap();
+    assert_eq!(holepuncher.session_count(), 2);
+    assert_eq!(holepuncher.active_sessions().len(), 2);
+}
+
+#[test]
+fn relay_only_connects_the_server_with_joined_clients() {
+    let config = PassiveHolepuncherConfig {
+        relay_enabled: true,
+        ..Default::default()
+    };
+    let holepuncher = common::spawn_holepuncher_with(config);
+    let server = common::register_fake_server(holepuncher, b"relayed");
+    let server_addr = SocketAddr::from(([127, 0, 0, 1], server.get_port().unwrap()));
+    let client = ProtocolSocket::bind("127.0.0.1:0").unwrap();
+    client.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+    let client_addr = SocketAddr::from(([127, 0, 0, 1], client.get_port().unwrap()));
+    let join = Message::Join(JoinContents { session_id: b"relayed".to_vec(), candidates: Vec::new(), mac: Vec::new() });
+    client.send_message(&join, holepuncher).unwrap();
+    assert!(matches!(client.get_message().unwrap().0, Message::PeerInfo(_)));
+    assert!(matches!(server.get_message().unwrap().0, Message::PeerInfo(_)));
+    let stranger = ProtocolSocket::bind("127.0.0.1:0").unwrap();
+    stranger.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+    let stranger_addr = SocketAddr::from(([127, 0, 0, 1], stranger.get_port().unwrap()));
+    let relay = |peer_addr: SocketAddr, payload: &[u8]| Message::RelayData(RelayDataContents {
+        session_id: b"relayed".to_vec(),
+        peer_addr,
+        payload: payload.to_vec(),
+    });
+
+    // a peer which didn't join can neither reach the server nor be reached through the relay...
+    stranger.send_message(&relay(holepuncher, b"from the stranger"), holepuncher).unwrap();
+    server.send_message(&relay(stranger_addr, b"to the stranger"), holepuncher).unwrap();
+    // ...while a client which joined can, both ways
+    client.send_message(&relay(holepuncher, b"from the client"), holepuncher).unwrap();
+    server.send_message(&relay(client_addr, b"to the client"), holepuncher).unwrap();
+    match server.get_message().unwrap() {
+        (Message::RelayData(contents), _) => {
+            assert_eq!(contents.peer_addr, client_addr);
+            assert_eq!(contents.payload, b"from the client");
+        },
+        (other, _) => panic!("expected a RelayData, got {:?}", other),
+    }
+    match client.get_message().unwrap() {
+        (Message::RelayData(contents), _) => {
+            assert_eq!(contents.peer_addr, server_addr);
+            assert_eq!(contents.payload, b"to the client");
+        },
+        (other, _) => panic!("expected a RelayData, got {:?}", other),
+    }
+    assert!(stranger.get_message().is_err());
+}
+
+#[test]
+fn expired_datagrams_are_not_relayed() {
+    let config = PassiveHolepuncherConfig {
+        relay_enabled: true,
+        ..Default::default()
+    };
+    let holepuncher = common::spawn_holepuncher_with(config);
+    let server = common::register_fake_server(holepuncher, b"expiring");
+    let client = ProtocolSocket::bind("127.0.0.1:0").unwrap();
+    client.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+    let join = Message::Join(JoinContents { session_id: b"expiring".to_vec(), candidates: Vec::new(), mac: Vec::new() });
+    client.send_message(&join, holepuncher).unwrap();
+    assert!(matches!(client.get_message().unwrap().0, Message::PeerInfo(_)));
+    assert!(matches!(server.get_message().unwrap().0, Message::PeerInfo(_)));
+
+    // a late datagram and a fragment of a late one are dropped, a fresh one still goes through
+    let late = Message::Data(DataContents { data: Payload::from(b"late".to_vec()), expires_at: Some(1) });
+    let late_fragment = fragment(&vec![7; MAX_DATA_SIZE + 1], 0, Some(1)).unwrap().remove(0);
+    let fresh = Message::Data(DataContents { data: Payload::from(b"fresh".to_vec()), expires_at: Some(DataContents::deadline_after(Duration::from_secs(5))) });
+    for msg in [late, late_fragment, fresh] {
+        let relayed = Message::RelayData(RelayDataContents {
+            session_id: b"expiring".to_vec(),
+            peer_addr: holepuncher,
+            payload: msg.serialize().unwrap(),
+        });
+        client.send_message(&relayed, holepuncher).unwrap();
+    }
+    match server.get_message().unwrap() {
+        (Message::RelayData(contents), _) => match Message::deserialize(&contents.payload).unwrap() {
+            Message::Data(data) => assert_eq!(data.data, b"fresh"[..]),
+            other => panic!("expected the fresh Data, got {:?}", other),
+        },
+        (other, _) => panic!("expected a RelayData, got {:?}", other),
+    }
+}
+
+// Configuration of a holepuncher which requires the given pre-shared key.
+#[cfg(feature = "auth")]
+fn requiring_key(key: &[u8]) -> PassiveHolepuncherConfig {
+    return PassiveHolepuncherConfig {
+        auth_key: Some(key.to_vec()),
+        ..Default::default()
+    };
+}
+
+#[cfg(feature = "auth")]
+#[test]
+fn peers_with_the_key_are_accepted() {
+    let holepuncher = common::spawn_holepuncher_with(requiring_key(b"secret"));
+    let server_config = PassiveServerConfig {
+        auth_key: Some(b"secret".to_vec()),
+        ..Default::default()
+    };
+    let server = common::spawn_server_with(holepuncher, b"private", server_config, Duration::from_secs(2));
+    let client_config = PassiveClientConfig {
+        auth_key: Some(b"secret".to_vec()),
+        ..Default::default()
+    };
+    PassiveClient::with_config(holepuncher, b"private".to_vec(), client_config).unwrap();
+    server.join().unwrap();
+}
+
+#[cfg(feature = "auth")]
+#[test]
+fn signed_metadata_and_candidates_are_accepted() {
+    let holepuncher = common::spawn_holepuncher_with(requiring_key(b"secret"));
+    let server_config = PassiveServerConfig {
+        auth_key: Some(b"secret".to_vec()),
+        metadata: b"2 slots free".to_vec(),
+        gather_candidates: true,
+        ..Default::default()
+    };
+    let server = common::spawn_server_with(holepuncher, b"private", server_config, Duration::from_secs(2));
+    let client_config = PassiveClientConfig {
+        auth_key: Some(b"secret".to_vec()),
+        gather_candidates: true,
+        ..Default::default()
+    };
+    PassiveClient::with_config(holepuncher, b"private".to_vec(), client_config).unwrap();
+    server.join().unwrap();
+}
+
+#[cfg(feature = "auth")]
+#[test]
+fn peers_without_the_key_are_rejected() {
+    let holepuncher = common::spawn_holepuncher_with(requiring_key(b"secret"));
+    for auth_key in [None, Some(b"wrong".to_vec())] {
+        let config = PassiveServerConfig {
+            auth_key,
+            handshake_timeout: Duration::from_secs(1),
+            ..Default::default()
+        };
+        assert!(matches!(PassiveServer::with_config(holepuncher, b"private".to_vec(), config), Err(RuphinError::HandshakeTimeout { .. })));
+    }
+    let _server = PassiveServer::with_config(holepuncher, b"private".to_vec(), PassiveServerConfig {
+        auth_key: Some(b"secret".to_vec()),
+        ..Default::default()
+    }).unwrap();
+    let config = PassiveClientConfig {
+        auth_key: Some(b"wrong".to_vec()),
+        handshake_timeout: Duration::from_secs(1),
+        ..Default::default()
+    };
+    assert!(matches!(PassiveClient::with_config(holepuncher, b"private".to_vec(), config), Err(RuphinError::HandshakeTimeout { .. })));
+}
+
+#[cfg(feature = "auth")]
+#[test]
+fn replayed_registrations_are_rejected() {
+    use std::time::SystemTime;
+
+    let holepuncher = common::spawn_holepuncher_with(requiring_key(b"secret"));
+    let register = Message::Register(RegisterContents {
+        session_id: b"captured".to_vec(),
+        metadata: Vec::new(),
+        candidates: Vec::new(),
+        mac: ruphin::auth::session_mac(b"secret", ruphin::framing::REGISTER, b"captured", &[], &[], SystemTime::now()),
+    });
+    let server = ProtocolSocket::bind("127.0.0.1:0").unwrap();
+    server.send_message(&register, holepuncher).unwrap();
+    assert_eq!(count_answers(&server), 1);
+    // a retransmission from the same source is answered again
+    server.send_message(&register, holepuncher).unwrap();
+    assert_eq!(count_answers(&server), 1);
+
+    let attacker = ProtocolSocket::bind("127.0.0.2:0").unwrap();
+    attacker.send_message(&register, holepuncher).unwrap();
+    assert_eq!(count_answers(&attacker), 0);
+}
+
+#[cfg(feature = "auth")]
+#[test]
+fn stale_registrations_are_rejected() {
+    use std::time::SystemTime;
+
+    let holepuncher = common::spawn_holepuncher_with(requiring_key(b"secret"));
+    let made_at = SystemTime::now() - Duration::from_secs(120);
+    let register = Message::Register(RegisterContents {
+        session_id: b"captured".to_vec(),
+        metadata: Vec::new(),
+        candidates: Vec::new(),
+        mac: ruphin::auth::session_mac(b"secret", ruphin::framing::REGISTER, b"captured", &[], &[], made_at),
+    });
+    let server = ProtocolSocket::bind("127.0.0.1:0").unwrap();
+    server.send_message(&register, holepuncher).unwrap();
+    assert_eq!(count_answers(&server), 0);
+}
+
+#[cfg(feature = "auth")]
+#[test]
+fn registrations_with_altered_candidates_are_rejected() {
+    use std::time::SystemTime;
+
+    let holepuncher = common::spawn_holepuncher_with(requiring_key(b"secret"));
+    let signed: SocketAddr = "10.0.0.2:5".parse().unwrap();
+    let mut register = RegisterContents {
+        session_id: b"captured".to_vec(),
+        metadata: b"meta".to_vec(),
+        candidates: vec![signed],
+        mac: ruphin::auth::session_mac(b"secret", ruphin::framing::REGISTER, b"captured", b"meta", &[signed], SystemTime::now()),
+    };
+    register.candidates = vec!["10.0.0.66:5".parse().unwrap()];
+    let server = ProtocolSocket::bind("127.0.0.1:0").unwrap();
+    server.send_message(&Message::Register(register.clone()), holepuncher).unwrap();
+    assert_eq!(count_answers(&server), 0);
+    // nor may the metadata be altered
+    register.candidates = vec![signed];
+    register.metadata = b"other".to_vec();
+    server.send_message(&Message::Register(register.clone()), holepuncher).unwrap();
+    assert_eq!(count_answers(&server), 0);
+    // while the Register as signed is accepted
+    register.metadata = b"meta".to_vec();
+    server.send_message(&Message::Register(register), holepuncher).unwrap();
+    assert_eq!(count_answers(&server), 1);
+}
+
+#[test]
+fn shutting_the_server_down_removes_the_session() {
+    let store = Arc::new(Mutex::new(InMemorySessionStore::new()));
+    let holepuncher = spawn_with_store(Arc::clone(&store), PassiveHolepuncherConfig::default());
+    let server = PassiveServer::new(holepuncher, b"shut down".to_vec()).unwrap();
+    server.shutdown().unwrap();
+    assert!(common::eventually(Duration::from_secs(1), || store.get(b"shut down").is_none()));
+    assert!(matches!(PassiveClient::new(holepuncher, b"shut down".to_vec()), Err(RuphinError::SessionNotFound)));
+
+    // dropping it does the same
+    let server = PassiveServer::new(holepuncher, b"dropped".to_vec()).unwrap();
+    drop(server);
+    assert!(common::eventually(Duration::from_secs(1), || store.get(b"dropped").is_none()));
+    assert!(matches!(PassiveClient::new(holepuncher, b"dropped".to_vec()), Err(RuphinError::SessionNotFound)));
+}
+
+// Starts a holepuncher with a probe port on loopback.
+fn spawn_with_probe_port() -> SocketAddr {
+    let config = PassiveHolepuncherConfig {
+        probe_addr: Some("127.0.0.1:0".parse().unwrap()),
+        ..Default::default()
+    };
+    return common::spawn_holepuncher_with(config);
+}
+
+#[test]
+fn loopback_is_not_behind_a_nat() {
+    assert_eq!(detect_nat_type(spawn_with_probe_port()).unwrap(), NatType::Open);
+    // without a probe port, Open can still be told apart
+    assert_eq!(detect_nat_type(common::spawn_holepuncher()).unwrap(), NatType::Open);
+}
+
+#[test]
+fn addr_reqs_can_be_answered_from_the_probe_port() {
+    let holepuncher = spawn_with_probe_port();
+    let sock = ProtocolSocket::bind("127.0.0.1:0").unwrap();
+    sock.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+    sock.send_message(&Message::AddrReq(AddrReqContents { change_port: true }), holepuncher).unwrap();
+    let (resp, probe) = sock.get_message().unwrap();
+    match resp {
+        Message::AddrResp(contents) => {
+            assert_eq!(contents.mapped_addr, sock.local_addr().unwrap());
+            assert_eq!(contents.probe_port, Some(probe.port()));
+        },
+        other => panic!("expected an AddrResp, got {:?}", other),
+    }
+    assert_ne!(probe, holepuncher);
+    // asked on the probe port, the other port is the main one
+    sock.send_message(&Message::AddrReq(AddrReqContents { change_port: true }), probe).unwrap();
+    let (_, source) = sock.get_message().unwrap();
+    assert_eq!(source, holepuncher);
+}
+
+#[test]
+fn live_holepuncher_answers_pings() {
+    let rtt = ping_holepuncher(common::spawn_holepuncher(), Duration::from_secs(1)).unwrap();
+    assert!(rtt < Duration::from_secs(1));
+}
+
+#[test]
+fn dead_holepuncher_times_out_pings() {
+    // a bound socket which never answers
+    let dead = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let started = Instant::now();
+    assert!(matches!(ping_holepuncher(dead.local_addr().unwrap(), Duration::from_millis(300)), Err(RuphinError::Timeout)));
+    assert!(started.elapsed() < Duration::from_secs(1));
+}
+
+#[test]
+fn unexpected_messages_reach_the_unhandled_observer() {
+    let mut holepuncher = PassiveHolepuncher::new("127.0.0.1:0").unwrap();
+    let holepuncher_addr = holepuncher.local_addr().unwrap();
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let recorded = Arc::clone(&seen);
+    holepuncher.set_unhandled_observer(move |msg, source| recorded.lock().unwrap().push((msg.clone(), source)));
+    thread::spawn(move || {
+        let _ = holepuncher.serve(None, false);
+    });
+
+    let sock = ProtocolSocket::bind("127.0.0.1:0").unwrap();
+    // an ack is only ever sent by the holepuncher, never to it
+    let ack = RegisterAckContents { session_id: b"unexpected".to_vec(), mapped_addr: None };
+    sock.send_message(&Message::RegisterAck(ack), holepuncher_addr).unwrap();
+    assert!(common::eventually(Duration::from_secs(1), || !seen.lock().unwrap().is_empty()));
+    let seen = seen.lock().unwrap();
+    assert_eq!(seen.len(), 1);
+    assert!(matches!(seen[0].0, Message::RegisterAck(_)));
+    assert_eq!(seen[0].1, sock.local_addr().unwrap());
+}
+
+#[test]
+fn worker_threads_share_the_socket_and_the_store() {
+    let store = Arc::new(RwLock::new(InMemorySessionStore::new()));
+    let mut first = PassiveHolepuncher::with_session_store("127.0.0.1:0", PassiveHolepuncherConfig::default(), Arc::clone(&store)).unwrap();
+    let holepuncher = first.local_addr().unwrap();
+    let mut second = first.try_clone().unwrap();
+    let (first_unhandled, second_unhandled) = (Arc::new(Mutex::new(0)), Arc::new(Mutex::new(0)));
+    let (first_count, second_count) = (Arc::clone(&first_unhandled), Arc::clone(&second_unhandled));
+    first.set_unhandled_observer(move |_, _| *first_count.lock().unwrap() += 1);
+    second.set_unhandled_observer(move |_, _| *second_count.lock().unwrap() += 1);
+    let unhandled = || *first_unhandled.lock().unwrap() + *second_unhandled.lock().unwrap();
+    for mut worker in [first, second] {
+        thread::spawn(move || {
+            let _ = worker.serve(None, false);
+        });
+    }
+
+    // sessions registered through one worker are found by the other
+    let pairs: Vec<_> = (0..6u8).map(|i| thread::spawn(move || {
+        let session_id = vec![b'w', i];
+        let mut server = PassiveServer::new(holepuncher, session_id.clone()).unwrap();
+        let server_port = server.get_port().unwrap();
+        let receiver = thread::spawn(move || loop {
+            if let Some((_, data)) = server.wait_for_data(Some(Duration::from_secs(5)), false).unwrap() {
+                return data;
+            }
+        });
+        let mut client = PassiveClient::new(holepuncher, session_id).unwrap();
+        assert_eq!(client.get_server().port(), server_port);
+        let server_addr = client.get_server();
+        client.send_datagram(server_addr, vec![i]).unwrap();
+        assert_eq!(receiver.join().unwrap(), vec![i]);
+    })).collect();
+    for pair in pairs {
+        pair.join().unwrap();
+    }
+    assert!(common::eventually(Duration::from_secs(1), || store.read().unwrap().is_empty()));
+
+    // every datagram is handled by exactly one of the workers
+    let sock = ProtocolSocket::bind("127.0.0.1:0").unwrap();
+    for _ in 0..200 {
+        sock.send_message(&Message::HelloResp(HelloRespContents::default()), holepuncher).unwrap();
+        thread::sleep(Duration::from_micros(200));
+    }
+    assert!(common::eventually(Duration::from_secs(1), || unhandled() == 200));
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(unhandled(), 200);
+    // and both workers took a share of them
+    assert!(*first_unhandled.lock().unwrap() > 0);
+    assert!(*second_unhandled.lock().unwrap() > 0);
+}
+
+#[test]
+fn worker_threads_keep_their_own_deadlines() {
+    let store = Arc::new(Mutex::new(InMemorySessionStore::new()));
+    let mut first = PassiveHolepuncher::with_session_store("127.0.0.1:0", PassiveHolepuncherConfig::default(), store).unwrap();
+    let mut second = first.try_clone().unwrap();
+    let interrupter = second.interrupt_handle().unwrap();
+    let background = thread::spawn(move || second.serve(None, true));
+
+    // the socket's read timeout is shared, but a short serve still returns on time next to one without a deadline
+    let started = Instant::now();
+    first.serve(Some(Duration::from_millis(200)), false).unwrap();
+    let elapsed = started.elapsed();
+    assert!(elapsed >= Duration::from_millis(200));
+    assert!(elapsed < Duration::from_secs(1));
+
+    interrupter.interrupt().unwrap();
+    background.join().unwrap().unwrap();
+}
+
+#[test]
+fn concurrent_registrations_claim_a_session_once() {
+    let store = Arc::new(RwLock::new(InMemorySessionStore::new()));
+    let registrations: Vec<_> = (0..8u16).map(|i| {
+        let mut store = Arc::clone(&store);
+        thread::spawn(move || {
+            let addr = SocketAddr::from(([127, 0, 0, 1], 1000 + i));
+            return store.try_register(b"contested", addr, &[], &[], Some(1));
+        })
+    }).collect();
+    let outcomes: Vec<_> = registrations.into_iter().map(|registration| registration.join().unwrap()).collect();
+    assert_eq!(outcomes.iter().filter(|outcome| **outcome == Registration::Accepted { new: true }).count(), 1);
+    assert!(outcomes.iter().all(|outcome| *outcome == Registration::Accepted { new: true } || *outcome == Registration::Taken));
+
+    let mut store = Arc::clone(&store);
+    let addr = SocketAddr::from(([127, 0, 0, 1], 2000));
+    assert_eq!(store.try_register(b"another", addr, &[], &[], Some(1)), Registration::Full);
+    let owner = store.get(b"contested").unwrap();
+    assert_eq!(store.try_register(b"contested", owner, &[], &[], Some(1)), Registration::Accepted { new: false });
+}
+
+#[test]
+fn holepuncher_drops_are_reported_with_their_reason() {
+    use ruphin::event::DropReason;
+
+    let config = PassiveHolepuncherConfig {
+        rate_limit: Some(RateLimit { max_requests: 1, per: Duration::from_secs(60) }),
+        ..Default::default()
+    };
+    let mut holepuncher = PassiveHolepuncher::with_config("127.0.0.1:0", config).unwrap();
+    let drops = Arc::new(Mutex::new(Vec::new()));
+    let recorded = Arc::clone(&drops);
+    holepuncher.set_drop_observer(move |reason, _| recorded.lock().unwrap().push(reason));
+    let holepuncher_addr = holepuncher.local_addr().unwrap();
+    let sock = ProtocolSocket::bind("127.0.0.1:0").unwrap();
+    let query = Message::Query(QueryContents { session_id: b"queried".to_vec(), mac: vec![] });
+    sock.send_message(&query, holepuncher_addr).unwrap();
+    sock.send_message(&query, holepuncher_addr).unwrap();
+    // relaying is off by default
+    let relay_data = RelayDataContents { session_id: b"none".to_vec(), peer_addr: holepuncher_addr, payload: vec![] };
+    sock.send_message(&Message::RelayData(relay_data), holepuncher_addr).unwrap();
+    sock.send_message(&Message::Goodbye(GoodbyeContents { session_id: b"queried".to_vec() }), holepuncher_addr).unwrap();
+    sock.send_message(&Message::Pong(PongContents { nonce: 1 }), holepuncher_addr).unwrap();
+    std::net::UdpSocket::bind("127.0.0.1:0").unwrap().send_to(&[0; 20], holepuncher_addr).unwrap();
+    holepuncher.serve(Some(Duration::from_millis(300)), false).unwrap();
+    assert_eq!(*drops.lock().unwrap(), [
+        DropReason::RateLimited,
+        DropReason::UnexpectedMessage,
+        DropReason::WrongSource,
+        DropReason::UnexpectedMessage,
+        DropReason::Malformed,
+    ]);
+}
+
+#[cfg(feature = "auth")]
+#[test]
+fn unsigned_joins_are_reported_as_unauthenticated() {
+    use ruphin::event::DropReason;
+
+    let mut holepuncher = PassiveHolepuncher::with_config("127.0.0.1:0", requiring_key(&[7; 32])).unwrap();
+    let drops = Arc::new(Mutex::new(Vec::new()));
+    let recorded = Arc::clone(&drops);
+    holepuncher.set_drop_observer(move |reason, _| recorded.lock().unwrap().push(reason));
+    let holepuncher_addr = holepuncher.local_addr().unwrap();
+    let sock = ProtocolSocket::bind("127.0.0.1:0").unwrap();
+    sock.send_message(&Message::Join(JoinContents { session_id: b"signed".to_vec(), candidates: vec![], mac: vec![] }), holepuncher_addr).unwrap();
+    holepuncher.serve(Some(Duration::from_millis(200)), false).unwrap();
+    assert_eq!(*drops.lock().unwrap(), [DropReason::Unauthenticated]);
+}
+
+#[test]
+fn migrating_tells_only_the_clients() {
+    let holepuncher = common::spawn_holepuncher();
+    let server = common::register_fake_server(holepuncher, b"moving");
+    let mut clients = Vec::new();
+    for _ in 0..3 {
+        let client = ProtocolSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+        let join = Message::Join(JoinContents { session_id: b"moving".to_vec(), candidates: vec![], mac: vec![] });
+        client.send_message(&join, holepuncher).unwrap();
+        assert!(matches!(client.get_message().unwrap().0, Message::PeerInfo(_)));
+        assert!(matches!(server.get_message().unwrap().0, Message::PeerInfo(_)));
+        clients.push(client);
+    }
+    let new_server = ProtocolSocket::bind("127.0.0.1:0").unwrap();
+    new_server.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+    let new_addr = new_server.local_addr().unwrap();
+
+    let migrate = Message::Migrate(MigrateContents { session_id: b"moving".to_vec(), new_addr, mac: vec![] });
+    server.send_message(&migrate, holepuncher).unwrap();
+    for client in &clients {
+        match client.get_message().unwrap() {
+            (Message::PeerInfo(contents), _) => assert_eq!(contents.peer_addr, new_addr),
+            (other, _) => panic!("expected a PeerInfo, got {:?}", other),
+        }
+    }
+    // the new address is only ever contacted by the clients themselves
+    assert!(new_server.get_message().is_err());
+}
+
+#[test]
+fn check_session_tells_existing_from_missing_sessions() {
+    let holepuncher = common::spawn_holepuncher();
+    let server = common::register_fake_server(holepuncher, b"online");
+    assert!(check_session(holepuncher, b"online", Duration::from_secs(2)).unwrap());
+    assert!(!check_session(holepuncher, b"offline", Duration::from_secs(2)).unwrap());
+    // the server is not told about the check, there is nothing to punch
+    server.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+    match server.get_message() {
+        Err(_) => {},
+        Ok((other, _)) => panic!("expected no message, got {:?}", other),
+    }
+}
+
+#[test]
+fn check_session_times_out_without_a_holepuncher() {
+    let dead = ProtocolSocket::bind("127.0.0.1:0").unwrap();
+    let result = check_session(dead.local_addr().unwrap(), b"online", Duration::from_millis(300));
+    assert!(matches!(result, Err(RuphinError::Timeout)), "{:?}", result);
+    assert!(matches!(check_session(dead.local_addr().unwrap(), b"online", Duration::ZERO), Err(RuphinError::InvalidConfig(_))));
+}
+
+#[test]
+fn buffer_sizes_can_be_configured() {
+    let config = PassiveHolepuncherConfig {
+        recv_buffer_size: Some(65536),
+        send_buffer_size: Some(32768),
+        ..Default::default()
+    };
+    let holepuncher = common::spawn_holepuncher_with(config);
+    assert!(PassiveServer::new(holepuncher, b"buffered".to_vec()).is_ok());
+    // zero sizes are refused rather than handed to the OS
+    assert!(matches!(PassiveHolepuncher::builder().bind("127.0.0.1:0").recv_buffer_size(0).build(), Err(RuphinError::InvalidConfig(_))));
+    assert!(matches!(PassiveHolepuncher::builder().bind("127.0.0.1:0").send_buffer_size(0).build(), Err(RuphinError::InvalidConfig(_))));
+}
+
+#[test]
+fn rejoins_pair_the_client_again_after_the_store_is_lost() {
+    let store = Arc::new(Mutex::new(InMemorySessionStore::new()));
+    let holepuncher = spawn_with_store(Arc::clone(&store), PassiveHolepuncherConfig::default());
+    let server_config = PassiveServerConfig {
+        keepalive_interval: Duration::from_millis(200),
+        ..Default::default()
+    };
+    let server = common::spawn_server_with(holepuncher, b"rejoin", server_config, Duration::from_secs(3));
+    let client_config = PassiveClientConfig {
+        rejoin_interval: Some(Duration::from_millis(300)),
+        ..Default::default()
+    };
+    let mut client = PassiveClient::with_config(holepuncher, b"rejoin".to_vec(), client_config).unwrap();
+    let peer_infos = Arc::new(Mutex::new(0));
+    let counted = Arc::clone(&peer_infos);
+    client.set_observer(move |msg, _| {
+        if let Message::PeerInfo(_) = msg {
+            *counted.lock().unwrap() += 1;
+        }
+    });
+
+    // as if the holepuncher restarted
+    store.lock().unwrap().remove(b"rejoin");
+    let end = Instant::now() + Duration::from_millis(1500);
+    while Instant::now() < end {
+        client.wait_for_event(Some(Duration::from_millis(50)), false).unwrap();
+    }
+    // the server registered again, and the client's Joins found it
+    assert!(store.get(b"rejoin").is_some());
+    assert!(*peer_infos.lock().unwrap() >= 1);
+    server.join().unwrap();
+}
+
+#[test]
+fn serve_accepts_the_longest_timeout() {
+    let mut holepuncher = PassiveHolepuncher::new("127.0.0.1:0").unwrap();
+    let interrupter = holepuncher.interrupt_handle().unwrap();
+    let serving = thread::spawn(move || holepuncher.serve(Some(Duration::MAX), true));
+    thread::sleep(Duration::from_millis(100));
+    interrupter.interrupt().unwrap();
+    serving.join().unwrap().unwrap();
+}
+
+#[test]
+fn full_send_buffer_doesnt_stop_serving() {
+    use std::net::UdpSocket;
+    use std::sync::atomic::{
+        AtomicUsize,
+        Ordering,
+    };
+
+    let full_for = Arc::new(AtomicUsize::new(0));
+    let sock = ProtocolSocket::with_transport(common::CongestedTransport {
+        udp: UdpSocket::bind("127.0.0.1:0").unwrap(),
+        full_for: Arc::clone(&full_for),
+    });
+    let config = PassiveHolepuncherConfig {
+        send_buffer_size: Some(1),
+        ..Default::default()
+    };
+    let mut holepuncher = PassiveHolepuncher::with_socket(sock, config, InMemorySessionStore::new()).unwrap();
+    let holepuncher_addr = holepuncher.local_addr().unwrap();
+    let serving = thread::spawn(move || holepuncher.serve(Some(Duration::from_secs(1)), false));
+
+    let peer = ProtocolSocket::bind("127.0.0.1:0").unwrap();
+    peer.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+    let requests = [
+        Message::HelloReq(HelloReqContents::default()),
+        Message::Register(RegisterContents { session_id: b"congested".to_vec(), metadata: vec![], candidates: vec![], mac: vec![] }),
+        Message::Join(JoinContents { session_id: b"missing".to_vec(), candidates: vec![], mac: vec![] }),
+    ];
+    for request in requests {
+        // the answer to the first request finds the send buffer full, the one to the retry gets through
+        full_for.store(1, Ordering::Relaxed);
+        peer.send_message(&request, holepuncher_addr).unwrap();
+        peer.send_message(&request, holepuncher_addr).unwrap();
+        match peer.get_message().unwrap().0 {
+            Message::HelloResp(_) | Message::RegisterAck(_) | Message::SessionNotFound(_) => {},
+            other => panic!("unexpected answer {:?}", other),
+        }
+    }
+    serving.join().unwrap().unwrap();
+}
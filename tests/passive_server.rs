@@ -0,0 +1,774 @@
+#![cfg(feature = "std")]
+
+mod common;
+
+use std::net::SocketAddr;
+use std::sync::{
+    Arc,
+    Mutex,
+};
+use std::time::{
+    Duration,
+    Instant,
+};
+use ruphin::error::RuphinError;
+use ruphin::messages::*;
+use ruphin::passive_server::*;
+use ruphin::protocol_socket::*;
+
+// Returns the loopback address at which the server can be reached.
+fn loopback_addr(server: &PassiveServer) -> SocketAddr {
+    return SocketAddr::from(([127, 0, 0, 1], server.get_port().unwrap()));
+}
+
+// Binds a socket standing in for a client, which says hello to the server at the given address.
+fn fake_client(server_addr: SocketAddr) -> ProtocolSocket {
+    let sock = ProtocolSocket::bind("127.0.0.1:0").unwrap();
+    sock.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+    say_hello(&sock, server_addr);
+    return sock;
+}
+
+// Sends a HelloReq, which is how clients connect and keep the connection alive.
+fn say_hello(sock: &ProtocolSocket, server_addr: SocketAddr) {
+    sock.send_message(&Message::HelloReq(HelloReqContents::default()), server_addr).unwrap();
+}
+
+// Lets the server handle whatever arrives within the given time.
+fn pump(server: &mut PassiveServer, time: Duration) {
+    let end = Instant::now() + time;
+    while Instant::now() < end {
+        server.wait_for_event(Some(end.saturating_duration_since(Instant::now())), false).unwrap();
+    }
+}
+
+// Returns the payload of the next Data message the socket receives, skipping other messages.
+fn next_data(sock: &ProtocolSocket) -> Vec<u8> {
+    loop {
+        match sock.get_message().unwrap() {
+            (Message::Data(contents), _) => return contents.into_vec(),
+            _ => continue,
+        }
+    }
+}
+
+#[test]
+fn session_id_matches_the_one_registered() {
+    let holepuncher = common::spawn_holepuncher();
+    let server = PassiveServer::new(holepuncher, b"server session".to_vec()).unwrap();
+    assert_eq!(server.get_session_id(), b"server session");
+}
+
+#[test]
+fn silent_clients_drop_off_the_list() {
+    let holepuncher = common::spawn_holepuncher();
+    let config = PassiveServerConfig {
+        client_timeout: Duration::from_millis(300),
+        ..Default::default()
+    };
+    let mut server = PassiveServer::with_config(holepuncher, b"connected clients".to_vec(), config).unwrap();
+    let server_addr = loopback_addr(&server);
+    let chatty = fake_client(server_addr);
+    let silent = fake_client(server_addr);
+    pump(&mut server, Duration::from_millis(100));
+    let mut clients = server.connected_clients();
+    clients.sort();
+    let mut expected = vec![chatty.local_addr().unwrap(), silent.local_addr().unwrap()];
+    expected.sort();
+    assert_eq!(clients, expected);
+
+    for _ in 0..6 {
+        say_hello(&chatty, server_addr);
+        pump(&mut server, Duration::from_millis(100));
+    }
+    assert_eq!(server.connected_clients(), vec![chatty.local_addr().unwrap()]);
+}
+
+#[test]
+fn broadcast_reaches_every_client() {
+    let holepuncher = common::spawn_holepuncher();
+    let mut server = PassiveServer::new(holepuncher, b"broadcast".to_vec()).unwrap();
+    let server_addr = loopback_addr(&server);
+    let clients: Vec<ProtocolSocket> = (0..3).map(|_| fake_client(server_addr)).collect();
+    pump(&mut server, Duration::from_millis(100));
+    assert_eq!(server.connected_clients().len(), 3);
+
+    server.broadcast(b"game state".to_vec()).unwrap();
+    let received: Vec<Vec<u8>> = clients.iter().map(next_data).collect();
+    assert_eq!(received, vec![b"game state".to_vec(); 3]);
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn undecryptable_data_is_dropped() {
+    use ruphin::crypto::DataCipher;
+    use ruphin::event::*;
+
+    let holepuncher = common::spawn_holepuncher();
+    let config = PassiveServerConfig {
+        encryption_key: Some([3; 32]),
+        ..Default::default()
+    };
+    let mut server = PassiveServer::with_config(holepuncher, b"encrypted session".to_vec(), config).unwrap();
+    let drops = Arc::new(Mutex::new(Vec::new()));
+    let recorded = Arc::clone(&drops);
+    server.set_drop_observer(move |reason, _| recorded.lock().unwrap().push(reason));
+    let server_addr = loopback_addr(&server);
+    let client = fake_client(server_addr);
+    pump(&mut server, Duration::from_millis(100));
+
+    let forged = DataCipher::new(&[4; 32]).encrypt(b"forged");
+    let genuine = DataCipher::new(&[3; 32]).encrypt(b"genuine");
+    for data in [forged, genuine] {
+        client.send_message(&Message::Data(DataContents { data: Payload::from(data), expires_at: None }), server_addr).unwrap();
+    }
+    loop {
+        match server.wait_for_event(Some(Duration::from_secs(2)), false).unwrap() {
+            Event::Data(_, data) => {
+                assert_eq!(data, b"genuine");
+                break;
+            },
+            Event::Timeout => panic!("the genuine datagram never arrived"),
+            _ => continue,
+        }
+    }
+    assert_eq!(*drops.lock().unwrap(), vec![DropReason::Undecryptable]);
+}
+
+#[test]
+fn observer_sees_hello_requests() {
+    let holepuncher = common::spawn_holepuncher();
+    let mut server = PassiveServer::new(holepuncher, b"observed session".to_vec()).unwrap();
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let recorded = Arc::clone(&seen);
+    server.set_observer(move |msg, source| recorded.lock().unwrap().push((format!("{:?}", msg), source)));
+    let client = fake_client(loopback_addr(&server));
+
+    assert!(server.wait_for_data(Some(Duration::from_millis(300)), false).unwrap().is_none());
+    let hello = (format!("{:?}", Message::HelloReq(HelloReqContents::default())), client.local_addr().unwrap());
+    assert!(seen.lock().unwrap().contains(&hello));
+}
+
+#[test]
+fn duplicate_reliable_datagrams_are_delivered_once() {
+    let holepuncher = common::spawn_holepuncher();
+    let mut server = PassiveServer::new(holepuncher, b"reliable".to_vec()).unwrap();
+    let server_addr = loopback_addr(&server);
+    let client = fake_client(server_addr);
+    let first = Message::ReliableData(ReliableDataContents { seq: 9, data: vec![1] });
+    let second = Message::ReliableData(ReliableDataContents { seq: 10, data: vec![2] });
+    for msg in [&first, &first, &second] {
+        client.send_message(msg, server_addr).unwrap();
+    }
+
+    let timeout = Some(Duration::from_millis(300));
+    assert_eq!(server.wait_for_data(timeout, false).unwrap().unwrap().1, vec![1]);
+    assert_eq!(server.wait_for_data(timeout, false).unwrap().unwrap().1, vec![2]);
+    assert!(server.wait_for_data(timeout, false).unwrap().is_none());
+    // every copy is acknowledged though, in case an acknowledgement was lost
+    let mut acks = Vec::new();
+    while let Ok((msg, _)) = client.get_message() {
+        if let Message::DataAck(contents) = msg {
+            acks.push(contents.seq);
+        }
+    }
+    assert_eq!(acks, vec![9, 9, 10]);
+}
+
+#[test]
+fn external_addr_is_the_source_the_holepuncher_saw() {
+    let holepuncher = common::spawn_holepuncher();
+    let server = PassiveServer::new(holepuncher, b"external".to_vec()).unwrap();
+    assert_eq!(server.external_addr(), Some(loopback_addr(&server)));
+}
+
+#[test]
+fn drain_discards_queued_datagrams_and_events() {
+    use ruphin::event::Event;
+
+    let holepuncher = common::spawn_holepuncher();
+    let mut server = PassiveServer::new(holepuncher, b"drained".to_vec()).unwrap();
+    let server_addr = loopback_addr(&server);
+    let client = fake_client(server_addr);
+    pump(&mut server, Duration::from_millis(100));
+
+    // a burst of data and another client connecting: the first datagram is returned, the rest become pending events
+    for i in 0..3 {
+        client.send_message(&Message::Data(DataContents { data: Payload::from(vec![i]), expires_at: None }), server_addr).unwrap();
+    }
+    fake_client(server_addr);
+    std::thread::sleep(Duration::from_millis(50));
+    assert!(matches!(server.wait_for_event(Some(Duration::from_secs(1)), false).unwrap(), Event::Data(_, data) if data == [0]));
+    // and some stale datagrams are waiting on the socket
+    for i in 3..5 {
+        client.send_message(&Message::Data(DataContents { data: Payload::from(vec![i]), expires_at: None }), server_addr).unwrap();
+    }
+    std::thread::sleep(Duration::from_millis(50));
+    assert_eq!(server.drain().unwrap(), 2);
+    assert!(matches!(server.wait_for_event(Some(Duration::from_millis(100)), false).unwrap(), Event::Timeout));
+}
+
+// Registers with a holepuncher which never answers, and returns the gaps between the Registers it received.
+fn register_retry_gaps(retry_interval: Duration, max_retry_interval: Duration) -> Vec<Duration> {
+    let silent = ProtocolSocket::bind("127.0.0.1:0").unwrap();
+    let holepuncher = silent.local_addr().unwrap();
+    let listener = std::thread::spawn(move || {
+        silent.set_read_timeout(Some(Duration::from_millis(2500))).unwrap();
+        let mut arrivals = Vec::new();
+        while let Ok((Message::Register(_), _)) = silent.get_message() {
+            arrivals.push(Instant::now());
+        }
+        return arrivals.windows(2).map(|pair| pair[1] - pair[0]).collect::<Vec<_>>();
+    });
+    let config = PassiveServerConfig {
+        handshake_timeout: Duration::from_secs(2),
+        message_timeout: Duration::from_millis(20),
+        retry_interval,
+        max_retry_interval,
+        ..Default::default()
+    };
+    assert!(PassiveServer::with_config(holepuncher, b"unanswered".to_vec(), config).is_err());
+    return listener.join().unwrap();
+}
+
+#[test]
+fn register_retries_back_off() {
+    let gaps = register_retry_gaps(Duration::from_millis(100), Duration::from_millis(800));
+    assert!(gaps.len() >= 4, "only {} retries", gaps.len());
+    for pair in gaps.windows(2).take(3) {
+        assert!(pair[1] > pair[0] + Duration::from_millis(50), "gaps {:?}", gaps);
+    }
+}
+
+#[test]
+fn register_retries_can_be_spaced_evenly() {
+    let gaps = register_retry_gaps(Duration::from_millis(100), Duration::from_millis(100));
+    assert!(gaps.len() >= 12, "only {} retries", gaps.len());
+    assert!(gaps.iter().all(|gap| *gap < Duration::from_millis(200)), "gaps {:?}", gaps);
+}
+
+#[test]
+fn evict_idle_removes_only_stale_clients() {
+    let holepuncher = common::spawn_holepuncher();
+    let mut server = PassiveServer::new(holepuncher, b"eviction".to_vec()).unwrap();
+    let server_addr = loopback_addr(&server);
+    let active = fake_client(server_addr);
+    let stale = fake_client(server_addr);
+    pump(&mut server, Duration::from_millis(100));
+    assert_eq!(server.connected_clients().len(), 2);
+
+    std::thread::sleep(Duration::from_millis(300));
+    say_hello(&active, server_addr);
+    pump(&mut server, Duration::from_millis(100));
+    assert_eq!(server.evict_idle(Duration::from_millis(250)), vec![stale.local_addr().unwrap()]);
+    assert_eq!(server.connected_clients(), vec![active.local_addr().unwrap()]);
+    assert!(server.evict_idle(Duration::from_secs(5)).is_empty());
+}
+
+#[test]
+fn datagrams_reach_clients_by_their_handle() {
+    let holepuncher = common::spawn_holepuncher();
+    let mut server = PassiveServer::new(holepuncher, b"handles".to_vec()).unwrap();
+    let server_addr = loopback_addr(&server);
+    let mut clients = Vec::new();
+    for handle in [&b"alice"[..], &b"bob"[..]] {
+        let sock = ProtocolSocket::bind("127.0.0.1:0").unwrap();
+        sock.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+        sock.send_message(&Message::HelloReq(HelloReqContents { handle: handle.to_vec() }), server_addr).unwrap();
+        clients.push(sock);
+    }
+    pump(&mut server, Duration::from_millis(100));
+    assert_eq!(server.client_by_handle(b"alice"), Some(clients[0].local_addr().unwrap()));
+    assert_eq!(server.client_by_handle(b"bob"), Some(clients[1].local_addr().unwrap()));
+
+    server.send_to_client(b"bob", b"to bob".to_vec()).unwrap();
+    server.send_to_client(b"alice", b"to alice".to_vec()).unwrap();
+    assert_eq!(next_data(&clients[0]), b"to alice");
+    assert_eq!(next_data(&clients[1]), b"to bob");
+    assert!(matches!(server.send_to_client(b"carol", vec![1]), Err(RuphinError::UnknownHandle)));
+}
+
+#[cfg(unix)]
+#[test]
+fn raw_fd_is_the_server_socket() {
+    use std::mem::ManuallyDrop;
+    use std::net::UdpSocket;
+    use std::os::unix::io::{
+        AsRawFd,
+        FromRawFd,
+    };
+
+    let holepuncher = common::spawn_holepuncher();
+    let server = PassiveServer::new(holepuncher, b"raw fd".to_vec()).unwrap();
+    let borrowed = ManuallyDrop::new(unsafe { UdpSocket::from_raw_fd(server.as_raw_fd()) });
+    assert_eq!(borrowed.local_addr().unwrap().port(), server.get_port().unwrap());
+}
+
+#[test]
+fn data_from_peers_without_a_handshake_is_dropped() {
+    use ruphin::event::*;
+
+    let holepuncher = common::spawn_holepuncher();
+    let mut server = PassiveServer::new(holepuncher, b"filtered".to_vec()).unwrap();
+    let drops = Arc::new(Mutex::new(Vec::new()));
+    let recorded = Arc::clone(&drops);
+    server.set_drop_observer(move |reason, source| recorded.lock().unwrap().push((reason, source)));
+    let server_addr = loopback_addr(&server);
+    let stranger = ProtocolSocket::bind("127.0.0.1:0").unwrap();
+    let client = fake_client(server_addr);
+    for (sock, data) in [(&stranger, b"stray"), (&client, b"known")] {
+        sock.send_message(&Message::Data(DataContents { data: Payload::from(data.to_vec()), expires_at: None }), server_addr).unwrap();
+    }
+    loop {
+        match server.wait_for_event(Some(Duration::from_secs(2)), false).unwrap() {
+            Event::Data(source, data) => {
+                assert_eq!(source, client.local_addr().unwrap());
+                assert_eq!(data, b"known");
+                break;
+            },
+            Event::Timeout => panic!("the handshaked peer's datagram never arrived"),
+            _ => continue,
+        }
+    }
+    assert_eq!(*drops.lock().unwrap(), [(DropReason::UnknownPeer, stranger.local_addr().unwrap())]);
+}
+
+#[test]
+fn relayed_data_needs_a_handshake_through_the_relay() {
+    use ruphin::event::*;
+
+    // a bare socket acknowledges the Register, then stands in for the holepuncher relaying to the server
+    let holepuncher = ProtocolSocket::bind("127.0.0.1:0").unwrap();
+    holepuncher.set_read_timeout(Some(Duration::from_secs(3))).unwrap();
+    let holepuncher_addr = holepuncher.local_addr().unwrap();
+    let registering = std::thread::spawn(move || loop {
+        if let (Message::Register(contents), source) = holepuncher.get_message().unwrap() {
+            let ack = Message::RegisterAck(RegisterAckContents { session_id: contents.session_id, mapped_addr: Some(source) });
+            holepuncher.send_message(&ack, source).unwrap();
+            return holepuncher;
+        }
+    });
+    let mut server = PassiveServer::new(holepuncher_addr, b"relayed".to_vec()).unwrap();
+    let holepuncher = registering.join().unwrap();
+    let drops = Arc::new(Mutex::new(Vec::new()));
+    let recorded = Arc::clone(&drops);
+    server.set_drop_observer(move |reason, source| recorded.lock().unwrap().push((reason, source)));
+    let server_addr = loopback_addr(&server);
+    let client = ProtocolSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let stranger = ProtocolSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let relay = |peer_addr: SocketAddr, msg: Message| {
+        let relayed = RelayDataContents { session_id: b"relayed".to_vec(), peer_addr, payload: msg.serialize().unwrap() };
+        holepuncher.send_message(&Message::RelayData(relayed), server_addr).unwrap();
+    };
+
+    // without a handshake, relayed data is dropped like any other
+    relay(stranger, Message::Data(DataContents { data: Payload::from(b"stray".to_vec()), expires_at: None }));
+    pump(&mut server, Duration::from_millis(100));
+    assert_eq!(*drops.lock().unwrap(), [(DropReason::UnknownPeer, stranger)]);
+
+    // the handshake is answered through the relay, after which the client's data gets through
+    relay(client, Message::HelloReq(HelloReqContents::default()));
+    pump(&mut server, Duration::from_millis(100));
+    loop {
+        match holepuncher.get_message().unwrap() {
+            (Message::RelayData(contents), _) => {
+                assert_eq!(contents.peer_addr, client);
+                assert!(matches!(Message::deserialize(&contents.payload), Ok(Message::HelloResp(_))));
+                break;
+            },
+            _ => continue,
+        }
+    }
+    relay(client, Message::Data(DataContents { data: Payload::from(b"known".to_vec()), expires_at: None }));
+    let received = server.wait_for_data(Some(Duration::from_secs(2)), false).unwrap();
+    assert_eq!(received, Some((client, b"known".to_vec())));
+    assert_eq!(drops.lock().unwrap().len(), 1);
+}
+
+#[test]
+fn bursts_are_picked_up_at_once_and_returned_one_by_one() {
+    let holepuncher = common::spawn_holepuncher();
+    let mut server = PassiveServer::new(holepuncher, b"bursty".to_vec()).unwrap();
+    let server_addr = SocketAddr::from(([127, 0, 0, 1], server.get_port().unwrap()));
+    let client = fake_client(server_addr);
+    pump(&mut server, Duration::from_millis(100));
+
+    for i in 0..3 {
+        client.send_message(&Message::Data(DataContents { data: Payload::from(vec![i]), expires_at: None }), server_addr).unwrap();
+    }
+    std::thread::sleep(Duration::from_millis(50));
+    let received_before = server.stats().messages_received;
+    let first = server.wait_for_data(Some(Duration::from_secs(1)), false).unwrap().unwrap().1;
+    // all three were read off the socket by the first call
+    assert_eq!(server.stats().messages_received, received_before + 3);
+    let second = server.wait_for_data(Some(Duration::ZERO), false).unwrap().unwrap().1;
+    let third = server.wait_for_data(Some(Duration::ZERO), false).unwrap().unwrap().1;
+    assert_eq!([first, second, third], [[0], [1], [2]]);
+}
+
+#[test]
+fn expired_data_is_dropped_on_receipt() {
+    use ruphin::event::*;
+
+    let holepuncher = common::spawn_holepuncher();
+    let mut server = PassiveServer::new(holepuncher, b"expiring".to_vec()).unwrap();
+    let drops = Arc::new(Mutex::new(Vec::new()));
+    let recorded = Arc::clone(&drops);
+    server.set_drop_observer(move |reason, _| recorded.lock().unwrap().push(reason));
+    let server_addr = loopback_addr(&server);
+    let client = fake_client(server_addr);
+    pump(&mut server, Duration::from_millis(100));
+
+    // a late datagram, a late one large enough to be fragmented, and a fresh one
+    let late = Message::Data(DataContents { data: Payload::from(b"late".to_vec()), expires_at: Some(1) });
+    let late_fragments = fragment(&vec![7; MAX_DATA_SIZE + 1], 0, Some(1)).unwrap();
+    let fresh = Message::Data(DataContents { data: Payload::from(b"fresh".to_vec()), expires_at: Some(DataContents::deadline_after(Duration::from_secs(5))) });
+    for msg in std::iter::once(late).chain(late_fragments).chain([fresh]) {
+        client.send_message(&msg, server_addr).unwrap();
+    }
+    loop {
+        match server.wait_for_event(Some(Duration::from_secs(2)), false).unwrap() {
+            Event::Data(_, data) => {
+                assert_eq!(data, b"fresh");
+                break;
+            },
+            Event::Timeout => panic!("the fresh datagram never arrived"),
+            _ => continue,
+        }
+    }
+    assert_eq!(*drops.lock().unwrap(), [DropReason::Expired; 3]);
+}
+
+#[test]
+fn holepuncher_which_stops_acking_is_detected() {
+    use ruphin::event::Event;
+
+    // a holepuncher which acknowledges the first Register only, and counts the ones after it
+    let holepuncher = ProtocolSocket::bind("127.0.0.1:0").unwrap();
+    let holepuncher_addr = holepuncher.local_addr().unwrap();
+    let counter = std::thread::spawn(move || {
+        if let (Message::Register(register), source) = holepuncher.get_message().unwrap() {
+            let ack = RegisterAckContents { session_id: register.session_id, mapped_addr: Some(source) };
+            holepuncher.send_message(&Message::RegisterAck(ack), source).unwrap();
+        }
+        holepuncher.set_read_timeout(Some(Duration::from_millis(1500))).unwrap();
+        let mut keepalives = 0;
+        while holepuncher.get_message().is_ok() {
+            keepalives += 1;
+        }
+        return keepalives;
+    });
+    let config = PassiveServerConfig {
+        keepalive_interval: Duration::from_millis(100),
+        holepuncher_timeout: Some(Duration::from_millis(400)),
+        ..Default::default()
+    };
+    let mut server = PassiveServer::with_config(holepuncher_addr, b"abandoned".to_vec(), config).unwrap();
+    let started = Instant::now();
+    let event = server.wait_for_event(Some(Duration::from_secs(2)), false).unwrap();
+    assert!(matches!(event, Event::HolepuncherTimedOut(addr) if addr == holepuncher_addr), "got {:?}", event);
+    assert!(started.elapsed() >= Duration::from_millis(300));
+    assert!(server.holepuncher_last_seen() <= started);
+    // reported once
+    assert!(matches!(server.wait_for_event(Some(Duration::from_millis(300)), false).unwrap(), Event::Timeout));
+    assert!(counter.join().unwrap() >= 3);
+}
+
+#[test]
+fn drops_are_reported_with_their_reason() {
+    use ruphin::event::DropReason;
+
+    let holepuncher = common::spawn_holepuncher();
+    let mut server = PassiveServer::new(holepuncher, b"dropping".to_vec()).unwrap();
+    let drops = Arc::new(Mutex::new(Vec::new()));
+    let recorded = Arc::clone(&drops);
+    server.set_drop_observer(move |reason, source| recorded.lock().unwrap().push((reason, source)));
+    let server_addr = loopback_addr(&server);
+    let peer = ProtocolSocket::bind("127.0.0.1:0").unwrap();
+    peer.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+    let peer_addr = peer.local_addr().unwrap();
+    let expect_drop = |server: &mut PassiveServer, msg: Message, reason: DropReason| {
+        peer.send_message(&msg, server_addr).unwrap();
+        pump(server, Duration::from_millis(100));
+        assert_eq!(drops.lock().unwrap().drain(..).collect::<Vec<_>>(), [(reason, peer_addr)], "after {:?}", msg);
+    };
+
+    let register = RegisterContents { session_id: b"other".to_vec(), metadata: vec![], candidates: vec![], mac: vec![] };
+    expect_drop(&mut server, Message::Register(register), DropReason::UnexpectedMessage);
+    let peer_info = PeerInfoContents { session_id: b"dropping".to_vec(), peer_addr, candidates: vec![], mac: vec![] };
+    expect_drop(&mut server, Message::PeerInfo(peer_info), DropReason::WrongSource);
+    expect_drop(&mut server, Message::Data(DataContents { data: Payload::from(vec![1]), expires_at: None }), DropReason::UnknownPeer);
+    expect_drop(&mut server, Message::Goodbye(GoodbyeContents { session_id: b"other".to_vec() }), DropReason::WrongSession);
+    say_hello(&peer, server_addr);
+    pump(&mut server, Duration::from_millis(100));
+    expect_drop(&mut server, Message::Data(DataContents { data: Payload::from(vec![1]), expires_at: Some(1) }), DropReason::Expired);
+
+    std::net::UdpSocket::bind("127.0.0.1:0").unwrap().send_to(b"not a message", server_addr).unwrap();
+    pump(&mut server, Duration::from_millis(100));
+    assert!(matches!(drops.lock().unwrap()[..], [(DropReason::Malformed, _)]));
+}
+
+#[test]
+fn register_retries_can_be_capped() {
+    use ruphin::multi_session_server::MultiSessionServer;
+
+    // nothing answers on this port
+    let dead = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    dead.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+    let holepuncher = dead.local_addr().unwrap();
+    let count_registers = || {
+        let mut buf = [0u8; MAX_MESSAGE_SIZE];
+        let mut registers = 0;
+        while dead.recv_from(&mut buf).is_ok() {
+            registers += 1;
+        }
+        return registers;
+    };
+    let config = PassiveServerConfig {
+        register_retries: Some(2),
+        retry_interval: Duration::from_millis(100),
+        max_retry_interval: Duration::from_millis(100),
+        ..Default::default()
+    };
+    let started = Instant::now();
+    let result = PassiveServer::with_config(holepuncher, b"capped".to_vec(), config.clone());
+    assert!(matches!(result, Err(RuphinError::HandshakeTimeout { .. })));
+    assert!(started.elapsed() < Duration::from_secs(2), "gave up after {:?}", started.elapsed());
+    assert_eq!(count_registers(), 2);
+
+    let started = Instant::now();
+    assert!(MultiSessionServer::with_config(holepuncher, vec![b"capped".to_vec()], config.clone()).is_err());
+    assert!(started.elapsed() < Duration::from_secs(2), "gave up after {:?}", started.elapsed());
+    assert_eq!(count_registers(), 2);
+
+    let config = PassiveServerConfig {
+        register_retries: Some(0),
+        ..config
+    };
+    assert!(matches!(PassiveServer::with_config(holepuncher, b"capped".to_vec(), config), Err(RuphinError::InvalidConfig(_))));
+}
+
+#[test]
+fn keepalives_follow_the_clock() {
+    use ruphin::clock::MockClock;
+
+    // a fake holepuncher, acknowledging the first Register and then just collecting them
+    let holepuncher = ProtocolSocket::bind("127.0.0.1:0").unwrap();
+    holepuncher.set_read_timeout(Some(Duration::from_secs(3))).unwrap();
+    let holepuncher_addr = holepuncher.local_addr().unwrap();
+    let acking = holepuncher.try_clone().unwrap();
+    let acker = std::thread::spawn(move || {
+        while let Ok((msg, source)) = acking.get_message() {
+            if let Message::Register(contents) = msg {
+                acking.send_message(&Message::RegisterAck(RegisterAckContents { session_id: contents.session_id, mapped_addr: None }), source).unwrap();
+                return;
+            }
+        }
+    });
+    let mut server = PassiveServer::new(holepuncher_addr, b"mock clock".to_vec()).unwrap();
+    acker.join().unwrap();
+    let clock = MockClock::new();
+    server.set_clock(clock.clone());
+    // counts the keepalives which reached the holepuncher by now
+    holepuncher.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+    let keepalives = || {
+        let mut count = 0;
+        while let Ok((msg, _)) = holepuncher.get_message() {
+            if let Message::Register(_) = msg {
+                count += 1;
+            }
+        }
+        return count;
+    };
+
+    server.wait_for_event(Some(Duration::ZERO), false).unwrap();
+    assert_eq!(keepalives(), 0);
+    clock.advance(Duration::from_secs(9));
+    server.wait_for_event(Some(Duration::ZERO), false).unwrap();
+    assert_eq!(keepalives(), 0);
+    // past the default 10 s keepalive interval, without waiting for it
+    clock.advance(Duration::from_secs(2));
+    server.wait_for_event(Some(Duration::ZERO), false).unwrap();
+    assert_eq!(keepalives(), 1);
+}
+
+#[test]
+fn reliable_pushes_complete_once_the_client_acks() {
+    use ruphin::passive_client::PassiveClient;
+
+    let holepuncher = common::spawn_holepuncher();
+    let config = PassiveServerConfig {
+        reliable_timeout: Duration::from_millis(600),
+        ..Default::default()
+    };
+    let mut server = PassiveServer::with_config(holepuncher, b"push".to_vec(), config).unwrap();
+    let joining = std::thread::spawn(move || PassiveClient::new(holepuncher, b"push".to_vec()).unwrap());
+    while !joining.is_finished() {
+        server.wait_for_event(Some(Duration::from_millis(20)), false).unwrap();
+    }
+    let mut client = joining.join().unwrap();
+    let client_addr = server.connected_clients()[0];
+    // the client only starts acking after a while
+    let receiving = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(300));
+        let (_, data) = client.wait_for_data(Some(Duration::from_secs(2)), false).unwrap().unwrap();
+        // retransmissions are not delivered twice
+        assert!(client.wait_for_data(Some(Duration::from_millis(300)), false).unwrap().is_none());
+        return data;
+    });
+    let started = Instant::now();
+    server.send_reliable(client_addr, b"critical".to_vec()).unwrap();
+    assert!(started.elapsed() >= Duration::from_millis(250), "resolved after {:?}", started.elapsed());
+    assert_eq!(receiving.join().unwrap(), b"critical");
+}
+
+#[test]
+fn unacked_reliable_pushes_time_out() {
+    let holepuncher = common::spawn_holepuncher();
+    let config = PassiveServerConfig {
+        reliable_timeout: Duration::from_millis(300),
+        ..Default::default()
+    };
+    let mut server = PassiveServer::with_config(holepuncher, b"unacked push".to_vec(), config).unwrap();
+    // a client which never acks
+    let client = fake_client(loopback_addr(&server));
+    let result = server.send_reliable(client.local_addr().unwrap(), b"critical".to_vec());
+    assert!(matches!(result, Err(RuphinError::Timeout)), "{:?}", result);
+    // it was sent all the same, at least once
+    loop {
+        match client.get_message().unwrap() {
+            (Message::ReliableData(contents), _) => {
+                assert_eq!(contents.data, b"critical");
+                break;
+            },
+            _ => continue,
+        }
+    }
+}
+
+#[test]
+fn clients_which_stop_acking_are_lost() {
+    use ruphin::event::Event;
+
+    let holepuncher = common::spawn_holepuncher();
+    let config = PassiveServerConfig {
+        reliable_timeout: Duration::from_millis(100),
+        max_unacked_reliable: Some(3),
+        ..Default::default()
+    };
+    let mut server = PassiveServer::with_config(holepuncher, b"unacked".to_vec(), config).unwrap();
+    // a client which connects but never acks
+    let client = fake_client(loopback_addr(&server));
+    let client_addr = client.local_addr().unwrap();
+    pump(&mut server, Duration::from_millis(100));
+    assert_eq!(server.connected_clients(), vec![client_addr]);
+
+    for _ in 0..2 {
+        assert!(matches!(server.send_reliable(client_addr, b"x".to_vec()), Err(RuphinError::Timeout)));
+    }
+    assert_eq!(server.wait_for_event(Some(Duration::from_millis(50)), false).unwrap(), Event::Timeout);
+    assert!(matches!(server.send_reliable(client_addr, b"x".to_vec()), Err(RuphinError::Timeout)));
+    // the third send in a row going unacked gives the client up, long before client_timeout
+    assert_eq!(server.wait_for_event(Some(Duration::from_millis(50)), false).unwrap(), Event::PeerLost(client_addr));
+    assert!(server.connected_clients().is_empty());
+}
+
+#[test]
+fn gathered_candidates_cover_every_interface_but_loopback() {
+    // a bare socket stands in for the holepuncher, to see the candidates the Register carries
+    let holepuncher = ProtocolSocket::bind("127.0.0.1:0").unwrap();
+    holepuncher.set_read_timeout(Some(Duration::from_secs(3))).unwrap();
+    let holepuncher_addr = holepuncher.local_addr().unwrap();
+    let registering = std::thread::spawn(move || loop {
+        if let (Message::Register(contents), source) = holepuncher.get_message().unwrap() {
+            let ack = Message::RegisterAck(RegisterAckContents { session_id: contents.session_id, mapped_addr: Some(source) });
+            holepuncher.send_message(&ack, source).unwrap();
+            return contents.candidates;
+        }
+    });
+    let config = PassiveServerConfig {
+        gather_candidates: true,
+        ..Default::default()
+    };
+    let server = PassiveServer::with_config(holepuncher_addr, b"candidates".to_vec(), config).unwrap();
+    let candidates = registering.join().unwrap();
+
+    // the route to the holepuncher is loopback, so only the other interfaces are listed
+    let expected: Vec<std::net::IpAddr> = if_addrs::get_if_addrs().unwrap().iter()
+        .map(|interface| interface.ip())
+        .filter(|ip| ip.is_ipv4() && !ip.is_loopback())
+        .take(MAX_CANDIDATES)
+        .collect();
+    assert_eq!(candidates.iter().map(|candidate| candidate.ip()).collect::<Vec<_>>(), expected);
+    assert!(candidates.iter().all(|candidate| candidate.port() == server.get_port().unwrap()));
+}
+
+#[test]
+fn lan_joins_from_this_host_are_answered_with_the_loopback_address() {
+    let port = ProtocolSocket::bind("0.0.0.0:0").unwrap().local_addr().unwrap().port();
+    let config = PassiveServerConfig {
+        bind_addr: Some(SocketAddr::from(([0, 0, 0, 0], port))),
+        ..Default::default()
+    };
+    let mut server = PassiveServer::lan_only(b"same host".to_vec(), config).unwrap();
+    let client = ProtocolSocket::bind("127.0.0.1:0").unwrap();
+    client.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+    let join = Message::Join(JoinContents { session_id: b"same host".to_vec(), candidates: vec![], mac: vec![] });
+    client.send_message(&join, SocketAddr::from(([127, 0, 0, 1], port))).unwrap();
+    pump(&mut server, Duration::from_millis(100));
+    match client.get_message().unwrap() {
+        (Message::PeerInfo(contents), _) => assert_eq!(contents.peer_addr, SocketAddr::from(([127, 0, 0, 1], port))),
+        other => panic!("expected a PeerInfo, got {:?}", other),
+    }
+}
+
+#[test]
+fn lan_only_servers_leave_the_holepuncher_alone() {
+    use ruphin::event::Event;
+
+    let port = ProtocolSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let config = PassiveServerConfig {
+        bind_addr: Some(SocketAddr::from(([127, 0, 0, 1], port))),
+        keepalive_interval: Duration::from_millis(50),
+        holepuncher_timeout: Some(Duration::from_millis(100)),
+        ..Default::default()
+    };
+    let mut server = PassiveServer::lan_only(b"no holepuncher".to_vec(), config).unwrap();
+    // no keepalives, and no holepuncher to time out
+    let started = Instant::now();
+    while started.elapsed() < Duration::from_millis(400) {
+        let event = server.wait_for_event(Some(Duration::from_millis(100)), false).unwrap();
+        assert!(matches!(event, Event::Timeout), "got {:?}", event);
+    }
+    assert_eq!(server.stats().messages_sent, 0);
+}
+
+#[test]
+fn huge_intervals_and_timeouts_dont_overflow() {
+    use ruphin::passive_client::PassiveClient;
+
+    let holepuncher = common::spawn_holepuncher();
+    let config = PassiveServerConfig {
+        keepalive_interval: Duration::from_secs(u64::MAX / 2),
+        keepalive_jitter: 0.5,
+        holepuncher_timeout: Some(Duration::MAX),
+        client_timeout: Duration::MAX,
+        data_ttl: Some(Duration::MAX),
+        reliable_timeout: Duration::MAX,
+        ..Default::default()
+    };
+    let mut server = PassiveServer::with_config(holepuncher, b"forever".to_vec(), config).unwrap();
+    let joining = std::thread::spawn(move || PassiveClient::new(holepuncher, b"forever".to_vec()).unwrap());
+    while !joining.is_finished() {
+        server.wait_for_event(Some(Duration::from_millis(20)), false).unwrap();
+    }
+    let mut client = joining.join().unwrap();
+    let client_addr = server.connected_clients()[0];
+    let receiving = std::thread::spawn(move || client.wait_for_data(Some(Duration::MAX), false).unwrap());
+    server.send_reliable(client_addr, b"data".to_vec()).unwrap();
+    match receiving.join().unwrap() {
+        Some((_, data)) => assert_eq!(data, b"data"),
+        None => panic!("expected the data, got nothing"),
+    }
+    server.wait_for_event(Some(Duration::from_millis(50)), false).unwrap();
+}
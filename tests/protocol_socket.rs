@@ -0,0 +1,308 @@
+#![cfg(feature = "std")]
+
+mod common;
+
+use std::io::{
+    Error,
+    ErrorKind,
+};
+use std::net::{
+    SocketAddr,
+    UdpSocket,
+};
+use std::sync::Arc;
+use std::sync::atomic::{
+    AtomicUsize,
+    Ordering,
+};
+use std::time::{
+    Duration,
+    Instant,
+};
+use ruphin::error::RuphinError;
+use ruphin::messages::*;
+use ruphin::protocol_socket::*;
+
+// Binds a socket on a loopback port, returning it along with the address to reach it at.
+fn bind_loopback() -> (ProtocolSocket, SocketAddr) {
+    let sock = ProtocolSocket::bind("127.0.0.1:0").unwrap();
+    sock.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+    let addr = sock.local_addr().unwrap();
+    return (sock, addr);
+}
+
+// Returns a Data message carrying the given number of bytes. They hardly compress,
+// so that the size on the wire is the same with and without the compression feature.
+fn data_of_size(size: usize) -> Message {
+    let data = (0..size).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect();
+    return Message::Data(DataContents { data, expires_at: None });
+}
+
+#[test]
+fn too_small_buffer_is_an_error() {
+    let (sender, _) = bind_loopback();
+    let (receiver, receiver_addr) = bind_loopback();
+    let mut buf = [0u8; 50];
+    sender.send_message(&data_of_size(100), receiver_addr).unwrap();
+    assert!(matches!(receiver.get_message_into(&mut buf), Err(ReceiveError::BufferTooSmall)));
+    // the buffer is fine for smaller messages
+    sender.send_message(&data_of_size(10), receiver_addr).unwrap();
+    assert!(matches!(receiver.get_message_into(&mut buf), Ok((Message::Data(_), _))));
+}
+
+#[test]
+fn transient_send_errors_are_not_fatal() {
+    assert!(!SendError::IO(Error::from(ErrorKind::WouldBlock)).is_fatal());
+    assert!(!SendError::IO(Error::from(ErrorKind::Interrupted)).is_fatal());
+    assert!(SendError::IO(Error::from(ErrorKind::PermissionDenied)).is_fatal());
+    assert!(SendError::SerializationFailed.is_fatal());
+}
+
+#[test]
+fn oversized_payloads_are_reported_as_too_large() {
+    let (sock, addr) = bind_loopback();
+    let result = sock.send_message(&data_of_size(MAX_DATA_SIZE + 1), addr);
+    assert!(matches!(result, Err(SendError::MessageTooLarge { size, max }) if size == MAX_DATA_SIZE + 1 && max == MAX_DATA_SIZE));
+    assert!(matches!(RuphinError::from(result.unwrap_err()), RuphinError::DatagramTooLarge { size, max } if size == MAX_DATA_SIZE + 1 && max == MAX_DATA_SIZE));
+    // other messages which can't be serialized are not a matter of fragmenting
+    let result = sock.send_message(&Message::Goodbye(GoodbyeContents { session_id: vec![0; MAX_SESSION_ID_SIZE + 1] }), addr);
+    assert!(matches!(result, Err(SendError::SerializationFailed)));
+}
+
+#[test]
+fn connection_resets_are_not_fatal() {
+    assert!(!ReceiveError::IO(Error::from(ErrorKind::ConnectionReset)).is_fatal());
+    assert!(!ReceiveError::IO(Error::from(ErrorKind::ConnectionRefused)).is_fatal());
+    assert!(!ReceiveError::IO(Error::from(ErrorKind::ConnectionReset)).is_timeout());
+    assert!(ReceiveError::IO(Error::from(ErrorKind::PermissionDenied)).is_fatal());
+
+    // a connected socket learns that its peer is gone from the ICMP error, which the next read reports
+    let (sock, _) = bind_loopback();
+    let gone = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    sock.connect(gone).unwrap();
+    let _ = sock.send_message(&Message::HelloReq(HelloReqContents::default()), gone);
+    let err = sock.get_message().unwrap_err();
+    assert!(!err.is_fatal(), "{:?}", err);
+}
+
+#[test]
+fn try_get_message_returns_immediately_on_an_idle_socket() {
+    let (sock, addr) = bind_loopback();
+    let started = Instant::now();
+    assert!(sock.try_get_message().unwrap().is_none());
+    assert!(started.elapsed() < Duration::from_millis(100));
+
+    sock.send_message(&Message::HelloReq(HelloReqContents::default()), addr).unwrap();
+    assert!(matches!(sock.get_message(), Ok((Message::HelloReq(_), _))));
+    // the read timeout still applies to blocking reads afterwards
+    let started = Instant::now();
+    assert!(sock.get_message().is_err());
+    assert!(started.elapsed() >= Duration::from_millis(400));
+}
+
+#[test]
+fn try_get_message_keeps_the_socket_non_blocking() {
+    let (sock, _) = bind_loopback();
+    sock.set_nonblocking(true).unwrap();
+    assert!(sock.try_get_message().unwrap().is_none());
+    sock.drain().unwrap();
+    let started = Instant::now();
+    assert!(matches!(sock.get_message(), Err(e) if e.is_timeout()));
+    assert!(started.elapsed() < Duration::from_millis(100));
+}
+
+#[test]
+fn ttl_is_applied_to_the_socket() {
+    let (sock, addr) = bind_loopback();
+    sock.set_ttl(7).unwrap();
+    assert_eq!(sock.ttl().unwrap(), 7);
+    // a single message can be sent with another TTL, the socket's is restored afterwards
+    sock.send_message_with_ttl(&Message::HelloReq(HelloReqContents::default()), addr, 2).unwrap();
+    assert_eq!(sock.ttl().unwrap(), 7);
+}
+
+#[test]
+fn stats_count_the_traffic() {
+    let (sock, addr) = bind_loopback();
+    for _ in 0..3 {
+        sock.send_message(&Message::HelloReq(HelloReqContents::default()), addr).unwrap();
+    }
+    std::net::UdpSocket::bind("127.0.0.1:0").unwrap().send_to(b"junk", addr).unwrap();
+    for _ in 0..3 {
+        sock.get_message().unwrap();
+    }
+    assert!(sock.get_message().is_err());
+
+    // a HelloReq without a handle has an empty payload
+    let message_size = frame_size(0) as u64;
+    assert_eq!(sock.stats(), SocketStats {
+        messages_sent: 3,
+        messages_received: 3,
+        bytes_sent: 3 * message_size,
+        bytes_received: 3 * message_size + 4,
+        deserialization_failures: 1,
+    });
+}
+
+#[test]
+fn local_addr_has_the_bound_port() {
+    let sock = ProtocolSocket::bind("0.0.0.0:0").unwrap();
+    let addr = sock.local_addr().unwrap();
+    assert!(addr.ip().is_unspecified());
+    assert_ne!(addr.port(), 0);
+    assert_eq!(sock.get_port().unwrap(), addr.port());
+}
+
+#[test]
+fn connected_socket_ignores_third_parties() {
+    let (sock, addr) = bind_loopback();
+    let (peer, peer_addr) = bind_loopback();
+    let (third_party, _) = bind_loopback();
+    sock.connect(peer_addr).unwrap();
+    third_party.send_message(&Message::HelloReq(HelloReqContents::default()), addr).unwrap();
+    peer.send_message(&Message::HelloResp(HelloRespContents::default()), addr).unwrap();
+    assert!(matches!(sock.get_message(), Ok((Message::HelloResp(_), source)) if source == peer_addr));
+    assert!(sock.get_message().is_err());
+
+    sock.send_message_connected(&Message::HelloReq(HelloReqContents::default())).unwrap();
+    assert!(matches!(peer.get_message(), Ok((Message::HelloReq(_), source)) if source == addr));
+}
+
+#[test]
+fn oversized_datagrams_are_skipped() {
+    let (sender, _) = bind_loopback();
+    let (receiver, receiver_addr) = bind_loopback();
+    let size = MAX_MESSAGE_SIZE + 1;
+    let mut oversized = vec![0u8; size];
+    oversized[..2].copy_from_slice(&MAGIC);
+    oversized[2..4].copy_from_slice(&(size as u16).to_be_bytes());
+    sender.send_bytes(&oversized, receiver_addr).unwrap();
+    sender.send_message(&data_of_size(10), receiver_addr).unwrap();
+
+    match receiver.get_message() {
+        Err(e) => assert!(matches!(e, ReceiveError::TooLarge) && !e.is_fatal()),
+        Ok((msg, _)) => panic!("expected the oversized datagram to be rejected, got {:?}", msg),
+    }
+    assert!(matches!(receiver.get_message(), Ok((Message::Data(_), _))));
+}
+
+#[test]
+fn blocking_send_waits_out_a_full_send_buffer() {
+    let (receiver, receiver_addr) = bind_loopback();
+    let full_for = Arc::new(AtomicUsize::new(2));
+    let sender = ProtocolSocket::with_transport(common::CongestedTransport {
+        udp: UdpSocket::bind("127.0.0.1:0").unwrap(),
+        full_for: Arc::clone(&full_for),
+    });
+    let msg = data_of_size(10);
+    assert!(matches!(sender.send_message(&msg, receiver_addr), Err(SendError::IO(e)) if e.kind() == ErrorKind::WouldBlock));
+    sender.send_message_blocking(&msg, receiver_addr, Duration::from_secs(1)).unwrap();
+    assert_eq!(full_for.load(Ordering::Relaxed), 0);
+    assert!(matches!(receiver.get_message(), Ok((Message::Data(_), _))));
+
+    // once the budget is used up, the error is returned after all
+    full_for.store(usize::MAX, Ordering::Relaxed);
+    let started = Instant::now();
+    assert!(sender.send_message_blocking(&msg, receiver_addr, Duration::from_millis(100)).is_err());
+    assert!(started.elapsed() >= Duration::from_millis(100));
+}
+
+#[test]
+fn drain_discards_every_queued_datagram() {
+    let (receiver, receiver_addr) = bind_loopback();
+    let (sender, _) = bind_loopback();
+    for _ in 0..5 {
+        sender.send_message(&Message::HelloReq(HelloReqContents::default()), receiver_addr).unwrap();
+    }
+    std::thread::sleep(Duration::from_millis(50));
+    assert_eq!(receiver.drain().unwrap(), 5);
+    receiver.set_nonblocking(true).unwrap();
+    assert!(receiver.try_get_message().unwrap().is_none());
+    receiver.set_nonblocking(false).unwrap();
+    assert_eq!(receiver.drain().unwrap(), 0);
+    // messages sent afterwards arrive as usual
+    sender.send_message(&Message::HelloResp(HelloRespContents::default()), receiver_addr).unwrap();
+    assert!(matches!(receiver.get_message(), Ok((Message::HelloResp(_), _))));
+}
+
+#[test]
+fn messages_decode_from_the_reused_buffer() {
+    let (sender, _) = bind_loopback();
+    let (receiver, receiver_addr) = bind_loopback();
+    // a short message after a long one must not pick up the long one's leftovers
+    for data in [vec![1; 1000], vec![2; 3]] {
+        sender.send_message(&Message::Data(DataContents { data: Payload::from(data.clone()), expires_at: None }), receiver_addr).unwrap();
+        match receiver.get_message().unwrap() {
+            (Message::Data(contents), _) => assert_eq!(contents.data, data),
+            (other, _) => panic!("expected Data, got {:?}", other),
+        }
+    }
+}
+
+#[test]
+fn threads_can_receive_on_the_same_socket_at_once() {
+    let (sender, _) = bind_loopback();
+    let (receiver, receiver_addr) = bind_loopback();
+    let receiver = Arc::new(receiver);
+    let other_receiver = Arc::clone(&receiver);
+    // blocks in get_message, holding the shared buffer, while the main thread receives too
+    let other = std::thread::spawn(move || other_receiver.get_message().unwrap().0.type_id());
+    std::thread::sleep(Duration::from_millis(100));
+    sender.send_message(&Message::HelloReq(HelloReqContents::default()), receiver_addr).unwrap();
+    sender.send_message(&Message::HelloResp(HelloRespContents::default()), receiver_addr).unwrap();
+    let mut received = vec![receiver.get_message().unwrap().0.type_id(), other.join().unwrap()];
+    received.sort();
+    assert_eq!(received, [HELLO_REQ, HELLO_RESP]);
+}
+
+#[cfg(unix)]
+#[test]
+fn raw_fd_is_the_socket_receiving_the_datagrams() {
+    use std::mem::ManuallyDrop;
+    use std::os::unix::io::{
+        AsRawFd,
+        FromRawFd,
+    };
+
+    let (sender, _) = bind_loopback();
+    let (receiver, receiver_addr) = bind_loopback();
+    // borrow the fd as a UdpSocket, without closing it when done
+    let borrowed = ManuallyDrop::new(unsafe { UdpSocket::from_raw_fd(receiver.as_raw_fd()) });
+    assert_eq!(borrowed.local_addr().unwrap(), receiver_addr);
+    sender.send_message(&data_of_size(10), receiver_addr).unwrap();
+    // readable, as a poller would report, and the datagram is still there for the socket to receive
+    let mut buf = [0u8; 64];
+    assert!(borrowed.peek_from(&mut buf).is_ok());
+    assert!(matches!(receiver.try_get_message(), Ok(Some((Message::Data(_), _)))));
+}
+
+#[test]
+fn reachable_addr_maps_between_address_families() {
+    let dual_stack = ProtocolSocket::bind("[::]:0").unwrap();
+    assert_eq!(dual_stack.reachable_addr("1.2.3.4:5".parse().unwrap()), Some("[::ffff:1.2.3.4]:5".parse().unwrap()));
+    let ipv4 = ProtocolSocket::bind("0.0.0.0:0").unwrap();
+    assert_eq!(ipv4.reachable_addr("[::ffff:1.2.3.4]:5".parse().unwrap()), Some("1.2.3.4:5".parse().unwrap()));
+    assert_eq!(ipv4.reachable_addr("1.2.3.4:5".parse().unwrap()), Some("1.2.3.4:5".parse().unwrap()));
+    assert_eq!(ipv4.reachable_addr("[::1]:5".parse().unwrap()), None);
+}
+
+#[test]
+fn batches_arrive_as_one_datagram() {
+    let (sender, _) = bind_loopback();
+    let (receiver, receiver_addr) = bind_loopback();
+    let msgs = [Message::Ping(PingContents { nonce: 7 }), data_of_size(10)];
+    sender.send_batch(&msgs, receiver_addr).unwrap();
+    let (received, _) = receiver.get_messages().unwrap();
+    assert_eq!(format!("{:?}", received), format!("{:?}", msgs));
+    assert_eq!(receiver.stats().messages_received, 2);
+}
+
+#[test]
+fn buffer_sizes_read_back_at_least_as_large_as_requested() {
+    let (sock, _) = bind_loopback();
+    // small enough not to be clamped by the OS's limits, which Linux doubles on top
+    sock.set_recv_buffer_size(65536).unwrap();
+    sock.set_send_buffer_size(32768).unwrap();
+    assert!(sock.recv_buffer_size().unwrap() >= 65536, "got {}", sock.recv_buffer_size().unwrap());
+    assert!(sock.send_buffer_size().unwrap() >= 32768, "got {}", sock.send_buffer_size().unwrap());
+}
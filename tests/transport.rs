@@ -0,0 +1,82 @@
+#![cfg(feature = "std")]
+
+use std::net::SocketAddr;
+use std::thread;
+use std::time::{Duration, Instant};
+use ruphin::clock::*;
+use ruphin::messages::*;
+use ruphin::passive_client::*;
+use ruphin::passive_holepuncher::*;
+use ruphin::passive_server::*;
+use ruphin::protocol_socket::ProtocolSocket;
+use ruphin::transport::*;
+
+// Binds a ProtocolSocket at the given address of the network.
+fn bind(network: &MemoryNetwork, addr: &str) -> ProtocolSocket {
+    let sock = ProtocolSocket::with_transport(network.bind(addr.parse().unwrap()).unwrap());
+    sock.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+    return sock;
+}
+
+#[test]
+fn datagrams_reach_the_bound_address() {
+    let network = MemoryNetwork::new();
+    let first = bind(&network, "10.0.0.1:1000");
+    let second = bind(&network, "10.0.0.2:0");
+    let second_addr = second.local_addr().unwrap();
+    assert_eq!(second_addr.ip().to_string(), "10.0.0.2");
+    assert_ne!(second_addr.port(), 0);
+
+    first.send_message(&Message::HelloReq(HelloReqContents::default()), second_addr).unwrap();
+    let (msg, source) = second.get_message().unwrap();
+    assert!(matches!(msg, Message::HelloReq(_)));
+    assert_eq!(source, "10.0.0.1:1000".parse().unwrap());
+    // nobody listens there
+    first.send_message(&Message::HelloReq(HelloReqContents::default()), "10.0.0.3:1000".parse().unwrap()).unwrap();
+    assert!(second.get_message().is_err());
+    assert!(network.bind("10.0.0.1:1000".parse().unwrap()).is_err());
+}
+
+#[test]
+fn handshake_succeeds_despite_heavy_loss() {
+    let clock = MockClock::new();
+    let virtual_start = clock.now();
+    let real_start = Instant::now();
+    let network = MemoryNetwork::with_clock(clock.clone());
+    network.set_loss(0.5, 42);
+    let holepuncher_addr: SocketAddr = "10.0.0.1:3478".parse().unwrap();
+    let mut holepuncher = PassiveHolepuncher::with_socket(bind(&network, "10.0.0.1:3478"), PassiveHolepuncherConfig::default(), InMemorySessionStore::new()).unwrap();
+    thread::spawn(move || {
+        let _ = holepuncher.serve(None, false);
+    });
+
+    let config = PassiveServerConfig {
+        retry_interval: Duration::from_millis(200),
+        max_retry_interval: Duration::from_millis(400),
+        message_timeout: Duration::from_millis(200),
+        ..Default::default()
+    };
+    let mut server = PassiveServer::with_socket(bind(&network, "10.0.0.2:0"), holepuncher_addr, b"lossy".to_vec(), config).unwrap();
+    let receiver = thread::spawn(move || loop {
+        if let Some((_, data)) = server.wait_for_data(Some(Duration::from_secs(10)), false).unwrap() {
+            return data;
+        }
+    });
+    let config = PassiveClientConfig {
+        retry_interval: Duration::from_millis(200),
+        max_retry_interval: Duration::from_millis(400),
+        message_timeout: Duration::from_millis(200),
+        hello_retries: 100,
+        ..Default::default()
+    };
+    let mut client = PassiveClient::with_socket(bind(&network, "10.0.0.3:0"), vec![holepuncher_addr], b"lossy".to_vec(), config).unwrap();
+    let server_addr = client.get_server();
+    assert_eq!(server_addr.ip().to_string(), "10.0.0.2");
+    for _ in 0..50 {
+        client.send_datagram(server_addr, b"through".to_vec()).unwrap();
+    }
+    assert_eq!(receiver.join().unwrap(), b"through");
+    assert!(network.dropped() > 0);
+    // the retries waited on the network's clock, which ran ahead of real time
+    assert!(clock.now() - virtual_start > real_start.elapsed());
+}